@@ -1,14 +1,21 @@
+use std::cell::RefCell;
 use std::cmp;
-use rand::Rng;
+use clap::{Parser, ValueEnum};
+use rand::{Rng, SeedableRng, StdRng};
 use rand::distributions::{Weighted, WeightedChoice, IndependentSample};
 use tcod::colors;
 use tcod::console::*;
-use tcod::input::{self, Key, Event, Mouse};
+use tcod::input::{self, Key, KeyCode, Event, Mouse};
 use tcod::map::{FovAlgorithm, Map as FovMap};
+use tcod::pathfinding::AStar;
 
-use std::io::{Read, Write};
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{BufReader, LineWriter, Read, Write};
+use std::fs::{File, OpenOptions};
 use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
 
 const MAP_WIDTH: i32 = 80;
 const MAP_HEIGHT: i32 = 43;
@@ -32,21 +39,155 @@ struct Tile {
     blocked: bool,
     block_sight: bool,
     explored: bool,
+    #[serde(default)]
+    water: bool,
+    // Turns of fire left on a burning bush
+    #[serde(default)]
+    burning: i32,
+    // Spun by the spiders over the floor, catches whoever walks in
+    #[serde(default)]
+    web: bool,
+    #[serde(default)]
+    cloud: Option<Cloud>,
+    // Turns until the flood water on the tile drains away
+    #[serde(default)]
+    flooded: i32,
+    // What was on the tile when the player last saw it
+    #[serde(default)]
+    glimpse: Option<Glimpse>,
+}
+
+/// An object as the player remembers it, drawn dimmed out of sight
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Glimpse {
+    char: char,
+    color: colors::Color,
+    // Likely gone by now, so drawn fainter
+    monster: bool,
 }
 
 impl Tile {
     pub fn empty() -> Self {
-        Tile{ blocked: false, block_sight: false, explored: false, }
+        Tile{
+            blocked: false, block_sight: false, explored: false, water: false, burning: 0, web: false, cloud: None,
+            flooded: 0, glimpse: None,
+        }
     }
     pub fn wall() -> Self {
-        Tile{ blocked: true, block_sight: true, explored: false, }
+        Tile{ blocked: true, block_sight: true, ..Tile::empty() }
     }
     pub fn bushes() -> Self {
-        Tile{ blocked: false, block_sight: true, explored: false, }
+        Tile{ blocked: false, block_sight: true, ..Tile::empty() }
+    }
+    pub fn water() -> Self {
+        Tile{ water: true, ..Tile::empty() }
+    }
+
+    pub fn is_bush(&self) -> bool {
+        !self.blocked && self.block_sight
+    }
+
+    /// Blocks the sight, on its own or with the smoke over it
+    pub fn opaque(&self) -> bool {
+        self.block_sight || self.cloud.is_some_and(|cloud| cloud.kind.blocks_sight())
+    }
+}
+
+/// The tiles of a level, stored row by row and indexed by (x, y)
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "StoredMap")]
+struct GameMap {
+    width: i32,
+    height: i32,
+    tiles: Vec<Tile>,
+}
+
+impl GameMap {
+    pub fn new(width: i32, height: i32, tile: Tile) -> Self {
+        GameMap { width, height, tiles: vec![tile; (width * height) as usize] }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn in_bounds(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && x < self.width && y < self.height
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> Option<&Tile> {
+        if self.in_bounds(x, y) {
+            Some(&self.tiles[self.offset(x, y)])
+        } else {
+            None
+        }
+    }
+
+    /// Every position on the map, row by row
+    pub fn positions(&self) -> impl Iterator<Item = (i32, i32)> {
+        let (width, height) = (self.width, self.height);
+        (0..height).flat_map(move |y| (0..width).map(move |x| (x, y)))
+    }
+
+    pub fn tiles(&self) -> impl Iterator<Item = &Tile> {
+        self.tiles.iter()
+    }
+
+    pub fn tiles_mut(&mut self) -> impl Iterator<Item = &mut Tile> {
+        self.tiles.iter_mut()
+    }
+
+    fn offset(&self, x: i32, y: i32) -> usize {
+        assert!(self.in_bounds(x, y), "({}, {}) is off the map", x, y);
+        (y * self.width + x) as usize
+    }
+}
+
+impl std::ops::Index<(i32, i32)> for GameMap {
+    type Output = Tile;
+
+    fn index(&self, (x, y): (i32, i32)) -> &Tile {
+        &self.tiles[self.offset(x, y)]
+    }
+}
+
+impl std::ops::IndexMut<(i32, i32)> for GameMap {
+    fn index_mut(&mut self, (x, y): (i32, i32)) -> &mut Tile {
+        let offset = self.offset(x, y);
+        &mut self.tiles[offset]
     }
 }
 
-type Map = Vec<Vec<Tile>>;
+/// Saves made before the flat map kept the tiles column by column
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredMap {
+    Flat { width: i32, height: i32, tiles: Vec<Tile> },
+    Columns(Vec<Vec<Tile>>),
+}
+
+impl From<StoredMap> for GameMap {
+    fn from(stored: StoredMap) -> Self {
+        match stored {
+            StoredMap::Flat { width, height, tiles } => GameMap { width, height, tiles },
+            StoredMap::Columns(columns) => {
+                let width = columns.len() as i32;
+                let height = columns.first().map_or(0, |column| column.len() as i32);
+                let mut map = GameMap::new(width, height, Tile::wall());
+                for (x, column) in columns.into_iter().enumerate() {
+                    for (y, tile) in column.into_iter().enumerate() {
+                        map[(x as i32, y as i32)] = tile;
+                    }
+                }
+                map
+            }
+        }
+    }
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 struct Fighter {
@@ -54,17 +195,40 @@ struct Fighter {
     base_max_hp: i32,
     base_defence: i32,
     base_power: i32,
+    #[serde(default)]
+    attributes: Attributes,
     xp: i32,
     on_death: DeathCallback,
 }
 
+/// Strength adds to melee damage, dexterity helps to dodge and to aim, intelligence powers the spells,
+/// perception spots and disarms traps
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Attributes {
+    strength: i32,
+    dexterity: i32,
+    intelligence: i32,
+    #[serde(default)]
+    perception: i32,
+}
+
+impl Attributes {
+    fn plus(self, other: Attributes) -> Attributes {
+        Attributes {
+            strength: self.strength + other.strength,
+            dexterity: self.dexterity + other.dexterity,
+            intelligence: self.intelligence + other.intelligence,
+            perception: self.perception + other.perception,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 enum Ai {
     Basic,
-    Confused {
-        previous_ai: Box<Ai>,
-        num_turns: i32,
-    },
+    Companion,
+    // Runs for the stairs to get away
+    Flee,
 }
 
 
@@ -83,6 +247,241 @@ enum Item {
     Sword,
     Shield,
     Helmet,
+    Idol,
+    RemoveCurse,
+    EnchantWeapon,
+    EnchantArmor,
+    RepairKit,
+    Bow,
+    Arrow,
+    Teleport,
+    Blink,
+    Strength,
+    Toughness,
+    Agility,
+    Intellect,
+    Antidote,
+    Speed,
+    Invisibility,
+    Armor,
+    Boots,
+    Gloves,
+    Ring,
+    RingOfRegeneration,
+    RingOfSeeing,
+    RingOfSustenance,
+    RingOfTeleportation,
+    Corpse,
+    Torch,
+    StinkingCloud,
+    Pickaxe,
+    WandOfDigging,
+    Bag,
+    // Whatever goes in weighs a fraction of what it should
+    BagOfHolding,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Trap {
+    Spike,
+    PoisonDart,
+    Teleport,
+    Alarm,
+    // Lets out a cloud of poison gas
+    Gas,
+}
+
+/// Friendly characters the player can talk to
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Npc {
+    Shopkeeper,
+    Healer,
+    QuestGiver,
+    // Met in the dungeon, talks according to the dialogue file
+    Wanderer,
+    // Locked in a cage, follows the player once freed
+    Prisoner,
+}
+
+/// Who fights whom
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum Faction {
+    // Townsfolk and everything that is not a creature
+    #[default]
+    Neutral,
+    Player,
+    Vermin,
+    Orcs,
+    Undead,
+    // Angered townsfolk, only the player is their enemy
+    Outlaw,
+}
+
+impl Faction {
+    pub fn hostile_to(self, other: Faction) -> bool {
+        use Faction::*;
+        match (self, other) {
+            (Neutral, _) | (_, Neutral) => false,
+            (Outlaw, Player) | (Player, Outlaw) => true,
+            (Outlaw, _) | (_, Outlaw) => false,
+            (Player, _) | (_, Player) => self != other,
+            // Orcs keep vermin around, the undead hate all the living
+            (Orcs, Undead) | (Undead, Orcs) => true,
+            (Vermin, Undead) | (Undead, Vermin) => true,
+            _ => false,
+        }
+    }
+}
+
+fn monster_faction(name: &str) -> Faction {
+    match name {
+        "rat" | "leech" | "fungus" | "slime" | "snake" | "spider" | "mimic" | "rat queen" => Faction::Vermin,
+        "skeleton" | "zombie" | "ghost" | "bone warden" | "shadow" => Faction::Undead,
+        "thief" => Faction::Outlaw,
+        _ => Faction::Orcs,
+    }
+}
+
+/// How often a creature acts compared to the player
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum SpeedTier {
+    // Every other turn
+    Slow,
+    #[default]
+    Normal,
+    // Twice a turn
+    Fast,
+}
+
+impl SpeedTier {
+    fn name(self) -> &'static str {
+        match self {
+            SpeedTier::Slow => "slow",
+            SpeedTier::Normal => "normal speed",
+            SpeedTier::Fast => "fast",
+        }
+    }
+}
+
+/// What a creature can move through
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum Movement {
+    #[default]
+    Walk,
+    // Walls don't stop it, only the edge of the map and other creatures do
+    Phase,
+}
+
+/// Champion monsters are tougher versions of the regular ones, with a twist
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Affix {
+    // Acts twice a turn, but drops its guard
+    Frenzied,
+    // Arrows and thrown things glance off it
+    Armored,
+    // Poisons with every hit
+    Venomous,
+}
+
+impl Affix {
+    const ALL: [Affix; 3] = [Affix::Frenzied, Affix::Armored, Affix::Venomous];
+
+    fn name(self) -> &'static str {
+        match self {
+            Affix::Frenzied => "Frenzied",
+            Affix::Armored => "Armored",
+            Affix::Venomous => "Venomous",
+        }
+    }
+
+    fn color(self) -> colors::Color {
+        match self {
+            Affix::Frenzied => colors::FLAME,
+            Affix::Armored => colors::LIGHT_BLUE,
+            Affix::Venomous => colors::CHARTREUSE,
+        }
+    }
+
+    /// Changes to the max HP, defence and power of the monster
+    fn modify(self, fighter: &mut Fighter) {
+        match self {
+            Affix::Frenzied => {
+                fighter.base_power += 2;
+                fighter.base_defence = cmp::max(fighter.base_defence - 1, 0);
+            }
+            Affix::Armored => {
+                fighter.base_max_hp += fighter.base_max_hp / 2;
+                fighter.base_defence += 3;
+            }
+            Affix::Venomous => {
+                fighter.base_max_hp += fighter.base_max_hp / 4;
+                fighter.base_power += 1;
+            }
+        }
+        fighter.hp = fighter.base_max_hp;
+        fighter.xp *= 2;
+    }
+}
+
+/// The trick a vault boss has up its sleeve
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum BossAbility {
+    // Spits poison gas over the player
+    Plague,
+    // Heals a little every now and then
+    Regenerate,
+    // Jumps next to the player from afar
+    ShadowStep,
+}
+
+/// What a monster may leave behind besides its corpse
+#[derive(Clone, Copy, Debug)]
+enum Loot {
+    // Up to this many coins per dungeon level
+    Gold(i32),
+    Item(Item),
+    // Worn from the start and fought with, dropped on death
+    Gear(Item),
+}
+
+/// Chance in percent for every drop, rolled separately
+fn monster_loot(name: &str) -> Vec<(u32, Loot)> {
+    match name {
+        "Orc" => vec![(40, Loot::Gold(10)), (10, Loot::Gear(Item::Sword)), (10, Loot::Item(Item::Heal))],
+        "Troll" => vec![(60, Loot::Gold(25)), (10, Loot::Gear(Item::Helmet)), (15, Loot::Item(Item::Heal))],
+        "Gorbash the Sewer King" => vec![(100, Loot::Gold(50)), (50, Loot::Gear(Item::Helmet))],
+        "Gnawmother the Rat Queen" | "The Bone Warden" | "Vethis the Shadow" => {
+            vec![(100, Loot::Gold(80)), (100, Loot::Item(Item::Heal))]
+        }
+        "Skeleton" => vec![(20, Loot::Gold(10)), (15, Loot::Gear(Item::Sword)), (10, Loot::Gear(Item::Shield))],
+        "Sewer snake" | "Cave spider" => vec![(10, Loot::Item(Item::Antidote))],
+        _ => vec![],
+    }
+}
+
+/// Chests and other objects that hold items
+#[derive(Debug, Serialize, Deserialize)]
+struct Container {
+    items: Vec<Object>,
+    locked: bool,
+    trap: Option<Trap>,
+}
+
+/// Fixtures that do something when the player uses them where they stand
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Furniture {
+    // Doesn't answer prayers again before the turn
+    Altar { ready_at: i32 },
+}
+
+/// What praying at an altar brings, for better or worse
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Prayer {
+    Healing,
+    Blessing,
+    Silence,
+    Curse,
+    Guardians,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
@@ -92,6 +491,180 @@ struct Equipment {
     power_bonus: i32,
     defence_bonus: i32,
     max_hp_bonus: i32,
+    // Can't be taken off once equipped
+    #[serde(default)]
+    cursed: bool,
+    // Already added to the bonuses, kept to show it and to cap it
+    #[serde(default)]
+    enchantment: i32,
+    // Broken equipment gives no bonuses until repaired
+    #[serde(default = "old_durability")]
+    durability: i32,
+    #[serde(default = "old_durability")]
+    max_durability: i32,
+    // Magic that works every turn while worn, on top of the bonuses
+    #[serde(default)]
+    effect: Option<PassiveEffect>,
+    #[serde(default)]
+    attributes: Attributes,
+}
+
+/// How worn the gear from the saves made before the wear and tear is
+fn old_durability() -> i32 {
+    50
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum PassiveEffect {
+    Regeneration,
+    SeeInvisible,
+    SustainHunger,
+    Teleportitis,
+    // Lights up the dark levels around the wearer
+    Light,
+}
+
+impl Equipment {
+    pub fn condition(&self) -> &'static str {
+        if self.durability <= 0 {
+            "broken"
+        } else if self.durability * 2 < self.max_durability {
+            "damaged"
+        } else if self.durability < self.max_durability {
+            "worn"
+        } else {
+            "good"
+        }
+    }
+
+    /// Every bonus with its name, broken equipment gives none
+    fn bonus_list(&self) -> [(i32, &'static str); 7] {
+        let working = if self.durability > 0 { 1 } else { 0 };
+        [
+            (self.power_bonus * working, "power"),
+            (self.defence_bonus * working, "defence"),
+            (self.max_hp_bonus * working, "HP"),
+            (self.attributes.strength * working, "str"),
+            (self.attributes.dexterity * working, "dex"),
+            (self.attributes.intelligence * working, "int"),
+            (self.attributes.perception * working, "per"),
+        ]
+    }
+
+    pub fn bonuses(&self) -> String {
+        describe_bonuses(self.bonus_list().iter().cloned())
+    }
+
+    /// What wearing this instead of the other would change
+    pub fn bonuses_over(&self, other: Option<&Equipment>) -> String {
+        let others = other.map_or([(0, ""); 7], |other| other.bonus_list());
+        let mine = self.bonus_list();
+        describe_bonuses(mine.iter().zip(others.iter()).map(|(&(value, name), &(other, _))| (value - other, name)))
+    }
+}
+
+fn describe_bonuses<I: Iterator<Item = (i32, &'static str)>>(bonuses: I) -> String {
+    let text = bonuses
+        .filter(|&(value, _)| value != 0)
+        .map(|(value, name)| format!("{:+} {}", value, name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if text.is_empty() { "no bonuses".to_string() } else { text }
+}
+
+/// Something special that happens when a monster hits
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum AttackEffect {
+    // Eats away at the player's equipment
+    Corrode,
+    // Hurts a little every turn for a while
+    Poison,
+    Slow,
+    // Grabs something from the player's pack
+    Steal,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum StatusKind {
+    Poisoned,
+    Burning,
+    Slowed,
+    Hasted,
+    Invisible,
+    Confused,
+    // Stuck in a web, can't move until it wears off
+    Entangled,
+}
+
+impl StatusKind {
+    pub fn name(self) -> &'static str {
+        use StatusKind::*;
+        match self {
+            Poisoned => "poisoned",
+            Burning => "burning",
+            Slowed => "slowed",
+            Hasted => "hasted",
+            Invisible => "invisible",
+            Confused => "confused",
+            Entangled => "entangled",
+        }
+    }
+
+    pub fn color(self) -> colors::Color {
+        use StatusKind::*;
+        match self {
+            Poisoned => colors::GREEN,
+            Burning => colors::FLAME,
+            Slowed => colors::LIGHT_BLUE,
+            Hasted => colors::LIGHT_YELLOW,
+            Invisible => colors::LIGHT_GREY,
+            Confused => colors::LIGHT_MAGENTA,
+            Entangled => colors::LIGHTER_GREY,
+        }
+    }
+
+    /// Drawn over the affected monster on the map
+    pub fn marker(self) -> char {
+        use StatusKind::*;
+        match self {
+            Poisoned => '!',
+            Burning => '^',
+            Slowed => '-',
+            Hasted => '+',
+            Invisible => '.',
+            Confused => '?',
+            Entangled => '"',
+        }
+    }
+}
+
+/// How hurt a monster looks, None while unharmed
+fn wound_level(object: &Object, game: &Game) -> Option<(&'static str, colors::Color)> {
+    let hp = object.fighter.map(|f| f.hp)?;
+    let max_hp = object.max_hp(game);
+    if hp >= max_hp || max_hp <= 0 {
+        None
+    } else if hp * 3 > max_hp * 2 {
+        Some(("lightly wounded", colors::DARK_GREEN))
+    } else if hp * 3 > max_hp {
+        Some(("wounded", colors::DARK_YELLOW))
+    } else {
+        Some(("badly wounded", colors::DARK_RED))
+    }
+}
+
+/// Hurt enough to be marked when the colors alone aren't to be relied on
+fn low_health(object: &Object, game: &Game) -> bool {
+    let hp = object.fighter.map_or(0, |f| f.hp);
+    let max_hp = object.max_hp(game);
+    hp < max_hp && hp * 3 <= max_hp
+}
+
+/// Temporary condition that wears off after a number of turns
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct StatusEffect {
+    kind: StatusKind,
+    turns: i32,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
@@ -99,6 +672,21 @@ enum Slot {
     LeftHand,
     RightHand,
     Head,
+    Ranged,
+    Body,
+    Feet,
+    Hands,
+    LeftRing,
+    RightRing,
+}
+
+impl Slot {
+    const ALL: [Slot; 9] = [Slot::Head, Slot::Body, Slot::RightHand, Slot::LeftHand, Slot::Hands,
+                            Slot::LeftRing, Slot::RightRing, Slot::Feet, Slot::Ranged];
+
+    fn is_ring(self) -> bool {
+        self == Slot::LeftRing || self == Slot::RightRing
+    }
 }
 
 impl std::fmt::Display for Slot {
@@ -107,6 +695,12 @@ impl std::fmt::Display for Slot {
             Slot::LeftHand => write!(f, "left hand"),
             Slot::RightHand => write!(f, "right hand"),
             Slot::Head => write!(f, "head"),
+            Slot::Ranged => write!(f, "back"),
+            Slot::Body => write!(f, "body"),
+            Slot::Feet => write!(f, "feet"),
+            Slot::Hands => write!(f, "hands"),
+            Slot::LeftRing => write!(f, "left ring finger"),
+            Slot::RightRing => write!(f, "right ring finger"),
         }
     }
 }
@@ -123,13 +717,84 @@ struct Object {
     blocks: bool,
     always_visible: bool,
     alive: bool,
+    #[serde(default)]
+    unique: bool,
+    // The way down stays shut while it lives
+    #[serde(default)]
+    guards_stairs: bool,
+    #[serde(default)]
+    ability: Option<BossAbility>,
+    // A freed prisoner to be led to the stairs
+    #[serde(default)]
+    escort: bool,
+    #[serde(default)]
+    introduced: bool,
+    #[serde(default)]
+    hidden: bool,
+    #[serde(default)]
+    faction: Faction,
 
     fighter: Option<Fighter>,
     ai: Option<Ai>,
+    attack_effect: Option<AttackEffect>,
     item: Option<Item>,
     equipment: Option<Equipment>,
+    trap: Option<Trap>,
+    container: Option<Container>,
+    #[serde(default)]
+    furniture: Option<Furniture>,
+    npc: Option<Npc>,
+    #[serde(default)]
+    gold: i32,
+    // Size of an item stack
+    #[serde(default = "single")]
+    count: i32,
+    #[serde(default)]
+    statuses: Vec<StatusEffect>,
+    affix: Option<Affix>,
+    #[serde(default)]
+    speed: SpeedTier,
+    #[serde(default)]
+    movement: Movement,
+    // Only magic hurts it, and it keeps fading from sight
+    #[serde(default)]
+    spectral: bool,
+    // Gets back up a while after being killed
+    #[serde(default)]
+    revives: bool,
+    // Has been killed once already, doing it again earns nothing
+    #[serde(default)]
+    risen: bool,
+    // Spins webs behind it as it goes, and walks over them freely
+    #[serde(default)]
+    weaves: bool,
+    // Set on the corpses that are going to get back up
+    #[serde(default)]
+    revival: Option<Revival>,
+    // What a mimic passes for until someone reaches for it
+    #[serde(default)]
+    disguise: Option<Box<Object>>,
+    // Where the player last saw the monster, and whether it has gone out of sight since
+    #[serde(skip)]
+    last_seen: Option<((i32, i32), bool)>,
+    // Title of the note from the notes file, read when picked up
+    #[serde(default)]
+    note: Option<String>,
+    // Left by a venomous monster, makes the one who eats it sick
+    #[serde(default)]
+    poisonous: bool,
+    // Radius of the light it gives off on the dark levels
+    #[serde(default)]
+    light: Option<i32>,
+    // What a monster carries and wears, the player's belongings live in the game
+    #[serde(default)]
+    inventory: Vec<Object>,
 
+    // Character level for the player, the depth a monster was drawn from for the rest
     level: i32,
+    // The player goes by the name picked at the start
+    #[serde(default)]
+    player: bool,
 }
 
 impl Object {
@@ -142,12 +807,128 @@ impl Object {
             blocks: blocks,
             always_visible: false,
             alive: false,
+            unique: false,
+            guards_stairs: false,
+            ability: None,
+            escort: false,
+            introduced: false,
+            hidden: false,
+            faction: Faction::Neutral,
             fighter: None,
             ai: None,
+            attack_effect: None,
             item: None,
             equipment: None,
+            trap: None,
+            container: None,
+            furniture: None,
+            npc: None,
+            gold: 0,
+            count: 1,
+            statuses: vec![],
+            affix: None,
+            speed: SpeedTier::Normal,
+            movement: Movement::Walk,
+            spectral: false,
+            revives: false,
+            risen: false,
+            weaves: false,
+            revival: None,
+            disguise: None,
+            last_seen: None,
+            note: None,
+            poisonous: false,
+            light: None,
+            inventory: vec![],
             level: 1,
+            player: false,
+        }
+    }
+
+    pub fn is_player(&self) -> bool {
+        self.player
+    }
+
+    /// Name with the enchantment level and stack size, like "+2 sword" or "healing potion (x3)"
+    pub fn display_name(&self) -> String {
+        match self.equipment {
+            Some(equipment) if equipment.enchantment > 0 => {
+                format!("+{} {}", equipment.enchantment, self.name)
+            }
+            _ if self.count > 1 => format!("{} (x{})", self.name, self.count),
+            _ => match self.affix {
+                Some(affix) => format!("{} {}", affix.name(), self.name.to_lowercase()),
+                None => self.name.clone(),
+            },
+        }
+    }
+
+    pub fn status_turns(&self, kind: StatusKind) -> i32 {
+        self.statuses
+            .iter()
+            .find(|effect| effect.kind == kind)
+            .map_or(0, |effect| effect.turns)
+    }
+
+    pub fn has_status(&self, kind: StatusKind) -> bool {
+        self.status_turns(kind) > 0
+    }
+
+    /// Start the effect, or make it last longer if it's already there
+    pub fn add_status(&mut self, kind: StatusKind, turns: i32) {
+        match self.statuses.iter_mut().find(|effect| effect.kind == kind) {
+            Some(effect) => effect.turns = cmp::max(effect.turns, turns),
+            None => self.statuses.push(StatusEffect { kind, turns }),
+        }
+    }
+
+    pub fn remove_status(&mut self, kind: StatusKind) -> bool {
+        let before = self.statuses.len();
+        self.statuses.retain(|effect| effect.kind != kind);
+        self.statuses.len() != before
+    }
+
+    /// Hidden traps and invisible monsters don't show up, the player always does
+    pub fn shown(&self, see_invisible: bool) -> bool {
+        !self.hidden && (see_invisible || self.is_player() || !self.has_status(StatusKind::Invisible))
+    }
+
+    /// Potions and scrolls pile up in a single inventory slot
+    pub fn stackable(&self) -> bool {
+        use Item::*;
+        matches!(self.item,
+                 Some(Heal | Lightning | Confuse | Fireball | StinkingCloud | RemoveCurse | EnchantWeapon | EnchantArmor |
+                      Arrow | Teleport | Blink | Antidote | Speed | Invisibility))
+    }
+
+    /// Returns false if the item can't take more enchantment
+    pub fn enchant(&mut self, weapon: bool) -> bool {
+        match self.equipment {
+            Some(ref mut equipment) if equipment.enchantment < MAX_ENCHANTMENT => {
+                equipment.enchantment += 1;
+                if weapon {
+                    equipment.power_bonus += 1;
+                } else {
+                    equipment.defence_bonus += 1;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// What the others see, the disguise while there is one
+    pub fn appearance(&self) -> &Object {
+        self.disguise.as_deref().unwrap_or(self)
+    }
+
+    /// Drops the disguise and returns it, the mimic blocks the way from now on
+    pub fn reveal(&mut self) -> Option<Box<Object>> {
+        let disguise = self.disguise.take();
+        if disguise.is_some() {
+            self.blocks = true;
         }
+        disguise
     }
 
     pub fn draw(&self, con: &mut Console) {
@@ -165,6 +946,10 @@ impl Object {
         }
         self.x = x;
         self.y = y;
+        if let Some(disguise) = self.disguise.as_mut() {
+            disguise.x = x;
+            disguise.y = y;
+        }
     }
 
     pub fn distance_to(&self, other: &Object) -> f32 {
@@ -177,16 +962,37 @@ impl Object {
         (((x - self.x).pow(2) + (y - self.y).pow(2)) as f32).sqrt()
     }
 
-    pub fn take_damage(&mut self, damage: i32, messages: &mut Messages) -> Option<i32> {
+    /// The XP of the kill if it dies, the player gets the credit only for the blows they dealt themselves
+    pub fn take_damage(&mut self, damage: i32, by_player: bool, game: &mut Game) -> Option<i32> {
         if let Some(fighter) = self.fighter.as_mut() {
             if damage > 0 {
                 fighter.hp -= damage;
+                if self.player && game.wizard {
+                    fighter.hp = cmp::max(fighter.hp, 1);
+                }
+                debug!(target: "combat", "{} takes {} damage, {} hp left", self.name, damage, fighter.hp);
+                if self.player {
+                    game.stats.damage_taken += damage;
+                }
+            }
+        }
+        if damage > 0 {
+            if let Some(disguise) = self.reveal() {
+                game.log.add_in(Category::Danger,
+                    format!("The {} was a {} all along!", disguise.display_name().to_lowercase(), self.name),
+                    colors::RED);
             }
         }
+        // Fire, poison and traps hurt townsfolk as well, only the player's blows turn them hostile
+        if damage > 0 && by_player && self.npc.take().is_some() {
+            game.log.add_in(Category::Danger, format!("{} gets angry!", self.name), colors::RED);
+            self.ai = Some(Ai::Basic);
+            self.faction = Faction::Outlaw;
+        }
         if let Some(fighter) = self.fighter {
             if fighter.hp <= 0 {
                 self.alive = false;
-                fighter.on_death.callback(self, messages);
+                fighter.on_death.callback(self, by_player, game);
                 return Some(fighter.xp);
             }
         }
@@ -194,16 +1000,94 @@ impl Object {
     }
 
     pub fn attack(&mut self, target: &mut Object, game: &mut Game) {
-        let damage = self.power(game) - target.defence(game);
+        if target.spectral {
+            // Fights between monsters would flood the log, so only the player's are told
+            if self.is_player() || target.is_player() {
+                game.log.add_in(Category::Combat, format!("{} passes right through {}.", self.name, target.name),
+                                colors::GREY);
+            }
+            return;
+        }
+        let combat = game.combat;
+        let hit_roll = game_rng().gen_range(0, 100);
+        debug!(target: "combat", "{} attacks {}: hit roll {} against {}", self.name, target.name, hit_roll,
+               combat.hit_chance);
+        if hit_roll >= combat.hit_chance {
+            game.log.add_in(Category::Combat, format!("{} misses {}.", self.name, target.name), colors::GREY);
+            return;
+        }
+        let dodge_chance = cmp::min(
+            target.defence(game) * combat.dodge_per_defence + target.attributes(game).dexterity * DODGE_PER_DEXTERITY,
+            combat.max_dodge);
+        let dodge_roll = game_rng().gen_range(0, 100);
+        debug!(target: "combat", "{} dodge roll {} against {}", target.name, dodge_roll, dodge_chance);
+        if dodge_roll < dodge_chance {
+            game.log.add_in(Category::Combat, format!("{} dodges the attack of {}.", target.name, self.name), colors::LIGHT_SKY);
+            return;
+        }
+        let critical_chance = if self.is_player() && game.class == Class::Warrior {
+            combat.critical_chance + WARRIOR_CRITICAL_BONUS
+        } else {
+            combat.critical_chance
+        };
+        let critical_roll = game_rng().gen_range(0, 100);
+        let critical = critical_roll < critical_chance;
+        let power = if critical {
+            self.power(game) * combat.critical_multiplier
+        } else {
+            self.power(game)
+        };
+        let defence = target.defence(game);
+        let damage = power - defence;
+        debug!(target: "combat", "critical roll {} against {}, power {} against defence {}, {} damage",
+               critical_roll, critical_chance, power, defence, damage);
         if damage > 0 {
-            game.log.add(
-                format!("{} swings and hits {} for {} damage!", self.name, target.name, damage),
-                colors::WHITE);
-            if let Some(xp) = target.take_damage(damage, &mut game.log) {
+            if critical {
+                game.log.add_in(Category::Combat,
+                    format!("{} lands a critical hit on {} for {} damage!", self.name, target.name, damage),
+                    colors::LIGHT_RED);
+            } else {
+                game.log.add_in(Category::Combat,
+                    format!("{} swings and hits {} for {} damage!", self.name, target.name, damage),
+                    colors::WHITE);
+            }
+            game.events.push(GameEvent::Sound(Sound::Hit));
+            if self.is_player() {
+                game.stats.damage_dealt += damage;
+                wear_equipment(true, 1, game);
+            } else if target.is_player() {
+                match self.attack_effect {
+                    Some(AttackEffect::Corrode) => {
+                        game.log.add_in(Category::Danger, format!("{} spits acid at your gear!", self.name), colors::LIGHT_LIME);
+                        wear_equipment(false, CORRODE_DAMAGE, game);
+                    }
+                    _ => wear_equipment(false, 1, game),
+                }
+            }
+            let inflicted = match self.attack_effect {
+                Some(AttackEffect::Poison) => Some((StatusKind::Poisoned, POISON_TURNS)),
+                Some(AttackEffect::Slow) => Some((StatusKind::Slowed, SLOW_TURNS)),
+                _ => None,
+            };
+            let category = if target.is_player() { Category::Danger } else { Category::Combat };
+            if let Some((kind, turns)) = inflicted {
+                if !target.has_status(kind) {
+                    game.log.add_in(category, format!("{} is {}!", target.name, kind.name()), kind.color());
+                }
+                target.add_status(kind, turns);
+            }
+            if self.attack_effect == Some(AttackEffect::Steal) && target.is_player() && self.inventory.is_empty() {
+                steal_item(self, game);
+            }
+            if let Some(xp) = target.take_damage(damage, self.is_player(), game) {
+                // Companions and monsters killing something keep the experience, the player gains nothing
                 self.fighter.as_mut().unwrap().xp += xp;
+                if self.is_player() {
+                    game.log.add_in(Category::Combat, format!("You gain {} XP.", xp), colors::ORANGE);
+                }
             }
         } else {
-            game.log.add(
+            game.log.add_in(Category::Combat,
                 format!("{} attacks the {} but it has no effect!", self.name, target.name),
                 colors::WHITE);
         }
@@ -219,9 +1103,9 @@ impl Object {
         }
     }
 
-    pub fn equip(&mut self, log: &mut Vec<(String, colors::Color)>) {
+    pub fn equip(&mut self, log: &mut Messages) {
         if self.item.is_none() {
-            log.add(format!("Can't equip {:?} as it is not an item.", self),
+            log.add_in(Category::Item, format!("Can't equip {:?} as it is not an item.", self),
                 colors::RED,
             );
             return;
@@ -229,46 +1113,88 @@ impl Object {
         if let Some(ref mut equipment) = self.equipment {
             if !equipment.equipped {
                 equipment.equipped = true;
-                log.add(format!("Equipped {} on {}.", self.name, equipment.slot),
+                log.add_in(Category::Item, format!("Equipped {} on {}.", self.name, equipment.slot),
                     colors::LIGHT_GREEN,
                 );
+                if equipment.cursed && !self.name.starts_with("cursed ") {
+                    log.add_in(Category::Item, format!("The {} is cursed! You can't take it off.", self.name),
+                        colors::RED,
+                    );
+                    self.name = format!("cursed {}", self.name);
+                }
             }
         } else {
-            log.add(format!("Can't equip {:?} as it is not an equipment.", self),
+            log.add_in(Category::Item, format!("Can't equip {:?} as it is not an equipment.", self),
                 colors::RED,
             );
         }
     }
 
-    pub fn unequip(&mut self, log: &mut Vec<(String, colors::Color)>) {
+    /// Returns false if the item is still equipped
+    pub fn unequip(&mut self, log: &mut Messages) -> bool {
         if self.item.is_none() {
-            log.add(format!("Can't unequip {:?} as it's not an item", self),
+            log.add_in(Category::Item, format!("Can't unequip {:?} as it's not an item", self),
                 colors::RED,
             );
-            return;
+            return false;
         }
         if let Some(ref mut equipment) = self.equipment {
+            if equipment.equipped && equipment.cursed {
+                log.add_in(Category::Item, format!("The {} is stuck to you!", self.name),
+                    colors::RED,
+                );
+                return false;
+            }
             if equipment.equipped {
                 equipment.equipped = false;
-                log.add(format!("Unequipped {} on {}.", self.name, equipment.slot),
+                log.add_in(Category::Item, format!("Unequipped {} on {}.", self.name, equipment.slot),
                     colors::LIGHT_YELLOW,
                 );
             }
+            true
         } else {
-            log.add(format!("Can't unequip {:?} as it is not an equipment.", self),
+            log.add_in(Category::Item, format!("Can't unequip {:?} as it is not an equipment.", self),
                 colors::RED,
             );
+            false
+        }
+    }
+
+    /// Cursed items are worse than they look
+    pub fn curse(&mut self) {
+        if let Some(ref mut equipment) = self.equipment {
+            equipment.cursed = true;
+            equipment.power_bonus = -(equipment.power_bonus + 1) / 2;
+            equipment.defence_bonus = -(equipment.defence_bonus + 1) / 2;
+            equipment.max_hp_bonus = -equipment.max_hp_bonus / 4;
         }
     }
 
+    pub fn attributes(&self, game: &Game) -> Attributes {
+        let base = self.fighter.map_or(Attributes::default(), |f| f.attributes);
+        self.get_all_equipped(game)
+            .iter()
+            .fold(base, |total, e| total.plus(e.attributes))
+    }
+
     pub fn power(&self, game: &Game) -> i32 {
         let base_power = self.fighter.map_or(0, |f| f.base_power);
+        // Bows only help with shooting
         let bonus: i32 = self
             .get_all_equipped(game)
             .iter()
+            .filter(|e| e.slot != Slot::Ranged)
             .map(|e| e.power_bonus)
             .sum();
-        base_power + bonus
+        base_power + bonus + self.attributes(game).strength
+    }
+
+    pub fn ranged_power(&self, game: &Game) -> i32 {
+        self.get_all_equipped(game)
+            .iter()
+            .filter(|e| e.slot == Slot::Ranged)
+            .map(|e| e.power_bonus)
+            .sum()
     }
 
     pub fn defence(&self, game: &Game) -> i32 {
@@ -292,75 +1218,660 @@ impl Object {
     }
 
     pub fn get_all_equipped(&self, game: &Game) -> Vec<Equipment> {
-        if self.name == "Player" {
-            game.inventory
-                .iter()
-                .filter(|item| item.equipment.map_or(false, |e| e.equipped))
-                .map(|item| item.equipment.unwrap())
-                .collect()
-        } else {
-            vec![]
-        }
+        let inventory = if self.is_player() { &game.inventory } else { &self.inventory };
+        // Broken equipment doesn't count
+        inventory
+            .iter()
+            .filter(|item| item.equipment.is_some_and(|e| e.equipped && e.durability > 0))
+            .map(|item| item.equipment.unwrap())
+            .collect()
     }
 }
 
-type Messages = Vec<(String, colors::Color)>;
-
-#[derive(Serialize, Deserialize)]
-struct Game {
-    map: Map,
-    log: Messages,
-    inventory: Vec<Object>,
-    dungeon_level: u32,
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum Category {
+    Combat,
+    Item,
+    // Things happening to the player that they really shouldn't miss
+    Danger,
+    #[default]
+    System,
 }
 
-trait MessageLog {
-    fn add<T: Into<String>>(&mut self, message: T, color: colors::Color);
-}
+impl Category {
+    const ALL: [Category; 4] = [Category::Combat, Category::Item, Category::Danger, Category::System];
 
-impl MessageLog for Messages {
+    fn name(self) -> &'static str {
+        match self {
+            Category::Combat => "Combat",
+            Category::Item => "Items",
+            Category::Danger => "Danger",
+            Category::System => "System",
+        }
+    }
+}
+
+/// A line of the message log, repeats of the last message just bump its count
+#[derive(Serialize, Deserialize)]
+struct LogEntry {
+    text: String,
+    color: colors::Color,
+    category: Category,
+    // Turn of the latest repeat
+    turn: i32,
+    count: i32,
+}
+
+impl LogEntry {
+    fn display(&self) -> String {
+        if self.count > 1 {
+            format!("{} (x{})", self.text, self.count)
+        } else {
+            self.text.clone()
+        }
+    }
+}
+
+// Older messages drop off the log, and out of the save
+const MAX_LOG_ENTRIES: usize = 1000;
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(from = "StoredLog")]
+struct Messages {
+    entries: VecDeque<LogEntry>,
+    // Stamped on the new entries
+    turn: i32,
+    // Danger messages since the player last acknowledged them
+    #[serde(skip)]
+    unseen_danger: i32,
+    // Waiting to be mirrored to the text output, repeats included
+    #[serde(skip)]
+    unnarrated: Vec<String>,
+    // Messages added since the start, repeats included
+    #[serde(skip)]
+    added: usize,
+}
+
+/// Saves made before the log entries kept just the text and color of every message
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredLog {
+    Entries { entries: VecDeque<LogEntry>, turn: i32 },
+    Lines(Vec<(String, colors::Color)>),
+}
+
+impl From<StoredLog> for Messages {
+    fn from(stored: StoredLog) -> Self {
+        match stored {
+            StoredLog::Entries { entries, turn } => Messages { entries, turn, ..Default::default() },
+            StoredLog::Lines(lines) => {
+                let mut log = Messages::default();
+                for (text, color) in lines {
+                    log.entries.push_back(LogEntry { text, color, category: Category::System, turn: 0, count: 1 });
+                }
+                log
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Game {
+    map: GameMap,
+    log: Messages,
+    inventory: Vec<Object>,
+    dungeon_level: u32,
+    #[serde(default)]
+    objective: RunObjective,
+    #[serde(default)]
+    gold: i32,
+    // Levels the player has left, by depth
+    #[serde(default)]
+    levels: HashMap<u32, Level>,
+    #[serde(default)]
+    quests: Vec<Quest>,
+    // Notes found during the run, in the order they were read
+    #[serde(default)]
+    journal: Vec<Note>,
+    // Permanent stat potions drunk during the run
+    #[serde(default)]
+    stat_potions: i32,
+    // Happened during the current turn, processed at its end
+    #[serde(skip)]
+    events: Vec<GameEvent>,
+    #[serde(skip, default = "CombatConfig::load")]
+    combat: CombatConfig,
+    #[serde(skip, default = "Tuning::load")]
+    tuning: Tuning,
+    #[serde(skip, default = "Bestiary::load")]
+    bestiary: Bestiary,
+    #[serde(skip, default = "Dialogues::load")]
+    dialogues: Dialogues,
+    #[serde(skip, default = "Notes::load")]
+    notes: Notes,
+    #[serde(default)]
+    class: Class,
+    // Goes down every turn, eat before it runs out
+    #[serde(default = "full_stomach")]
+    nutrition: i32,
+    #[serde(default)]
+    conducts: Vec<ConductState>,
+    #[serde(default)]
+    stats: RunStats,
+    // Turns taken since the start of the run
+    #[serde(default)]
+    turn: i32,
+    // Places to travel back to, with the level they are on
+    travel_mark: Option<(u32, (i32, i32))>,
+    last_shop: Option<(u32, (i32, i32))>,
+    #[serde(skip)]
+    activity: Option<Activity>,
+    #[serde(default)]
+    hotkeys: [Option<Hotkey>; 9],
+    // Copied from the settings every turn, for the monsters to follow the same rule
+    #[serde(skip)]
+    corner_cutting: bool,
+    // Started with --wizard, stays that way for the whole run
+    #[serde(default)]
+    wizard: bool,
+    #[serde(default = "default_name")]
+    name: String,
+}
+
+const DEFAULT_NAME: &str = "Stranger";
+const MAX_NAME_LENGTH: usize = 16;
+
+/// What the runs saved before the characters had names are called
+fn default_name() -> String {
+    "Player".to_string()
+}
+
+/// The runs saved before the hunger start out fed
+fn full_stomach() -> i32 {
+    MAX_NUTRITION
+}
+
+/// The items saved before the stacks are one each
+fn single() -> i32 {
+    1
+}
+
+/// An item kind put on a number key, used up stack by stack
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Hotkey {
+    item: Item,
+    // Shown in the hotbar even when none are left
+    char: char,
+}
+
+/// Picked at the start of the run, decides the starting stats, gear and perk
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+enum Class {
+    #[default]
+    Warrior,
+    Rogue,
+    Mage,
+}
+
+impl Class {
+    const ALL: [Class; 3] = [Class::Warrior, Class::Rogue, Class::Mage];
+
+    fn name(self) -> &'static str {
+        match self {
+            Class::Warrior => "Warrior",
+            Class::Rogue => "Rogue",
+            Class::Mage => "Mage",
+        }
+    }
+
+    fn perk(self) -> &'static str {
+        match self {
+            Class::Warrior => "more critical hits",
+            Class::Rogue => "spots traps easily",
+            Class::Mage => "stronger spells",
+        }
+    }
+
+    fn fighter(self) -> Fighter {
+        let (hp, defence, power, attributes) = match self {
+            Class::Warrior => (120, 2, 2, Attributes { strength: 2, ..Default::default() }),
+            Class::Rogue => (100, 1, 1, Attributes { dexterity: 2, perception: 1, ..Default::default() }),
+            Class::Mage => (80, 0, 1, Attributes { intelligence: 2, ..Default::default() }),
+        };
+        Fighter {
+            base_max_hp: hp,
+            hp,
+            base_defence: defence,
+            base_power: power,
+            attributes,
+            xp: 0,
+            on_death: DeathCallback::Player,
+        }
+    }
+
+    fn starting_kit(self) -> Vec<Object> {
+        let mut kit = match self {
+            Class::Warrior => vec![make_item(Item::Sword, 0, 0), make_item(Item::Shield, 0, 0)],
+            Class::Rogue => {
+                let mut arrows = make_item(Item::Arrow, 0, 0);
+                arrows.count *= 2;
+                vec![make_dagger(), make_item(Item::Bow, 0, 0), arrows]
+            }
+            Class::Mage => {
+                let mut lightning = make_item(Item::Lightning, 0, 0);
+                lightning.count = 2;
+                vec![make_dagger(), lightning, make_item(Item::Confuse, 0, 0)]
+            }
+        };
+        for item in kit.iter_mut() {
+            if let Some(ref mut equipment) = item.equipment {
+                equipment.equipped = true;
+            }
+        }
+        kit
+    }
+}
+
+/// Spells hit harder the smarter the caster is, and harder still for a mage
+fn spell_damage(damage: i32, game: &Game, caster: &Object) -> i32 {
+    let mut bonus = caster.attributes(game).intelligence * SPELL_POWER_PER_INTELLIGENCE;
+    if game.class == Class::Mage {
+        bonus += MAGE_SPELL_BONUS;
+    }
+    damage * (100 + bonus) / 100
+}
+
+const COMBAT_FILE: &str = "combat.toml";
+
+/// Odds of the combat rolls, in percent
+#[derive(Clone, Copy, Debug, Deserialize)]
+struct CombatConfig {
+    hit_chance: i32,
+    critical_chance: i32,
+    critical_multiplier: i32,
+    // Every point of defence makes it this much more likely to dodge
+    dodge_per_defence: i32,
+    max_dodge: i32,
+}
+
+impl Default for CombatConfig {
+    fn default() -> Self {
+        CombatConfig {
+            hit_chance: 90,
+            critical_chance: 5,
+            critical_multiplier: 2,
+            dodge_per_defence: 2,
+            max_dodge: 25,
+        }
+    }
+}
+
+impl CombatConfig {
+    /// Read the combat file, falling back to the defaults if it's missing or broken
+    pub fn load() -> Self {
+        match load_combat_config() {
+            Ok(config) => config,
+            Err(error) => {
+                warn!("Cannot load {}: {}", COMBAT_FILE, error);
+                CombatConfig::default()
+            }
+        }
+    }
+}
+
+fn load_combat_config() -> Result<CombatConfig, Box<dyn Error>> {
+    let mut text = String::new();
+    let mut file = File::open(COMBAT_FILE)?;
+    file.read_to_string(&mut text)?;
+    let result = toml::from_str::<CombatConfig>(&text)?;
+    Ok(result)
+}
+
+const TUNING_FILE: &str = "config.toml";
+
+/// Balance numbers that can be changed without recompiling, missing ones keep their defaults
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+struct Tuning {
+    heal_amount: i32,
+    lightning_damage: i32,
+    lightning_range: i32,
+    confuse_range: i32,
+    confuse_turns: i32,
+    fireball_damage: i32,
+    fireball_radius: i32,
+    // Experience for the next level is base + factor * current level
+    level_up_base: i32,
+    level_up_factor: i32,
+    max_rooms: i32,
+    room_min_size: i32,
+    room_max_size: i32,
+    // Per room, by the dungeon level
+    room_items: Vec<Transition>,
+    room_monsters: Vec<Transition>,
+    // Sight radii the options go through, zero lights up everything in the line of sight
+    torch_radii: Vec<i32>,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning {
+            heal_amount: 40,
+            lightning_damage: 40,
+            lightning_range: 5,
+            confuse_range: 10,
+            confuse_turns: 8,
+            fireball_damage: 25,
+            fireball_radius: 3,
+            level_up_base: 200,
+            level_up_factor: 150,
+            max_rooms: 30,
+            room_min_size: 6,
+            room_max_size: 10,
+            room_items: vec![Transition { level: 1, value: 1 }, Transition { level: 4, value: 2 }],
+            room_monsters: vec![
+                Transition { level: 1, value: 2 },
+                Transition { level: 4, value: 3 },
+                Transition { level: 6, value: 5 },
+            ],
+            torch_radii: vec![6, 8, 10, 12, 15, 0],
+        }
+    }
+}
+
+impl Tuning {
+    /// Read the config file, falling back to the defaults if it's missing, broken or out of range
+    pub fn load() -> Self {
+        match load_tuning() {
+            Ok(tuning) => tuning,
+            Err(error) => {
+                warn!("Cannot load {}: {}", TUNING_FILE, error);
+                Tuning::default()
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let positive = [
+            ("heal_amount", self.heal_amount),
+            ("lightning_damage", self.lightning_damage),
+            ("lightning_range", self.lightning_range),
+            ("confuse_range", self.confuse_range),
+            ("confuse_turns", self.confuse_turns),
+            ("fireball_damage", self.fireball_damage),
+            ("fireball_radius", self.fireball_radius),
+            ("level_up_base", self.level_up_base),
+            ("max_rooms", self.max_rooms),
+        ];
+        if let Some((name, _)) = positive.iter().find(|&&(_, value)| value <= 0) {
+            return Err(format!("{} has to be above zero", name));
+        }
+        if self.level_up_factor < 0 {
+            return Err("level_up_factor can't be negative".into());
+        }
+        // Rooms need some floor inside the walls and have to fit on the map
+        if self.room_min_size < 3 || self.room_min_size > self.room_max_size || self.room_max_size >= MAP_HEIGHT - 1 {
+            return Err(format!("room sizes have to go from 3 up to {}", MAP_HEIGHT - 2));
+        }
+        for (name, table) in [("room_items", &self.room_items), ("room_monsters", &self.room_monsters)] {
+            if table.windows(2).any(|pair| pair[0].level >= pair[1].level) {
+                return Err(format!("the levels of {} have to go up", name));
+            }
+        }
+        if self.torch_radii.is_empty() || self.torch_radii.iter().any(|&radius| radius < 0) {
+            return Err("torch_radii needs at least one radius, none of them negative".into());
+        }
+        Ok(())
+    }
+}
+
+fn load_tuning() -> Result<Tuning, Box<dyn Error>> {
+    let mut text = String::new();
+    let mut file = File::open(TUNING_FILE)?;
+    file.read_to_string(&mut text)?;
+    let result = toml::from_str::<Tuning>(&text)?;
+    result.validate()?;
+    Ok(result)
+}
+
+/// Things that happened in the game that other systems keep track of
+#[derive(Debug)]
+enum GameEvent {
+    // Infighting, companions, traps and burning don't count as the player's kills
+    MonsterKilled { name: String, pos: (i32, i32), carried: Vec<Object>, stats: MonsterStats, by_player: bool },
+    PickedUp(Item),
+    // Used up or put on, cancelling doesn't count
+    UsedItem(Item),
+    // Put on from the inventory, taking things off doesn't count
+    Equipped(Item),
+    Sound(Sound),
+}
+
+/// A level kept aside to be restored when the player comes back
+#[derive(Serialize, Deserialize)]
+struct Level {
+    map: GameMap,
+    objects: Vec<Object>,
+}
+
+trait MessageLog {
     fn add<T: Into<String>>(&mut self, message: T, color: colors::Color) {
-        self.push((message.into(), color));
+        self.add_in(Category::System, message, color);
+    }
+
+    fn add_in<T: Into<String>>(&mut self, category: Category, message: T, color: colors::Color);
+}
+
+impl Messages {
+    /// Changes whenever a message is added, repeats included
+    fn version(&self) -> usize {
+        self.added
+    }
+}
+
+impl MessageLog for Messages {
+    fn add_in<T: Into<String>>(&mut self, category: Category, message: T, color: colors::Color) {
+        if category == Category::Danger {
+            self.unseen_danger += 1;
+        }
+        let text = message.into();
+        self.unnarrated.push(text.clone());
+        self.added += 1;
+        if let Some(last) = self.entries.back_mut() {
+            if last.text == text && last.color == color && last.category == category {
+                last.count += 1;
+                last.turn = self.turn;
+                return;
+            }
+        }
+        // Saves from before the cap may hold more
+        while self.entries.len() >= MAX_LOG_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(LogEntry { text, color, category, turn: self.turn, count: 1 });
     }
 }
 
 
 impl DeathCallback {
-    fn callback(self, object: &mut Object, messages: &mut Messages) {
+    fn callback(self, object: &mut Object, by_player: bool, game: &mut Game) {
         use DeathCallback::*;
-        let callback: fn(&mut Object, &mut Messages) = match self {
+        let callback: fn(&mut Object, bool, &mut Game) = match self {
             Player => player_death,
             Monster => monster_death,
         };
-        callback(object, messages);
+        callback(object, by_player, game);
     }
 }
 
-fn player_death(player: &mut Object, messages: &mut Messages) {
-    messages.add("You die!", colors::RED);
+fn player_death(player: &mut Object, _by_player: bool, game: &mut Game) {
+    game.log.add_in(Category::Danger, "You die!", colors::RED);
+    game.events.push(GameEvent::Sound(Sound::Death));
 
     player.char = '%';
     player.color = colors::DARK_RED;
 }
 
-fn monster_death(monster: &mut Object, messages: &mut Messages) {
-    messages.add(
-        format!("{} dies! You gain {} XP.",
-                monster.name, monster.fighter.as_mut().unwrap().xp),
-        colors::ORANGE);
+fn monster_death(monster: &mut Object, by_player: bool, game: &mut Game) {
+    if monster.faction == Faction::Player {
+        game.log.add_in(Category::Danger, format!("Your {} dies!", monster.name.to_lowercase()), colors::RED);
+        if monster.escort {
+            game.stats.prisoners_lost += 1;
+        }
+    } else {
+        game.log.add_in(Category::Combat, format!("{} dies!", monster.name), colors::ORANGE);
+        let (x, y) = monster.pos();
+        let mut carried: Vec<Object> = monster.inventory.drain(..).collect();
+        for item in carried.iter_mut() {
+            item.set_pos(x, y);
+            if let Some(ref mut equipment) = item.equipment {
+                equipment.equipped = false;
+            }
+        }
+        let stats = MonsterStats::of(monster, game);
+        let name = monster.name.clone();
+        // Only the first death of the undead counts for the player
+        let by_player = by_player && !monster.risen;
+        game.events.push(GameEvent::MonsterKilled { name, pos: (x, y), carried, stats, by_player });
+    }
+    if monster.revives && game_rng().gen_range(0, 100) < REVIVAL_CHANCE {
+        if let Some(fighter) = monster.fighter {
+            monster.revival = Some(Revival {
+                turns: REVIVAL_TURNS,
+                name: monster.name.clone(),
+                char: monster.char,
+                color: monster.color,
+                fighter,
+            });
+        }
+    }
+    monster.faction = Faction::Neutral;
+    monster.poisonous = monster.attack_effect == Some(AttackEffect::Poison);
+    monster.attack_effect = None;
     monster.char = '%';
     monster.color = colors::DARK_RED;
     monster.blocks = false;
     monster.fighter = None;
     monster.ai = None;
-    monster.name = format!("Remains of {}", monster.name);
+    monster.affix = None;
+    monster.item = Some(Item::Corpse);
+    monster.name = format!("{} corpse", monster.name.to_lowercase());
+    // Nothing is left of a spirit
+    if monster.spectral {
+        monster.item = None;
+        monster.hidden = true;
+    }
 }
 
-fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
-    if x < 0 || y < 0 || x >= MAP_WIDTH || y >= MAP_HEIGHT {
-        return true;
+const REVIVAL_CHANCE: u32 = 50;
+const REVIVAL_TURNS: i32 = 5;
+// In percent of the full health
+const REVIVAL_HP: i32 = 50;
+
+/// What a corpse needs to rise again as the monster it was
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Revival {
+    turns: i32,
+    name: String,
+    char: char,
+    color: colors::Color,
+    fighter: Fighter,
+}
+
+/// Count down the corpses of the undead, they rise with part of their health unless burned first
+fn revive_corpses(game: &mut Game, objects: &mut [Object], fov: &Vision) {
+    for id in 0..objects.len() {
+        if objects[id].revival.is_none() {
+            continue;
+        }
+        let (x, y) = objects[id].pos();
+        if game.map[(x, y)].burning > 0 {
+            if fov.is_in_fov(x, y) {
+                game.log.add_in(Category::Combat, format!("The {} burns to ashes.", objects[id].name), colors::ORANGE);
+            }
+            objects[id].revival = None;
+            continue;
+        }
+        // Someone standing on the remains keeps them down for now
+        let occupied = objects.iter().any(|object| object.blocks && object.pos() == (x, y));
+        let revival = objects[id].revival.as_mut().unwrap();
+        revival.turns -= 1;
+        if revival.turns > 0 || occupied {
+            continue;
+        }
+        let revival = objects[id].revival.take().unwrap();
+        if fov.is_in_fov(x, y) {
+            game.log.add_in(Category::Danger, format!("The {} rises again!", objects[id].name), colors::RED);
+        }
+        let monster = &mut objects[id];
+        let max_hp = revival.fighter.base_max_hp;
+        monster.name = revival.name;
+        monster.char = revival.char;
+        monster.color = revival.color;
+        monster.fighter = Some(Fighter { hp: cmp::max(1, max_hp * REVIVAL_HP / 100), xp: 0, ..revival.fighter });
+        monster.risen = true;
+        monster.item = None;
+        monster.ai = Some(Ai::Basic);
+        monster.faction = Faction::Undead;
+        monster.blocks = true;
+        monster.alive = true;
+    }
+}
+
+/// Roll the loot table of the dead monster and scatter the results on its tile
+fn drop_loot(name: &str, (x, y): (i32, i32), game: &Game, objects: &mut Vec<Object>) {
+    let level = cmp::max(game.dungeon_level, 1);
+    for (chance, loot) in monster_loot(name) {
+        if game_rng().gen_range(0, 100) >= chance {
+            continue;
+        }
+        match loot {
+            Loot::Gold(max) => {
+                let mut gold = Object::new("gold coins", x, y, '$', colors::GOLD, false);
+                gold.gold = game_rng().gen_range(1, max + 1) * level as i32;
+                objects.push(gold);
+            }
+            Loot::Item(item) => objects.push(make_found_item(item, x, y, level)),
+            // Already handed out on spawn
+            Loot::Gear(_) => (),
+        }
+    }
+}
+
+/// Turn the monster into a champion with a random affix, it also carries a bit of extra loot
+fn make_champion(monster: &mut Object, level: u32) {
+    let affix = *game_rng().choose(&Affix::ALL).unwrap();
+    if let Some(ref mut fighter) = monster.fighter {
+        affix.modify(fighter);
+    }
+    if affix == Affix::Venomous {
+        monster.attack_effect = Some(AttackEffect::Poison);
+    }
+    monster.affix = Some(affix);
+    monster.color = affix.color();
+
+    let item_chances = &mut item_table(level);
+    let item = WeightedChoice::new(item_chances).ind_sample(&mut game_rng());
+    monster.inventory.push(make_found_item(item, monster.x, monster.y, level));
+}
+
+/// Hand out the gear from the monster's loot table, it fights with it and drops it on death
+fn arm_monster(monster: &mut Object, level: u32) {
+    for (chance, loot) in monster_loot(&monster.name) {
+        if let Loot::Gear(item) = loot {
+            if game_rng().gen_range(0, 100) < chance {
+                let mut gear = make_found_item(item, monster.x, monster.y, level);
+                if let Some(ref mut equipment) = gear.equipment {
+                    equipment.equipped = true;
+                }
+                monster.inventory.push(gear);
+            }
+        }
     }
-    if map[x as usize][y as usize].blocked {
+}
+
+fn is_blocked(x: i32, y: i32, map: &GameMap, objects: &[Object]) -> bool {
+    if map.get(x, y).is_none_or(|tile| tile.blocked) {
         return true;
     }
     objects.iter().any(|object| {
@@ -368,190 +1879,562 @@ fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
     })
 }
 
-fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
+/// A diagonal step squeezing between two walls, only allowed with corner cutting on
+fn cuts_corner(x: i32, y: i32, dx: i32, dy: i32, game: &Game) -> bool {
+    let wall = |x, y| game.map.get(x, y).is_none_or(|tile: &Tile| tile.blocked);
+    !game.corner_cutting && dx != 0 && dy != 0 && wall(x + dx, y) && wall(x, y + dy)
+}
+
+fn move_by(id: usize, dx: i32, dy: i32, game: &Game, objects: &mut [Object]) {
+    if objects[id].has_status(StatusKind::Entangled) {
+        return;
+    }
     let (x, y) = objects[id].pos();
-    if !is_blocked(x+dx, y+dy, map, objects) {
+    let free = match objects[id].movement {
+        Movement::Walk => !is_blocked(x+dx, y+dy, &game.map, objects) && !cuts_corner(x, y, dx, dy, game),
+        Movement::Phase => {
+            game.map.in_bounds(x+dx, y+dy) &&
+                !objects.iter().any(|object| object.blocks && object.pos() == (x+dx, y+dy))
+        }
+    };
+    if free {
         objects[id].set_pos(x+dx, y+dy);
     }
 }
 
-fn ai_take_turn(monster_id: usize, game: &mut Game, objects: &mut [Object], fov_map: &FovMap) {
+const GHOST_FADE_CHANCE: u32 = 20;
+const GHOST_FADE_TURNS: i32 = 3;
+const MIMIC_DISGUISES: [Item; 6] = [Item::Heal, Item::Sword, Item::Shield, Item::Armor, Item::Ring, Item::Idol];
+// Free bites the mimic gets on whoever reached for it
+const MIMIC_SURPRISE_ATTACKS: i32 = 2;
+const BOSS_ABILITY_CHANCE: u32 = 25;
+const BOSS_PLAGUE_TURNS: i32 = 6;
+// In percent of the full health
+const BOSS_REGENERATION: i32 = 10;
+
+fn ai_take_turn(monster_id: usize, game: &mut Game, objects: &mut [Object], fov_map: &Vision) {
     use Ai::*;
 
-    if let Some(ai) = objects[monster_id].ai.take() {
+    // Lies still until someone reaches for it
+    if objects[monster_id].disguise.is_some() {
+        return;
+    }
+    if let Some(ability) = objects[monster_id].ability {
+        let (x, y) = objects[monster_id].pos();
+        if fov_map.in_line_of_sight(x, y) && game_rng().gen_range(0, 100) < BOSS_ABILITY_CHANCE &&
+            use_boss_ability(monster_id, ability, game, objects) {
+            debug!(target: "ai", "{} #{} uses {:?}", objects[monster_id].name, monster_id, ability);
+            return;
+        }
+    }
+    let previous_pos = objects[monster_id].pos();
+    if objects[monster_id].spectral && !objects[monster_id].has_status(StatusKind::Invisible) &&
+        game_rng().gen_range(0, 100) < GHOST_FADE_CHANCE {
+        objects[monster_id].add_status(StatusKind::Invisible, GHOST_FADE_TURNS);
+    }
+    if objects[monster_id].has_status(StatusKind::Confused) {
+        debug!(target: "ai", "{} #{} stumbles around confused", objects[monster_id].name, monster_id);
+        stumble(monster_id, game, objects);
+    } else if let Some(ai) = objects[monster_id].ai.take() {
         let new_ai = match ai {
             Basic => ai_basic(monster_id, game, objects, fov_map),
-            Confused {
-                previous_ai,
-                num_turns,
-            } => ai_confused(monster_id, game, objects, previous_ai, num_turns),
+            Companion => ai_companion(monster_id, game, objects, fov_map),
+            Flee => ai_flee(monster_id, game, objects),
         };
-        objects[monster_id].ai = Some(new_ai);
+        debug!(target: "ai", "{} #{} {:?} -> {:?}, moved from {:?} to {:?}", objects[monster_id].name, monster_id,
+               ai, new_ai, previous_pos, objects[monster_id].pos());
+        // Monsters that left the level don't get to act again
+        if objects[monster_id].alive {
+            objects[monster_id].ai = Some(new_ai);
+        }
+    }
+    // Spirits float over the traps
+    if objects[monster_id].pos() != previous_pos && !objects[monster_id].spectral {
+        spring_trap(monster_id, game, objects);
+        walk_into_web(monster_id, game, objects);
+    }
+    if objects[monster_id].pos() != previous_pos && objects[monster_id].weaves &&
+        game_rng().gen_range(0, 100) < WEB_CHANCE {
+        let tile = &mut game.map[previous_pos];
+        if !tile.blocked && !tile.is_bush() && !tile.water {
+            tile.web = true;
+        }
+    }
+}
+
+/// Returns false if the ability is no use right now and the boss should just fight
+fn use_boss_ability(id: usize, ability: BossAbility, game: &mut Game, objects: &mut [Object]) -> bool {
+    let name = objects[id].name.clone();
+    match ability {
+        BossAbility::Plague => {
+            game.log.add_in(Category::Danger, format!("{} spits a cloud of plague at you!", name),
+                            CloudKind::Poison.color());
+            release_cloud(objects[PLAYER].pos(), 1, CloudKind::Poison, BOSS_PLAGUE_TURNS, &mut game.map);
+        }
+        BossAbility::Regenerate => {
+            let max_hp = objects[id].max_hp(game);
+            if objects[id].fighter.is_none_or(|f| f.hp >= max_hp) {
+                return false;
+            }
+            objects[id].heal(max_hp * BOSS_REGENERATION / 100, game);
+            game.log.add_in(Category::Combat, format!("The bones of {} knit back together.", name), colors::LIGHT_GREY);
+        }
+        BossAbility::ShadowStep => {
+            if objects[id].distance_to(&objects[PLAYER]) < 2.0 {
+                return false;
+            }
+            let (x, y) = objects[PLAYER].pos();
+            let spots = (-1..=1)
+                .flat_map(|dx| (-1..=1).map(move |dy| (x + dx, y + dy)))
+                .filter(|&(nx, ny)| !is_blocked(nx, ny, &game.map, objects))
+                .collect::<Vec<_>>();
+            match game_rng().choose(&spots) {
+                Some(&(nx, ny)) => objects[id].set_pos(nx, ny),
+                None => return false,
+            }
+            game.log.add_in(Category::Danger, format!("{} steps out of the shadows next to you!", name),
+                            colors::DARK_VIOLET);
+        }
+    }
+    true
+}
+
+const WEB_CHANCE: u32 = 30;
+const WEB_ENTANGLE_TURNS: i32 = 3;
+
+/// Stepping into a web tears it, and holds the one who did it for a few turns
+fn walk_into_web(id: usize, game: &mut Game, objects: &mut [Object]) {
+    let pos = objects[id].pos();
+    if !game.map[pos].web || objects[id].weaves {
+        return;
     }
+    game.map[pos].web = false;
+    let category = if id == PLAYER { Category::Danger } else { Category::Combat };
+    game.log.add_in(category, format!("{} gets caught in a web!", objects[id].name), StatusKind::Entangled.color());
+    objects[id].add_status(StatusKind::Entangled, WEB_ENTANGLE_TURNS);
 }
 
 fn ai_basic(
     monster_id: usize,
     game: &mut Game,
     objects: &mut [Object],
-    fov_map: &FovMap,
+    fov_map: &Vision,
 ) -> Ai {
     let (monster_x, monster_y) = objects[monster_id].pos();
-    if fov_map.is_in_fov(monster_x, monster_y) {
+    // Enemies from other factions standing next to the monster get attacked first
+    let enemy_id = objects.iter().enumerate().position(|(id, object)| {
+        id != PLAYER && is_hostile(&objects[monster_id], object) &&
+            object.distance_to(&objects[monster_id]) < 2.0
+    });
+    let player_adjacent = objects[monster_id].distance_to(&objects[PLAYER]) < 2.0;
+    if let (Some(enemy_id), false) = (enemy_id, player_adjacent) {
+        let (monster, enemy) = mut_two(monster_id, enemy_id, objects);
+        monster.attack(enemy, game);
+    } else if fov_map.in_line_of_sight(monster_x, monster_y) && !objects[PLAYER].has_status(StatusKind::Invisible) &&
+        !concealed(&objects[PLAYER], &objects[monster_id], &game.map) {
         if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
             let (player_x, player_y) = objects[PLAYER].pos();
-            move_towards(monster_id, player_x, player_y, &game.map, objects);
+            move_towards(monster_id, player_x, player_y, game, objects);
         } else if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
             let (monster, player) = mut_two(monster_id, PLAYER, objects);
             monster.attack(player, game);
         }
     } else {
         let choices = [-1, 0, 1];
-        let tx = match rand::thread_rng().choose(&choices) {
-            Some(dx) => monster_x + dx,
-            _ => monster_x,
+        let step = || {
+            let tx = match game_rng().choose(&choices) {
+                Some(dx) => monster_x + dx,
+                _ => monster_x,
+            };
+            let ty = match game_rng().choose(&choices) {
+                Some(dy) => monster_y + dy,
+                _ => monster_y,
+            };
+            (tx.clamp(0, MAP_WIDTH - 1), ty.clamp(0, MAP_HEIGHT - 1))
         };
-        let ty = match rand::thread_rng().choose(&choices) {
-            Some(dy) => monster_y + dy,
-            _ => monster_y,
+        // Wanderers keep to the shadows, out of two steps they take the darker one
+        let (first, second) = (step(), step());
+        let (tx, ty) = if fov_map.light_at(second.0, second.1) < fov_map.light_at(first.0, first.1) {
+            second
+        } else {
+            first
         };
-        move_towards(monster_id, tx, ty, &game.map, objects);
+        move_towards(monster_id, tx, ty, game, objects);
+    }
+    // A thief with loot in its hands makes a run for it
+    if objects[monster_id].attack_effect == Some(AttackEffect::Steal) && !objects[monster_id].inventory.is_empty() {
+        Ai::Flee
+    } else {
+        Ai::Basic
     }
-    Ai::Basic
 }
 
-fn ai_confused(
-    monster_id: usize,
-    game: &mut Game,
-    objects: &mut [Object],
-    previous_ai: Box<Ai>,
-    num_turns: i32,
-) -> Ai {
-    if num_turns < 0 {
-        game.log.add(format!("{} is no longer confused!", objects[monster_id].name),
-            colors::RED);
-        *previous_ai
-    } else {
-        move_by(monster_id,
-                rand::thread_rng().gen_range(-1, 2),
-                rand::thread_rng().gen_range(-1, 2),
-                &mut game.map,
-                objects,
-        );
-        Ai::Confused {
-            previous_ai: previous_ai,
-            num_turns: num_turns - 1,
+/// Head for the closest stairs and leave the level, or at least get away from the player
+fn ai_flee(monster_id: usize, game: &mut Game, objects: &mut [Object]) -> Ai {
+    let stairs = objects
+        .iter()
+        .filter(|object| object.name == "stairs down" || object.name == "stairs up")
+        .map(|object| object.pos())
+        .min_by_key(|&(x, y)| (x - objects[monster_id].x).pow(2) + (y - objects[monster_id].y).pow(2));
+    match stairs {
+        Some(pos) if pos == objects[monster_id].pos() => {
+            let monster = &mut objects[monster_id];
+            game.log.add(format!("{} escapes by the stairs!", monster.name), colors::ORANGE);
+            // Gone for good, along with whatever it carried
+            monster.alive = false;
+            monster.hidden = true;
+            monster.blocks = false;
+            monster.fighter = None;
+            monster.inventory.clear();
+            return Ai::Basic;
+        }
+        Some((x, y)) => move_towards(monster_id, x, y, game, objects),
+        None => {
+            let (monster_x, monster_y) = objects[monster_id].pos();
+            let (player_x, player_y) = objects[PLAYER].pos();
+            move_towards(monster_id, 2 * monster_x - player_x, 2 * monster_y - player_y, game, objects);
         }
     }
+    Ai::Flee
 }
 
-fn get_equipped_in_slot(slot: Slot, inventory: &[Object]) -> Option<usize> {
-    for (inventory_id, item) in inventory.iter().enumerate() {
-        if item.equipment.as_ref().map_or(false, |e| e.equipped && e.slot == slot) {
-            return Some(inventory_id);
+/// Move a random item that isn't worn from the player's pack to the thief
+fn steal_item(thief: &mut Object, game: &mut Game) {
+    let loose: Vec<usize> = game.inventory
+        .iter()
+        .enumerate()
+        .filter(|&(_, item)| !item.equipment.is_some_and(|e| e.equipped))
+        .map(|(id, _)| id)
+        .collect();
+    if let Some(&id) = game_rng().choose(&loose) {
+        let item = game.inventory.remove(id);
+        game.log.add_in(Category::Danger, format!("{} snatches your {}!", thief.name, item.display_name()), colors::LIGHT_RED);
+        thief.inventory.push(item);
+    }
+}
+
+/// Stay close to the player and fight the hostiles nearby
+fn ai_companion(
+    companion_id: usize,
+    game: &mut Game,
+    objects: &mut [Object],
+    fov_map: &Vision,
+) -> Ai {
+    let target = objects
+        .iter()
+        .enumerate()
+        .filter(|&(_, object)| is_hostile(&objects[companion_id], object) && fov_map.is_in_fov(object.x, object.y))
+        .map(|(id, object)| (id, objects[companion_id].distance_to(object)))
+        .filter(|&(_, distance)| distance <= COMPANION_CHASE_RANGE)
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+    let player_distance = objects[companion_id].distance_to(&objects[PLAYER]);
+
+    match target {
+        Some((target_id, distance)) if distance < 2.0 => {
+            // Whatever the companion kills counts for the player
+            let xp = objects[companion_id].fighter.map_or(0, |f| f.xp);
+            let (companion, target) = mut_two(companion_id, target_id, objects);
+            companion.attack(target, game);
+            let companion = companion.fighter.as_mut().unwrap();
+            let gained = companion.xp - xp;
+            companion.xp = xp;
+            objects[PLAYER].fighter.as_mut().unwrap().xp += gained;
+        }
+        Some((target_id, _)) if player_distance <= COMPANION_FOLLOW_DISTANCE => {
+            let (target_x, target_y) = objects[target_id].pos();
+            move_towards(companion_id, target_x, target_y, game, objects);
         }
+        _ if player_distance > COMPANION_FOLLOW_DISTANCE => {
+            let (player_x, player_y) = objects[PLAYER].pos();
+            move_towards(companion_id, player_x, player_y, game, objects);
+        }
+        _ => {}
     }
-    None
+    Ai::Companion
 }
 
-//////////////////////// MAPGEN
-const ROOM_MAX_SIZE: i32 = 10;
-const ROOM_MIN_SIZE: i32 = 6;
-const MAX_ROOMS: i32 = 30;
+fn is_hostile(attacker: &Object, object: &Object) -> bool {
+    object.alive && object.fighter.is_some() && object.disguise.is_none() && attacker.faction.hostile_to(object.faction)
+}
 
-#[derive(Clone, Copy, Debug)]
-struct Rect {
-    x1: i32, y1: i32,
-    x2: i32, y2: i32,
+/// Crouching in the bushes makes it harder to be seen and shot
+fn in_cover(object: &Object, map: &GameMap) -> bool {
+    map[object.pos()].is_bush()
 }
 
-impl Rect {
-    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Rect {
-        Rect{x1: x, y1: y, x2: x + w, y2: y + h}
-    }
-    pub fn center(&self) -> (i32, i32) {
-        let center_x = (self.x2 + self.x1) / 2;
-        let center_y = (self.y2 + self.y1) / 2;
-        (center_x, center_y)
-    }
-    pub fn intersects_with(&self, other: &Rect) -> bool {
-        (self.x1 <= other.x2) && (self.x2 >= other.x1) &&
-            (self.y1 <= other.y2) && (self.y2 >= other.y1)
-    }
+/// Too well hidden in the bushes to be noticed from where the other one stands
+fn concealed(object: &Object, from: &Object, map: &GameMap) -> bool {
+    in_cover(object, map) && object.distance_to(from) > CONCEALMENT_RANGE
 }
 
-fn create_room(room: Rect, map: &mut Map, objects: &mut Vec<Object>, first_room: bool, level: u32) {
-    // Just a rectangle
-    let tiles = &mut [
-        Weighted { item: false, weight: 10 },
-        Weighted { item: true, weight: 90 },
-    ];
-    let tiles_choices = WeightedChoice::new(tiles);
-    for x in (room.x1 + 1)..room.x2 {
-        for y in (room.y1 + 1)..room.y2 {
-            let tile = match tiles_choices.ind_sample(&mut rand::thread_rng()) {
-                false => Tile::bushes(),
-                true => Tile::empty(),
-            };
-            map[x as usize][y as usize] = tile;
+/// Confused creatures move at random
+fn stumble(id: usize, game: &mut Game, objects: &mut [Object]) {
+    let dx = game_rng().gen_range(-1, 2);
+    let dy = game_rng().gen_range(-1, 2);
+    let (x, y) = (objects[id].x + dx, objects[id].y + dy);
+    // Stumbling into someone means hitting them, friend or foe
+    let target_id = objects
+        .iter()
+        .position(|object| object.fighter.is_some() && object.pos() == (x, y));
+    match target_id {
+        Some(target_id) if target_id != id => {
+            let (monster, target) = mut_two(id, target_id, objects);
+            monster.attack(target, game);
         }
+        _ => move_by(id, dx, dy, game, objects),
     }
-    // Let's add some chaos to the boring rectangular room
-    let wall_burrow = &mut [
+}
+
+/// How many times the object gets to act this turn, its speed tier doubled or halved by the statuses
+fn actions_this_turn(object: &Object, turn: i32) -> i32 {
+    // In quarters of an action per turn, spread evenly over the turns
+    let quarters = match object.speed {
+        SpeedTier::Slow => 2,
+        SpeedTier::Normal => 4,
+        SpeedTier::Fast => 8,
+    };
+    let quarters = if object.has_status(StatusKind::Hasted) || object.affix == Some(Affix::Frenzied) {
+        quarters * 2
+    } else if object.has_status(StatusKind::Slowed) {
+        quarters / 2
+    } else {
+        quarters
+    };
+    quarters * (turn + 1) / 4 - quarters * turn / 4
+}
+
+/// Tick the status effects of everyone, they hurt and wear off here
+fn tick_statuses(game: &mut Game, objects: &mut [Object]) {
+    for object in objects.iter_mut() {
+        if !object.alive {
+            object.statuses.clear();
+            continue;
+        }
+        if object.has_status(StatusKind::Poisoned) {
+            object.take_damage(POISON_DAMAGE, false, game);
+        }
+        if object.has_status(StatusKind::Burning) && object.alive {
+            let category = if object.is_player() { Category::Danger } else { Category::Combat };
+            game.log.add_in(category, format!("{} burns for {} damage!", object.name, FIRE_DAMAGE), colors::ORANGE);
+            object.take_damage(FIRE_DAMAGE, false, game);
+        }
+        for effect in object.statuses.iter_mut() {
+            effect.turns -= 1;
+            if effect.turns <= 0 && object.alive {
+                game.log.add(format!("{} is no longer {}.", object.name, effect.kind.name()),
+                             colors::LIGHT_GREY);
+            }
+        }
+        object.statuses.retain(|effect| effect.turns > 0);
+    }
+}
+
+/// Is the player wearing working gear with this effect?
+fn wears_effect(effect: PassiveEffect, game: &Game) -> bool {
+    game.inventory
+        .iter()
+        .any(|item| item.equipment.is_some_and(|e| e.equipped && e.durability > 0 && e.effect == Some(effect)))
+}
+
+/// The player gets hungrier every turn, and starves every few turns once the food runs out
+fn digest(game: &mut Game, objects: &mut [Object]) {
+    if !objects[PLAYER].alive || wears_effect(PassiveEffect::SustainHunger, game) {
+        return;
+    }
+    game.nutrition = cmp::max(game.nutrition - 1, 0);
+    if game.nutrition == HUNGRY_NUTRITION {
+        game.log.add_in(Category::Danger, "You are getting hungry.", colors::YELLOW);
+    } else if game.nutrition == 0 && game.turn % STARVATION_TURNS == 0 {
+        game.log.add_in(Category::Danger, "You are starving!", colors::RED);
+        objects[PLAYER].take_damage(STARVATION_DAMAGE, false, game);
+    }
+}
+
+/// Magic rings do their thing once a turn, whether the player likes it or not
+fn apply_passive_effects(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    if !objects[PLAYER].alive {
+        return;
+    }
+    if wears_effect(PassiveEffect::Regeneration, game) && game.turn % REGENERATION_TURNS == 0 {
+        objects[PLAYER].heal(1, game);
+    }
+    if wears_effect(PassiveEffect::Teleportitis, game)
+        && game_rng().gen_range(0, 100) < TELEPORTITIS_CHANCE {
+        if let Some((x, y)) = random_free_tile(&game.map, objects) {
+            objects[PLAYER].set_pos(x, y);
+            compute_fov(&mut tcod.fov, x, y, &tcod.settings);
+            game.log.add_in(Category::Item, "Your ring flashes, and you find yourself somewhere else.", colors::LIGHT_MAGENTA);
+        }
+    }
+}
+
+fn get_equipped_in_slot(slot: Slot, inventory: &[Object]) -> Option<usize> {
+    for (inventory_id, item) in inventory.iter().enumerate() {
+        if item.equipment.as_ref().map_or(false, |e| e.equipped && e.slot == slot) {
+            return Some(inventory_id);
+        }
+    }
+    None
+}
+
+/// Rings fit on either hand: pick a free finger, if there is one
+fn free_ring_slot(inventory: &[Object]) -> Option<Slot> {
+    [Slot::LeftRing, Slot::RightRing]
+        .iter()
+        .cloned()
+        .find(|&slot| get_equipped_in_slot(slot, inventory).is_none())
+}
+
+//////////////////////// MAPGEN
+
+#[derive(Clone, Copy, Debug)]
+struct Rect {
+    x1: i32, y1: i32,
+    x2: i32, y2: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Rect {
+        Rect{x1: x, y1: y, x2: x + w, y2: y + h}
+    }
+    pub fn center(&self) -> (i32, i32) {
+        let center_x = (self.x2 + self.x1) / 2;
+        let center_y = (self.y2 + self.y1) / 2;
+        (center_x, center_y)
+    }
+    pub fn intersects_with(&self, other: &Rect) -> bool {
+        (self.x1 <= other.x2) && (self.x2 >= other.x1) &&
+            (self.y1 <= other.y2) && (self.y2 >= other.y1)
+    }
+}
+
+fn create_room(room: Rect, map: &mut GameMap, objects: &mut Vec<Object>, first_room: bool, level: u32,
+               tuning: &Tuning) {
+    // Just a rectangle, furnished according to the theme of the level
+    let (bushes_weight, water_weight) = match Theme::for_level(level) {
+        Theme::Town | Theme::Sewers => (10, 6),
+        Theme::Caverns => (25, 0),
+        Theme::Crypt => (4, 0),
+    };
+    let tiles = &mut [
+        Weighted { item: Tile::bushes(), weight: bushes_weight },
+        Weighted { item: Tile::water(), weight: water_weight },
+        Weighted { item: Tile::empty(), weight: 90 },
+    ];
+    let tiles_choices = WeightedChoice::new(tiles);
+    for x in (room.x1 + 1)..room.x2 {
+        for y in (room.y1 + 1)..room.y2 {
+            map[(x, y)] = tiles_choices.ind_sample(&mut game_rng());
+        }
+    }
+    // Let's add some chaos to the boring rectangular room
+    let wall_burrow = &mut [
         Weighted { item: true, weight: 60 },
         Weighted { item: false, weight: 40 },
     ];
     let wall_burrow_choice = WeightedChoice::new(wall_burrow);
     let mut walls = vec![];
     for x in (room.x1 + 1)..(room.x2 - 1) {
-        if x <= 0 || x >= MAP_WIDTH {
+        if x <= 0 || x >= map.width() {
             continue;
         }
         walls.push((x, room.y1));
         walls.push((x, room.y2));
     }
     for y in (room.y1 + 1)..(room.y2 - 1) {
-        if y <= 0 || y >= MAP_HEIGHT {
+        if y <= 0 || y >= map.height() {
             continue;
         }
         walls.push((room.x1, y));
         walls.push((room.x2, y));
     }
     for (wall_x, wall_y) in walls {
-        match wall_burrow_choice.ind_sample(&mut rand::thread_rng()) {
-            false => map[wall_x as usize][wall_y as usize] = Tile::empty(),
-            _ => {}
-        };
+        if !wall_burrow_choice.ind_sample(&mut game_rng()) {
+            map[(wall_x, wall_y)] = Tile::empty();
+        }
     }
 
     // Place all the player, monsters and items
-    place_objects(room, objects, map, first_room, level)
+    place_objects(room, objects, map, first_room, level, tuning)
+}
+
+/// A big bare room away from the others, joined to the last of them
+fn carve_vault(rooms: &[Rect], map: &mut GameMap) -> Option<Rect> {
+    let vault = (0..VAULT_TRIES)
+        .map(|_| {
+            let x = game_rng().gen_range(1, MAP_WIDTH - VAULT_WIDTH - 1);
+            let y = game_rng().gen_range(1, MAP_HEIGHT - VAULT_HEIGHT - 1);
+            Rect::new(x, y, VAULT_WIDTH, VAULT_HEIGHT)
+        })
+        .find(|vault| !rooms.iter().any(|room| vault.intersects_with(room)))?;
+    for x in (vault.x1 + 1)..vault.x2 {
+        for y in (vault.y1 + 1)..vault.y2 {
+            map[(x, y)] = Tile::empty();
+        }
+    }
+    let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
+    let (x, y) = vault.center();
+    create_h_tunnel(prev_x, x, prev_y, map);
+    create_v_tunnel(x, prev_y, y, map);
+    Some(vault)
+}
+
+/// Clear the vault and put its boss between the stairs and the way in
+fn place_boss(vault: Rect, previous: Rect, objects: &mut Vec<Object>, level: u32) {
+    let inside = |object: &Object| {
+        object.x > vault.x1 && object.x < vault.x2 && object.y > vault.y1 && object.y < vault.y2
+    };
+    objects.retain(|object| object.is_player() || !(inside(object) && object.fighter.is_some()));
+
+    let (x, y) = vault.center();
+    let (from_x, from_y) = previous.center();
+    let (boss_x, boss_y) = (x + (from_x - x).signum(), y + (from_y - y).signum());
+    let choice = match (level / BOSS_LEVEL_INTERVAL) % 3 {
+        1 => "rat queen",
+        2 => "bone warden",
+        _ => "shadow",
+    };
+    let mut boss = make_monster(choice, boss_x, boss_y);
+    boss.unique = true;
+    boss.guards_stairs = true;
+    boss.level = level as i32;
+    // Fine gear it fights with and drops: never cursed, always enchanted
+    let item = *game_rng().choose(&BOSS_GEAR).unwrap();
+    let mut gear = make_item(item, boss_x, boss_y);
+    for _ in 0..BOSS_GEAR_ENCHANTMENT {
+        gear.enchant(item == Item::Sword);
+    }
+    if let Some(ref mut equipment) = gear.equipment {
+        equipment.equipped = true;
+    }
+    boss.inventory.push(gear);
+    objects.push(boss);
 }
 
-fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
+fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut GameMap) {
     for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
+        map[(x, y)] = Tile::empty();
     }
 }
 
-fn create_v_tunnel(x: i32, y1: i32, y2: i32, map: &mut Map) {
+fn create_v_tunnel(x: i32, y1: i32, y2: i32, map: &mut GameMap) {
     for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
+        map[(x, y)] = Tile::empty();
     }
 }
 
-fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
-    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+fn make_map(objects: &mut Vec<Object>, level: u32, tuning: &Tuning, dialogues: &Dialogues, notes: &Notes) -> GameMap {
+    let mut map = GameMap::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
     assert_eq!(&objects[PLAYER] as *const _, &objects[0] as *const _);
     objects.truncate(1);
 
     // Generate the map as a series of rooms connected with tunnels
     let mut rooms = vec![];
-    for _ in 0..MAX_ROOMS {
-        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
+    for _ in 0..tuning.max_rooms {
+        let w = game_rng().gen_range(tuning.room_min_size, tuning.room_max_size + 1);
+        let h = game_rng().gen_range(tuning.room_min_size, tuning.room_max_size + 1);
+        let x = game_rng().gen_range(0, MAP_WIDTH - w);
+        let y = game_rng().gen_range(0, MAP_HEIGHT - h);
         let new_room = Rect::new(x, y, w, h);
 
         let failed = rooms
@@ -559,14 +2442,14 @@ fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
             .any(|other_room| new_room.intersects_with(other_room));
 
         if !failed {
-            create_room(new_room, &mut map, objects, rooms.is_empty(), level);
+            create_room(new_room, &mut map, objects, rooms.is_empty(), level, tuning);
             let (new_x, new_y) = new_room.center();
             if rooms.is_empty() {
                 objects[PLAYER].set_pos(new_x, new_y);
             } else {
                 let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
 
-                if rand::random() {
+                if game_rng().gen() {
                     // Horizontal then vertical
                     create_h_tunnel(prev_x, new_x, prev_y, &mut map);
                     create_v_tunnel(new_x, prev_y, new_y, &mut map);
@@ -581,24 +2464,51 @@ fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
         }
     }
     // Relax some of the rough edges
-    for x in 2..MAP_WIDTH-2 {
-        for y in 2..MAP_HEIGHT-2 {
+    for x in 2..map.width() - 2 {
+        for y in 2..map.height() - 2 {
             let mut walls = 0;
             for dx in (x - 1) .. (x + 2) {
                 for dy in (y - 1) .. (y + 2) {
-                    let tile = map[dx as usize][dy as usize];
+                    let tile = map[(dx, dy)];
                     if tile.blocked && tile.block_sight {
                         walls += 1;
                     }
                 }
             }
-            let tile = map[x as usize][y as usize];
+            let tile = map[(x, y)];
             if walls < 5 && tile.blocked {
-                map[x as usize][y as usize] = Tile::empty();
+                map[(x, y)] = Tile::empty();
             }
         }
     }
 
+    if level.is_multiple_of(SHOP_LEVEL_INTERVAL) && rooms.len() > 2 {
+        place_shop(rooms[1], &mut map, objects, level);
+    }
+
+    // The stairs go in the last room, on boss levels that's the vault
+    if level.is_multiple_of(BOSS_LEVEL_INTERVAL) && rooms.len() > 1 {
+        if let Some(vault) = carve_vault(&rooms, &mut map) {
+            rooms.push(vault);
+        }
+        place_boss(rooms[rooms.len() - 1], rooms[rooms.len() - 2], objects, level);
+    }
+
+    if game_rng().gen_range(0, 100) < WANDERER_LEVEL_CHANCE && rooms.len() > 2 {
+        let room = rooms[game_rng().gen_range(1, rooms.len())];
+        place_wanderer(room, &map, objects, level, dialogues);
+    }
+
+    if game_rng().gen_range(0, 100) < PRISONER_LEVEL_CHANCE && rooms.len() > 2 {
+        let room = rooms[game_rng().gen_range(1, rooms.len())];
+        place_prisoner(room, &map, objects);
+    }
+
+    if game_rng().gen_range(0, 100) < NOTE_LEVEL_CHANCE && rooms.len() > 1 {
+        let room = rooms[game_rng().gen_range(1, rooms.len())];
+        place_note(room, &map, objects, level, notes);
+    }
+
     let (last_room_x, last_room_y) = rooms[rooms.len() - 1].center();
     let mut stairs = Object::new(
         "stairs down",
@@ -609,535 +2519,7066 @@ fn make_map(objects: &mut Vec<Object>, level: u32) -> Map {
     );
     stairs.always_visible = true;
     objects.push(stairs);
+
+    // Every level has a way back, the first one leads to the town
+    let (first_room_x, first_room_y) = rooms[0].center();
+    let mut stairs_up = Object::new(
+        "stairs up",
+        first_room_x, first_room_y,
+        '>',
+        colors::WHITE,
+        false,
+    );
+    stairs_up.always_visible = true;
+    objects.push(stairs_up);
     map
 }
 
+#[derive(Clone, Copy, Debug, Deserialize)]
 struct Transition {
     level: u32,
     value: u32,
 }
 
-/// Returns a value that depends on level
-fn from_dungeon_level(table: &[Transition], level: u32) -> u32 {
-    table
-        .iter()
-        .rev()
-        .find(|transition| level >= transition.level)
-        .map_or(0, |transition| transition.value)
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Theme {
+    Town,
+    Sewers,
+    Caverns,
+    Crypt,
 }
 
-/////////////////////// Logic
-const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
-const FOV_LIGHT_WALLS: bool = true;
-const TORCH_RADIUS: i32 = 10;
-
-const HEAL_AMOUNT: i32 = 40;
-const LIGHTNING_RANGE: i32 = 5;
-const LIGHTNING_DAMAGE: i32 = 40;
-const CONFUSE_RANGE: i32 = 10;
-const CONFUSE_NUM_TURNS: i32 = 8;
-const FIREBALL_RADIUS: i32 = 3;
-const FIREBALL_DAMAGE: i32 = 25;
-const LEVEL_UP_BASE: i32 = 200;
-const LEVEL_UP_FACTOR: i32 = 150;
-
-fn place_objects(room: Rect, objects: &mut Vec<Object>, map: &Map, first_room: bool, level: u32) {
-    let item_chances = &mut [
-        Weighted { item: Item::Heal, weight: 35 },
-        Weighted { item: Item::Lightning,
-                    weight: from_dungeon_level(
-                        &[Transition { level: 4, value: 25 }, ],
-                        level
-                    )},
-        Weighted { item: Item::Fireball,
-                    weight: from_dungeon_level(
-                        &[Transition { level: 6, value: 25 }, ],
-                        level
-                    )},
-        Weighted { item: Item::Confuse,
-                    weight: from_dungeon_level(
-                        &[Transition { level: 2, value: 10 }, ],
-                        level
-                    )},
-        Weighted { item: Item::Sword,
-                    weight: from_dungeon_level(
-                        &[Transition { level: 4, value: 5 }, ],
-                        level
-                    )},
-        Weighted { item: Item::Shield,
-                    weight: from_dungeon_level(
-                        &[Transition { level: 8, value: 15 }, ],
-                        level
-                    )},
-        Weighted { item: Item::Helmet,
-                    weight: from_dungeon_level(
-                        &[Transition { level: 5, value: 20 }, ],
-                        level
-                    )},
-    ];
-    let item_choice = WeightedChoice::new(item_chances);
-
-    let max_items = from_dungeon_level(
-        &[Transition { level: 1, value: 1 },
-          Transition { level: 4, value: 2 }, ],
-        level,
-    );
-    let num_items = rand::thread_rng().gen_range(0, max_items + 1);
-    for _ in 0..num_items {
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
-        if !is_blocked(x, y, map, objects) {
-            let item = match item_choice.ind_sample(&mut rand::thread_rng()) {
-                Item::Heal => {
-                    let mut object = Object::new("healing potion", x, y,
-                                                 '!', colors::VIOLET, false);
-                    object.item = Some(Item::Heal);
-                    object
-                }
-                Item::Lightning => {
-                    let mut object = Object::new("scroll of lightning", x, y,
-                                                 '#', colors::LIGHT_YELLOW, false);
-                    object.item = Some(Item::Lightning);
-                    object
-                }
-                Item::Fireball => {
-                    let mut object = Object::new("scroll of fireball", x, y,
-                                                 '#', colors::LIGHT_YELLOW, false);
-                    object.item = Some(Item::Fireball);
-                    object
-                }
-                Item::Confuse => {
-                    let mut object = Object::new("scroll of confusion", x, y,
-                                                 '&', colors::LIGHT_YELLOW, false);
-                    object.item = Some(Item::Confuse);
-                    object
-                }
-                Item::Sword => {
-                    let mut object = Object::new("sword", x, y, '/', colors::SKY, false);
-                    object.item = Some(Item::Sword);
-                    object.equipment = Some(Equipment{
-                        equipped: false, power_bonus: 3, defence_bonus: 0, max_hp_bonus: 1,
-                        slot: Slot::RightHand});
-                    object
-                }
-                Item::Shield => {
-                    let mut object = Object::new("shield", x, y, '*', colors::DARKER_ORANGE, false);
-                    object.item = Some(Item::Shield);
-                    object.equipment = Some(Equipment{
-                        equipped: false, power_bonus: 0, defence_bonus: 1, max_hp_bonus: 1,
-                        slot: Slot::LeftHand});
-                    object
-                }
-                Item::Helmet => {
-                    let mut object = Object::new("helmet", x, y, '^', colors::DARKER_ORANGE, false);
-                    object.item = Some(Item::Helmet);
-                    object.equipment = Some(Equipment{
-                        equipped: false, power_bonus: 0, defence_bonus: 0,
-                        max_hp_bonus: 100,
-                        slot: Slot::Head});
-                    object
-                }
-            };
-            objects.push(item);
+impl Theme {
+    pub fn for_level(level: u32) -> Theme {
+        match level {
+            0 => Theme::Town,
+            1..=3 => Theme::Sewers,
+            4..=6 => Theme::Caverns,
+            _ => Theme::Crypt,
         }
     }
 
-    if first_room {
-        return
+    /// Only the lights show anything down there
+    pub fn dark(self) -> bool {
+        self == Theme::Caverns || self == Theme::Crypt
     }
+}
 
-    let troll_chance = from_dungeon_level(
-        &[Transition { level: 3, value: 15, },
-          Transition { level: 5, value: 30, },
-          Transition { level: 7, value: 60, }, ],
-        level,
-    );
-    let monster_chances = &mut [
-        Weighted { weight: 80, item: "orc", },
-        Weighted { weight: troll_chance, item: "troll", },
-    ];
-    let monster_choice = WeightedChoice::new(monster_chances);
-
-    let max_monsters = from_dungeon_level(
-        &[Transition { level: 1, value: 2},
-          Transition { level: 4, value: 3},
-          Transition { level: 6, value: 5}, ],
-        level,
-    );
-    let num_monsters = rand::thread_rng().gen_range(0, max_monsters + 1);
-    for _ in 0..num_monsters {
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
-        if is_blocked(x, y, map, objects) {
-            continue;
+impl std::fmt::Display for Theme {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Theme::Town => write!(f, "Town"),
+            Theme::Sewers => write!(f, "Sewers"),
+            Theme::Caverns => write!(f, "Caverns"),
+            Theme::Crypt => write!(f, "Crypt"),
         }
-        let mut monster = match monster_choice.ind_sample(&mut rand::thread_rng()) {
-            "orc" => {
-                let mut orc = Object::new("Orc", x, y, '0', colors::LIGHT_GREEN, true);
-                orc.fighter = Some(Fighter {
-                    hp: 20,
-                    base_max_hp: 20,
-                    base_defence: 0,
-                    base_power: 4,
-                    xp: 35,
-                    on_death: DeathCallback::Monster,
-                });
-                orc.ai = Some(Ai::Basic);
-                orc
-            }
-            "troll" => {
-                let mut troll = Object::new("Troll", x, y, 'T', colors::RED, true);
-                troll.fighter = Some(Fighter {
-                    hp: 30,
-                    base_max_hp: 30,
-                    base_defence: 2,
-                    base_power: 8,
-                    xp: 100,
-                    on_death: DeathCallback::Monster,
-                });
-                troll.ai = Some(Ai::Basic);
-                troll
-            }
-            _ => unreachable!(),
-        };
-        monster.alive = true;
-        objects.push(monster);
     }
 }
 
-fn next_level(tcod: &mut Tcod, objects: &mut Vec<Object>, game: &mut Game) {
-    game.log.add("You take a moment to rest and recover your strength.",
-                 colors::VIOLET,
-                 );
-    let heal_hp = objects[PLAYER].max_hp(game) / 2;
-    objects[PLAYER].heal(heal_hp, &game);
-
-    game.log.add("After a rare moment of peace you descend deeper into the sewers...",
-                 colors::RED,
-                 );
-    game.dungeon_level += 1;
-    game.map = make_map(objects, game.dungeon_level);
-    initialise_fov(tcod, &game.map);
+/// Where a monster is allowed to spawn
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Habitat {
+    Anywhere,
+    // Needs water next to the spawn point
+    Water,
+    Caverns,
+    Crypt,
 }
 
-fn pick_item_up(
-    object_id: usize,
-    objects: &mut Vec<Object>,
-    game: &mut Game,
-) {
-    if game.inventory.len() >= 26 {
-        game.log.add(format!("Your inventory is full, cannot pick up {}.", objects[object_id].name),
-                     colors::RED);
-    } else {
-        let item = objects.swap_remove(object_id);
-        game.log.add(format!("You picked up a {}!", item.name), colors::GREEN);
-        let index = game.inventory.len();
-        let slot = item.equipment.map(|e| e.slot);
-        game.inventory.push(item);
+fn monster_habitat(name: &str) -> Habitat {
+    match name {
+        "leech" => Habitat::Water,
+        "fungus" | "spider" => Habitat::Caverns,
+        "skeleton" | "zombie" | "ghost" => Habitat::Crypt,
+        _ => Habitat::Anywhere,
+    }
+}
 
-        if let Some(slot) = slot {
-            if get_equipped_in_slot(slot, &game.inventory).is_none() {
-                game.inventory[index].equip(&mut game.log);
-            }
+fn habitat_allows(habitat: Habitat, x: i32, y: i32, map: &GameMap, level: u32) -> bool {
+    match habitat {
+        Habitat::Anywhere => true,
+        Habitat::Water => {
+            ((x - 1)..(x + 2)).any(|tx| ((y - 1)..(y + 2)).any(|ty| map.get(tx, ty).is_some_and(|tile| tile.water)))
         }
+        Habitat::Caverns => Theme::for_level(level) == Theme::Caverns,
+        Habitat::Crypt => Theme::for_level(level) == Theme::Crypt,
     }
 }
 
-fn level_up(objects: &mut [Object], game: &mut Game, tcod: &mut Tcod) {
-    let player = &mut objects[PLAYER];
-    let level_up_xp = LEVEL_UP_BASE + player.level * LEVEL_UP_FACTOR;
-    if player.fighter.as_ref().map_or(0, |f| f.xp) >= level_up_xp {
-        player.level += 1;
-        game.log.add(
-            format!("Your battle skills grow stronger! You've reached level {}!",
-                    player.level),
-            colors::YELLOW,
-        );
+/// Returns a value that depends on level
+fn from_dungeon_level(table: &[Transition], level: u32) -> u32 {
+    table
+        .iter()
+        .rev()
+        .find(|transition| level >= transition.level)
+        .map_or(0, |transition| transition.value)
+}
 
-        let fighter = player.fighter.as_mut().unwrap();
-        let mut choice = None;
-        while choice.is_none() {
-            choice = menu(
-                "Level up! Choose a stat to raise!\n",
-                &[format!("Constitution (+20 HP from {})", fighter.base_max_hp),
-                  format!("Strength (+1 attack from {})", fighter.base_power),
-                  format!("Agility (+1 defence from {})", fighter.base_defence),
-                ],
-                LEVEL_SCREEN_WIDTH,
-                &mut tcod.root,
-                );
-        }
-        fighter.xp -= level_up_xp;
-        match choice.unwrap() {
-            0 => {
-                fighter.base_max_hp += 20;
-                fighter.hp += 20;
-            }
-            1 => {
-                fighter.base_power += 1;
-            }
-            2 => {
-                fighter.base_defence += 1;
-            }
-            _ => unreachable!(),
-        }
+/////////////////////// Run objectives
+const DESCENT_DEPTH: u32 = 10;
+const VICTORY_BONUS: i32 = 1000;
+const STAT_POTION_SCORE: i32 = 100;
+
+/// What the player has to do to win the run
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum RunObjective {
+    /// Take the stairs down from the given dungeon level alive, past the boss guarding them
+    Descent { depth: u32 },
+    /// Hold out against the waves for as long as possible, the lull counts the turns to the next one
+    Arena { wave: u32, lull: i32 },
+    /// Go through the lessons of the tutorial level and take its stairs
+    Tutorial { step: TutorialStep },
+}
+
+impl Default for RunObjective {
+    fn default() -> Self {
+        RunObjective::Descent { depth: DESCENT_DEPTH }
     }
 }
 
+/// What kind of run the main menu starts
 #[derive(Clone, Copy, Debug, PartialEq)]
-enum PlayerAction {
-    TookTurn,
-    DidntTakeTurn,
-    Exit,
+enum GameMode {
+    Dungeon,
+    Arena,
+    Tutorial,
 }
 
-fn player_move_or_attack(dx: i32, dy: i32, game: &mut Game, objects: &mut [Object]) {
-    let x = objects[PLAYER].x + dx;
-    let y = objects[PLAYER].y + dy;
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    Victory,
+    Defeat,
+}
 
-    let target_id = objects.iter().position(|object| object.fighter.is_some() && object.pos() == (x, y));
-    match target_id {
-        Some(target_id) => {
-            let (player, monster) = mut_two(PLAYER, target_id, objects);
-            player.attack(monster, game);
+impl RunObjective {
+    /// Checked every turn, returns the outcome and its reason once the run is over
+    pub fn evaluate(self, objects: &[Object], game: &Game) -> Option<(Outcome, String)> {
+        if !objects[PLAYER].alive {
+            let reason = match self {
+                RunObjective::Arena { wave, .. } => format!("Fell in wave {} of the arena", wave),
+                RunObjective::Descent { .. } => format!("Died on level {}", game.dungeon_level),
+                RunObjective::Tutorial { .. } => "Fell in the tutorial".to_string(),
+            };
+            return Some((Outcome::Defeat, reason));
         }
-        None => {
-            move_by(PLAYER, dx, dy, &mut game.map, objects)
+        match self {
+            RunObjective::Descent { depth } if game.dungeon_level > depth => {
+                Some((Outcome::Victory, format!("Made it through level {} of the sewers", depth)))
+            }
+            RunObjective::Tutorial { step: TutorialStep::Done } => {
+                Some((Outcome::Victory, "Finished the tutorial".to_string()))
+            }
+            RunObjective::Descent { .. } | RunObjective::Arena { .. } | RunObjective::Tutorial { .. } => None,
         }
     }
-}
 
-fn move_towards(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mut [Object]) {
-    let dx = target_x - objects[id].x;
+    /// The wave reached in the arena, nothing for the other runs
+    pub fn arena_wave(self) -> Option<u32> {
+        match self {
+            RunObjective::Arena { wave, .. } => Some(wave),
+            RunObjective::Descent { .. } | RunObjective::Tutorial { .. } => None,
+        }
+    }
+
+    /// Tutorial runs aren't saved and don't count in the stats
+    pub fn is_tutorial(self) -> bool {
+        matches!(self, RunObjective::Tutorial { .. })
+    }
+}
+
+/// Result of a finished run, shown to the player and written to the morgue file
+struct RunSummary {
+    outcome: Outcome,
+    reason: String,
+    name: String,
+    class: Class,
+    wizard: bool,
+    dungeon_level: u32,
+    character_level: i32,
+    xp: i32,
+    stat_potions: i32,
+    conducts: Vec<ConductState>,
+    arena_wave: Option<u32>,
+    stats: RunStats,
+    score: i32,
+}
+
+impl RunSummary {
+    pub fn new(outcome: Outcome, reason: String, objects: &[Object], game: &Game) -> Self {
+        let player = &objects[PLAYER];
+        let xp = player.fighter.map_or(0, |f| f.xp);
+        let mut score = game.dungeon_level as i32 * 100 + player.level * 50 + xp +
+            game.stat_potions * STAT_POTION_SCORE;
+        if outcome == Outcome::Victory {
+            score += VICTORY_BONUS;
+        }
+        let arena_wave = game.objective.arena_wave();
+        score += arena_wave.unwrap_or(0) as i32 * ARENA_WAVE_SCORE;
+        RunSummary {
+            outcome,
+            reason,
+            name: game.name.clone(),
+            class: game.class,
+            wizard: game.wizard,
+            dungeon_level: game.dungeon_level,
+            character_level: player.level,
+            xp,
+            stat_potions: game.stat_potions,
+            conducts: game.conducts.clone(),
+            arena_wave,
+            stats: current_stats(game),
+            score,
+        }
+    }
+}
+
+impl std::fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let title = match self.outcome {
+            Outcome::Victory => "VICTORY",
+            Outcome::Defeat => "DEFEAT",
+        };
+        writeln!(f, "{}{}", title, if self.wizard { " (wizard mode)" } else { "" })?;
+        writeln!(f, "{}.", self.reason)?;
+        writeln!(f)?;
+        writeln!(f, "{} the {}", self.name, self.class.name())?;
+        match self.arena_wave {
+            Some(wave) => writeln!(f, "Arena wave: {}", wave)?,
+            None => writeln!(f, "Dungeon level: {}", self.dungeon_level)?,
+        }
+        writeln!(f, "Character level: {}", self.character_level)?;
+        writeln!(f, "Experience: {}", self.xp)?;
+        writeln!(f, "Stat potions drunk: {}", self.stat_potions)?;
+        for state in &self.conducts {
+            writeln!(f, "{} - {}", state.conduct.name(), if state.broken { "broken" } else { "honored" })?;
+        }
+        writeln!(f, "Score: {}", self.score)?;
+        writeln!(f)?;
+        write!(f, "{}", self.stats)
+    }
+}
+
+fn write_morgue(summary: &RunSummary) -> Result<(), Box<dyn Error>> {
+    let mut file = OpenOptions::new().create(true).append(true).open("morgue.txt")?;
+    writeln!(file, "{}\n", summary)?;
+    Ok(())
+}
+
+/// Show the end screen and clean up after a finished run
+fn end_run(summary: &RunSummary, tcod: &mut Tcod) {
+    if let Err(error) = write_morgue(summary) {
+        warn!("Cannot write the morgue file: {}", error);
+    }
+    // Nothing is earned with the help of wizardry
+    if !summary.wizard {
+        if let Err(error) = record_lifetime_stats(summary) {
+            warn!("Cannot write {}: {}", STATS_FILE, error);
+        }
+    }
+    // A finished run can't be continued, not even from the backup
+    tcod.save_slot.delete();
+    msgbox(&format!("{}\n\nPress any key.", summary), END_SCREEN_WIDTH, &mut tcod.root);
+}
+
+/////////////////////// Conducts
+/// Self-imposed challenges picked at the start of the run
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Conduct {
+    NoScrolls,
+    Vegetarian,
+    Pacifist,
+    NoEquipment,
+}
+
+impl Conduct {
+    const ALL: [Conduct; 4] = [Conduct::NoScrolls, Conduct::Vegetarian, Conduct::Pacifist, Conduct::NoEquipment];
+
+    fn name(self) -> &'static str {
+        match self {
+            Conduct::NoScrolls => "Illiterate: read no scrolls",
+            Conduct::Vegetarian => "Vegetarian: eat no corpses",
+            Conduct::Pacifist => "Pacifist: let few monsters die",
+            Conduct::NoEquipment => "Naked: equip nothing",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct ConductState {
+    conduct: Conduct,
+    broken: bool,
+}
+
+/// Let the player toggle the conducts until the run starts
+fn choose_conducts(root: &mut Root) -> Vec<ConductState> {
+    let mut chosen: Vec<Conduct> = vec![];
+    loop {
+        let mut options = Conduct::ALL
+            .iter()
+            .map(|conduct| format!("[{}] {}", if chosen.contains(conduct) { "x" } else { " " }, conduct.name()))
+            .collect::<Vec<_>>();
+        options.push("Start the run".into());
+        match menu("Take on any challenges?\n", &options, INVENTORY_WIDTH, root) {
+            Some(choice) if choice < Conduct::ALL.len() => {
+                let conduct = Conduct::ALL[choice];
+                if chosen.contains(&conduct) {
+                    chosen.retain(|&c| c != conduct);
+                } else {
+                    chosen.push(conduct);
+                }
+            }
+            _ => break,
+        }
+    }
+    chosen.into_iter().map(|conduct| ConductState { conduct, broken: false }).collect()
+}
+
+/// Was the conduct picked at the start and not broken yet?
+fn keeps_conduct(conduct: Conduct, game: &Game) -> bool {
+    game.conducts.iter().any(|state| state.conduct == conduct && !state.broken)
+}
+
+fn break_conduct(conduct: Conduct, game: &mut Game) {
+    if let Some(state) = game.conducts.iter_mut().find(|state| state.conduct == conduct && !state.broken) {
+        state.broken = true;
+        game.log.add(format!("You have broken your conduct: {}.", conduct.name()), colors::LIGHT_RED);
+    }
+}
+
+/////////////////////// Statistics
+const STATS_FILE: &str = "stats.json";
+
+/// What happened during a run, also summed up over all the runs
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct RunStats {
+    turns: i32,
+    // Turns spent on each depth
+    #[serde(default)]
+    depth_turns: BTreeMap<u32, i32>,
+    kills: BTreeMap<String, i32>,
+    damage_dealt: i32,
+    damage_taken: i32,
+    items_used: i32,
+    tiles_explored: i32,
+    #[serde(default)]
+    prisoners_rescued: i32,
+    #[serde(default)]
+    prisoners_lost: i32,
+}
+
+impl RunStats {
+    fn total_kills(&self) -> i32 {
+        self.kills.values().sum()
+    }
+
+    fn add(&mut self, other: &RunStats) {
+        self.turns += other.turns;
+        for (depth, turns) in &other.depth_turns {
+            *self.depth_turns.entry(*depth).or_insert(0) += turns;
+        }
+        for (name, count) in &other.kills {
+            *self.kills.entry(name.clone()).or_insert(0) += count;
+        }
+        self.damage_dealt += other.damage_dealt;
+        self.damage_taken += other.damage_taken;
+        self.items_used += other.items_used;
+        self.tiles_explored += other.tiles_explored;
+        self.prisoners_rescued += other.prisoners_rescued;
+        self.prisoners_lost += other.prisoners_lost;
+    }
+}
+
+impl std::fmt::Display for RunStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Turns: {}", self.turns)?;
+        for (depth, turns) in &self.depth_turns {
+            writeln!(f, "  {}: {}", level_name(*depth), turns)?;
+        }
+        writeln!(f, "Damage dealt: {}", self.damage_dealt)?;
+        writeln!(f, "Damage taken: {}", self.damage_taken)?;
+        writeln!(f, "Items used: {}", self.items_used)?;
+        writeln!(f, "Tiles explored: {}", self.tiles_explored)?;
+        writeln!(f, "Prisoners rescued: {}", self.prisoners_rescued)?;
+        writeln!(f, "Prisoners lost: {}", self.prisoners_lost)?;
+        write!(f, "Monsters killed: {}", self.total_kills())?;
+        for (name, count) in &self.kills {
+            write!(f, "\n  {}: {}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct LifetimeStats {
+    runs: i32,
+    victories: i32,
+    best_score: i32,
+    #[serde(default)]
+    best_wave: u32,
+    totals: RunStats,
+    // Best first
+    #[serde(default)]
+    high_scores: Vec<HighScore>,
+}
+
+const HIGH_SCORES: usize = 10;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct HighScore {
+    score: i32,
+    #[serde(default)]
+    name: String,
+    class: Class,
+    character_level: i32,
+    reason: String,
+    #[serde(default)]
+    turns: i32,
+    // Last wave reached, for the arena runs
+    #[serde(default)]
+    arena_wave: Option<u32>,
+}
+
+/// Stats of the current run, with the explored tiles of every visited level counted up
+fn current_stats(game: &Game) -> RunStats {
+    let explored = |map: &GameMap| map.tiles().filter(|tile| tile.explored).count() as i32;
+    let mut stats = game.stats.clone();
+    stats.turns = game.turn;
+    stats.tiles_explored = explored(&game.map) + game.levels.values().map(|level| explored(&level.map)).sum::<i32>();
+    stats
+}
+
+fn load_lifetime_stats() -> Result<LifetimeStats, Box<dyn Error>> {
+    let mut text = String::new();
+    let mut file = File::open(STATS_FILE)?;
+    file.read_to_string(&mut text)?;
+    let result = serde_json::from_str::<LifetimeStats>(&text)?;
+    Ok(result)
+}
+
+fn record_lifetime_stats(summary: &RunSummary) -> Result<(), Box<dyn Error>> {
+    let mut lifetime = load_lifetime_stats().unwrap_or_default();
+    lifetime.runs += 1;
+    if summary.outcome == Outcome::Victory {
+        lifetime.victories += 1;
+    }
+    lifetime.best_score = cmp::max(lifetime.best_score, summary.score);
+    lifetime.best_wave = cmp::max(lifetime.best_wave, summary.arena_wave.unwrap_or(0));
+    lifetime.totals.add(&summary.stats);
+    add_high_score(&mut lifetime.high_scores, HighScore {
+        score: summary.score,
+        name: summary.name.clone(),
+        class: summary.class,
+        character_level: summary.character_level,
+        reason: summary.reason.clone(),
+        turns: summary.stats.turns,
+        arena_wave: summary.arena_wave,
+    });
+    let mut file = File::create(STATS_FILE)?;
+    file.write_all(serde_json::to_string(&lifetime)?.as_bytes())?;
+    Ok(())
+}
+
+/// Best first, only the top HIGH_SCORES are kept
+fn add_high_score(high_scores: &mut Vec<HighScore>, entry: HighScore) {
+    high_scores.push(entry);
+    high_scores.sort_by_key(|entry| -entry.score);
+    high_scores.truncate(HIGH_SCORES);
+}
+
+/// The current run if there is one, and everything before it
+fn show_stats(run: Option<&RunStats>, root: &mut Root) {
+    let mut text = String::new();
+    if let Some(run) = run {
+        text.push_str(&format!("This run\n\n{}\n\n", run));
+    }
+    match load_lifetime_stats() {
+        Ok(lifetime) => text.push_str(&format!(
+            "All runs\n\nRuns: {}\nVictories: {}\nBest score: {}\nBest arena wave: {}\n{}",
+            lifetime.runs, lifetime.victories, lifetime.best_score, lifetime.best_wave, lifetime.totals)),
+        Err(_) => text.push_str("No finished runs yet."),
+    }
+    msgbox(&text, STATS_SCREEN_WIDTH, root);
+}
+
+fn show_high_scores(root: &mut Root) {
+    let scores = load_lifetime_stats().map(|lifetime| lifetime.high_scores).unwrap_or_default();
+    let mut text = String::from("High scores\n");
+    for (place, entry) in scores.iter().enumerate() {
+        let who = if entry.name.is_empty() {
+            entry.class.name().to_string()
+        } else {
+            format!("{} the {}", entry.name, entry.class.name())
+        };
+        let wave = entry.arena_wave.map(|wave| format!(", arena wave {}", wave)).unwrap_or_default();
+        text.push_str(&format!("\n{:>2}. {:>6}  {} level {}, {} turns{}\n    {}", place + 1, entry.score,
+                               who, entry.character_level, entry.turns, wave, entry.reason));
+    }
+    if scores.is_empty() {
+        text.push_str("\nNo finished runs yet.");
+    }
+    msgbox(&text, STATS_SCREEN_WIDTH, root);
+}
+
+/////////////////////// Bestiary
+const BESTIARY_FILE: &str = "bestiary.json";
+const LORE_FILE: &str = "bestiary.toml";
+// Kills it takes to learn the health of a kind of monster, and then the rest of its strength
+const BESTIARY_HP_KILLS: i32 = 3;
+const BESTIARY_STATS_KILLS: i32 = 10;
+
+/// How strong a monster was when it died
+#[derive(Clone, Copy, Debug)]
+struct MonsterStats {
+    char: char,
+    max_hp: i32,
+    power: i32,
+    defence: i32,
+    xp: i32,
+    // Champions are tougher than the rest of their kind
+    champion: bool,
+}
+
+impl MonsterStats {
+    fn of(monster: &Object, game: &Game) -> Self {
+        MonsterStats {
+            char: monster.char,
+            max_hp: monster.max_hp(game),
+            power: monster.power(game),
+            defence: monster.defence(game),
+            xp: monster.fighter.map_or(0, |fighter| fighter.xp),
+            champion: monster.affix.is_some(),
+        }
+    }
+}
+
+/// What is known about one kind of monster, over all the runs
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct BestiaryEntry {
+    char: char,
+    kills: i32,
+    // The weakest and the toughest one killed
+    min_hp: i32,
+    max_hp: i32,
+    // Of the strongest one killed
+    power: i32,
+    defence: i32,
+    xp: i32,
+}
+
+/// Every kind of monster met so far, by name
+#[derive(Default, Serialize, Deserialize)]
+struct Bestiary {
+    entries: BTreeMap<String, BestiaryEntry>,
+}
+
+impl Bestiary {
+    pub fn load() -> Self {
+        read_bestiary().unwrap_or_default()
+    }
+
+    fn save(&self) {
+        if let Err(error) = write_bestiary(self) {
+            warn!("Cannot save {}: {}", BESTIARY_FILE, error);
+        }
+    }
+
+    /// True the first time this kind of monster is seen
+    fn sight(&mut self, monster: &Object) -> bool {
+        if self.entries.contains_key(&monster.name) {
+            return false;
+        }
+        let entry = BestiaryEntry { char: monster.char, ..Default::default() };
+        self.entries.insert(monster.name.clone(), entry);
+        true
+    }
+
+    /// Champions count as kills, but their stats would make the whole kind look stronger than it is
+    fn record_kill(&mut self, name: &str, stats: &MonsterStats) {
+        let entry = self.entries.entry(name.to_string()).or_default();
+        entry.char = stats.char;
+        entry.kills += 1;
+        if stats.champion {
+            return;
+        }
+        entry.min_hp = if entry.max_hp == 0 { stats.max_hp } else { cmp::min(entry.min_hp, stats.max_hp) };
+        entry.max_hp = cmp::max(entry.max_hp, stats.max_hp);
+        entry.power = cmp::max(entry.power, stats.power);
+        entry.defence = cmp::max(entry.defence, stats.defence);
+        entry.xp = cmp::max(entry.xp, stats.xp);
+    }
+}
+
+fn read_bestiary() -> Result<Bestiary, Box<dyn Error>> {
+    let mut text = String::new();
+    let mut file = File::open(BESTIARY_FILE)?;
+    file.read_to_string(&mut text)?;
+    let result = serde_json::from_str::<Bestiary>(&text)?;
+    Ok(result)
+}
+
+fn write_bestiary(bestiary: &Bestiary) -> Result<(), Box<dyn Error>> {
+    let mut file = File::create(BESTIARY_FILE)?;
+    file.write_all(serde_json::to_string(bestiary)?.as_bytes())?;
+    Ok(())
+}
+
+#[derive(Default, Deserialize)]
+struct LoreFile {
+    #[serde(default)]
+    monster: HashMap<String, String>,
+}
+
+/// Flavor text of the monsters by name, none if the file is missing
+fn load_lore() -> HashMap<String, String> {
+    match read_lore_file() {
+        Ok(file) => file.monster,
+        Err(error) => {
+            warn!("Cannot load {}: {}", LORE_FILE, error);
+            HashMap::new()
+        }
+    }
+}
+
+fn read_lore_file() -> Result<LoreFile, Box<dyn Error>> {
+    let mut text = String::new();
+    let mut file = File::open(LORE_FILE)?;
+    file.read_to_string(&mut text)?;
+    let result = toml::from_str::<LoreFile>(&text)?;
+    Ok(result)
+}
+
+/// Add the monsters in view to the bestiary the first time they show up
+fn note_sightings(tcod: &Tcod, objects: &[Object], game: &mut Game) {
+    if game.wizard || game.objective.is_tutorial() {
+        return;
+    }
+    let mut changed = false;
+    for object in objects {
+        // Only the monsters, not the townsfolk or the player's companions
+        let monster = !matches!(object.faction, Faction::Neutral | Faction::Player);
+        if monster && object.alive && object.fighter.is_some() && object.disguise.is_none() &&
+            tcod.fov.is_in_fov(object.x, object.y) && game.bestiary.sight(object) {
+            game.log.add(format!("New bestiary entry: {}.", object.name), colors::LIGHT_BLUE);
+            changed = true;
+        }
+    }
+    if changed {
+        game.bestiary.save();
+    }
+}
+
+/// The monsters met in all the runs, more is told about the ones killed often
+fn show_bestiary(root: &mut Root) {
+    let bestiary = Bestiary::load();
+    if bestiary.entries.is_empty() {
+        msgbox("\nNo monsters met yet.\n", 24, root);
+        return;
+    }
+    let lore = load_lore();
+    let names: Vec<&String> = bestiary.entries.keys().collect();
+    let options: Vec<String> = bestiary.entries.iter()
+        .map(|(name, entry)| format!("{} {} ({} killed)", entry.char, name, entry.kills))
+        .collect();
+    let mut first = 0;
+    loop {
+        let header = format!("Bestiary, {} kinds of monsters met\n", names.len());
+        let (_, choice) = paged_menu_key(&header, &options, STATS_SCREEN_WIDTH, root, &mut first);
+        let index = match choice {
+            Some(index) => index,
+            None => break,
+        };
+        let name = names[index];
+        let entry = &bestiary.entries[name];
+        let mut text = format!("{} {}\n\n", entry.char, name);
+        text.push_str(lore.get(name).map_or("Nothing more is known about it.", |lore| lore.as_str()));
+        text.push_str(&format!("\n\nKilled: {}", entry.kills));
+        if entry.kills >= BESTIARY_HP_KILLS && entry.max_hp > 0 {
+            if entry.min_hp == entry.max_hp {
+                text.push_str(&format!("\nHealth: {}", entry.max_hp));
+            } else {
+                text.push_str(&format!("\nHealth: {} to {}", entry.min_hp, entry.max_hp));
+            }
+        } else if entry.kills >= BESTIARY_HP_KILLS {
+            text.push_str("\nHealth: only champions were killed so far");
+        } else {
+            text.push_str(&format!("\nHealth: kill {} more to learn", BESTIARY_HP_KILLS - entry.kills));
+        }
+        if entry.kills >= BESTIARY_STATS_KILLS && entry.max_hp > 0 {
+            text.push_str(&format!("\nAttack: {}\nDefence: {}\nExperience: {}", entry.power, entry.defence, entry.xp));
+        } else {
+            text.push_str(&format!("\nStrength: kill {} more to learn", BESTIARY_STATS_KILLS - entry.kills));
+        }
+        msgbox(&text, STATS_SCREEN_WIDTH, root);
+    }
+}
+
+/////////////////////// Narration
+const NARRATION_FILE: &str = "narration.txt";
+
+/// Where the messages and the surroundings are written for a screen reader
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Narration {
+    Off,
+    Stdout,
+    File,
+}
+
+impl Narration {
+    fn name(self) -> &'static str {
+        match self {
+            Narration::Off => "off",
+            Narration::Stdout => "standard output",
+            Narration::File => NARRATION_FILE,
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            Narration::Off => Narration::Stdout,
+            Narration::Stdout => Narration::File,
+            Narration::File => Narration::Off,
+        }
+    }
+}
+
+fn number_word(number: i32) -> String {
+    const WORDS: [&str; 11] = ["zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten"];
+    WORDS.get(number as usize).map_or_else(|| number.to_string(), |word| word.to_string())
+}
+
+/// Compass direction of the offset, y grows to the south
+fn direction_name(dx: i32, dy: i32) -> &'static str {
+    const NAMES: [&str; 8] = ["east", "southeast", "south", "southwest", "west", "northwest", "north", "northeast"];
+    let octant = ((dy as f32).atan2(dx as f32) / std::f32::consts::FRAC_PI_4).round() as i32;
+    NAMES[((octant + 8) % 8) as usize]
+}
+
+/// Everything in sight, nearest first: "Orc two tiles north, stairs down east"
+fn describe_surroundings(objects: &[Object], game: &Game, fov: &Vision) -> String {
+    let player = &objects[PLAYER];
+    let see_invisible = wears_effect(PassiveEffect::SeeInvisible, game);
+    let mut seen: Vec<_> = objects
+        .iter()
+        .skip(PLAYER + 1)
+        .filter(|obj| obj.shown(see_invisible) && fov.is_in_fov(obj.x, obj.y))
+        .collect();
+    seen.sort_by_key(|obj| cmp::max((obj.x - player.x).abs(), (obj.y - player.y).abs()));
+    let described: Vec<String> = seen
+        .iter()
+        .map(|obj| {
+            let (dx, dy) = (obj.x - player.x, obj.y - player.y);
+            let name = &obj.appearance().name;
+            match cmp::max(dx.abs(), dy.abs()) {
+                0 => format!("{} here", name),
+                1 => format!("{} {}", name, direction_name(dx, dy)),
+                distance => format!("{} {} tiles {}", name, number_word(distance), direction_name(dx, dy)),
+            }
+        })
+        .collect();
+    let hp = player.fighter.map_or(0, |f| f.hp);
+    let sight = if described.is_empty() { "Nothing in sight".to_string() } else { described.join(", ") };
+    format!("HP {} of {}. {}.", hp, player.max_hp(game), sight)
+}
+
+/// Mirror the new messages, and the surroundings after a turn, to the text output
+fn narrate(settings: &Settings, game: &mut Game, objects: &[Object], fov: &mut Vision, took_turn: bool) {
+    let mut lines = std::mem::take(&mut game.log.unnarrated);
+    if settings.narration == Narration::Off {
+        return;
+    }
+    if took_turn {
+        // The map is only redrawn on the next frame, so the view is still from before the move
+        let player = &objects[PLAYER];
+        compute_fov(fov, player.x, player.y, settings);
+        lines.push(describe_surroundings(objects, game, fov));
+    }
+    if lines.is_empty() {
+        return;
+    }
+    match settings.narration {
+        Narration::Stdout => {
+            for line in &lines {
+                println!("{}", line);
+            }
+        }
+        Narration::File => {
+            if let Err(error) = write_narration(&lines) {
+                warn!("Cannot write the narration file: {}", error);
+            }
+        }
+        Narration::Off => {}
+    }
+}
+
+fn write_narration(lines: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut file = OpenOptions::new().create(true).append(true).open(NARRATION_FILE)?;
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    Ok(())
+}
+
+/////////////////////// Spectating
+const SPECTATE_FILE: &str = "spectate.json";
+const SPECTATE_SOCKET: &str = "spectate.sock";
+const SPECTATE_LOG_LINES: usize = 5;
+
+/// Where the view of the game is sent every turn for the overlays, bots and viewers
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Spectate {
+    Off,
+    // Rewritten every turn
+    File,
+    // A line of JSON for everyone connected, UNIX only
+    Socket,
+}
+
+impl Spectate {
+    fn name(self) -> &'static str {
+        match self {
+            Spectate::Off => "off",
+            Spectate::File => SPECTATE_FILE,
+            Spectate::Socket => SPECTATE_SOCKET,
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            Spectate::Off => Spectate::File,
+            Spectate::File => Spectate::Socket,
+            Spectate::Socket => Spectate::Off,
+        }
+    }
+}
+
+/// What the player sees right now, nothing the renderer is needed for
+#[derive(Serialize)]
+struct SpectatorView<'a> {
+    turn: i32,
+    level: u32,
+    hp: i32,
+    max_hp: i32,
+    x: i32,
+    y: i32,
+    // A row of characters for every map line, blank where the player can't see
+    map: Vec<String>,
+    objects: Vec<SpectatedObject<'a>>,
+    log: Vec<&'a str>,
+}
+
+#[derive(Serialize)]
+struct SpectatedObject<'a> {
+    name: &'a str,
+    char: char,
+    x: i32,
+    y: i32,
+}
+
+fn spectator_view<'a>(game: &'a Game, objects: &'a [Object], fov: &Vision) -> SpectatorView<'a> {
+    let player = &objects[PLAYER];
+    let see_invisible = wears_effect(PassiveEffect::SeeInvisible, game);
+    let map = (0..MAP_HEIGHT)
+        .map(|y| {
+            let row: String = (0..MAP_WIDTH)
+                .map(|x| {
+                    let tile = game.map[(x, y)];
+                    match tile {
+                        _ if !fov.is_in_fov(x, y) => ' ',
+                        tile if tile.blocked => '#',
+                        tile if tile.water => '~',
+                        tile if tile.is_bush() => '*',
+                        _ => '.',
+                    }
+                })
+                .collect();
+            row.trim_end().to_string()
+        })
+        .collect();
+    let objects = objects
+        .iter()
+        .filter(|object| object.shown(see_invisible) && fov.is_in_fov(object.x, object.y))
+        .map(|object| {
+            let look = object.appearance();
+            SpectatedObject { name: &look.name, char: look.char, x: object.x, y: object.y }
+        })
+        .collect();
+    let skipped = game.log.entries.len().saturating_sub(SPECTATE_LOG_LINES);
+    SpectatorView {
+        turn: game.turn,
+        level: game.dungeon_level,
+        hp: player.fighter.map_or(0, |f| f.hp),
+        max_hp: player.max_hp(game),
+        x: player.x,
+        y: player.y,
+        map,
+        objects,
+        log: game.log.entries.iter().skip(skipped).map(|entry| entry.text.as_str()).collect(),
+    }
+}
+
+/// The socket and whoever is listening on it, opened when the option is first used
+#[derive(Default)]
+struct Spectators {
+    #[cfg(unix)]
+    listener: Option<std::os::unix::net::UnixListener>,
+    #[cfg(unix)]
+    clients: Vec<std::os::unix::net::UnixStream>,
+}
+
+impl Spectators {
+    fn send(&mut self, mode: Spectate, line: &str) -> Result<(), Box<dyn Error>> {
+        if mode != Spectate::Socket {
+            self.close();
+        }
+        match mode {
+            Spectate::Off => Ok(()),
+            Spectate::File => {
+                // Written aside and moved in place, so nobody reads a half written file
+                let temporary = format!("{}.tmp", SPECTATE_FILE);
+                File::create(&temporary)?.write_all(line.as_bytes())?;
+                std::fs::rename(&temporary, SPECTATE_FILE)?;
+                Ok(())
+            }
+            Spectate::Socket => self.broadcast(line),
+        }
+    }
+
+    #[cfg(unix)]
+    fn broadcast(&mut self, line: &str) -> Result<(), Box<dyn Error>> {
+        use std::os::unix::net::UnixListener;
+
+        if self.listener.is_none() {
+            // Left behind by an earlier game
+            let _ = std::fs::remove_file(SPECTATE_SOCKET);
+            let listener = UnixListener::bind(SPECTATE_SOCKET)?;
+            listener.set_nonblocking(true)?;
+            self.listener = Some(listener);
+        }
+        if let Some(ref listener) = self.listener {
+            while let Ok((client, _)) = listener.accept() {
+                client.set_nonblocking(true)?;
+                self.clients.push(client);
+            }
+        }
+        // The ones that went away or can't keep up are dropped
+        let message = format!("{}\n", line);
+        self.clients.retain_mut(|client| client.write_all(message.as_bytes()).is_ok());
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn broadcast(&mut self, _line: &str) -> Result<(), Box<dyn Error>> {
+        Err("sockets are only supported on UNIX".into())
+    }
+
+    fn close(&mut self) {
+        #[cfg(unix)]
+        {
+            if self.listener.take().is_some() {
+                let _ = std::fs::remove_file(SPECTATE_SOCKET);
+            }
+            self.clients.clear();
+        }
+    }
+}
+
+/// Send the view out after every turn the player takes
+fn spectate(tcod: &mut Tcod, game: &Game, objects: &[Object], took_turn: bool) {
+    let mode = tcod.settings.spectate;
+    if mode == Spectate::Off {
+        tcod.spectators.close();
+        return;
+    }
+    if !took_turn {
+        return;
+    }
+    let player = &objects[PLAYER];
+    compute_fov(&mut tcod.fov, player.x, player.y, &tcod.settings);
+    let view = spectator_view(game, objects, &tcod.fov);
+    let sent = serde_json::to_string(&view)
+        .map_err(|error| error.into())
+        .and_then(|line| tcod.spectators.send(mode, &line));
+    if let Err(error) = sent {
+        warn!("Cannot send the spectator view: {}", error);
+        tcod.settings.spectate = Spectate::Off;
+    }
+}
+
+/////////////////////// Randomness
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::new().expect("Cannot seed the random generator"));
+}
+
+/// Every random draw of the game goes through here, so the same seed and moves replay the same game
+struct GameRng;
+
+impl Rng for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        RNG.with(|rng| rng.borrow_mut().next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        RNG.with(|rng| rng.borrow_mut().next_u64())
+    }
+}
+
+fn game_rng() -> GameRng {
+    GameRng
+}
+
+fn seed_rng(seed: u64) {
+    let key = [seed as usize, (seed >> 32) as usize];
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::from_seed(&key[..]));
+}
+
+/////////////////////// Tracing
+const TRACE_FILE: &str = "trace.log";
+// The window has no console, so the problems are kept here as well
+const ERRORS_FILE: &str = "errors.log";
+
+/// Warnings go to the standard error and the errors file, everything to the trace when it's on.
+/// Every line is on disk as soon as it's written, so a crash doesn't take the end with it
+struct GameLog {
+    errors: Option<Mutex<LineWriter<File>>>,
+    trace: Option<Mutex<LineWriter<File>>>,
+}
+
+impl log::Log for GameLog {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn || (self.trace.is_some() && metadata.level() <= log::Level::Debug)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Nowhere left to report a failing log, the game goes on without it
+        if record.level() <= log::Level::Warn {
+            eprintln!("{}: {}", record.level(), record.args());
+            if let Some(Ok(mut file)) = self.errors.as_ref().map(Mutex::lock) {
+                let _ = writeln!(file, "{:<5} {}", record.level(), record.args());
+            }
+        }
+        if let Some(Ok(mut file)) = self.trace.as_ref().map(Mutex::lock) {
+            let _ = writeln!(file, "{:<5} {:<6} {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        for file in self.errors.iter().chain(self.trace.iter()) {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+/// Keep the warnings of every session, and with `trace` record the turns, rolls and monster decisions
+/// of this one, replacing the previous trace
+fn start_logging(trace: bool) -> Result<(), Box<dyn Error>> {
+    let errors = OpenOptions::new().create(true).append(true).open(ERRORS_FILE).ok();
+    let trace_file = if trace { Some(File::create(TRACE_FILE)?) } else { None };
+    let logger = GameLog {
+        errors: errors.map(|file| Mutex::new(LineWriter::new(file))),
+        trace: trace_file.map(|file| Mutex::new(LineWriter::new(file))),
+    };
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(if trace { log::LevelFilter::Debug } else { log::LevelFilter::Warn });
+    info!(target: "trace", "{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    Ok(())
+}
+
+/////////////////////// Sound
+const SOUNDS_DIR: &str = "sounds";
+// Effects playing at once, the ones past that are skipped instead of piling up
+const MAX_SOUNDS: usize = 4;
+
+/// The sound device, opened once and shared by everything that plays
+struct AudioOutput {
+    // Nothing plays once the stream is dropped
+    _stream: rodio::OutputStream,
+    handle: rodio::OutputStreamHandle,
+    effects: Vec<rodio::Sink>,
+    // Files that are there but can't be decoded, reported only the first time
+    broken: Vec<String>,
+}
+
+impl AudioOutput {
+    /// The decoded file, or None if it's missing or broken
+    fn decode(&mut self, path: &str) -> Option<rodio::Decoder<BufReader<File>>> {
+        let file = File::open(path).ok()?;
+        match rodio::Decoder::new(BufReader::new(file)) {
+            Ok(source) => Some(source),
+            Err(error) => {
+                if !self.broken.iter().any(|broken| broken == path) {
+                    warn!("Cannot play {}: {}", path, error);
+                    self.broken.push(path.to_string());
+                }
+                None
+            }
+        }
+    }
+}
+
+thread_local! {
+    // Opened with the first sound, and left as None for good when there's no device to play on
+    static AUDIO_OUTPUT: RefCell<Option<Option<AudioOutput>>> = const { RefCell::new(None) };
+}
+
+/// Run `play` with the sound device, unless there isn't one
+fn with_audio<T>(play: impl FnOnce(&mut AudioOutput) -> Option<T>) -> Option<T> {
+    AUDIO_OUTPUT.with(|output| {
+        let mut output = output.borrow_mut();
+        let output = output.get_or_insert_with(|| match rodio::OutputStream::try_default() {
+            Ok((stream, handle)) => Some(AudioOutput { _stream: stream, handle, effects: vec![], broken: vec![] }),
+            Err(error) => {
+                warn!("Cannot open the sound device, the game stays silent: {}", error);
+                None
+            }
+        });
+        output.as_mut().and_then(play)
+    })
+}
+
+/// Short effects, played from the wav files of the sounds folder
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Sound {
+    Hit,
+    Death,
+    LevelUp,
+    Pickup,
+    Stairs,
+    Spell,
+}
+
+impl Sound {
+    fn file_name(self) -> &'static str {
+        match self {
+            Sound::Hit => "hit.wav",
+            Sound::Death => "death.wav",
+            Sound::LevelUp => "level_up.wav",
+            Sound::Pickup => "pickup.wav",
+            Sound::Stairs => "stairs.wav",
+            Sound::Spell => "spell.wav",
+        }
+    }
+}
+
+/// Play the effect over whatever else is playing, a missing file just stays quiet
+fn play_sound(sound: Sound, settings: &Settings) {
+    if settings.muted || settings.volume <= 0 {
+        return;
+    }
+    let path = format!("{}/{}", SOUNDS_DIR, sound.file_name());
+    with_audio(|audio| {
+        audio.effects.retain(|sink| !sink.empty());
+        if audio.effects.len() >= MAX_SOUNDS {
+            return None;
+        }
+        let source = audio.decode(&path)?;
+        let sink = rodio::Sink::try_new(&audio.handle).ok()?;
+        sink.set_volume(settings.volume as f32 / 100.0);
+        sink.append(source);
+        audio.effects.push(sink);
+        Some(())
+    });
+}
+
+const MUSIC_DIR: &str = "music";
+
+/// Background tracks, one for the menu and one for every level theme
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Track {
+    Menu,
+    Level(Theme),
+}
+
+impl Track {
+    fn file_name(self) -> &'static str {
+        match self {
+            Track::Menu => "menu.wav",
+            Track::Level(Theme::Town) => "town.wav",
+            Track::Level(Theme::Sewers) => "sewers.wav",
+            Track::Level(Theme::Caverns) => "caverns.wav",
+            Track::Level(Theme::Crypt) => "crypt.wav",
+        }
+    }
+}
+
+// Time it takes a track to fade in or out
+const MUSIC_FADE: Duration = Duration::from_millis(1500);
+// Volume steps of a fade out
+const MUSIC_FADE_STEPS: u32 = 30;
+// A track that ends sooner than this couldn't be played
+const MUSIC_MIN_RUN: Duration = Duration::from_secs(1);
+
+/// One track playing on a sink of its own
+struct Channel {
+    track: Track,
+    sink: Arc<rodio::Sink>,
+    started: Instant,
+}
+
+impl Channel {
+    fn start(track: Track, volume: i32, fade_in: bool) -> Option<Channel> {
+        let path = format!("{}/{}", MUSIC_DIR, track.file_name());
+        with_audio(|audio| {
+            let source = audio.decode(&path)?;
+            let sink = rodio::Sink::try_new(&audio.handle).ok()?;
+            sink.set_volume(volume as f32 / 100.0);
+            if fade_in {
+                sink.append(rodio::Source::fade_in(source, MUSIC_FADE));
+            } else {
+                sink.append(source);
+            }
+            Some(Channel { track, sink: Arc::new(sink), started: Instant::now() })
+        })
+    }
+
+    /// Ramp the volume down to silence on its own thread, so the fade goes on while a menu waits for a key
+    fn fade_out(&self) {
+        let sink = Arc::clone(&self.sink);
+        std::thread::spawn(move || {
+            let start = sink.volume();
+            for step in (0..MUSIC_FADE_STEPS).rev() {
+                std::thread::sleep(MUSIC_FADE / MUSIC_FADE_STEPS);
+                sink.set_volume(start * step as f32 / MUSIC_FADE_STEPS as f32);
+            }
+            sink.stop();
+        });
+    }
+}
+
+/// The music playing on its own channel, separate from the effects
+#[derive(Default)]
+struct Music {
+    current: Option<Channel>,
+    // Tracks switched away from, fading out
+    leaving: Vec<Channel>,
+    // What couldn't be played, it's not tried again until the track changes
+    failed: Option<Track>,
+}
+
+impl Music {
+    /// Switch to the track when it changes, fading between the two, and start it over when it ends
+    pub fn play(&mut self, track: Track, settings: &Settings) {
+        if settings.music_muted || settings.music_volume <= 0 {
+            self.stop();
+            return;
+        }
+        let volume = settings.music_volume;
+        self.leaving.retain(|channel| !channel.sink.empty());
+        let switched = self.current.as_ref().is_some_and(|current| current.track != track);
+        if switched {
+            let old = self.current.take().unwrap();
+            old.fade_out();
+            self.leaving.push(old);
+        }
+        if let Some(current) = self.current.take() {
+            if !current.sink.empty() {
+                current.sink.set_volume(volume as f32 / 100.0);
+                self.current = Some(current);
+            } else if current.started.elapsed() < MUSIC_MIN_RUN {
+                self.failed = Some(track);
+            }
+        }
+        if self.failed.is_some_and(|failed| failed != track) {
+            self.failed = None;
+        }
+        if self.current.is_none() && self.failed.is_none() {
+            // Only a new track fades in, not the same one starting over
+            let fade_in = switched || !self.leaving.is_empty();
+            self.current = Channel::start(track, volume, fade_in);
+            if self.current.is_none() {
+                self.failed = Some(track);
+            }
+        }
+    }
+
+    pub fn stop(&mut self) {
+        for channel in self.current.take().into_iter().chain(self.leaving.drain(..)) {
+            channel.sink.stop();
+        }
+        self.failed = None;
+    }
+}
+
+impl Drop for Music {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/////////////////////// Quests
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum QuestGoal {
+    Kill { monster: String, count: i32 },
+    Fetch { item: String, level: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum QuestState {
+    Active,
+    // The goal is reached, the giver has to be told about it
+    Done,
+    Rewarded,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Quest {
+    giver: String,
+    goal: QuestGoal,
+    progress: i32,
+    state: QuestState,
+    reward_gold: i32,
+    reward_xp: i32,
+}
+
+impl Quest {
+    pub fn description(&self) -> String {
+        match self.goal {
+            QuestGoal::Kill { ref monster, count } => {
+                format!("Kill {} {}s for {} ({}/{})",
+                        count, monster.to_lowercase(), self.giver, self.progress, count)
+            }
+            QuestGoal::Fetch { ref item, level } => {
+                format!("Bring the {} from level {} to {}", item, level, self.giver)
+            }
+        }
+    }
+}
+
+fn new_quest(giver: &str, game: &Game) -> Quest {
+    let deepest = game.levels.keys().cloned().chain(Some(game.dungeon_level)).max().unwrap_or(0);
+    let goal = match game_rng().gen_range(0, 3) {
+        0 => QuestGoal::Kill { monster: "Orc".into(), count: 6 },
+        1 => QuestGoal::Kill { monster: "Troll".into(), count: 3 },
+        _ => QuestGoal::Fetch {
+            item: "golden idol".into(),
+            level: deepest + game_rng().gen_range(1, 3),
+        },
+    };
+    Quest {
+        giver: giver.into(),
+        goal,
+        progress: 0,
+        state: QuestState::Active,
+        reward_gold: 100 + 25 * deepest as i32,
+        reward_xp: 100 + 50 * deepest as i32,
+    }
+}
+
+/// Update the quests with what happened during the turn
+fn process_events(game: &mut Game, objects: &mut Vec<Object>, settings: &Settings) {
+    let events: Vec<GameEvent> = game.events.drain(..).collect();
+    // The same sound several times in a turn would only get louder
+    let mut played = vec![];
+    for event in events {
+        if let RunObjective::Tutorial { step } = game.objective {
+            if let Some(next) = step.next(&event) {
+                game.objective = RunObjective::Tutorial { step: next };
+            }
+        }
+        match event {
+            GameEvent::PickedUp(_) | GameEvent::UsedItem(_) | GameEvent::Equipped(_) => {}
+            GameEvent::Sound(sound) => {
+                if !played.contains(&sound) {
+                    play_sound(sound, settings);
+                    played.push(sound);
+                }
+            }
+            GameEvent::MonsterKilled { name, pos, carried, stats, by_player } => {
+                if !played.contains(&Sound::Death) {
+                    play_sound(Sound::Death, settings);
+                    played.push(Sound::Death);
+                }
+                objects.extend(carried);
+                drop_loot(&name, pos, game, objects);
+                if !by_player {
+                    continue;
+                }
+                *game.stats.kills.entry(name.clone()).or_insert(0) += 1;
+                if !game.wizard && !game.objective.is_tutorial() {
+                    game.bestiary.record_kill(&name, &stats);
+                    game.bestiary.save();
+                }
+                if game.stats.total_kills() > PACIFIST_MAX_KILLS {
+                    break_conduct(Conduct::Pacifist, game);
+                }
+                for quest in game.quests.iter_mut() {
+                    if let QuestGoal::Kill { ref monster, count } = quest.goal {
+                        if quest.state == QuestState::Active && *monster == name {
+                            quest.progress += 1;
+                            if quest.progress >= count {
+                                quest.state = QuestState::Done;
+                                game.log.add(
+                                    format!("Quest complete! Return to {} for your reward.", quest.giver),
+                                    colors::LIGHT_CYAN);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Hide the items of fetch quests on freshly generated levels
+fn place_quest_items(game: &Game, objects: &mut Vec<Object>) {
+    for quest in &game.quests {
+        if let QuestGoal::Fetch { level, .. } = quest.goal {
+            if level == game.dungeon_level && quest.state == QuestState::Active {
+                if let Some((x, y)) = random_free_tile(&game.map, objects) {
+                    objects.push(make_item(Item::Idol, x, y));
+                }
+            }
+        }
+    }
+}
+
+fn talk_to_quest_giver(giver_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    let giver = objects[giver_id].name.clone();
+
+    // Hand in the finished quests first
+    for quest_id in 0..game.quests.len() {
+        if game.quests[quest_id].giver != giver {
+            continue;
+        }
+        if let (QuestGoal::Fetch { ref item, .. }, QuestState::Active) =
+            (&game.quests[quest_id].goal, game.quests[quest_id].state) {
+            if take_named_item(item, &mut game.inventory).is_some() {
+                game.quests[quest_id].state = QuestState::Done;
+            }
+        }
+        if game.quests[quest_id].state == QuestState::Done {
+            let quest = &mut game.quests[quest_id];
+            quest.state = QuestState::Rewarded;
+            game.gold += quest.reward_gold;
+            objects[PLAYER].fighter.as_mut().unwrap().xp += quest.reward_xp;
+            game.log.add(
+                format!("\"Well done!\" {} gives you {} gold. You gain {} XP.",
+                        giver, quest.reward_gold, quest.reward_xp),
+                colors::LIGHT_CYAN);
+        }
+    }
+
+    let active = game.quests
+        .iter()
+        .find(|quest| quest.giver == giver && quest.state == QuestState::Active);
+    if let Some(quest) = active {
+        game.log.add(format!("\"Come back when you're done.\" {}", quest.description()),
+                     colors::LIGHT_AZURE);
+        return;
+    }
+
+    let quest = new_quest(&giver, game);
+    let choice = menu(
+        &format!("\"I have a task for you, stranger.\"\n\n{}\nReward: {} gold, {} XP\n",
+                 quest.description(), quest.reward_gold, quest.reward_xp),
+        &["Accept", "Decline"],
+        INVENTORY_WIDTH,
+        &mut tcod.root);
+    if choice == Some(0) {
+        game.log.add(format!("New quest: {}", quest.description()), colors::LIGHT_CYAN);
+        game.quests.push(quest);
+    }
+}
+
+/// Looks through the bags as well
+fn take_named_item(name: &str, items: &mut Vec<Object>) -> Option<Object> {
+    if let Some(id) = items.iter().position(|object| object.name == name) {
+        return Some(items.remove(id));
+    }
+    items
+        .iter_mut()
+        .filter_map(|object| object.container.as_mut())
+        .find_map(|container| take_named_item(name, &mut container.items))
+}
+
+/// The quests, and below them the notes found so far to read again
+fn show_journal(game: &Game, root: &mut Root) {
+    let mut text = "Quests\n\n".to_string();
+    if game.quests.is_empty() {
+        text.push_str("You have no quests. Talk to the town elder.\n");
+    }
+    for quest in &game.quests {
+        let state = match quest.state {
+            QuestState::Active => "",
+            QuestState::Done => " [done]",
+            QuestState::Rewarded => " [rewarded]",
+        };
+        text.push_str(&format!("- {}{}\n", quest.description(), state));
+    }
+    if game.journal.is_empty() {
+        text.push_str("\nNotes\n\nNo notes found yet.\n");
+        msgbox(&text, QUESTS_SCREEN_WIDTH, root);
+        return;
+    }
+    text.push_str("\nNotes, pick one to read it again\n");
+    let titles: Vec<String> = game.journal.iter()
+        .map(|note| format!("{} (level {})", note.title, note.level))
+        .collect();
+    let mut first = 0;
+    while let (_, Some(index)) = paged_menu_key(&text, &titles, QUESTS_SCREEN_WIDTH, root, &mut first) {
+        let note = &game.journal[index];
+        msgbox(&format!("{}\n\n{}", note.title, note.text), QUESTS_SCREEN_WIDTH, root);
+    }
+}
+
+/////////////////////// Notes
+const NOTES_FILE: &str = "notes.toml";
+
+/// Something written down by those who came before, kept in the journal once read
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Note {
+    title: String,
+    text: String,
+    // The depth it was found on, or the shallowest one it lies on in the notes file
+    #[serde(default = "first_dungeon_level")]
+    level: u32,
+}
+
+#[derive(Default, Deserialize)]
+struct Notes {
+    #[serde(default)]
+    note: Vec<Note>,
+}
+
+impl Notes {
+    /// Without the file there is nothing to find
+    pub fn load() -> Self {
+        match load_notes() {
+            Ok(notes) => notes,
+            Err(error) => {
+                warn!("Cannot load {}: {}", NOTES_FILE, error);
+                Notes::default()
+            }
+        }
+    }
+}
+
+fn load_notes() -> Result<Notes, Box<dyn Error>> {
+    let mut text = String::new();
+    let mut file = File::open(NOTES_FILE)?;
+    file.read_to_string(&mut text)?;
+    let result = toml::from_str::<Notes>(&text)?;
+    Ok(result)
+}
+
+/// Leave one of the notes that can be found this deep somewhere in the room
+fn place_note(room: Rect, map: &GameMap, objects: &mut Vec<Object>, level: u32, notes: &Notes) {
+    let titles: Vec<&String> = notes.note.iter().filter(|note| note.level <= level).map(|note| &note.title).collect();
+    let title = match game_rng().choose(&titles) {
+        Some(title) => title.to_string(),
+        None => return,
+    };
+    let x = game_rng().gen_range(room.x1 + 1, room.x2);
+    let y = game_rng().gen_range(room.y1 + 1, room.y2);
+    if !is_blocked(x, y, map, objects) {
+        let mut note = Object::new("note", x, y, '?', colors::LIGHTEST_SEPIA, false);
+        note.note = Some(title);
+        objects.push(note);
+    }
+}
+
+/// Read the note on the floor and copy it into the journal, unless it's already there
+fn read_note(note_id: usize, game: &mut Game, objects: &mut Vec<Object>, root: &mut Root) {
+    let object = objects.swap_remove(note_id);
+    let title = match object.note {
+        Some(title) => title,
+        None => return,
+    };
+    let text = game.notes.note
+        .iter()
+        .find(|note| note.title == title)
+        .map_or_else(|| "The ink has run, you can't make out a word.".to_string(), |note| note.text.clone());
+    msgbox(&format!("{}\n\n{}", title, text), QUESTS_SCREEN_WIDTH, root);
+    if game.journal.iter().any(|note| note.title == title) {
+        game.log.add("You already know what this note says.", colors::LIGHT_GREY);
+    } else {
+        game.log.add(format!("You copy \"{}\" into your journal.", title), colors::LIGHT_CYAN);
+        game.journal.push(Note { title, text, level: game.dungeon_level });
+    }
+}
+
+/////////////////////// Dialogue
+const DIALOGUE_FILE: &str = "dialogue.toml";
+
+#[derive(Default, Deserialize)]
+struct Dialogues {
+    npc: Vec<NpcDialogue>,
+}
+
+impl Dialogues {
+    /// Without the file there is nobody to meet
+    pub fn load() -> Self {
+        load_dialogues().unwrap_or_default()
+    }
+}
+
+/// Conversation tree of a single NPC, it starts at the first node
+#[derive(Clone, Deserialize)]
+struct NpcDialogue {
+    name: String,
+    #[serde(default = "first_dungeon_level")]
+    min_level: u32,
+    #[serde(rename = "node")]
+    nodes: Vec<DialogueNode>,
+}
+
+fn first_dungeon_level() -> u32 {
+    1
+}
+
+#[derive(Clone, Deserialize)]
+struct DialogueNode {
+    id: String,
+    text: String,
+    #[serde(default, rename = "option")]
+    options: Vec<DialogueOption>,
+}
+
+/// An answer the player can pick, the conversation ends when there is no next node
+#[derive(Clone, Deserialize)]
+struct DialogueOption {
+    text: String,
+    next: Option<String>,
+    action: Option<DialogueAction>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DialogueAction {
+    // Tell something useful about the current level
+    Hint,
+    // Offer a quest or take a finished one
+    Quest,
+}
+
+fn load_dialogues() -> Result<Dialogues, Box<dyn Error>> {
+    let mut text = String::new();
+    let mut file = File::open(DIALOGUE_FILE)?;
+    file.read_to_string(&mut text)?;
+    let result = toml::from_str::<Dialogues>(&text)?;
+    Ok(result)
+}
+
+/// Put someone from the dialogue file into the room. Monsters leave them alone
+fn place_wanderer(room: Rect, map: &GameMap, objects: &mut Vec<Object>, level: u32, dialogues: &Dialogues) {
+    let names: Vec<&String> = dialogues.npc
+        .iter()
+        .filter(|npc| npc.min_level <= level)
+        .map(|npc| &npc.name)
+        .collect();
+    let name = match game_rng().choose(&names) {
+        Some(name) => name,
+        None => return,
+    };
+    let x = game_rng().gen_range(room.x1 + 1, room.x2);
+    let y = game_rng().gen_range(room.y1 + 1, room.y2);
+    if !is_blocked(x, y, map, objects) {
+        objects.push(make_npc(name, x, y, Npc::Wanderer));
+    }
+}
+
+fn place_prisoner(room: Rect, map: &GameMap, objects: &mut Vec<Object>) {
+    let x = game_rng().gen_range(room.x1 + 1, room.x2);
+    let y = game_rng().gen_range(room.y1 + 1, room.y2);
+    if !is_blocked(x, y, map, objects) {
+        let mut prisoner = make_npc("Caged prisoner", x, y, Npc::Prisoner);
+        // Weak from the captivity, needs the player to keep the monsters off
+        prisoner.fighter = Some(Fighter {
+            hp: 25,
+            base_max_hp: 25,
+            base_defence: 0,
+            base_power: 3,
+            attributes: Attributes::default(),
+            xp: 0,
+            on_death: DeathCallback::Monster,
+        });
+        objects.push(prisoner);
+    }
+}
+
+/// Break the cage open, the prisoner follows the player from now on
+fn free_prisoner(prisoner_id: usize, game: &mut Game, objects: &mut [Object]) {
+    let prisoner = &mut objects[prisoner_id];
+    prisoner.npc = None;
+    prisoner.name = "Prisoner".into();
+    prisoner.ai = Some(Ai::Companion);
+    prisoner.faction = Faction::Player;
+    prisoner.escort = true;
+    game.log.add("You break the lock of the cage.", colors::WHITE);
+    game.log.add("\"Thank you! Get me to the stairs and I'll make it worth your while.\"", colors::LIGHT_AZURE);
+}
+
+/// The prisoners that made it next to the stairs slip away and pay up
+fn escort_prisoners(game: &mut Game, objects: &mut Vec<Object>) {
+    let stairs: Vec<(i32, i32)> = objects
+        .iter()
+        .filter(|object| object.name == "stairs down" || object.name == "stairs up")
+        .map(|object| object.pos())
+        .collect();
+    let at_stairs = |object: &Object| stairs.iter().any(|&(x, y)| object.distance(x, y) < 2.0);
+    while let Some(id) = objects.iter().position(|object| object.escort && object.alive && at_stairs(object)) {
+        objects.swap_remove(id);
+        game.log.add("The prisoner thanks you and slips away up the stairs.", colors::LIGHT_AZURE);
+        game.log.add_in(Category::Item, format!("You receive {} gold and {} XP for the rescue.",
+                        PRISONER_REWARD_GOLD, PRISONER_REWARD_XP), colors::GOLD);
+        game.gold += PRISONER_REWARD_GOLD;
+        objects[PLAYER].fighter.as_mut().unwrap().xp += PRISONER_REWARD_XP;
+        game.stats.prisoners_rescued += 1;
+    }
+}
+
+fn talk_to_npc(npc_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    let name = objects[npc_id].name.clone();
+    // Copied out, the quest talk below needs the game
+    let dialogue = game.dialogues.npc.iter().find(|npc| npc.name == name).cloned();
+    let dialogue = match dialogue {
+        Some(ref dialogue) if !dialogue.nodes.is_empty() => dialogue,
+        _ => {
+            game.log.add(format!("{} has nothing to say.", name), colors::LIGHT_GREY);
+            return;
+        }
+    };
+
+    let mut node = &dialogue.nodes[0];
+    loop {
+        let options: Vec<&str> = if node.options.is_empty() {
+            vec!["Farewell"]
+        } else {
+            node.options.iter().map(|option| option.text.as_str()).collect()
+        };
+        render_all(tcod, objects, game, false);
+        let choice = menu(
+            &format!("{}\n\n\"{}\"\n", name, node.text),
+            &options,
+            DIALOGUE_WIDTH,
+            &mut tcod.root);
+        let option = match choice.and_then(|index| node.options.get(index)) {
+            Some(option) => option,
+            None => break,
+        };
+        match option.action {
+            Some(DialogueAction::Hint) => {
+                let hint = level_hint(game, objects);
+                game.log.add(format!("{}: \"{}\"", name, hint), colors::LIGHT_AZURE);
+            }
+            Some(DialogueAction::Quest) => talk_to_quest_giver(npc_id, tcod, game, objects),
+            None => {}
+        }
+        let next = option.next
+            .as_ref()
+            .and_then(|next| dialogue.nodes.iter().find(|node| node.id == *next));
+        match next {
+            Some(next) => node = next,
+            None => break,
+        }
+    }
+}
+
+/// Something the locals know about the current level
+fn level_hint(game: &Game, objects: &[Object]) -> String {
+    let player = &objects[PLAYER];
+    let unique = objects.iter().find(|object| object.unique && object.alive);
+    if let Some(unique) = unique {
+        return format!("Beware, {} is somewhere on this level.", unique.name);
+    }
+    let monsters = objects
+        .iter()
+        .filter(|object| object.alive && object.ai.is_some())
+        .count();
+    if monsters > 0 && game_rng().gen() {
+        return format!("I counted {} creatures prowling around here.", monsters);
+    }
+    match objects.iter().find(|object| object.name == "stairs down") {
+        Some(stairs) => {
+            let (dx, dy) = (stairs.x - player.x, stairs.y - player.y);
+            let vertical = if dy < -MAP_HEIGHT / 4 { "north" } else if dy > MAP_HEIGHT / 4 { "south" } else { "" };
+            let horizontal = if dx < -MAP_WIDTH / 4 { "west" } else if dx > MAP_WIDTH / 4 { "east" } else { "" };
+            if vertical.is_empty() && horizontal.is_empty() {
+                "The way down is close by.".into()
+            } else {
+                format!("The way down lies to the {}{}.", vertical, horizontal)
+            }
+        }
+        None => format!("This is the {}, there is not much more to know.", level_name(game.dungeon_level)),
+    }
+}
+
+/////////////////////// Logic
+const STINKING_CLOUD_RADIUS: i32 = 2;
+const STINKING_CLOUD_TURNS: i32 = 8;
+const TRAP_ROOM_CHANCE: u32 = 25;
+const CHEST_ROOM_CHANCE: u32 = 15;
+const CHEST_MAX_ITEMS: u32 = 3;
+const CHEST_CAPACITY: usize = 20;
+const BAG_CAPACITY: usize = 10;
+const BAG_OF_HOLDING_CAPACITY: usize = 20;
+const BAG_OF_HOLDING_DIVISOR: i32 = 4;
+const CHEST_LOCKED_CHANCE: u32 = 30;
+const CHEST_TRAPPED_CHANCE: u32 = 20;
+const CHEST_FORCE_CHANCE: i32 = 30;
+const ALTAR_ROOM_CHANCE: u32 = 4;
+const ALTAR_COOLDOWN: i32 = 300;
+const ALTAR_GUARDIANS: i32 = 2;
+const GOLD_ROOM_CHANCE: u32 = 40;
+// Rooms of the dark levels that get a brazier
+const BRAZIER_ROOM_CHANCE: u32 = 35;
+const SHOP_LEVEL_INTERVAL: u32 = 3;
+const BOSS_LEVEL_INTERVAL: u32 = 5;
+const VAULT_WIDTH: i32 = 16;
+const VAULT_HEIGHT: i32 = 11;
+const VAULT_TRIES: i32 = 200;
+const BOSS_GEAR: [Item; 4] = [Item::Sword, Item::Shield, Item::Armor, Item::Helmet];
+const BOSS_GEAR_ENCHANTMENT: i32 = 2;
+const SHOP_STOCK_SIZE: u32 = 8;
+const HEALER_COST: i32 = 20;
+const MAX_ENCHANTMENT: i32 = 3;
+const BOW_RANGE: f32 = 8.0;
+const BOW_BASE_DAMAGE: i32 = 2;
+const ARROW_BUNDLE: i32 = 10;
+const MELEE_SHOT_MISS_CHANCE: i32 = 50;
+const SHOT_MISS_CHANCE: i32 = 15;
+// Shooting into the bushes is harder, and what flies there may miss anyway
+const COVER_AIM_PENALTY: i32 = 15;
+const COVER_DODGE_CHANCE: u32 = 25;
+// Someone in the bushes is only noticed this close
+const CONCEALMENT_RANGE: f32 = 3.0;
+const ACCURACY_PER_DEXTERITY: i32 = 5;
+const DODGE_PER_DEXTERITY: i32 = 2;
+const SPELL_POWER_PER_INTELLIGENCE: i32 = 10;
+const THROW_RANGE: f32 = 6.0;
+const BLINK_RANGE: f32 = 6.0;
+const TOUGHNESS_HP: i32 = 10;
+const POISON_TURNS: i32 = 6;
+const MAX_NUTRITION: i32 = 1500;
+const HUNGRY_NUTRITION: i32 = 300;
+const CORPSE_NUTRITION: i32 = 400;
+const STARVATION_DAMAGE: i32 = 1;
+// A starving player takes the damage every this many turns
+const STARVATION_TURNS: i32 = 3;
+const BURN_TURNS: i32 = 4;
+const FIRE_DAMAGE: i32 = 5;
+const FIRE_SPREAD_CHANCE: u32 = 40;
+const POISON_DAMAGE: i32 = 2;
+const SLOW_TURNS: i32 = 6;
+const HASTE_TURNS: i32 = 10;
+const INVISIBILITY_TURNS: i32 = 15;
+const BURNING_TURNS: i32 = 2;
+const PACIFIST_MAX_KILLS: i32 = 10;
+const OUT_OF_DEPTH_CHANCE: u32 = 3;
+const OUT_OF_DEPTH_LEVELS: u32 = 3;
+const RICHES_FEELING_VALUE: i32 = 150;
+const WARRIOR_CRITICAL_BONUS: i32 = 10;
+const ROGUE_TRAP_DETECT_CHANCE: u32 = 40;
+const MAGE_SPELL_BONUS: i32 = 50;
+// The ring heals a point every this many turns
+const REGENERATION_TURNS: i32 = 4;
+const TELEPORTITIS_CHANCE: u32 = 2;
+const REST_HEAL_TURNS: i32 = 5;
+const THROWN_WEAPON_DAMAGE: i32 = 2;
+const THROWN_ITEM_DAMAGE: i32 = 1;
+const WEAR_CHANCE: u32 = 20;
+const CORRODE_DAMAGE: i32 = 3;
+const REPAIR_KIT_AMOUNT: i32 = 20;
+const REPAIR_COST: i32 = 2;
+const ENCHANTED_ITEM_CHANCE: u32 = 20;
+const ENCHANTMENT_PRICE: i32 = 60;
+const COMPANION_FOLLOW_DISTANCE: f32 = 2.0;
+const COMPANION_CHASE_RANGE: f32 = 5.0;
+const WANDERER_LEVEL_CHANCE: u32 = 30;
+const PRISONER_LEVEL_CHANCE: u32 = 15;
+const NOTE_LEVEL_CHANCE: u32 = 40;
+const PRISONER_REWARD_GOLD: i32 = 60;
+const PRISONER_REWARD_XP: i32 = 75;
+const TOWN_X: i32 = 10;
+const TOWN_Y: i32 = 4;
+const TOWN_WIDTH: i32 = 60;
+const TOWN_HEIGHT: i32 = 34;
+const TRAP_DETECT_CHANCE: u32 = 10;
+const TRAP_DETECT_PER_PERCEPTION: u32 = 8;
+const DISARM_CHANCE: i32 = 40;
+const ROGUE_DISARM_BONUS: i32 = 25;
+const DISARM_PER_ATTRIBUTE: i32 = 8;
+// A failed disarm sets the trap off this often
+const DISARM_SPRING_CHANCE: i32 = 40;
+const DISARM_XP: i32 = 10;
+const SPIKE_TRAP_DAMAGE: i32 = 8;
+const DART_TRAP_DAMAGE: i32 = 4;
+const ALARM_RADIUS: f32 = 15.0;
+const ALARM_STEPS: i32 = 5;
+
+/// Chances to find each kind of item on the given level
+fn item_table(level: u32) -> Vec<Weighted<Item>> {
+    vec![
+        Weighted { item: Item::Heal, weight: 35 },
+        Weighted { item: Item::Lightning,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 4, value: 25 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Fireball,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 6, value: 25 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Confuse,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 2, value: 10 }, ],
+                        level
+                    )},
+        Weighted { item: Item::StinkingCloud,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 3, value: 10 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Sword,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 4, value: 5 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Shield,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 8, value: 15 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Helmet,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 5, value: 20 }, ],
+                        level
+                    )},
+        Weighted { item: Item::RemoveCurse,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 3, value: 10 }, ],
+                        level
+                    )},
+        Weighted { item: Item::EnchantWeapon,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 2, value: 8 }, ],
+                        level
+                    )},
+        Weighted { item: Item::EnchantArmor,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 2, value: 8 }, ],
+                        level
+                    )},
+        Weighted { item: Item::RepairKit,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 2, value: 10 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Teleport,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 2, value: 12 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Blink,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 4, value: 5 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Speed,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 3, value: 8 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Invisibility,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 4, value: 6 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Antidote,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 2, value: 15 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Strength,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 6, value: 2 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Toughness,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 6, value: 2 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Agility,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 7, value: 2 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Intellect,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 7, value: 2 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Bow,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 2, value: 8 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Arrow,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 2, value: 15 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Armor,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 3, value: 10 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Boots,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 2, value: 8 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Gloves,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 3, value: 8 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Ring,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 5, value: 5 }, ],
+                        level
+                    )},
+        Weighted { item: Item::RingOfRegeneration,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 6, value: 3 }, ],
+                        level
+                    )},
+        Weighted { item: Item::RingOfSeeing,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 5, value: 3 }, ],
+                        level
+                    )},
+        Weighted { item: Item::RingOfSustenance,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 5, value: 3 }, ],
+                        level
+                    )},
+        Weighted { item: Item::RingOfTeleportation,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 4, value: 3 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Torch,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 3, value: 10 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Pickaxe,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 2, value: 5 }, ],
+                        level
+                    )},
+        Weighted { item: Item::WandOfDigging,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 4, value: 5 }, ],
+                        level
+                    )},
+        Weighted { item: Item::Bag,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 2, value: 5 }, ],
+                        level
+                    )},
+        Weighted { item: Item::BagOfHolding,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 6, value: 2 }, ],
+                        level
+                    )},
+    ]
+}
+
+/// Roll the dice on a freshly found item, deeper equipment is more often cursed
+fn make_found_item(item: Item, x: i32, y: i32, level: u32) -> Object {
+    let mut object = make_item(item, x, y);
+    let curse_chance = from_dungeon_level(
+        &[Transition { level: 3, value: 10 },
+          Transition { level: 6, value: 25 }, ],
+        level,
+    );
+    if object.equipment.is_some() && game_rng().gen_range(0, 100) < curse_chance {
+        object.curse();
+    } else if object.equipment.is_some() {
+        // Every next enchantment level is half as likely
+        let mut chance = ENCHANTED_ITEM_CHANCE;
+        while game_rng().gen_range(0, 100) < chance && object.enchant(object.item == Some(Item::Sword)) {
+            chance /= 2;
+        }
+    }
+    object
+}
+
+fn monster_table(level: u32) -> Vec<Weighted<&'static str>> {
+    let troll_chance = from_dungeon_level(
+        &[Transition { level: 3, value: 15, },
+          Transition { level: 5, value: 30, },
+          Transition { level: 7, value: 60, }, ],
+        level,
+    );
+    let sewer_king_chance = from_dungeon_level(
+        &[Transition { level: 4, value: 3, }, ],
+        level,
+    );
+    let slime_chance = from_dungeon_level(
+        &[Transition { level: 3, value: 15, }, ],
+        level,
+    );
+    let snake_chance = from_dungeon_level(
+        &[Transition { level: 2, value: 15, }, ],
+        level,
+    );
+    let spider_chance = from_dungeon_level(
+        &[Transition { level: 4, value: 20, }, ],
+        level,
+    );
+    let thief_chance = from_dungeon_level(
+        &[Transition { level: 2, value: 10, }, ],
+        level,
+    );
+    let zombie_chance = from_dungeon_level(
+        &[Transition { level: 3, value: 15, }, ],
+        level,
+    );
+    let ghost_chance = from_dungeon_level(
+        &[Transition { level: 4, value: 10, }, ],
+        level,
+    );
+    let mimic_chance = from_dungeon_level(
+        &[Transition { level: 3, value: 8, }, ],
+        level,
+    );
+    vec![
+        Weighted { weight: 80, item: "orc", },
+        Weighted { weight: troll_chance, item: "troll", },
+        Weighted { weight: sewer_king_chance, item: "sewer king", },
+        Weighted { weight: 20, item: "leech", },
+        Weighted { weight: 30, item: "fungus", },
+        Weighted { weight: 30, item: "skeleton", },
+        Weighted { weight: slime_chance, item: "slime", },
+        Weighted { weight: snake_chance, item: "snake", },
+        Weighted { weight: spider_chance, item: "spider", },
+        Weighted { weight: thief_chance, item: "thief", },
+        Weighted { weight: 25, item: "rat", },
+        Weighted { weight: zombie_chance, item: "zombie", },
+        Weighted { weight: ghost_chance, item: "ghost", },
+        Weighted { weight: mimic_chance, item: "mimic", },
+    ]
+}
+
+fn place_objects(room: Rect, objects: &mut Vec<Object>, map: &GameMap, first_room: bool, level: u32,
+                 tuning: &Tuning) {
+    let item_chances = &mut item_table(level);
+    let item_choice = WeightedChoice::new(item_chances);
+
+    let max_items = from_dungeon_level(&tuning.room_items, level);
+    let num_items = game_rng().gen_range(0, max_items + 1);
+    for _ in 0..num_items {
+        let x = game_rng().gen_range(room.x1 + 1, room.x2);
+        let y = game_rng().gen_range(room.y1 + 1, room.y2);
+        if !is_blocked(x, y, map, objects) {
+            let item = make_found_item(item_choice.ind_sample(&mut game_rng()), x, y, level);
+            objects.push(item);
+        }
+    }
+
+    if game_rng().gen_range(0, 100) < GOLD_ROOM_CHANCE {
+        let x = game_rng().gen_range(room.x1 + 1, room.x2);
+        let y = game_rng().gen_range(room.y1 + 1, room.y2);
+        if !is_blocked(x, y, map, objects) {
+            let mut gold = Object::new("gold coins", x, y, '$', colors::GOLD, false);
+            gold.gold = game_rng().gen_range(5, 16) * level as i32;
+            objects.push(gold);
+        }
+    }
+
+    if Theme::for_level(level).dark() && game_rng().gen_range(0, 100) < BRAZIER_ROOM_CHANCE {
+        let x = game_rng().gen_range(room.x1 + 1, room.x2);
+        let y = game_rng().gen_range(room.y1 + 1, room.y2);
+        if !is_blocked(x, y, map, objects) {
+            let mut brazier = Object::new("brazier", x, y, '#', colors::FLAME, true);
+            brazier.light = Some(BRAZIER_LIGHT);
+            objects.push(brazier);
+        }
+    }
+
+    if first_room {
+        return
+    }
+
+    let trap_chances = &mut [
+        Weighted { item: Trap::Spike, weight: 40 },
+        Weighted { item: Trap::PoisonDart,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 2, value: 30 }, ],
+                        level
+                    )},
+        Weighted { item: Trap::Teleport,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 3, value: 15 }, ],
+                        level
+                    )},
+        Weighted { item: Trap::Alarm, weight: 15 },
+        Weighted { item: Trap::Gas,
+                    weight: from_dungeon_level(
+                        &[Transition { level: 3, value: 15 }, ],
+                        level
+                    )},
+    ];
+    let trap_choice = WeightedChoice::new(trap_chances);
+
+    let max_traps = from_dungeon_level(
+        &[Transition { level: 1, value: 1 },
+          Transition { level: 5, value: 2 }, ],
+        level,
+    );
+    if game_rng().gen_range(0, 100) < TRAP_ROOM_CHANCE {
+        for _ in 0..game_rng().gen_range(1, max_traps + 1) {
+            let x = game_rng().gen_range(room.x1 + 1, room.x2);
+            let y = game_rng().gen_range(room.y1 + 1, room.y2);
+            if !is_blocked(x, y, map, objects) {
+                objects.push(make_trap(trap_choice.ind_sample(&mut game_rng()), x, y));
+            }
+        }
+    }
+
+    if game_rng().gen_range(0, 100) < CHEST_ROOM_CHANCE {
+        let x = game_rng().gen_range(room.x1 + 1, room.x2);
+        let y = game_rng().gen_range(room.y1 + 1, room.y2);
+        if !is_blocked(x, y, map, objects) {
+            let num_items = game_rng().gen_range(1, CHEST_MAX_ITEMS + 1);
+            let items = (0..num_items)
+                .map(|_| make_found_item(item_choice.ind_sample(&mut game_rng()), x, y, level))
+                .collect();
+            let trap = if game_rng().gen_range(0, 100) < CHEST_TRAPPED_CHANCE {
+                Some(trap_choice.ind_sample(&mut game_rng()))
+            } else {
+                None
+            };
+            let mut chest = Object::new("chest", x, y, '=', colors::DARK_AMBER, false);
+            chest.container = Some(Container {
+                items,
+                locked: game_rng().gen_range(0, 100) < CHEST_LOCKED_CHANCE,
+                trap,
+            });
+            objects.push(chest);
+        }
+    }
+
+    if game_rng().gen_range(0, 100) < ALTAR_ROOM_CHANCE {
+        let x = game_rng().gen_range(room.x1 + 1, room.x2);
+        let y = game_rng().gen_range(room.y1 + 1, room.y2);
+        if !is_blocked(x, y, map, objects) {
+            let mut altar = Object::new("altar", x, y, '_', colors::LIGHT_VIOLET, false);
+            altar.furniture = Some(Furniture::Altar { ready_at: 0 });
+            objects.push(altar);
+        }
+    }
+
+    let champion_chance = from_dungeon_level(
+        &[Transition { level: 3, value: 5, },
+          Transition { level: 6, value: 10, }, ],
+        level,
+    );
+    let max_monsters = from_dungeon_level(&tuning.room_monsters, level);
+    let num_monsters = game_rng().gen_range(0, max_monsters + 1);
+    for _ in 0..num_monsters {
+        let x = game_rng().gen_range(room.x1 + 1, room.x2);
+        let y = game_rng().gen_range(room.y1 + 1, room.y2);
+        if is_blocked(x, y, map, objects) {
+            continue;
+        }
+        // Now and then something wanders up from the depths
+        let spawn_level = if game_rng().gen_range(0, 100) < OUT_OF_DEPTH_CHANCE {
+            level + OUT_OF_DEPTH_LEVELS
+        } else {
+            level
+        };
+        // Only the monsters that live here, and only one king per level
+        let king = objects.iter().any(|object| object.unique);
+        let mut monster_chances: Vec<_> = monster_table(spawn_level)
+            .into_iter()
+            .filter(|chance| chance.weight > 0 && habitat_allows(monster_habitat(chance.item), x, y, map, level))
+            .filter(|chance| !(king && chance.item == "sewer king"))
+            .collect();
+        if monster_chances.is_empty() {
+            continue;
+        }
+        let choice = WeightedChoice::new(&mut monster_chances).ind_sample(&mut game_rng());
+        let mut monster = make_monster(choice, x, y);
+        monster.level = spawn_level as i32;
+        arm_monster(&mut monster, level);
+        if !monster.unique && game_rng().gen_range(0, 100) < champion_chance {
+            make_champion(&mut monster, level);
+        }
+        objects.push(monster);
+    }
+}
+
+/// A fresh monster of the kind from the monster table, standing at the spot
+fn make_monster(choice: &str, x: i32, y: i32) -> Object {
+    let mut monster = match choice {
+        "orc" => {
+            let mut orc = Object::new("Orc", x, y, '0', colors::LIGHT_GREEN, true);
+            orc.fighter = Some(Fighter {
+                hp: 20,
+                base_max_hp: 20,
+                base_defence: 0,
+                base_power: 4,
+                attributes: Attributes::default(),
+                xp: 35,
+                on_death: DeathCallback::Monster,
+            });
+            orc.ai = Some(Ai::Basic);
+            orc
+        }
+        "troll" => {
+            let mut troll = Object::new("Troll", x, y, 'T', colors::RED, true);
+            troll.fighter = Some(Fighter {
+                hp: 30,
+                base_max_hp: 30,
+                base_defence: 2,
+                base_power: 8,
+                attributes: Attributes::default(),
+                xp: 100,
+                on_death: DeathCallback::Monster,
+            });
+            troll.ai = Some(Ai::Basic);
+            troll
+        }
+        "sewer king" => {
+            let mut king = Object::new("Gorbash the Sewer King", x, y, 'K', colors::GOLD, true);
+            king.fighter = Some(Fighter {
+                hp: 80,
+                base_max_hp: 80,
+                base_defence: 3,
+                base_power: 10,
+                attributes: Attributes::default(),
+                xp: 500,
+                on_death: DeathCallback::Monster,
+            });
+            king.ai = Some(Ai::Basic);
+            king.unique = true;
+            king
+        }
+        "leech" => {
+            let mut leech = Object::new("Giant leech", x, y, 'l', colors::DARK_SEA, true);
+            leech.fighter = Some(Fighter {
+                hp: 10,
+                base_max_hp: 10,
+                base_defence: 0,
+                base_power: 3,
+                attributes: Attributes::default(),
+                xp: 20,
+                on_death: DeathCallback::Monster,
+            });
+            leech.ai = Some(Ai::Basic);
+            leech
+        }
+        "fungus" => {
+            let mut fungus = Object::new("Fungal crawler", x, y, 'f', colors::LIGHT_LIME, true);
+            fungus.fighter = Some(Fighter {
+                hp: 25,
+                base_max_hp: 25,
+                base_defence: 1,
+                base_power: 5,
+                attributes: Attributes::default(),
+                xp: 50,
+                on_death: DeathCallback::Monster,
+            });
+            fungus.ai = Some(Ai::Basic);
+            fungus.attack_effect = Some(AttackEffect::Slow);
+            fungus
+        }
+        "slime" => {
+            let mut slime = Object::new("Acid slime", x, y, 'j', colors::LIME, true);
+            slime.fighter = Some(Fighter {
+                hp: 15,
+                base_max_hp: 15,
+                base_defence: 0,
+                base_power: 4,
+                attributes: Attributes::default(),
+                xp: 40,
+                on_death: DeathCallback::Monster,
+            });
+            slime.ai = Some(Ai::Basic);
+            slime.attack_effect = Some(AttackEffect::Corrode);
+            slime
+        }
+        "snake" => {
+            let mut snake = Object::new("Sewer snake", x, y, 'S', colors::DARK_GREEN, true);
+            snake.fighter = Some(Fighter {
+                hp: 12,
+                base_max_hp: 12,
+                base_defence: 0,
+                base_power: 3,
+                attributes: Attributes::default(),
+                xp: 30,
+                on_death: DeathCallback::Monster,
+            });
+            snake.ai = Some(Ai::Basic);
+            snake.attack_effect = Some(AttackEffect::Poison);
+            snake
+        }
+        "spider" => {
+            let mut spider = Object::new("Cave spider", x, y, 'x', colors::DARK_VIOLET, true);
+            spider.fighter = Some(Fighter {
+                hp: 18,
+                base_max_hp: 18,
+                base_defence: 1,
+                base_power: 5,
+                attributes: Attributes::default(),
+                xp: 55,
+                on_death: DeathCallback::Monster,
+            });
+            spider.ai = Some(Ai::Basic);
+            spider.attack_effect = Some(AttackEffect::Poison);
+            spider.weaves = true;
+            spider
+        }
+        "skeleton" => {
+            let mut skeleton = Object::new("Skeleton", x, y, 's', colors::LIGHTEST_GREY, true);
+            skeleton.fighter = Some(Fighter {
+                hp: 25,
+                base_max_hp: 25,
+                base_defence: 3,
+                base_power: 7,
+                attributes: Attributes::default(),
+                xp: 80,
+                on_death: DeathCallback::Monster,
+            });
+            skeleton.ai = Some(Ai::Basic);
+            skeleton
+        }
+        "thief" => {
+            let mut thief = Object::new("Sewer thief", x, y, 'p', colors::DARK_AMBER, true);
+            thief.fighter = Some(Fighter {
+                hp: 15,
+                base_max_hp: 15,
+                base_defence: 1,
+                base_power: 3,
+                attributes: Attributes { dexterity: 3, perception: 2, ..Default::default() },
+                xp: 50,
+                on_death: DeathCallback::Monster,
+            });
+            thief.ai = Some(Ai::Basic);
+            thief.attack_effect = Some(AttackEffect::Steal);
+            thief
+        }
+        "rat" => {
+            let mut rat = Object::new("Giant rat", x, y, 'r', colors::LIGHT_SEPIA, true);
+            rat.fighter = Some(Fighter {
+                hp: 6,
+                base_max_hp: 6,
+                base_defence: 0,
+                base_power: 2,
+                attributes: Attributes::default(),
+                xp: 15,
+                on_death: DeathCallback::Monster,
+            });
+            rat.ai = Some(Ai::Basic);
+            rat.speed = SpeedTier::Fast;
+            rat
+        }
+        "zombie" => {
+            let mut zombie = Object::new("Zombie", x, y, 'Z', colors::DESATURATED_GREEN, true);
+            zombie.fighter = Some(Fighter {
+                hp: 35,
+                base_max_hp: 35,
+                base_defence: 1,
+                base_power: 8,
+                attributes: Attributes::default(),
+                xp: 70,
+                on_death: DeathCallback::Monster,
+            });
+            zombie.ai = Some(Ai::Basic);
+            zombie.speed = SpeedTier::Slow;
+            zombie.revives = true;
+            zombie
+        }
+        "ghost" => {
+            let mut ghost = Object::new("Ghost", x, y, 'G', colors::LIGHTEST_SKY, true);
+            ghost.fighter = Some(Fighter {
+                hp: 14,
+                base_max_hp: 14,
+                base_defence: 0,
+                base_power: 5,
+                attributes: Attributes::default(),
+                xp: 60,
+                on_death: DeathCallback::Monster,
+            });
+            ghost.ai = Some(Ai::Basic);
+            ghost.movement = Movement::Phase;
+            ghost.spectral = true;
+            ghost
+        }
+        "mimic" => {
+            // Lies about as an item and doesn't block the way until it is found out
+            let mut mimic = Object::new("Mimic", x, y, 'M', colors::DARK_ORANGE, false);
+            mimic.fighter = Some(Fighter {
+                hp: 20,
+                base_max_hp: 20,
+                base_defence: 1,
+                base_power: 6,
+                attributes: Attributes::default(),
+                xp: 80,
+                on_death: DeathCallback::Monster,
+            });
+            mimic.ai = Some(Ai::Basic);
+            let item = *game_rng().choose(&MIMIC_DISGUISES).unwrap();
+            mimic.disguise = Some(Box::new(make_item(item, x, y)));
+            mimic
+        }
+        "rat queen" => {
+            let mut queen = Object::new("Gnawmother the Rat Queen", x, y, 'R', colors::LIGHT_SEPIA, true);
+            queen.fighter = Some(Fighter {
+                hp: 90,
+                base_max_hp: 90,
+                base_defence: 3,
+                base_power: 9,
+                attributes: Attributes { dexterity: 2, ..Default::default() },
+                xp: 600,
+                on_death: DeathCallback::Monster,
+            });
+            queen.ai = Some(Ai::Basic);
+            queen.ability = Some(BossAbility::Plague);
+            queen
+        }
+        "bone warden" => {
+            let mut warden = Object::new("The Bone Warden", x, y, 'W', colors::LIGHTEST_GREY, true);
+            warden.fighter = Some(Fighter {
+                hp: 140,
+                base_max_hp: 140,
+                base_defence: 5,
+                base_power: 12,
+                attributes: Attributes { strength: 2, ..Default::default() },
+                xp: 1000,
+                on_death: DeathCallback::Monster,
+            });
+            warden.ai = Some(Ai::Basic);
+            warden.ability = Some(BossAbility::Regenerate);
+            warden
+        }
+        "shadow" => {
+            let mut shadow = Object::new("Vethis the Shadow", x, y, 'V', colors::DARK_VIOLET, true);
+            shadow.fighter = Some(Fighter {
+                hp: 160,
+                base_max_hp: 160,
+                base_defence: 4,
+                base_power: 16,
+                attributes: Attributes { dexterity: 3, ..Default::default() },
+                xp: 1500,
+                on_death: DeathCallback::Monster,
+            });
+            shadow.ai = Some(Ai::Basic);
+            shadow.ability = Some(BossAbility::ShadowStep);
+            shadow
+        }
+        _ => unreachable!(),
+    };
+    monster.alive = true;
+    monster.faction = monster_faction(choice);
+    monster
+}
+
+fn make_item(item: Item, x: i32, y: i32) -> Object {
+    match item {
+        Item::Heal => {
+            let mut object = Object::new("healing potion", x, y,
+                                         '!', colors::VIOLET, false);
+            object.item = Some(Item::Heal);
+            object
+        }
+        Item::Lightning => {
+            let mut object = Object::new("scroll of lightning", x, y,
+                                         '#', colors::LIGHT_YELLOW, false);
+            object.item = Some(Item::Lightning);
+            object
+        }
+        Item::Fireball => {
+            let mut object = Object::new("scroll of fireball", x, y,
+                                         '#', colors::LIGHT_YELLOW, false);
+            object.item = Some(Item::Fireball);
+            object
+        }
+        Item::Confuse => {
+            let mut object = Object::new("scroll of confusion", x, y,
+                                         '&', colors::LIGHT_YELLOW, false);
+            object.item = Some(Item::Confuse);
+            object
+        }
+        Item::StinkingCloud => {
+            let mut object = Object::new("scroll of stinking cloud", x, y,
+                                         '#', colors::LIGHT_YELLOW, false);
+            object.item = Some(Item::StinkingCloud);
+            object
+        }
+        Item::Sword => {
+            let mut object = Object::new("sword", x, y, '/', colors::SKY, false);
+            object.item = Some(Item::Sword);
+            object.equipment = Some(Equipment{
+                equipped: false, power_bonus: 3, defence_bonus: 0, max_hp_bonus: 1, cursed: false, enchantment: 0,
+                durability: 40, max_durability: 40, effect: None,
+                attributes: Attributes::default(),
+                slot: Slot::RightHand});
+            object
+        }
+        Item::Shield => {
+            let mut object = Object::new("shield", x, y, '*', colors::DARKER_ORANGE, false);
+            object.item = Some(Item::Shield);
+            object.equipment = Some(Equipment{
+                equipped: false, power_bonus: 0, defence_bonus: 1, max_hp_bonus: 1, cursed: false, enchantment: 0,
+                durability: 50, max_durability: 50, effect: None,
+                attributes: Attributes::default(),
+                slot: Slot::LeftHand});
+            object
+        }
+        Item::Helmet => {
+            let mut object = Object::new("helmet", x, y, '^', colors::DARKER_ORANGE, false);
+            object.item = Some(Item::Helmet);
+            object.equipment = Some(Equipment{
+                equipped: false, power_bonus: 0, defence_bonus: 0,
+                max_hp_bonus: 100, cursed: false, enchantment: 0,
+                durability: 40, max_durability: 40, effect: None,
+                attributes: Attributes::default(),
+                slot: Slot::Head});
+            object
+        }
+        Item::Idol => {
+            let mut object = Object::new("golden idol", x, y, '"', colors::GOLD, false);
+            object.item = Some(Item::Idol);
+            object
+        }
+        Item::RemoveCurse => {
+            let mut object = Object::new("scroll of remove curse", x, y,
+                                         '#', colors::LIGHT_VIOLET, false);
+            object.item = Some(Item::RemoveCurse);
+            object
+        }
+        Item::EnchantWeapon => {
+            let mut object = Object::new("scroll of enchant weapon", x, y,
+                                         '#', colors::LIGHT_SKY, false);
+            object.item = Some(Item::EnchantWeapon);
+            object
+        }
+        Item::EnchantArmor => {
+            let mut object = Object::new("scroll of enchant armor", x, y,
+                                         '#', colors::LIGHT_ORANGE, false);
+            object.item = Some(Item::EnchantArmor);
+            object
+        }
+        Item::RepairKit => {
+            let mut object = Object::new("repair kit", x, y, '&', colors::LIGHT_GREY, false);
+            object.item = Some(Item::RepairKit);
+            object
+        }
+        Item::Bow => {
+            let mut object = Object::new("bow", x, y, ')', colors::SEPIA, false);
+            object.item = Some(Item::Bow);
+            object.equipment = Some(Equipment{
+                equipped: false, power_bonus: 4, defence_bonus: 0, max_hp_bonus: 0, cursed: false, enchantment: 0,
+                durability: 40, max_durability: 40, effect: None,
+                attributes: Attributes::default(),
+                slot: Slot::Ranged});
+            object
+        }
+        Item::Teleport => {
+            let mut object = Object::new("scroll of teleportation", x, y,
+                                         '#', colors::LIGHT_MAGENTA, false);
+            object.item = Some(Item::Teleport);
+            object
+        }
+        Item::Blink => {
+            let mut object = Object::new("scroll of blink", x, y,
+                                         '#', colors::LIGHTER_MAGENTA, false);
+            object.item = Some(Item::Blink);
+            object
+        }
+        Item::Speed => {
+            let mut object = Object::new("potion of speed", x, y, '!', colors::LIGHT_YELLOW, false);
+            object.item = Some(Item::Speed);
+            object
+        }
+        Item::Invisibility => {
+            let mut object = Object::new("potion of invisibility", x, y, '!', colors::LIGHTEST_GREY, false);
+            object.item = Some(Item::Invisibility);
+            object
+        }
+        Item::Antidote => {
+            let mut object = Object::new("antidote", x, y, '!', colors::LIGHT_GREEN, false);
+            object.item = Some(Item::Antidote);
+            object
+        }
+        Item::Strength => {
+            let mut object = Object::new("potion of strength", x, y, '!', colors::CRIMSON, false);
+            object.item = Some(Item::Strength);
+            object
+        }
+        Item::Toughness => {
+            let mut object = Object::new("potion of toughness", x, y, '!', colors::AMBER, false);
+            object.item = Some(Item::Toughness);
+            object
+        }
+        Item::Agility => {
+            let mut object = Object::new("potion of agility", x, y, '!', colors::TURQUOISE, false);
+            object.item = Some(Item::Agility);
+            object
+        }
+        Item::Intellect => {
+            let mut object = Object::new("potion of intellect", x, y, '!', colors::LIGHT_BLUE, false);
+            object.item = Some(Item::Intellect);
+            object
+        }
+        Item::Corpse => {
+            let mut object = Object::new("corpse", x, y, '%', colors::DARK_RED, false);
+            object.item = Some(Item::Corpse);
+            object
+        }
+        Item::Arrow => {
+            let mut object = Object::new("arrow", x, y, '|', colors::LIGHT_SEPIA, false);
+            object.item = Some(Item::Arrow);
+            object.count = ARROW_BUNDLE;
+            object
+        }
+        Item::Armor => {
+            let mut object = Object::new("leather armor", x, y, '[', colors::DARKER_ORANGE, false);
+            object.item = Some(Item::Armor);
+            object.equipment = Some(Equipment{
+                equipped: false, power_bonus: 0, defence_bonus: 2, max_hp_bonus: 0, cursed: false, enchantment: 0,
+                durability: 60, max_durability: 60, effect: None,
+                attributes: Attributes::default(),
+                slot: Slot::Body});
+            object
+        }
+        Item::Boots => {
+            let mut object = Object::new("boots", x, y, ']', colors::DARKER_ORANGE, false);
+            object.item = Some(Item::Boots);
+            object.equipment = Some(Equipment{
+                equipped: false, power_bonus: 0, defence_bonus: 1, max_hp_bonus: 0, cursed: false, enchantment: 0,
+                durability: 40, max_durability: 40, effect: None,
+                attributes: Attributes::default(),
+                slot: Slot::Feet});
+            object
+        }
+        Item::Gloves => {
+            let mut object = Object::new("gauntlets", x, y, '(', colors::DARKER_ORANGE, false);
+            object.item = Some(Item::Gloves);
+            object.equipment = Some(Equipment{
+                equipped: false, power_bonus: 0, defence_bonus: 0, max_hp_bonus: 0, cursed: false, enchantment: 0,
+                durability: 40, max_durability: 40, effect: None,
+                attributes: Attributes { dexterity: 1, ..Default::default() },
+                slot: Slot::Hands});
+            object
+        }
+        Item::Ring => {
+            let mut object = Object::new("ring of protection", x, y, '=', colors::GOLD, false);
+            object.item = Some(Item::Ring);
+            object.equipment = Some(Equipment{
+                equipped: false, power_bonus: 0, defence_bonus: 1, max_hp_bonus: 5, cursed: false, enchantment: 0,
+                durability: 100, max_durability: 100, effect: None,
+                attributes: Attributes::default(),
+                slot: Slot::LeftRing});
+            object
+        }
+        Item::RingOfRegeneration => make_ring("ring of regeneration", item, PassiveEffect::Regeneration, x, y),
+        Item::RingOfSeeing => make_ring("ring of see invisible", item, PassiveEffect::SeeInvisible, x, y),
+        Item::RingOfSustenance => make_ring("ring of sustenance", item, PassiveEffect::SustainHunger, x, y),
+        Item::Torch => {
+            // Takes the place of the shield
+            let mut object = Object::new("torch", x, y, '/', colors::FLAME, false);
+            object.item = Some(Item::Torch);
+            object.equipment = Some(Equipment{
+                equipped: false, power_bonus: 0, defence_bonus: 0, max_hp_bonus: 0, cursed: false, enchantment: 0,
+                durability: 50, max_durability: 50, effect: Some(PassiveEffect::Light),
+                attributes: Attributes::default(),
+                slot: Slot::LeftHand});
+            object
+        }
+        Item::Pickaxe => {
+            let mut object = Object::new("pickaxe", x, y, '(', colors::LIGHT_SEPIA, false);
+            object.item = Some(Item::Pickaxe);
+            object
+        }
+        Item::WandOfDigging => {
+            let mut object = Object::new("wand of digging", x, y, '_', colors::LIGHT_VIOLET, false);
+            object.item = Some(Item::WandOfDigging);
+            object
+        }
+        Item::Bag | Item::BagOfHolding => {
+            let (name, color) = if item == Item::Bag {
+                ("bag", colors::SEPIA)
+            } else {
+                ("bag of holding", colors::LIGHT_VIOLET)
+            };
+            let mut object = Object::new(name, x, y, '(', color, false);
+            object.item = Some(item);
+            object.container = Some(Container { items: vec![], locked: false, trap: None });
+            object
+        }
+        Item::RingOfTeleportation => {
+            // Looks like a fine way to travel, until you try to take it off
+            let mut object = make_ring("ring of teleportation", item, PassiveEffect::Teleportitis, x, y);
+            object.curse();
+            object
+        }
+    }
+}
+
+fn make_ring(name: &str, item: Item, effect: PassiveEffect, x: i32, y: i32) -> Object {
+    let mut object = Object::new(name, x, y, '=', colors::GOLD, false);
+    object.item = Some(item);
+    object.equipment = Some(Equipment{
+        equipped: false, power_bonus: 0, defence_bonus: 0, max_hp_bonus: 0, cursed: false, enchantment: 0,
+        durability: 100, max_durability: 100, effect: Some(effect),
+        attributes: Attributes::default(),
+        slot: Slot::LeftRing});
+    object
+}
+
+/// Turn the room into a shop: chase the monsters out and put a shopkeeper with his stock in the middle
+fn place_shop(room: Rect, map: &mut GameMap, objects: &mut Vec<Object>, level: u32) {
+    let inside = |object: &Object| {
+        object.x > room.x1 && object.x < room.x2 && object.y > room.y1 && object.y < room.y2
+    };
+    objects.retain(|object| !(inside(object) && (object.ai.is_some() || object.trap.is_some())));
+
+    let (x, y) = room.center();
+    map[(x, y)] = Tile::empty();
+    objects.push(make_shopkeeper(x, y, level));
+}
+
+fn make_shopkeeper(x: i32, y: i32, level: u32) -> Object {
+    let item_chances = &mut item_table(level);
+    let item_choice = WeightedChoice::new(item_chances);
+    let stock = (0..SHOP_STOCK_SIZE)
+        .map(|_| make_item(item_choice.ind_sample(&mut game_rng()), x, y))
+        .collect();
+
+    let mut shopkeeper = make_npc("Shopkeeper", x, y, Npc::Shopkeeper);
+    shopkeeper.container = Some(Container { items: stock, locked: false, trap: None });
+    shopkeeper
+}
+
+fn make_dog(x: i32, y: i32) -> Object {
+    let mut dog = Object::new("Dog", x, y, 'd', colors::LIGHT_SEPIA, true);
+    dog.fighter = Some(Fighter {
+        hp: 30,
+        base_max_hp: 30,
+        base_defence: 0,
+        base_power: 4,
+        attributes: Attributes::default(),
+        xp: 0,
+        on_death: DeathCallback::Monster,
+    });
+    dog.ai = Some(Ai::Companion);
+    dog.faction = Faction::Player;
+    dog.alive = true;
+    dog
+}
+
+/// Friendly townsfolk are tough to discourage the player from attacking them
+fn make_npc(name: &str, x: i32, y: i32, npc: Npc) -> Object {
+    let mut object = Object::new(name, x, y, '@', colors::LIGHT_AZURE, true);
+    object.fighter = Some(Fighter {
+        hp: 100,
+        base_max_hp: 100,
+        base_defence: 5,
+        base_power: 12,
+        attributes: Attributes::default(),
+        xp: 300,
+        on_death: DeathCallback::Monster,
+    });
+    object.npc = Some(npc);
+    object.faction = Faction::Neutral;
+    object.alive = true;
+    object
+}
+
+/// The town above the sewers: an open square with the shop, the healer and a stash
+fn make_town(objects: &mut Vec<Object>) -> GameMap {
+    let mut map = GameMap::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+    objects.truncate(1);
+
+    let square = Rect::new(TOWN_X, TOWN_Y, TOWN_WIDTH, TOWN_HEIGHT);
+    for x in (square.x1 + 1)..square.x2 {
+        for y in (square.y1 + 1)..square.y2 {
+            map[(x, y)] = if game_rng().gen_range(0, 100) < 5 {
+                Tile::bushes()
+            } else {
+                Tile::empty()
+            };
+        }
+    }
+
+    let (center_x, center_y) = square.center();
+    // A fountain with the sewer entrance in the middle of it
+    for x in (center_x - 2)..(center_x + 3) {
+        for y in (center_y - 2)..(center_y + 3) {
+            map[(x, y)] = Tile::water();
+        }
+    }
+    map[(center_x, center_y)] = Tile::empty();
+    map[(center_x, (center_y + 1))] = Tile::empty();
+    map[(center_x, (center_y + 2))] = Tile::empty();
+
+    let mut stairs = Object::new("stairs down", center_x, center_y, '<', colors::WHITE, false);
+    stairs.always_visible = true;
+    objects.push(stairs);
+
+    let (shop_x, shop_y) = (square.x1 + 6, square.y1 + 4);
+    let (healer_x, healer_y) = (square.x2 - 6, square.y1 + 4);
+    let (stash_x, stash_y) = (square.x1 + 6, square.y2 - 4);
+    for &(x, y) in &[(shop_x, shop_y), (healer_x, healer_y), (stash_x, stash_y)] {
+        map[(x, y)] = Tile::empty();
+    }
+    objects.push(make_shopkeeper(shop_x, shop_y, 1));
+    objects.push(make_npc("Healer", healer_x, healer_y, Npc::Healer));
+    let mut stash = Object::new("storage chest", stash_x, stash_y, '=', colors::DARK_AMBER, false);
+    stash.container = Some(Container { items: vec![], locked: false, trap: None });
+    objects.push(stash);
+    let (elder_x, elder_y) = (square.x2 - 6, square.y2 - 4);
+    map[(elder_x, elder_y)] = Tile::empty();
+    objects.push(make_npc("Town elder", elder_x, elder_y, Npc::QuestGiver));
+
+    objects[PLAYER].set_pos(center_x, center_y + 3);
+    map
+}
+
+fn make_trap(trap: Trap, x: i32, y: i32) -> Object {
+    let (name, color) = match trap {
+        Trap::Spike => ("spike trap", colors::LIGHT_GREY),
+        Trap::PoisonDart => ("poison dart trap", colors::GREEN),
+        Trap::Teleport => ("teleport trap", colors::LIGHT_VIOLET),
+        Trap::Alarm => ("alarm trap", colors::YELLOW),
+        Trap::Gas => ("gas trap", colors::CHARTREUSE),
+    };
+    let mut object = Object::new(name, x, y, '^', color, false);
+    object.trap = Some(trap);
+    object.hidden = true;
+    object
+}
+
+/// Returns a random tile that nothing stands on
+fn random_free_tile(map: &GameMap, objects: &[Object]) -> Option<(i32, i32)> {
+    for _ in 0..1000 {
+        let x = game_rng().gen_range(0, MAP_WIDTH);
+        let y = game_rng().gen_range(0, MAP_HEIGHT);
+        if !is_blocked(x, y, map, objects) {
+            return Some((x, y));
+        }
+    }
+    None
+}
+
+/// Set off the trap under the object that has just moved there
+fn spring_trap(id: usize, game: &mut Game, objects: &mut [Object]) {
+    let pos = objects[id].pos();
+    let trap_id = objects
+        .iter()
+        .position(|object| object.trap.is_some() && object.pos() == pos);
+    let trap_id = match trap_id {
+        Some(trap_id) => trap_id,
+        None => return,
+    };
+    objects[trap_id].hidden = false;
+    let trap = objects[trap_id].trap.unwrap();
+    trap_effect(trap, id, game, objects);
+}
+
+fn trap_effect(trap: Trap, id: usize, game: &mut Game, objects: &mut [Object]) {
+    let pos = objects[id].pos();
+    let name = objects[id].name.clone();
+    let category = if id == PLAYER { Category::Danger } else { Category::Combat };
+    match trap {
+        Trap::Spike => {
+            game.log.add_in(category,
+                format!("{} steps on a spike trap and takes {} damage!", name, SPIKE_TRAP_DAMAGE),
+                colors::RED);
+            objects[id].take_damage(SPIKE_TRAP_DAMAGE, false, game);
+        }
+        Trap::PoisonDart => {
+            game.log.add_in(category,
+                format!("A poison dart hits {} for {} damage!", name, DART_TRAP_DAMAGE),
+                colors::GREEN);
+            objects[id].take_damage(DART_TRAP_DAMAGE, false, game);
+            objects[id].add_status(StatusKind::Poisoned, POISON_TURNS);
+        }
+        Trap::Teleport => {
+            if let Some((x, y)) = random_free_tile(&game.map, objects) {
+                game.log.add_in(category, format!("{} vanishes in a flash of light!", name), colors::LIGHT_VIOLET);
+                objects[id].set_pos(x, y);
+            }
+        }
+        Trap::Alarm => {
+            game.log.add_in(Category::Danger, "A loud alarm rings through the sewers!", colors::YELLOW);
+            for monster_id in 0..objects.len() {
+                let comes_running = monster_id != id && objects[monster_id].ai.is_some() &&
+                    objects[monster_id].distance(pos.0, pos.1) <= ALARM_RADIUS;
+                if comes_running {
+                    for _ in 0..ALARM_STEPS {
+                        move_towards(monster_id, pos.0, pos.1, game, objects);
+                    }
+                }
+            }
+        }
+        Trap::Gas => {
+            game.log.add_in(category, "A cloud of poison gas bursts out of the floor!", CloudKind::Poison.color());
+            release_cloud(pos, GAS_TRAP_RADIUS, CloudKind::Poison, GAS_TRAP_TURNS, &mut game.map);
+        }
+    }
+}
+
+/// Give the player a chance to notice hidden traps nearby
+fn detect_traps(game: &mut Game, objects: &mut [Object]) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let base_chance = if game.class == Class::Rogue { ROGUE_TRAP_DETECT_CHANCE } else { TRAP_DETECT_CHANCE };
+    let perception = objects[PLAYER].attributes(game).perception.max(0) as u32;
+    let detect_chance = base_chance + perception * TRAP_DETECT_PER_PERCEPTION;
+    for object in objects.iter_mut() {
+        if object.trap.is_some() && object.hidden && object.distance(player_x, player_y) < 2.0 &&
+            game_rng().gen_range(0, 100) < detect_chance {
+            object.hidden = false;
+            game.log.add(format!("You notice a {}!", object.name), colors::LIGHT_CYAN);
+        }
+    }
+}
+
+/// Try to take apart a known trap next to the player, a clumsy attempt may set it off
+fn disarm_trap(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let nearby: Vec<usize> = (0..objects.len())
+        .filter(|&id| {
+            objects[id].trap.is_some() && !objects[id].hidden && objects[id].distance(player_x, player_y) < 2.0
+        })
+        .collect();
+    let trap_id = match nearby.len() {
+        0 => {
+            game.log.add("There are no traps you know of nearby.", colors::WHITE);
+            return PlayerAction::DidntTakeTurn;
+        }
+        1 => nearby[0],
+        _ => {
+            let (dx, dy) = match ask_direction("Disarm the trap in which direction?", tcod, game, objects) {
+                Some(step) => step,
+                None => return PlayerAction::DidntTakeTurn,
+            };
+            match nearby.iter().find(|&&id| objects[id].pos() == (player_x + dx, player_y + dy)) {
+                Some(&id) => id,
+                None => {
+                    game.log.add("There is no trap there.", colors::WHITE);
+                    return PlayerAction::DidntTakeTurn;
+                }
+            }
+        }
+    };
+
+    let attributes = objects[PLAYER].attributes(game);
+    let rogue_bonus = if game.class == Class::Rogue { ROGUE_DISARM_BONUS } else { 0 };
+    let chance = DISARM_CHANCE + rogue_bonus + (attributes.dexterity + attributes.perception) * DISARM_PER_ATTRIBUTE;
+    let name = objects[trap_id].name.clone();
+    if game_rng().gen_range(0, 100) < chance {
+        objects.swap_remove(trap_id);
+        game.log.add(format!("You carefully disarm the {}.", name), colors::LIGHT_CYAN);
+        objects[PLAYER].fighter.as_mut().unwrap().xp += DISARM_XP;
+    } else if game_rng().gen_range(0, 100) < DISARM_SPRING_CHANCE {
+        game.log.add_in(Category::Danger, format!("Your fumbling sets off the {}!", name), colors::ORANGE);
+        let trap = objects[trap_id].trap.unwrap();
+        trap_effect(trap, PLAYER, game, objects);
+    } else {
+        game.log.add(format!("You fail to disarm the {}.", name), colors::WHITE);
+    }
+    PlayerAction::TookTurn
+}
+
+fn next_level(tcod: &mut Tcod, objects: &mut Vec<Object>, game: &mut Game) {
+    let next = game.dungeon_level + 1;
+    if game.levels.contains_key(&next) {
+        game.log.add("You descend the familiar stairs...", colors::RED);
+    } else {
+        game.log.add("You take a moment to rest and recover your strength.",
+                     colors::VIOLET,
+                     );
+        let heal_hp = objects[PLAYER].max_hp(game) / 2;
+        objects[PLAYER].heal(heal_hp, game);
+
+        game.log.add("After a rare moment of peace you descend deeper into the sewers...",
+                     colors::RED,
+                     );
+    }
+    change_level(next, tcod, objects, game);
+}
+
+fn previous_level(tcod: &mut Tcod, objects: &mut Vec<Object>, game: &mut Game) {
+    game.log.add("You climb back up the stairs...", colors::LIGHT_VIOLET);
+    let previous = game.dungeon_level - 1;
+    change_level(previous, tcod, objects, game);
+}
+
+/// Put the current level aside and either restore the requested one or generate it
+fn change_level(level: u32, tcod: &mut Tcod, objects: &mut Vec<Object>, game: &mut Game) {
+    game.events.push(GameEvent::Sound(Sound::Stairs));
+    let going_down = level > game.dungeon_level;
+    // Companions close enough follow the player to the new level
+    let (companions, old_objects): (Vec<Object>, Vec<Object>) = objects
+        .split_off(PLAYER + 1)
+        .into_iter()
+        .partition(|object| {
+            object.faction == Faction::Player && object.alive &&
+                object.distance_to(&objects[PLAYER]) <= COMPANION_FOLLOW_DISTANCE + 1.0
+        });
+    let old_map = std::mem::take(&mut game.map);
+    game.levels.insert(game.dungeon_level, Level { map: old_map, objects: old_objects });
+
+    game.dungeon_level = level;
+    match game.levels.remove(&level) {
+        Some(saved) => {
+            game.map = saved.map;
+            objects.extend(saved.objects);
+            // Arrive on the stairs leading back to where we came from
+            let arrival = if going_down { "stairs up" } else { "stairs down" };
+            let stairs = objects.iter().find(|object| object.name == arrival).map(|object| object.pos());
+            if let Some((x, y)) = stairs {
+                objects[PLAYER].set_pos(x, y);
+            }
+        }
+        None => {
+            game.map = make_map(objects, level, &game.tuning, &game.dialogues, &game.notes);
+            debug!(target: "level", "generated level {} with {} objects", level, objects.len());
+            place_quest_items(game, objects);
+            if let Some((feeling, color)) = level_feeling(objects, level) {
+                game.log.add(feeling, color);
+            }
+        }
+    }
+    for companion in companions {
+        place_near_player(companion, game, objects);
+    }
+    initialise_fov(tcod, &game.map);
+}
+
+/// A hint about what the freshly generated level holds, the scariest thing wins
+fn level_feeling(objects: &[Object], level: u32) -> Option<(&'static str, colors::Color)> {
+    let monsters = || objects.iter().filter(|object| object.ai.is_some() && object.faction != Faction::Player);
+    if monsters().any(|monster| monster.unique || monster.level > level as i32) {
+        return Some(("You feel a terrible presence...", colors::RED));
+    }
+    if monsters().any(|monster| monster.affix.is_some()) {
+        return Some(("Something unusually strong lurks here.", colors::ORANGE));
+    }
+    let riches: i32 = objects
+        .iter()
+        .map(|object| {
+            let contents = object.container.as_ref().map_or(0, |c| c.items.iter().map(item_price).sum());
+            object.gold + item_price(object) + contents
+        })
+        .sum();
+    if riches >= RICHES_FEELING_VALUE * level as i32 {
+        return Some(("This place smells of riches.", colors::GOLD));
+    }
+    if monsters().next().is_none() {
+        return Some(("This place seems quiet.", colors::LIGHT_GREY));
+    }
+    None
+}
+
+// Companions are put down this far from the player at most
+const COMPANION_PLACE_RADIUS: i32 = 10;
+
+fn place_near_player(mut object: Object, game: &mut Game, objects: &mut Vec<Object>) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    for radius in 1..COMPANION_PLACE_RADIUS {
+        for dx in -radius..(radius + 1) {
+            for dy in -radius..(radius + 1) {
+                if !is_blocked(player_x + dx, player_y + dy, &game.map, objects) {
+                    object.set_pos(player_x + dx, player_y + dy);
+                    objects.push(object);
+                    return;
+                }
+            }
+        }
+    }
+    game.log.add(format!("There is no room for your {}, it stays behind.", object.name.to_lowercase()),
+                 colors::LIGHT_GREY);
+}
+
+// Weights are in tenths of a pound
+const CARRY_BASE: i32 = 250;
+const CARRY_PER_STRENGTH: i32 = 50;
+// Past these shares of the capacity the player slows down more, and can't take any more
+const STRAINED_LOAD: i32 = 150;
+const MAX_LOAD: i32 = 200;
+
+/// How the load on the player's back slows them down
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Burden {
+    Unburdened,
+    Burdened,
+    Strained,
+}
+
+impl Burden {
+    fn name(self) -> &'static str {
+        match self {
+            Burden::Unburdened => "Unburdened",
+            Burden::Burdened => "Burdened",
+            Burden::Strained => "Strained",
+        }
+    }
+
+    fn color(self) -> colors::Color {
+        match self {
+            Burden::Unburdened => colors::WHITE,
+            Burden::Burdened => colors::YELLOW,
+            Burden::Strained => colors::ORANGE,
+        }
+    }
+
+    /// Monster rounds added to a step, a burdened one costs half a turn more and a strained one twice as much
+    fn extra_rounds(self, turn: i32) -> i32 {
+        match self {
+            Burden::Unburdened => 0,
+            Burden::Burdened => turn % 2,
+            Burden::Strained => 1,
+        }
+    }
+}
+
+fn item_weight(item: &Object) -> i32 {
+    use Item::*;
+    let weight = match item.item {
+        Some(Heal | Strength | Toughness | Agility | Intellect | Antidote | Speed | Invisibility) => 5,
+        Some(Lightning | Confuse | Fireball | RemoveCurse | EnchantWeapon | EnchantArmor | Teleport | Blink |
+             StinkingCloud) => 1,
+        Some(Ring | RingOfRegeneration | RingOfSeeing | RingOfSustenance | RingOfTeleportation) => 1,
+        Some(Arrow) => 1,
+        Some(WandOfDigging) => 5,
+        Some(Gloves) | Some(Torch) | Some(Bag) | Some(BagOfHolding) => 10,
+        Some(Bow) | Some(Boots) | Some(RepairKit) => 20,
+        Some(Helmet) => 30,
+        Some(Sword) => 40,
+        Some(Idol) => 50,
+        Some(Shield) => 60,
+        Some(Pickaxe) => 80,
+        Some(Armor) => 150,
+        Some(Corpse) => 200,
+        None => 0,
+    };
+    let contents: i32 = match &item.container {
+        Some(container) if item.item.is_some() => container.items.iter().map(item_weight).sum(),
+        _ => 0,
+    };
+    let contents = if item.item == Some(BagOfHolding) { contents / BAG_OF_HOLDING_DIVISOR } else { contents };
+    weight * item.count + contents
+}
+
+fn carried_weight(game: &Game) -> i32 {
+    game.inventory.iter().map(item_weight).sum()
+}
+
+/// Stronger characters carry more
+fn carry_capacity(player: &Object, game: &Game) -> i32 {
+    CARRY_BASE + cmp::max(0, player.attributes(game).strength) * CARRY_PER_STRENGTH
+}
+
+fn burden(player: &Object, game: &Game) -> Burden {
+    let load = carried_weight(game) * 100 / carry_capacity(player, game);
+    if load > STRAINED_LOAD {
+        Burden::Strained
+    } else if load > 100 {
+        Burden::Burdened
+    } else {
+        Burden::Unburdened
+    }
+}
+
+/// Weight as it's shown, in pounds
+fn format_weight(weight: i32) -> String {
+    format!("{}.{}", weight / 10, weight % 10)
+}
+
+/// False and a message if the item would be too much to carry
+fn can_carry(item: &Object, player: &Object, game: &mut Game) -> bool {
+    can_carry_besides(item, 0, player, game)
+}
+
+/// Same as can_carry, with some weight held outside the inventory, like the bags that are open
+fn can_carry_besides(item: &Object, held: i32, player: &Object, game: &mut Game) -> bool {
+    let max_load = carry_capacity(player, game) * MAX_LOAD / 100;
+    if carried_weight(game) + held + item_weight(item) <= max_load {
+        return true;
+    }
+    game.log.add_in(Category::Item, format!("The {} is too heavy to carry on top of your load.", item.name),
+                    colors::RED);
+    false
+}
+
+fn pick_item_up(
+    object_id: usize,
+    objects: &mut Vec<Object>,
+    game: &mut Game,
+) {
+    if objects[object_id].disguise.is_some() {
+        spring_mimic(object_id, game, objects);
+        return;
+    }
+    if can_carry(&objects[object_id], &objects[PLAYER], game) {
+        let item = objects.swap_remove(object_id);
+        if let Some(kind) = item.item {
+            game.events.push(GameEvent::PickedUp(kind));
+        }
+        add_to_inventory(item, game);
+        game.events.push(GameEvent::Sound(Sound::Pickup));
+    }
+}
+
+/// The mimic drops its disguise, gets out from under the player and bites first
+fn spring_mimic(id: usize, game: &mut Game, objects: &mut [Object]) {
+    let disguise = match objects[id].reveal() {
+        Some(disguise) => disguise,
+        None => return,
+    };
+    game.log.add_in(Category::Danger,
+        format!("The {} springs to life, it is a {}!", disguise.display_name().to_lowercase(), objects[id].name),
+        colors::RED);
+    let (x, y) = objects[id].pos();
+    let spot = (-1..=1)
+        .flat_map(|dx| (-1..=1).map(move |dy| (x + dx, y + dy)))
+        .find(|&(nx, ny)| game.map.in_bounds(nx, ny) && !is_blocked(nx, ny, &game.map, objects));
+    if let Some((nx, ny)) = spot {
+        objects[id].set_pos(nx, ny);
+    }
+    for _ in 0..MIMIC_SURPRISE_ATTACKS {
+        if objects[PLAYER].fighter.is_some_and(|f| f.hp > 0) {
+            let (mimic, player) = mut_two(id, PLAYER, objects);
+            mimic.attack(player, game);
+        }
+    }
+}
+
+/// Take some items off the stack, or the whole item when the stack runs out
+fn take_from_inventory(inventory_id: usize, count: i32, game: &mut Game) -> Object {
+    let stack = &mut game.inventory[inventory_id];
+    if count >= stack.count {
+        return game.inventory.remove(inventory_id);
+    }
+    stack.count -= count;
+    let mut item = make_item(stack.item.unwrap(), stack.x, stack.y);
+    item.count = count;
+    item
+}
+
+/// Put the item in the inventory and equip it if its slot is free
+fn add_to_inventory(item: Object, game: &mut Game) {
+    game.log.add_in(Category::Item, format!("You picked up a {}!", item.display_name()), colors::GREEN);
+    store_in_inventory(item, game);
+}
+
+/// Same as add_to_inventory, for when the caller has its own message
+fn store_in_inventory(item: Object, game: &mut Game) {
+    if item.stackable() {
+        if let Some(stack) = game.inventory.iter_mut().find(|stack| stack.item == item.item) {
+            stack.count += item.count;
+            return;
+        }
+    }
+    let index = game.inventory.len();
+    let slot = item.equipment.map(|e| e.slot);
+    game.inventory.push(item);
+
+    // Picking things up shouldn't break the conduct behind the player's back
+    if keeps_conduct(Conduct::NoEquipment, game) {
+        return;
+    }
+    if let Some(slot) = slot {
+        if slot.is_ring() {
+            if let Some(finger) = free_ring_slot(&game.inventory) {
+                game.inventory[index].equipment.as_mut().unwrap().slot = finger;
+                game.inventory[index].equip(&mut game.log);
+            }
+        } else if get_equipped_in_slot(slot, &game.inventory).is_none() {
+            game.inventory[index].equip(&mut game.log);
+        }
+    }
+}
+
+/// Use the chest or the furniture the player stands on
+fn interact(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let here = objects[PLAYER].pos();
+    let target_id = objects
+        .iter()
+        .position(|object| object.pos() == here && object.item.is_none() &&
+                  (object.container.is_some() || object.furniture.is_some()));
+    match target_id.map(|id| (id, objects[id].furniture)) {
+        Some((id, Some(Furniture::Altar { .. }))) => pray(id, game, objects),
+        Some((id, None)) => open_container(id, tcod, game, objects),
+        None => {
+            game.log.add("There is nothing here to use.", colors::WHITE);
+            PlayerAction::DidntTakeTurn
+        }
+    }
+}
+
+/// Ask the gods for help, they may just as well be in a bad mood
+fn pray(altar_id: usize, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    match objects[altar_id].furniture {
+        Some(Furniture::Altar { ready_at }) if ready_at > game.turn => {
+            game.log.add("You pray, but the altar stays cold and silent.", colors::GREY);
+            return PlayerAction::TookTurn;
+        }
+        Some(Furniture::Altar { .. }) => {}
+        None => return PlayerAction::DidntTakeTurn,
+    }
+    objects[altar_id].furniture = Some(Furniture::Altar { ready_at: game.turn + ALTAR_COOLDOWN });
+    game.log.add("You kneel at the altar and pray...", colors::LIGHT_VIOLET);
+
+    let prayers = &mut [
+        Weighted { weight: 30, item: Prayer::Healing },
+        Weighted { weight: 20, item: Prayer::Blessing },
+        Weighted { weight: 20, item: Prayer::Silence },
+        Weighted { weight: 15, item: Prayer::Curse },
+        Weighted { weight: 15, item: Prayer::Guardians },
+    ];
+    let worn: Vec<usize> = game.inventory
+        .iter()
+        .enumerate()
+        .filter(|&(_, item)| item.equipment.is_some_and(|e| e.equipped))
+        .map(|(id, _)| id)
+        .collect();
+    let worn_id = game_rng().choose(&worn).cloned();
+    match WeightedChoice::new(prayers).ind_sample(&mut game_rng()) {
+        Prayer::Healing => {
+            let max_hp = objects[PLAYER].max_hp(game);
+            objects[PLAYER].heal(max_hp, game);
+            objects[PLAYER].statuses.retain(|status| status.kind == StatusKind::Hasted);
+            game.log.add("A warm light washes over you, your wounds close.", colors::LIGHT_VIOLET);
+        }
+        Prayer::Blessing => match worn_id {
+            Some(id) => {
+                let item = &mut game.inventory[id];
+                let cursed = item.equipment.is_some_and(|e| e.cursed);
+                if cursed {
+                    item.equipment.as_mut().unwrap().cursed = false;
+                    item.name = item.name.trim_start_matches("cursed ").into();
+                    game.log.add_in(Category::Item, format!("The curse on your {} is lifted!", item.name),
+                                    colors::LIGHT_VIOLET);
+                } else if item.enchant(item.item == Some(Item::Sword)) {
+                    game.log.add_in(Category::Item, format!("Your {} is blessed. It is now {}.", item.name,
+                                    item.display_name()), colors::LIGHT_VIOLET);
+                } else {
+                    game.log.add("Your gear glows briefly, then the glow fades.", colors::WHITE);
+                }
+            }
+            None => game.log.add("You feel blessed, but there is nothing on you to bless.", colors::WHITE),
+        },
+        Prayer::Silence => game.log.add("Nobody seems to be listening.", colors::GREY),
+        Prayer::Curse => match worn_id {
+            Some(id) if !game.inventory[id].equipment.is_some_and(|e| e.cursed) => {
+                let item = &mut game.inventory[id];
+                item.curse();
+                game.log.add_in(Category::Danger, format!("The gods are angry! Your {} is cursed!", item.name),
+                                colors::RED);
+                item.name = format!("cursed {}", item.name);
+            }
+            _ => {
+                game.log.add_in(Category::Danger, "The gods are angry! You feel weak.", colors::RED);
+                objects[PLAYER].add_status(StatusKind::Slowed, SLOW_TURNS);
+            }
+        },
+        Prayer::Guardians => {
+            game.log.add_in(Category::Danger, "The altar's guardians rise to punish the intruder!", colors::RED);
+            let (x, y) = objects[PLAYER].pos();
+            for _ in 0..ALTAR_GUARDIANS {
+                let spot = (-1..=1)
+                    .flat_map(|dx| (-1..=1).map(move |dy| (x + dx, y + dy)))
+                    .filter(|&(nx, ny)| game.map.in_bounds(nx, ny) && !is_blocked(nx, ny, &game.map, objects))
+                    .collect::<Vec<_>>();
+                if let Some(&(nx, ny)) = game_rng().choose(&spot) {
+                    let mut guardian = make_monster("skeleton", nx, ny);
+                    guardian.name = "Altar guardian".into();
+                    objects.push(guardian);
+                }
+            }
+        }
+    }
+    PlayerAction::TookTurn
+}
+
+/// Force the lock, set off the trap and let the player move items in and out
+fn open_container(
+    container_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> PlayerAction {
+    let name = objects[container_id].name.clone();
+    let mut action = PlayerAction::DidntTakeTurn;
+
+    if objects[container_id].container.as_ref().is_some_and(|c| c.locked) {
+        action = PlayerAction::TookTurn;
+        let chance = CHEST_FORCE_CHANCE + objects[PLAYER].power(game) * 5;
+        if game_rng().gen_range(0, 100) < chance {
+            objects[container_id].container.as_mut().unwrap().locked = false;
+            game.log.add_in(Category::Item, format!("You force the lock of the {} open.", name), colors::LIGHT_GREEN);
+        } else {
+            game.log.add_in(Category::Item, format!("The {} is locked. You fail to force it open.", name), colors::RED);
+            return action;
+        }
+    }
+
+    if let Some(trap) = objects[container_id].container.as_mut().and_then(|c| c.trap.take()) {
+        action = PlayerAction::TookTurn;
+        game.log.add_in(Category::Danger, format!("The {} was trapped!", name), colors::RED);
+        trap_effect(trap, PLAYER, game, objects);
+        if !objects[PLAYER].alive {
+            return action;
+        }
+    }
+
+    loop {
+        render_all(tcod, objects, game, false);
+        let mut options: Vec<String> = objects[container_id]
+            .container
+            .as_ref()
+            .unwrap()
+            .items
+            .iter()
+            .map(|item| format!("Take {}", item.name))
+            .collect();
+        let items_count = options.len();
+        options.push("Put an item inside".into());
+
+        let choice = menu(
+            &format!("The {} contains:\n", name), &options, INVENTORY_WIDTH, &mut tcod.root);
+        match choice {
+            Some(index) if index < items_count => {
+                let item = &objects[container_id].container.as_ref().unwrap().items[index];
+                if can_carry(item, &objects[PLAYER], game) {
+                    let item = objects[container_id].container.as_mut().unwrap().items.remove(index);
+                    add_to_inventory(item, game);
+                }
+            }
+            Some(_) => {
+                if items_count >= CHEST_CAPACITY {
+                    game.log.add_in(Category::Item, format!("The {} is full.", name), colors::RED);
+                    continue;
+                }
+                let inventory_index = inventory_menu(
+                    &game.inventory,
+                    "Select an item to put inside\n",
+                    &mut tcod.root);
+                if let Some(inventory_index) = inventory_index {
+                    let item = &mut game.inventory[inventory_index];
+                    if item.equipment.is_some() && !item.unequip(&mut game.log) {
+                        continue;
+                    }
+                    let item = game.inventory.remove(inventory_index);
+                    game.log.add_in(Category::Item, format!("You put the {} in the {}.", item.name, name), colors::YELLOW);
+                    objects[container_id].container.as_mut().unwrap().items.push(item);
+                }
+            }
+            None => break,
+        }
+    }
+    action
+}
+
+fn level_up(objects: &mut [Object], game: &mut Game, tcod: &mut Tcod) {
+    let player = &mut objects[PLAYER];
+    let level_up_xp = game.tuning.level_up_base + player.level * game.tuning.level_up_factor;
+    if player.fighter.as_ref().map_or(0, |f| f.xp) >= level_up_xp {
+        player.level += 1;
+        game.events.push(GameEvent::Sound(Sound::LevelUp));
+        game.log.add(
+            format!("Your battle skills grow stronger! You've reached level {}!",
+                    player.level),
+            colors::YELLOW,
+        );
+
+        let fighter = player.fighter.as_mut().unwrap();
+        let mut choice = None;
+        while choice.is_none() {
+            choice = menu(
+                "Level up! Choose a stat to raise!\n",
+                &[format!("Constitution (+20 HP from {})", fighter.base_max_hp),
+                  format!("Strength (+1 from {}, hit harder)", fighter.attributes.strength),
+                  format!("Dexterity (+1 from {}, dodge and aim better)", fighter.attributes.dexterity),
+                  format!("Intelligence (+1 from {}, stronger spells)", fighter.attributes.intelligence),
+                  format!("Perception (+1 from {}, spot and disarm traps)", fighter.attributes.perception),
+                ],
+                LEVEL_SCREEN_WIDTH,
+                &mut tcod.root,
+                );
+        }
+        fighter.xp -= level_up_xp;
+        match choice.unwrap() {
+            0 => {
+                fighter.base_max_hp += 20;
+                fighter.hp += 20;
+            }
+            1 => {
+                fighter.attributes.strength += 1;
+            }
+            2 => {
+                fighter.attributes.dexterity += 1;
+            }
+            3 => {
+                fighter.attributes.intelligence += 1;
+            }
+            4 => {
+                fighter.attributes.perception += 1;
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlayerAction {
+    TookTurn,
+    DidntTakeTurn,
+    Exit,
+    Abandon,
+}
+
+fn player_move_or_attack(dx: i32, dy: i32, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    // A confused step goes anywhere, and on into the traps and the gold like any other
+    let (dx, dy) = if objects[PLAYER].has_status(StatusKind::Confused) {
+        game.log.add("You stumble around in confusion.", colors::LIGHT_MAGENTA);
+        (game_rng().gen_range(-1, 2), game_rng().gen_range(-1, 2))
+    } else {
+        (dx, dy)
+    };
+    if (dx, dy) == (0, 0) {
+        return;
+    }
+    let x = objects[PLAYER].x + dx;
+    let y = objects[PLAYER].y + dy;
+    if objects[PLAYER].has_status(StatusKind::Confused) {
+        // Stumbling into someone means hitting them, friend or foe
+        if let Some(target_id) = objects.iter().position(|object| object.fighter.is_some() && object.pos() == (x, y)) {
+            let (player, target) = mut_two(PLAYER, target_id, objects);
+            player.attack(target, game);
+            return;
+        }
+    }
+
+    let target_id = objects
+        .iter()
+        .position(|object| object.fighter.is_some() && object.disguise.is_none() && object.pos() == (x, y));
+    match target_id {
+        Some(target_id) if objects[target_id].npc.is_some() => {
+            match objects[target_id].npc.unwrap() {
+                Npc::Shopkeeper => trade(target_id, tcod, game, objects),
+                Npc::Healer => visit_healer(tcod, game, objects),
+                Npc::QuestGiver => talk_to_quest_giver(target_id, tcod, game, objects),
+                Npc::Wanderer => talk_to_npc(target_id, tcod, game, objects),
+                Npc::Prisoner => free_prisoner(target_id, game, objects),
+            }
+        }
+        Some(target_id) if objects[target_id].faction != Faction::Player => {
+            let (player, monster) = mut_two(PLAYER, target_id, objects);
+            player.attack(monster, game);
+        }
+        // Any step needs the legs free, swapping places with a companion too
+        _ if objects[PLAYER].has_status(StatusKind::Entangled) => {
+            game.log.add("You struggle against the web.", StatusKind::Entangled.color());
+        }
+        Some(target_id) => {
+            // Swap places with the companion instead of attacking it
+            let (player, ally) = mut_two(PLAYER, target_id, objects);
+            ally.set_pos(player.x, player.y);
+            player.set_pos(x, y);
+            spring_trap(PLAYER, game, objects);
+            walk_into_web(PLAYER, game, objects);
+            pick_up_gold(game, objects);
+        }
+        None if cuts_corner(objects[PLAYER].x, objects[PLAYER].y, dx, dy, game) => {
+            game.log.add("You can't squeeze between the walls.", colors::WHITE);
+        }
+        None => {
+            move_by(PLAYER, dx, dy, game, objects);
+            if objects[PLAYER].pos() == (x, y) {
+                spring_trap(PLAYER, game, objects);
+                walk_into_web(PLAYER, game, objects);
+                pick_up_gold(game, objects);
+                auto_pickup(&tcod.settings, game, objects);
+                for object in objects.iter().filter(|object| object.pos() == (x, y)) {
+                    if let Some(comparison) = compare_equipment(object.appearance(), &game.inventory) {
+                        game.log.add_in(Category::Item, format!("Here lies a {}.", comparison), colors::LIGHT_GREY);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Something the player keeps doing over several turns until it is done or interrupted
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Activity {
+    Travel((i32, i32)),
+    // Turns rested so far
+    Rest(i32),
+    // Direction, and which sides of it were open when the run started
+    Run((i32, i32), (bool, bool)),
+}
+
+/// Next step towards the goal over the explored tiles, walking around creatures and known traps
+fn travel_step(goal: (i32, i32), game: &Game, objects: &[Object]) -> Option<(i32, i32)> {
+    let map = &game.map;
+    let walkable = |(x, y): (i32, i32)| {
+        let tile = &map[(x, y)];
+        let occupied = objects
+            .iter()
+            .any(|object| object.pos() == (x, y) && (object.blocks || object.trap.is_some() && !object.hidden));
+        tile.explored && !tile.blocked && !tile.web && (!occupied || (x, y) == goal)
+    };
+    let mut path = AStar::new_from_callback(
+        MAP_WIDTH, MAP_HEIGHT,
+        |(x, y), to| if walkable(to) && !cuts_corner(x, y, to.0 - x, to.1 - y, game) { 1.0 } else { 0.0 },
+        1.0);
+    if path.find(objects[PLAYER].pos(), goal) {
+        path.walk_one_step(false)
+    } else {
+        None
+    }
+}
+
+fn hostile_in_sight(tcod: &Tcod, objects: &[Object]) -> bool {
+    objects
+        .iter()
+        .any(|object| is_hostile(&objects[PLAYER], object) && tcod.fov.is_in_fov(object.x, object.y))
+}
+
+fn start_travel(goal: (i32, i32), tcod: &Tcod, game: &mut Game, objects: &[Object]) {
+    if hostile_in_sight(tcod, objects) {
+        game.log.add("Not with enemies in sight!", colors::RED);
+    } else if goal == objects[PLAYER].pos() {
+        game.log.add("You are already there.", colors::WHITE);
+    } else if travel_step(goal, game, objects).is_none() {
+        game.log.add("You don't know the way there.", colors::RED);
+    } else {
+        game.activity = Some(Activity::Travel(goal));
+    }
+}
+
+/// Take the next step of the current activity, stopping it when it is done
+fn continue_activity(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let goal = match game.activity {
+        Some(Activity::Travel(goal)) => goal,
+        Some(Activity::Rest(turns)) => return rest(turns, tcod, game, objects),
+        Some(Activity::Run(step, sides)) => return run(step, sides, tcod, game, objects),
+        None => return PlayerAction::DidntTakeTurn,
+    };
+    if hostile_in_sight(tcod, objects) {
+        game.log.add_in(Category::Danger, "You stop, there is an enemy in sight!", colors::RED);
+        game.activity = None;
+        return PlayerAction::DidntTakeTurn;
+    }
+    let (x, y) = match travel_step(goal, game, objects) {
+        Some(step) => step,
+        None => {
+            game.activity = None;
+            return PlayerAction::DidntTakeTurn;
+        }
+    };
+    // Stop next to whoever stands at the goal instead of bumping into them
+    if (x, y) == goal && is_blocked(x, y, &game.map, objects) {
+        game.activity = None;
+        return PlayerAction::DidntTakeTurn;
+    }
+    let (px, py) = objects[PLAYER].pos();
+    player_move_or_attack(x - px, y - py, tcod, game, objects);
+    if objects[PLAYER].pos() == goal {
+        game.activity = None;
+    }
+    PlayerAction::TookTurn
+}
+
+fn start_rest(tcod: &Tcod, game: &mut Game, objects: &[Object]) {
+    if hostile_in_sight(tcod, objects) {
+        game.log.add("Not with enemies in sight!", colors::RED);
+    } else if game.nutrition <= HUNGRY_NUTRITION {
+        game.log.add("You are too hungry to rest.", colors::RED);
+    } else if objects[PLAYER].fighter.map_or(0, |f| f.hp) >= objects[PLAYER].max_hp(game) {
+        game.log.add("You are already at full health.", colors::WHITE);
+    } else {
+        game.log.add("You sit down to rest.", colors::LIGHT_CYAN);
+        game.activity = Some(Activity::Rest(0));
+    }
+}
+
+/// Pass a turn healing slowly, until healed or disturbed
+fn rest(turns: i32, tcod: &Tcod, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
+    let stop = if hostile_in_sight(tcod, objects) {
+        Some("You stop resting, there is an enemy in sight!")
+    } else if game.nutrition <= HUNGRY_NUTRITION {
+        Some("You are too hungry to keep resting.")
+    } else if objects[PLAYER].fighter.map_or(0, |f| f.hp) >= objects[PLAYER].max_hp(game) {
+        Some("You feel rested.")
+    } else {
+        None
+    };
+    if let Some(reason) = stop {
+        game.log.add(format!("{} ({} turns)", reason, turns), colors::LIGHT_CYAN);
+        game.activity = None;
+        return PlayerAction::DidntTakeTurn;
+    }
+    if game.turn % REST_HEAL_TURNS == 0 {
+        objects[PLAYER].heal(1, game);
+    }
+    game.activity = Some(Activity::Rest(turns + 1));
+    PlayerAction::TookTurn
+}
+
+/// Whether the tiles to the left and right of the direction can be walked on
+fn open_sides(x: i32, y: i32, (dx, dy): (i32, i32), map: &GameMap) -> (bool, bool) {
+    let open = |x, y| map.get(x, y).is_some_and(|tile: &Tile| !tile.blocked);
+    (open(x - dy, y + dx), open(x + dy, y - dx))
+}
+
+fn start_run(step: (i32, i32), tcod: &Tcod, game: &mut Game, objects: &[Object]) {
+    let (x, y) = objects[PLAYER].pos();
+    if hostile_in_sight(tcod, objects) {
+        game.log.add("Not with enemies in sight!", colors::RED);
+    } else if is_blocked(x + step.0, y + step.1, &game.map, objects) || cuts_corner(x, y, step.0, step.1, game) {
+        game.log.add("There is no way to run there.", colors::WHITE);
+    } else {
+        game.activity = Some(Activity::Run(step, open_sides(x, y, step, &game.map)));
+    }
+}
+
+/// Keep moving the same way until something interesting comes up: an enemy in sight,
+/// something in the way or underfoot, or a side passage opening or closing
+fn run(step: (i32, i32), sides: (bool, bool), tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let (x, y) = objects[PLAYER].pos();
+    if hostile_in_sight(tcod, objects) {
+        game.log.add_in(Category::Danger, "You stop, there is an enemy in sight!", colors::RED);
+        game.activity = None;
+        return PlayerAction::DidntTakeTurn;
+    }
+    let ahead = (x + step.0, y + step.1);
+    if is_blocked(ahead.0, ahead.1, &game.map, objects) || cuts_corner(x, y, step.0, step.1, game) ||
+        game.map[ahead].web {
+        game.activity = None;
+        return PlayerAction::DidntTakeTurn;
+    }
+    player_move_or_attack(step.0, step.1, tcod, game, objects);
+    let (x, y) = objects[PLAYER].pos();
+    let underfoot = objects.iter().skip(1).any(|object| object.pos() == (x, y));
+    if underfoot || open_sides(x, y, step, &game.map) != sides {
+        game.activity = None;
+    }
+    PlayerAction::TookTurn
+}
+
+/// Walk to the nearest explored object with the given name
+fn travel_to_named(name: &str, tcod: &Tcod, game: &mut Game, objects: &[Object]) {
+    let target = objects
+        .iter()
+        .filter(|object| object.name == name && game.map[(object.x, object.y)].explored)
+        .min_by_key(|object| object.distance_to(&objects[PLAYER]) as i32)
+        .map(Object::pos);
+    match target {
+        Some(goal) => start_travel(goal, tcod, game, objects),
+        None => game.log.add(format!("You haven't found the {} yet.", name), colors::RED),
+    }
+}
+
+/// Travel to a place remembered on some level, only works on that level
+fn travel_to_remembered(place: Option<(u32, (i32, i32))>, what: &str, tcod: &Tcod, game: &mut Game, objects: &[Object]) {
+    match place {
+        Some((level, goal)) if level == game.dungeon_level => start_travel(goal, tcod, game, objects),
+        Some(_) => game.log.add(format!("The {} is on another level.", what), colors::RED),
+        None => game.log.add(format!("There is no {} to go to.", what), colors::RED),
+    }
+}
+
+/// Left click walks to an explored tile, or attacks the monster next to the player
+fn handle_click(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    let (x, y) = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
+    if !game.map.in_bounds(x, y) || (x, y) == objects[PLAYER].pos() {
+        return PlayerAction::DidntTakeTurn;
+    }
+    let (dx, dy) = (x - objects[PLAYER].x, y - objects[PLAYER].y);
+    let adjacent_monster = objects
+        .iter()
+        .any(|object| object.pos() == (x, y) && is_hostile(&objects[PLAYER], object) && tcod.fov.is_in_fov(x, y));
+    if adjacent_monster && dx.abs() <= 1 && dy.abs() <= 1 {
+        player_move_or_attack(dx, dy, tcod, game, objects);
+        return PlayerAction::TookTurn;
+    }
+    let tile = &game.map[(x, y)];
+    if tile.explored && !tile.blocked {
+        start_travel((x, y), tcod, game, objects);
+    }
+    PlayerAction::DidntTakeTurn
+}
+
+fn travel_menu(tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    let choice = menu(
+        "Travel to\n",
+        &["Stairs down", "Stairs up", "Last shop", "Marked tile"],
+        INVENTORY_WIDTH,
+        &mut tcod.root);
+    match choice {
+        Some(0) => travel_to_named("stairs down", tcod, game, objects),
+        Some(1) => travel_to_named("stairs up", tcod, game, objects),
+        Some(2) => travel_to_remembered(game.last_shop, "shop", tcod, game, objects),
+        Some(3) => travel_to_remembered(game.travel_mark, "marked tile", tcod, game, objects),
+        _ => {}
+    }
+}
+
+/// Pick up the items the options ask for
+fn auto_pickup(settings: &Settings, game: &mut Game, objects: &mut Vec<Object>) {
+    while let Some(item_id) = objects.iter().position(|object| {
+        object.pos() == objects[PLAYER].pos() && object.appearance().item.is_some_and(|item| settings.picks_up(item))
+    }) {
+        let count = objects.len();
+        pick_item_up(item_id, objects, game);
+        if objects.len() == count {
+            // The inventory is full
+            break;
+        }
+    }
+}
+
+/// Gold is picked up just by walking over it
+fn pick_up_gold(game: &mut Game, objects: &mut Vec<Object>) {
+    while let Some(gold_id) = objects
+        .iter()
+        .position(|object| object.gold > 0 && object.pos() == objects[PLAYER].pos()) {
+        let gold = objects.swap_remove(gold_id);
+        game.gold += gold.gold;
+        game.log.add_in(Category::Item, format!("You pick up {} gold.", gold.gold), colors::GOLD);
+    }
+}
+
+fn item_price(item: &Object) -> i32 {
+    use Item::*;
+    let base = match item.item {
+        Some(Heal) => 30,
+        Some(Lightning) => 60,
+        Some(Confuse) => 40,
+        Some(Fireball) => 80,
+        Some(Sword) => 120,
+        Some(Shield) => 100,
+        Some(Helmet) => 150,
+        Some(Idol) => 250,
+        Some(RemoveCurse) => 70,
+        Some(EnchantWeapon) => 90,
+        Some(EnchantArmor) => 90,
+        Some(RepairKit) => 50,
+        Some(Bow) => 110,
+        Some(Arrow) => 2,
+        Some(Teleport) => 50,
+        Some(Blink) => 80,
+        Some(Strength) | Some(Toughness) | Some(Agility) | Some(Intellect) => 300,
+        Some(Corpse) => 1,
+        Some(Antidote) => 25,
+        Some(Speed) => 60,
+        Some(Invisibility) => 70,
+        Some(Armor) => 130,
+        Some(Boots) => 60,
+        Some(Gloves) => 80,
+        Some(Ring) => 200,
+        Some(RingOfRegeneration) => 350,
+        Some(RingOfSeeing) => 250,
+        Some(RingOfSustenance) => 250,
+        Some(RingOfTeleportation) => 150,
+        Some(Torch) => 30,
+        Some(StinkingCloud) => 60,
+        Some(Pickaxe) => 80,
+        Some(WandOfDigging) => 120,
+        Some(Bag) => 40,
+        Some(BagOfHolding) => 400,
+        None => 0,
+    };
+    base + item.equipment.map_or(0, |e| e.enchantment * ENCHANTMENT_PRICE)
+}
+
+fn visit_healer(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    let choice = menu(
+        &format!("\"Need some patching up?\"\nYou have {} gold.\n", game.gold),
+        &[format!("Heal your wounds ({} gold)", HEALER_COST), "Leave".into()],
+        INVENTORY_WIDTH,
+        &mut tcod.root);
+    if choice != Some(0) {
+        return;
+    }
+    let max_hp = objects[PLAYER].max_hp(game);
+    if objects[PLAYER].fighter.map_or(0, |f| f.hp) >= max_hp {
+        game.log.add("\"You look perfectly healthy to me.\"", colors::LIGHT_AZURE);
+    } else if game.gold < HEALER_COST {
+        game.log.add("You can't afford that.", colors::RED);
+    } else {
+        game.gold -= HEALER_COST;
+        objects[PLAYER].heal(max_hp, game);
+        game.log.add("The healer tends to your wounds. You feel much better.", colors::LIGHT_VIOLET);
+    }
+}
+
+/// Buy and sell items at the shopkeeper
+fn trade(shopkeeper_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    game.last_shop = Some((game.dungeon_level, objects[shopkeeper_id].pos()));
+    loop {
+        render_all(tcod, objects, game, false);
+        let choice = menu(
+            &format!("\"Welcome to my humble shop!\"\nYou have {} gold.\n", game.gold),
+            &["Buy", "Sell", "Repair your equipment", "Attack the shopkeeper"],
+            INVENTORY_WIDTH,
+            &mut tcod.root);
+        match choice {
+            Some(0) => {
+                let stock = &objects[shopkeeper_id].container.as_ref().unwrap().items;
+                let options: Vec<String> = stock
+                    .iter()
+                    .map(|item| format!("{} ({} gold)", item.display_name(), item_price(item)))
+                    .collect();
+                if options.is_empty() {
+                    game.log.add("\"Sorry, I'm sold out.\"", colors::LIGHT_AZURE);
+                    continue;
+                }
+                render_all(tcod, objects, game, false);
+                let index = menu("Select an item to buy\n", &options, INVENTORY_WIDTH, &mut tcod.root);
+                if let Some(index) = index {
+                    let price = item_price(&stock[index]);
+                    if price > game.gold {
+                        game.log.add_in(Category::Item, "You can't afford that.", colors::RED);
+                    } else if can_carry(&stock[index], &objects[PLAYER], game) {
+                        game.gold -= price;
+                        let item = objects[shopkeeper_id].container.as_mut().unwrap().items.remove(index);
+                        game.log.add_in(Category::Item, format!("You pay {} gold.", price), colors::GOLD);
+                        add_to_inventory(item, game);
+                    }
+                }
+            }
+            Some(1) => {
+                render_all(tcod, objects, game, false);
+                let inventory_index = inventory_menu(
+                    &game.inventory,
+                    "Select an item to sell for half its price\n",
+                    &mut tcod.root);
+                if let Some(inventory_index) = inventory_index {
+                    let item = &mut game.inventory[inventory_index];
+                    if item.equipment.is_some() && !item.unequip(&mut game.log) {
+                        continue;
+                    }
+                    let item = take_from_inventory(inventory_index, 1, game);
+                    let price = item_price(&item) / 2;
+                    game.gold += price;
+                    game.log.add_in(Category::Item, format!("You sell the {} for {} gold.", item.name, price), colors::GOLD);
+                    objects[shopkeeper_id].container.as_mut().unwrap().items.push(item);
+                }
+            }
+            Some(2) => {
+                let cost = repair_cost(game);
+                if cost == 0 {
+                    game.log.add("\"Your gear looks fine to me.\"", colors::LIGHT_AZURE);
+                } else if cost > game.gold {
+                    game.log.add_in(Category::Item, format!("Repairs cost {} gold, you can't afford that.", cost), colors::RED);
+                } else {
+                    game.gold -= cost;
+                    for item in game.inventory.iter_mut() {
+                        if let Some(ref mut equipment) = item.equipment {
+                            equipment.durability = equipment.max_durability;
+                        }
+                    }
+                    game.log.add_in(Category::Item, format!("You pay {} gold to have your equipment repaired.", cost),
+                                 colors::GOLD);
+                }
+            }
+            Some(3) => {
+                let (player, shopkeeper) = mut_two(PLAYER, shopkeeper_id, objects);
+                player.attack(shopkeeper, game);
+                break;
+            }
+            _ => break,
+        }
+    }
+}
+
+fn move_towards(id: usize, target_x: i32, target_y: i32, game: &Game, objects: &mut [Object]) {
+    let dx = target_x - objects[id].x;
     let dy = target_y - objects[id].y;
     let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
 
-    let dx = (dx as f32 / distance).round() as i32;
-    let dy = (dy as f32 / distance).round() as i32;
-    // Add some drift to help monsters move around the corners
-    if rand::random() {
-        move_by(id, dx, dy, map, objects);
-    } else {
-        move_by(id, dy, dx, map, objects);
+    let dx = (dx as f32 / distance).round() as i32;
+    let dy = (dy as f32 / distance).round() as i32;
+    let (x, y) = objects[id].pos();
+    let cover = |dx: i32, dy: i32| {
+        game.map.get(x + dx, y + dy).is_some_and(|tile: &Tile| tile.is_bush()) &&
+            !is_blocked(x + dx, y + dy, &game.map, objects)
+    };
+    // The way through the bushes is worth taking, otherwise
+    // add some drift to help monsters move around the corners
+    let straight = if cover(dx, dy) != cover(dy, dx) { cover(dx, dy) } else { game_rng().gen() };
+    if straight {
+        move_by(id, dx, dy, game, objects);
+    } else {
+        move_by(id, dy, dx, game, objects);
+    }
+}
+
+fn closest_monster(max_range: i32, objects: &mut [Object], map: &GameMap, tcod: &Tcod) -> Option<usize> {
+    let mut closest_enemy = None;
+    let mut closest_dist = (max_range + 1) as f32;
+
+    for (id, object) in objects.iter().enumerate() {
+        if id != PLAYER && object.fighter.is_some() && object.ai.is_some() &&
+            is_hostile(&objects[PLAYER], object) &&
+            tcod.fov.is_in_fov(object.x, object.y) && !concealed(object, &objects[PLAYER], map) {
+            let dist = objects[PLAYER].distance_to(object);
+            if dist < closest_dist {
+                closest_enemy = Some(id);
+                closest_dist = dist;
+            }
+        }
+    }
+    closest_enemy
+}
+
+enum UseResult {
+    UsedUp,
+    Cancelled,
+    UsedAndKept,
+}
+
+fn cast_heal(
+    _inventory_id: usize,
+    game: &mut Game,
+    objects: &mut [Object],
+    _tcod: &mut Tcod
+) -> UseResult {
+    let player = &mut objects[PLAYER];
+    if let Some(fighter) = player.fighter {
+        if fighter.hp == objects[PLAYER].max_hp(game) {
+            // Tend to a wounded companion instead
+            let ally_id = objects.iter().position(|object| {
+                object.faction == Faction::Player && object.fighter.is_some_and(|f| f.hp < f.base_max_hp) &&
+                    object.distance_to(&objects[PLAYER]) < 2.0
+            });
+            if let Some(ally_id) = ally_id {
+                game.log.add_in(Category::Item, format!("You tend to the wounds of your {}.", objects[ally_id].name.to_lowercase()),
+                             colors::LIGHT_VIOLET);
+                objects[ally_id].heal(game.tuning.heal_amount, game);
+                return UseResult::UsedUp;
+            }
+            game.log.add_in(Category::Item, "You are already at full health.", colors::RED);
+            return UseResult::Cancelled;
+        }
+        game.log.add_in(Category::Item, "Your wounds are healing!", colors::LIGHT_VIOLET);
+        objects[PLAYER].heal(game.tuning.heal_amount, game);
+        return UseResult::UsedUp;
+    }
+    UseResult::Cancelled
+}
+
+fn cast_lightning(
+    _inventory_id: usize,
+    game: &mut Game,
+    objects: &mut [Object],
+    tcod: &mut Tcod,
+) -> UseResult {
+    let monster_id = closest_monster(game.tuning.lightning_range, objects, &game.map, tcod);
+    if let Some(monster_id) = monster_id {
+        let damage = spell_damage(game.tuning.lightning_damage, game, &objects[PLAYER]);
+        game.log.add_in(Category::Combat, format!("A lightning strikes {} with a loud thunder for {} damage!",
+                             objects[monster_id].name, damage),
+                colors::LIGHT_BLUE,
+        );
+        game.stats.damage_dealt += damage;
+        if let Some(xp) = objects[monster_id].take_damage(damage, true, game) {
+            objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
+            game.log.add_in(Category::Combat, format!("You gain {} XP.", xp), colors::ORANGE);
+        }
+        UseResult::UsedUp
+    } else {
+        game.log.add_in(Category::Item, "No enemy is close enough to strike.", colors::RED);
+        UseResult::Cancelled
+    }
+}
+
+fn cast_confuse(
+    _inventory_id: usize,
+    game: &mut Game,
+    objects: &mut [Object],
+    tcod: &mut Tcod,
+) -> UseResult {
+    game.log.add(
+        "Left click an enemy to confuse it, or right click to cancel.",
+        colors::LIGHT_CYAN);
+    let range = game.tuning.confuse_range as f32;
+    let monster_id = target_monster(tcod, game, objects, Some(range), TargetArea::Tile);
+    if let Some(monster_id) = monster_id {
+        objects[monster_id].add_status(StatusKind::Confused, game.tuning.confuse_turns);
+        game.log.add_in(Category::Combat,
+            format!("{} starts stumbling around!", objects[monster_id].name),
+            colors::LIGHT_GREEN,
+        );
+        UseResult::UsedUp
+    } else {
+        game.log.add_in(Category::Item, "No enemy is close enought to strike.", colors::RED);
+        UseResult::Cancelled
+    }
+}
+
+fn cast_stinking_cloud(
+    _inventory_id: usize,
+    game: &mut Game,
+    objects: &mut [Object],
+    tcod: &mut Tcod,
+) -> UseResult {
+    game.log.add(
+        "Left click to target tile for the stinking cloud, right click to cancel",
+        colors::LIGHT_CYAN);
+    let pos = match target_tile(tcod, game, objects, None, TargetArea::Burst(STINKING_CLOUD_RADIUS)) {
+        Some(tile_pos) => tile_pos,
+        None => return UseResult::Cancelled,
+    };
+    game.log.add_in(Category::Combat, "A foul yellow cloud billows out.", CloudKind::Stinking.color());
+    release_cloud(pos, STINKING_CLOUD_RADIUS, CloudKind::Stinking, STINKING_CLOUD_TURNS, &mut game.map);
+    UseResult::UsedUp
+}
+
+fn cast_fireball(
+    _inventory_id: usize,
+    game: &mut Game,
+    objects: &mut [Object],
+    tcod: &mut Tcod,
+) -> UseResult {
+    game.log.add(
+        "Left click to target tile for the fireball, right click to cancel",
+        colors::LIGHT_CYAN);
+    let radius = game.tuning.fireball_radius;
+    let (x, y) = match target_tile(tcod, game, objects, None, TargetArea::Burst(radius)) {
+        Some(tile_pos) => tile_pos,
+        None => return UseResult::Cancelled,
+    };
+
+    game.log.add_in(Category::Combat,
+        format!("The fireball explodes, burning everything within {} tiles!", radius),
+        colors::ORANGE);
+
+    explode_terrain((x, y), radius, tcod, game, objects);
+
+    let damage = spell_damage(game.tuning.fireball_damage, game, &objects[PLAYER]);
+    let mut xp_to_gain = 0;
+    for (id, obj) in objects.iter_mut().enumerate() {
+        if obj.distance(x, y) <= radius as f32 && obj.fighter.is_some() {
+            game.log.add_in(Category::Combat,
+                format!("The {} gets burned for {} hit points.", obj.name, damage),
+                colors::ORANGE);
+            let ally = obj.faction == Faction::Player;
+            if id != PLAYER && !ally {
+                game.stats.damage_dealt += damage;
+            }
+            if let Some(xp) = obj.take_damage(damage, true, game) {
+                if id != PLAYER && !ally {
+                    xp_to_gain += xp;
+                }
+            }
+        }
+    }
+    objects[PLAYER].fighter.as_mut().unwrap().xp += xp_to_gain;
+    if xp_to_gain > 0 {
+        game.log.add_in(Category::Combat, format!("You gain {} XP.", xp_to_gain), colors::ORANGE);
+    }
+    UseResult::UsedUp
+}
+
+fn cast_remove_curse(_inventory_id: usize, game: &mut Game, _objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
+    let mut lifted = false;
+    for item in game.inventory.iter_mut() {
+        if let Some(ref mut equipment) = item.equipment {
+            if equipment.cursed {
+                equipment.cursed = false;
+                lifted = true;
+                item.name = item.name.trim_start_matches("cursed ").into();
+            }
+        }
+    }
+    if lifted {
+        game.log.add_in(Category::Item, "You feel as if someone is watching over you.", colors::LIGHT_VIOLET);
+    } else {
+        game.log.add_in(Category::Item, "The scroll crumbles to dust, nothing happens.", colors::WHITE);
+    }
+    UseResult::UsedUp
+}
+
+/// Sets the bushes on fire, the webs just burn away
+fn ignite(x: i32, y: i32, map: &mut GameMap) {
+    let tile = &mut map[(x, y)];
+    tile.web = false;
+    if tile.is_bush() && tile.burning == 0 {
+        tile.burning = BURN_TURNS;
+    }
+}
+
+/// Burning bushes set whoever stands in them on fire, spread to the neighbours and burn down to bare ground
+fn update_fires(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    let mut spreads = vec![];
+    for (x, y) in game.map.positions() {
+        if game.map[(x, y)].burning == 0 {
+            continue;
+        }
+        for object in objects.iter_mut() {
+            if object.pos() == (x, y) && object.alive && object.fighter.is_some() {
+                if !object.has_status(StatusKind::Burning) {
+                    game.log.add_in(Category::Combat, format!("{} catches fire!", object.name), colors::ORANGE);
+                }
+                object.add_status(StatusKind::Burning, BURNING_TURNS);
+            }
+        }
+        for (dx, dy) in &[(-1, 0), (1, 0), (0, -1), (0, 1)] {
+            let (nx, ny) = (x + dx, y + dy);
+            if game.map.in_bounds(nx, ny) && game_rng().gen_range(0, 100) < FIRE_SPREAD_CHANCE {
+                spreads.push((nx, ny));
+            }
+        }
+        let tile = &mut game.map[(x, y)];
+        if game_rng().gen_range(0, 100) < FIRE_SMOKE_CHANCE {
+            tile.cloud = Some(Cloud { kind: CloudKind::Smoke, turns: SMOKE_TURNS });
+        }
+        tile.burning -= 1;
+        if tile.burning == 0 {
+            *tile = Tile { explored: tile.explored, cloud: tile.cloud, ..Tile::empty() };
+            tcod.fov.set(x, y, !tile.opaque(), true);
+        }
+    }
+    for (x, y) in spreads {
+        ignite(x, y, &mut game.map);
+    }
+}
+
+/////////////////////// Digging
+const WAND_DIG_RANGE: i32 = 8;
+const EXPLOSION_CRUMBLE_CHANCE: u32 = 15;
+
+/// Every wall can be dug through but the ones along the edge of the map
+fn diggable(x: i32, y: i32, map: &GameMap) -> bool {
+    x > 0 && y > 0 && x < map.width() - 1 && y < map.height() - 1 && map[(x, y)].blocked
+}
+
+/// Turn the wall into floor, returns false if it can't be dug
+fn dig(x: i32, y: i32, map: &mut GameMap) -> bool {
+    if !map.in_bounds(x, y) || !diggable(x, y, map) {
+        return false;
+    }
+    let explored = map[(x, y)].explored;
+    map[(x, y)] = Tile { explored, ..Tile::empty() };
+    true
+}
+
+/// Rebuild the field of view after the map changed under it
+fn refresh_fov(tcod: &mut Tcod, game: &Game, objects: &[Object]) {
+    initialise_fov(tcod, &game.map);
+    let (x, y) = objects[PLAYER].pos();
+    compute_fov(&mut tcod.fov, x, y, &tcod.settings);
+}
+
+/// Ask for a direction with the movement keys, anything else cancels
+fn ask_direction(prompt: &str, tcod: &mut Tcod, game: &mut Game, objects: &[Object]) -> Option<(i32, i32)> {
+    game.log.add(prompt, colors::LIGHT_CYAN);
+    render_all(tcod, objects, game, false);
+    tcod.root.flush();
+    let key = tcod.root.wait_for_keypress(true);
+    tcod.bindings.action(key).and_then(Action::step)
+}
+
+fn use_pickaxe(_inventory_id: usize, game: &mut Game, objects: &mut [Object], tcod: &mut Tcod) -> UseResult {
+    let (dx, dy) = match ask_direction("Dig in which direction?", tcod, game, objects) {
+        Some(step) => step,
+        None => return UseResult::Cancelled,
+    };
+    let (x, y) = (objects[PLAYER].x + dx, objects[PLAYER].y + dy);
+    if !game.map.in_bounds(x, y) || !game.map[(x, y)].blocked {
+        game.log.add_in(Category::Item, "There is nothing to dig there.", colors::WHITE);
+        return UseResult::Cancelled;
+    }
+    if !dig(x, y, &mut game.map) {
+        game.log.add_in(Category::Item, "This rock is too hard to dig.", colors::WHITE);
+        return UseResult::Cancelled;
+    }
+    game.log.add_in(Category::Item, "You dig through the wall.", colors::LIGHT_SEPIA);
+    refresh_fov(tcod, game, objects);
+    UseResult::UsedAndKept
+}
+
+/// Carves a straight tunnel until it runs out of range or hits the rock at the edge
+fn zap_digging(_inventory_id: usize, game: &mut Game, objects: &mut [Object], tcod: &mut Tcod) -> UseResult {
+    let (dx, dy) = match ask_direction("Zap the wand in which direction?", tcod, game, objects) {
+        Some(step) => step,
+        None => return UseResult::Cancelled,
+    };
+    let (mut x, mut y) = objects[PLAYER].pos();
+    let mut dug = 0;
+    for _ in 0..WAND_DIG_RANGE {
+        x += dx;
+        y += dy;
+        if !game.map.in_bounds(x, y) {
+            break;
+        }
+        if game.map[(x, y)].blocked {
+            if !dig(x, y, &mut game.map) {
+                break;
+            }
+            dug += 1;
+        }
+    }
+    if dug > 0 {
+        game.log.add_in(Category::Item, "The wand bores a tunnel through the rock!", colors::LIGHT_VIOLET);
+        refresh_fov(tcod, game, objects);
+    } else {
+        game.log.add_in(Category::Item, "The wand fizzles against the rock.", colors::LIGHT_VIOLET);
+    }
+    UseResult::UsedUp
+}
+
+/// What the blast of an explosion does to a tile
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Blast {
+    Untouched,
+    Ignited,
+    Crumbled,
+    Evaporated,
+}
+
+/// A wall with open ground next to it, the blasts can bring it down
+fn weak_wall(x: i32, y: i32, map: &GameMap) -> bool {
+    diggable(x, y, map) &&
+        [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().any(|&(dx, dy)| !map[(x + dx, y + dy)].blocked)
+}
+
+fn blast_tile(x: i32, y: i32, map: &mut GameMap) -> Blast {
+    let tile = &mut map[(x, y)];
+    // Webs go up in flames whatever else the tile holds
+    tile.web = false;
+    if tile.water {
+        tile.water = false;
+        tile.cloud = Some(Cloud { kind: CloudKind::Steam, turns: STEAM_TURNS });
+        Blast::Evaporated
+    } else if tile.is_bush() && tile.burning == 0 {
+        ignite(x, y, map);
+        Blast::Ignited
+    } else if weak_wall(x, y, map) && game_rng().gen_range(0, 100) < EXPLOSION_CRUMBLE_CHANCE {
+        dig(x, y, map);
+        Blast::Crumbled
+    } else {
+        Blast::Untouched
+    }
+}
+
+/// Let an explosion loose on the terrain around the center, the creatures in it are up to the caller
+fn explode_terrain((x, y): (i32, i32), radius: i32, tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    let mut blasts = vec![];
+    for tx in (x - radius)..(x + radius + 1) {
+        for ty in (y - radius)..(y + radius + 1) {
+            let in_radius = (((tx - x).pow(2) + (ty - y).pow(2)) as f32).sqrt() <= radius as f32;
+            if in_radius && game.map.in_bounds(tx, ty) {
+                blasts.push(blast_tile(tx, ty, &mut game.map));
+            }
+        }
+    }
+    if blasts.contains(&Blast::Crumbled) {
+        game.log.add_in(Category::Combat, "Some of the walls crumble!", colors::LIGHT_GREY);
+    }
+    if blasts.contains(&Blast::Evaporated) {
+        game.log.add_in(Category::Combat, "The water boils away in a hiss of steam!", CloudKind::Steam.color());
+    }
+    if blasts.iter().any(|&blast| blast == Blast::Crumbled || blast == Blast::Evaporated) {
+        refresh_fov(tcod, game, objects);
+    }
+}
+
+/////////////////////// Level events
+// Every turn there is one chance in this many that something happens on the level
+const LEVEL_EVENT_ODDS: u32 = 500;
+// Out of earshot, but close enough to be worth a warning
+const COLLAPSE_MIN_DISTANCE: f32 = 8.0;
+const FLOOD_RADIUS: i32 = 5;
+const FLOOD_TURNS: i32 = 50;
+const MIGRATION_RATS: i32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LevelEvent {
+    // A corridor caves in, cutting off whatever was past it
+    Collapse,
+    Flood,
+    // Rats come in from the stairs
+    Migration,
+}
+
+/// Now and then the sewers change under the player's feet, the town stays quiet
+fn level_events(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    drain_floods(&mut game.map);
+    let scripted = game.objective.arena_wave().is_some() || game.objective.is_tutorial();
+    if game.dungeon_level == 0 || scripted || game_rng().gen_range(0, LEVEL_EVENT_ODDS) != 0 {
+        return;
+    }
+    let events = [LevelEvent::Collapse, LevelEvent::Flood, LevelEvent::Migration];
+    match *game_rng().choose(&events).unwrap() {
+        LevelEvent::Collapse => collapse_tunnel(tcod, game, objects),
+        LevelEvent::Flood => flood(game),
+        LevelEvent::Migration => rat_migration(game, objects),
+    }
+}
+
+/// Can the player still walk to the stairs down?
+fn stairs_reachable(game: &Game, objects: &[Object]) -> bool {
+    let stairs = match objects.iter().find(|object| object.name == "stairs down") {
+        Some(stairs) => stairs.pos(),
+        None => return true,
+    };
+    let map = &game.map;
+    let mut path = AStar::new_from_callback(
+        MAP_WIDTH, MAP_HEIGHT,
+        |_from, to| if map[to].blocked { 0.0 } else { 1.0 },
+        1.0);
+    path.find(objects[PLAYER].pos(), stairs)
+}
+
+fn collapse_tunnel(tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    let map = &game.map;
+    let open = |x: i32, y: i32| !map[(x, y)].blocked;
+    // A piece of corridor, open on two opposite sides only, with nothing on it
+    let candidates: Vec<(i32, i32)> = map
+        .positions()
+        .filter(|&(x, y)| x > 0 && y > 0 && x < map.width() - 1 && y < map.height() - 1)
+        .filter(|&(x, y)| {
+            let tile = &map[(x, y)];
+            let corridor = (open(x - 1, y) && open(x + 1, y) && !open(x, y - 1) && !open(x, y + 1)) ||
+                (open(x, y - 1) && open(x, y + 1) && !open(x - 1, y) && !open(x + 1, y));
+            corridor && !tile.blocked && !tile.water && !tile.is_bush() &&
+                objects[PLAYER].distance(x, y) >= COLLAPSE_MIN_DISTANCE &&
+                !objects.iter().any(|object| object.pos() == (x, y))
+        })
+        .collect();
+    let (x, y) = match game_rng().choose(&candidates) {
+        Some(&pos) => pos,
+        None => return,
+    };
+    let before = game.map[(x, y)];
+    game.map[(x, y)] = Tile { explored: before.explored, ..Tile::wall() };
+    // Nobody gets locked away from the way down
+    if !stairs_reachable(game, objects) {
+        game.map[(x, y)] = before;
+        return;
+    }
+    game.log.add_in(Category::Danger, "The ground shakes, somewhere a tunnel caves in!", colors::LIGHT_SEPIA);
+    refresh_fov(tcod, game, objects);
+}
+
+fn flood(game: &mut Game) {
+    let map = &game.map;
+    let water: Vec<(i32, i32)> = map.positions().filter(|&pos| map[pos].water).collect();
+    let floor: Vec<(i32, i32)> = map.positions().filter(|&pos| !map[pos].blocked).collect();
+    // Rises from the water already there, or from some drain
+    let (x, y) = match game_rng().choose(if water.is_empty() { &floor } else { &water }) {
+        Some(&pos) => pos,
+        None => return,
+    };
+    for tx in (x - FLOOD_RADIUS)..(x + FLOOD_RADIUS + 1) {
+        for ty in (y - FLOOD_RADIUS)..(y + FLOOD_RADIUS + 1) {
+            let in_radius = (((tx - x).pow(2) + (ty - y).pow(2)) as f32).sqrt() <= FLOOD_RADIUS as f32;
+            let floods = game.map.get(tx, ty).is_some_and(|tile| !tile.blocked && !tile.water && !tile.is_bush());
+            if in_radius && floods {
+                let tile = &mut game.map[(tx, ty)];
+                tile.water = true;
+                tile.flooded = FLOOD_TURNS;
+            }
+        }
+    }
+    game.log.add_in(Category::Danger, "Water gushes out of the drains, the sewers are flooding!", colors::LIGHT_BLUE);
+}
+
+fn drain_floods(map: &mut GameMap) {
+    for tile in map.tiles_mut().filter(|tile| tile.flooded > 0) {
+        tile.flooded -= 1;
+        if tile.flooded == 0 {
+            tile.water = false;
+        }
+    }
+}
+
+fn rat_migration(game: &mut Game, objects: &mut Vec<Object>) {
+    let stairs: Vec<(i32, i32)> = objects
+        .iter()
+        .filter(|object| object.name == "stairs down" || object.name == "stairs up")
+        .map(Object::pos)
+        .collect();
+    let (x, y) = match game_rng().choose(&stairs) {
+        Some(&pos) => pos,
+        None => return,
+    };
+    let mut arrived = 0;
+    for tx in (x - 1)..(x + 2) {
+        for ty in (y - 1)..(y + 2) {
+            if arrived < MIGRATION_RATS && !is_blocked(tx, ty, &game.map, objects) {
+                let mut rat = make_monster("rat", tx, ty);
+                rat.level = game.dungeon_level as i32;
+                objects.push(rat);
+                arrived += 1;
+            }
+        }
+    }
+    if arrived > 0 {
+        game.log.add_in(Category::Danger, "A horde of rats pours in from the stairs!", colors::LIGHT_SEPIA);
+    }
+}
+
+/////////////////////// Arena
+const ARENA_X: i32 = 10;
+const ARENA_Y: i32 = 4;
+const ARENA_WIDTH: i32 = 60;
+const ARENA_HEIGHT: i32 = 34;
+const ARENA_FIRST_LULL: i32 = 3;
+// Turns to catch a breath and grab the supplies between the waves
+const ARENA_LULL_TURNS: i32 = 10;
+const ARENA_DROPS: i32 = 2;
+// Every this many waves the last monster is a champion
+const ARENA_CHAMPION_WAVE: u32 = 5;
+// The monsters don't spawn closer to the player than this
+const ARENA_SPAWN_DISTANCE: f32 = 8.0;
+const ARENA_WAVE_SCORE: i32 = 100;
+
+/// One open pit with the same pillars every time, the player starts in the middle
+fn make_arena(objects: &mut Vec<Object>) -> GameMap {
+    let mut map = GameMap::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+    objects.truncate(1);
+
+    let pit = Rect::new(ARENA_X, ARENA_Y, ARENA_WIDTH, ARENA_HEIGHT);
+    for x in (pit.x1 + 1)..pit.x2 {
+        for y in (pit.y1 + 1)..pit.y2 {
+            // Pillars to duck behind, every tenth tile across and every seventh down
+            let pillar = (x - pit.x1) % 10 == 5 && (y - pit.y1) % 7 == 3;
+            map[(x, y)] = if pillar { Tile::wall() } else { Tile::empty() };
+        }
+    }
+    let (x, y) = pit.center();
+    objects[PLAYER].set_pos(x, y);
+    map
+}
+
+/// Sends in the next wave once the last one is beaten, with some supplies in between
+fn direct_arena(game: &mut Game, objects: &mut Vec<Object>) {
+    let (wave, lull) = match game.objective {
+        RunObjective::Arena { wave, lull } => (wave, lull),
+        RunObjective::Descent { .. } | RunObjective::Tutorial { .. } => return,
+    };
+    // A mimic still lying in wait counts as part of the wave
+    let fighting = objects.iter().any(|object| {
+        object.ai.is_some() && object.alive && object.fighter.is_some()
+            && objects[PLAYER].faction.hostile_to(object.faction)
+    });
+    if fighting {
+        return;
+    }
+    let lull = match lull {
+        0 => {
+            game.log.add(format!("Wave {} is beaten! Supplies are thrown into the arena.", wave), colors::GOLD);
+            arena_supplies(wave, game, objects);
+            ARENA_LULL_TURNS
+        }
+        1 => {
+            spawn_wave(wave + 1, game, objects);
+            game.objective = RunObjective::Arena { wave: wave + 1, lull: 0 };
+            return;
+        }
+        lull => lull - 1,
+    };
+    game.objective = RunObjective::Arena { wave, lull };
+}
+
+/// The monsters come from ever deeper levels and in ever bigger packs
+fn spawn_wave(wave: u32, game: &mut Game, objects: &mut Vec<Object>) {
+    let level = 1 + wave / 2;
+    let monster_chances = &mut monster_table(level);
+    let monster_choice = WeightedChoice::new(monster_chances);
+    let count = 2 + wave;
+    let mut spawned = 0;
+    for _ in 0..count * 20 {
+        if spawned == count {
+            break;
+        }
+        let (x, y) = match random_free_tile(&game.map, objects) {
+            Some(pos) => pos,
+            None => break,
+        };
+        let choice = monster_choice.ind_sample(&mut game_rng());
+        if objects[PLAYER].distance(x, y) < ARENA_SPAWN_DISTANCE || choice == "sewer king" {
+            continue;
+        }
+        let mut monster = make_monster(choice, x, y);
+        monster.level = level as i32;
+        arm_monster(&mut monster, level);
+        spawned += 1;
+        if spawned == count && wave.is_multiple_of(ARENA_CHAMPION_WAVE) {
+            make_champion(&mut monster, level);
+        }
+        objects.push(monster);
+    }
+    game.log.add_in(Category::Danger, format!("Wave {} enters the arena!", wave), colors::RED);
+}
+
+fn arena_supplies(wave: u32, game: &mut Game, objects: &mut Vec<Object>) {
+    let level = 1 + wave / 2;
+    let item_chances = &mut item_table(level);
+    let item_choice = WeightedChoice::new(item_chances);
+    for _ in 0..ARENA_DROPS {
+        if let Some((x, y)) = random_free_tile(&game.map, objects) {
+            objects.push(make_found_item(item_choice.ind_sample(&mut game_rng()), x, y, level));
+        }
+    }
+}
+
+/////////////////////// Tutorial
+// Where the top left corner of the tutorial level goes on the map
+const TUTORIAL_X: i32 = 8;
+const TUTORIAL_Y: i32 = 17;
+// '@' is where the player starts, 'o' an orc, '!' a healing potion, '/' a sword,
+// '?' a scroll of confusion and '<' the stairs out
+const TUTORIAL_MAP: &[&str] = &[
+    "################################################################",
+    "#.........####.............####...........####.................#",
+    "#.........####.............####...!.......####............o....#",
+    "#.........####.............####...........####.................#",
+    "#...@.................o.........................?..............#",
+    "#.........####.............####...........####.................#",
+    "#.........####.............####......./...####...............<.#",
+    "#.........####.............####...........####.................#",
+    "################################################################",
+];
+
+/// The lessons of the tutorial in order, each one pops up once the one before is done
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum TutorialStep {
+    Move,
+    Fight,
+    PickUp,
+    Inventory,
+    Equip,
+    Scroll,
+    Stairs,
+    Done,
+}
+
+impl TutorialStep {
+    /// Title and text of the popup, with the keys the player has bound
+    fn prompt(self, bindings: &KeyBindings) -> Option<(&'static str, String)> {
+        let keys = |action| bindings.keys(action).join(" or ");
+        let first = |action| bindings.keys(action).first().map_or("?", |key| *key).to_string();
+        let prompt = match self {
+            TutorialStep::Move => ("Welcome to the sewers", format!(
+                "This is a practice run. Walk with {}, {}, {} and {}, the diagonals work too. \
+                 Follow the corridor east, something is waiting there.",
+                first(Action::MoveNorth), first(Action::MoveSouth), first(Action::MoveWest),
+                first(Action::MoveEast))),
+            TutorialStep::Fight => ("An enemy", String::from(
+                "That's an orc. Walk into it to attack and keep hitting until it drops. \
+                 Your health is shown in the panel below.")),
+            TutorialStep::PickUp => ("Loot", format!(
+                "Well fought. There is a potion and a sword in the next room. \
+                 Stand on an item and press {} to pick it up.", keys(Action::PickUp))),
+            TutorialStep::Inventory => ("Inventory", format!(
+                "You're hurt. Open the inventory with {} and press the letter next to the healing potion \
+                 to drink it.", keys(Action::Inventory))),
+            TutorialStep::Equip => ("Equipment", format!(
+                "Your old sword has seen better days. Pick up the new one, then select it in the inventory \
+                 to wield it. {} shows everything you're wearing.", keys(Action::Equipment))),
+            TutorialStep::Scroll => ("Scrolls", String::from(
+                "Another orc guards the way out. Pick up the scroll in the next room and read it from \
+                 the inventory, then left click the orc to confuse it.")),
+            TutorialStep::Stairs => ("The way down", format!(
+                "Finish the orc off, then stand on the stairs < and press {} to leave the tutorial.",
+                keys(Action::Descend))),
+            TutorialStep::Done => return None,
+        };
+        Some(prompt)
+    }
+
+    /// The step after this one, if the event finishes it
+    fn next(self, event: &GameEvent) -> Option<TutorialStep> {
+        match (self, event) {
+            (TutorialStep::Fight, GameEvent::MonsterKilled { by_player: true, .. }) => Some(TutorialStep::PickUp),
+            (TutorialStep::PickUp, GameEvent::PickedUp(Item::Heal)) => Some(TutorialStep::Inventory),
+            (TutorialStep::Inventory, GameEvent::UsedItem(Item::Heal)) => Some(TutorialStep::Equip),
+            (TutorialStep::Equip, GameEvent::Equipped(Item::Sword)) => Some(TutorialStep::Scroll),
+            (TutorialStep::Scroll, GameEvent::UsedItem(item)) if is_scroll(*item) => Some(TutorialStep::Stairs),
+            _ => None,
+        }
+    }
+}
+
+/// The hand-drawn tutorial level, the same every time
+fn make_tutorial(objects: &mut Vec<Object>) -> GameMap {
+    let mut map = GameMap::new(MAP_WIDTH, MAP_HEIGHT, Tile::wall());
+    objects.truncate(1);
+
+    for (row, line) in TUTORIAL_MAP.iter().enumerate() {
+        for (column, char) in line.chars().enumerate() {
+            let (x, y) = (TUTORIAL_X + column as i32, TUTORIAL_Y + row as i32);
+            if char == '#' {
+                continue;
+            }
+            map[(x, y)] = Tile::empty();
+            match char {
+                '@' => objects[PLAYER].set_pos(x, y),
+                'o' => objects.push(make_monster("orc", x, y)),
+                '!' => objects.push(make_item(Item::Heal, x, y)),
+                '/' => objects.push(make_item(Item::Sword, x, y)),
+                '?' => objects.push(make_item(Item::Confuse, x, y)),
+                '<' => {
+                    let mut stairs = Object::new("stairs down", x, y, '<', colors::WHITE, false);
+                    stairs.always_visible = true;
+                    objects.push(stairs);
+                }
+                _ => {}
+            }
+        }
+    }
+    map
+}
+
+/// A worn sword to start with, so the one found in the tutorial has to be wielded by hand
+fn make_old_sword() -> Object {
+    let mut sword = make_item(Item::Sword, 0, 0);
+    sword.name = "old sword".into();
+    if let Some(ref mut equipment) = sword.equipment {
+        equipment.power_bonus = 1;
+        equipment.max_hp_bonus = 0;
+        equipment.equipped = true;
+    }
+    sword
+}
+
+/// Moves on to the fight once an enemy is in sight, and pops up every new lesson.
+/// True if something was drawn over the map
+fn direct_tutorial(tcod: &mut Tcod, objects: &[Object], game: &mut Game, shown: &mut Option<TutorialStep>) -> bool {
+    let mut step = match game.objective {
+        RunObjective::Tutorial { step } => step,
+        RunObjective::Descent { .. } | RunObjective::Arena { .. } => return false,
+    };
+    let enemy_in_sight = objects
+        .iter()
+        .any(|object| is_hostile(&objects[PLAYER], object) && tcod.fov.is_in_fov(object.x, object.y));
+    if step == TutorialStep::Move && enemy_in_sight {
+        step = TutorialStep::Fight;
+        game.objective = RunObjective::Tutorial { step };
+    }
+    if *shown == Some(step) {
+        return false;
+    }
+    *shown = Some(step);
+    match step.prompt(&tcod.bindings) {
+        Some((title, text)) => {
+            banner(title, &text, &mut tcod.root);
+            true
+        }
+        None => false,
+    }
+}
+
+fn finish_tutorial(outcome: Outcome, root: &mut Root) {
+    let text = match outcome {
+        Outcome::Victory => "Tutorial complete!\n\nThat's all it takes to get by down there, for a while. \
+                             Start a new game from the main menu when you're ready.",
+        Outcome::Defeat => "You fell in the tutorial.\n\nTry it again from the main menu, \
+                            the real sewers are less forgiving.",
+    };
+    msgbox(text, END_SCREEN_WIDTH, root);
+}
+
+/////////////////////// Clouds
+const GAS_TRAP_RADIUS: i32 = 1;
+const GAS_TRAP_TURNS: i32 = 8;
+const FIRE_SMOKE_CHANCE: u32 = 50;
+const SMOKE_TURNS: i32 = 5;
+const STEAM_TURNS: i32 = 6;
+const CLOUD_DRIFT_CHANCE: u32 = 30;
+const CLOUD_EFFECT_TURNS: i32 = 3;
+// How much of the cloud color shows over the tile
+const CLOUD_OPACITY: f32 = 0.4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum CloudKind {
+    Poison,
+    // Makes whoever breathes it reel
+    Stinking,
+    Smoke,
+    // Left by the water boiled away by the fire
+    Steam,
+}
+
+impl CloudKind {
+    fn name(self) -> &'static str {
+        match self {
+            CloudKind::Poison => "poison gas",
+            CloudKind::Stinking => "stinking cloud",
+            CloudKind::Smoke => "smoke",
+            CloudKind::Steam => "steam",
+        }
+    }
+
+    fn color(self) -> colors::Color {
+        match self {
+            CloudKind::Poison => colors::CHARTREUSE,
+            CloudKind::Stinking => colors::DARK_YELLOW,
+            CloudKind::Smoke => colors::GREY,
+            CloudKind::Steam => colors::LIGHTEST_GREY,
+        }
+    }
+
+    fn blocks_sight(self) -> bool {
+        self == CloudKind::Smoke || self == CloudKind::Steam
+    }
+
+    /// What breathing it in does, if anything
+    fn status(self) -> Option<StatusKind> {
+        match self {
+            CloudKind::Poison => Some(StatusKind::Poisoned),
+            CloudKind::Stinking => Some(StatusKind::Confused),
+            CloudKind::Smoke | CloudKind::Steam => None,
+        }
+    }
+}
+
+/// Gas hanging over a tile, thins out turn by turn
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Cloud {
+    kind: CloudKind,
+    turns: i32,
+}
+
+/// Fill the open tiles around the spot with a cloud
+fn release_cloud((x, y): (i32, i32), radius: i32, kind: CloudKind, turns: i32, map: &mut GameMap) {
+    for tx in (x - radius)..(x + radius + 1) {
+        for ty in (y - radius)..(y + radius + 1) {
+            let in_radius = (((tx - x).pow(2) + (ty - y).pow(2)) as f32).sqrt() <= radius as f32;
+            if in_radius && map.get(tx, ty).is_some_and(|tile| !tile.blocked) {
+                map[(tx, ty)].cloud = Some(Cloud { kind, turns });
+            }
+        }
+    }
+}
+
+/// Whoever stands in a cloud breathes it in, then the clouds drift about and thin out.
+/// Returns true if the smoke moved, so the field of view needs a recompute
+fn update_clouds(game: &mut Game, objects: &mut [Object], fov: &mut Vision) -> bool {
+    for object in objects.iter_mut().filter(|object| object.alive && object.fighter.is_some() && !object.spectral) {
+        let kind = match game.map[object.pos()].cloud {
+            Some(cloud) => cloud.kind,
+            None => continue,
+        };
+        if let Some(status) = kind.status() {
+            if !object.has_status(status) {
+                let category = if object.is_player() { Category::Danger } else { Category::Combat };
+                game.log.add_in(category, format!("{} breathes in the {}!", object.name, kind.name()), kind.color());
+            }
+            object.add_status(status, CLOUD_EFFECT_TURNS);
+        }
+    }
+
+    let smoky = |map: &GameMap| {
+        map.positions().filter(|&pos| map[pos].opaque() && !map[pos].block_sight).collect::<Vec<_>>()
+    };
+    let smoke_before = smoky(&game.map);
+    let mut drifted = vec![];
+    for pos in game.map.positions() {
+        let mut cloud = match game.map[pos].cloud.take() {
+            Some(cloud) => cloud,
+            None => continue,
+        };
+        cloud.turns -= 1;
+        if cloud.turns <= 0 {
+            continue;
+        }
+        let mut to = pos;
+        if game_rng().gen_range(0, 100) < CLOUD_DRIFT_CHANCE {
+            let (dx, dy) = (game_rng().gen_range(-1, 2), game_rng().gen_range(-1, 2));
+            if game.map.get(pos.0 + dx, pos.1 + dy).is_some_and(|tile| !tile.blocked) {
+                to = (pos.0 + dx, pos.1 + dy);
+            }
+        }
+        drifted.push((to, cloud));
+    }
+    for (pos, cloud) in drifted {
+        // Two clouds drifting together, the thicker one stays
+        let tile = &mut game.map[pos];
+        if tile.cloud.is_none_or(|other| other.turns < cloud.turns) {
+            tile.cloud = Some(cloud);
+        }
+    }
+    let smoke_after = smoky(&game.map);
+    if smoke_before == smoke_after {
+        return false;
+    }
+    for &(x, y) in smoke_before.iter().chain(smoke_after.iter()) {
+        let tile = &game.map[(x, y)];
+        fov.set(x, y, !tile.opaque(), !tile.blocked);
+    }
+    true
+}
+
+/// Weapons wear down when they hit, armor when it gets hit
+fn wear_equipment(weapon: bool, amount: i32, game: &mut Game) {
+    if amount == 1 && game_rng().gen_range(0, 100) >= WEAR_CHANCE {
+        return;
+    }
+    let worn: Vec<usize> = game.inventory
+        .iter()
+        .enumerate()
+        .filter(|&(_, item)| {
+            (item.item == Some(Item::Sword)) == weapon &&
+                item.equipment.is_some_and(|e| e.equipped && e.durability > 0 && e.slot != Slot::Ranged)
+        })
+        .map(|(id, _)| id)
+        .collect();
+    if let Some(&inventory_id) = game_rng().choose(&worn) {
+        let item = &mut game.inventory[inventory_id];
+        let equipment = item.equipment.as_mut().unwrap();
+        equipment.durability = cmp::max(0, equipment.durability - amount);
+        if equipment.durability == 0 {
+            game.log.add_in(Category::Danger, format!("Your {} breaks!", item.name), colors::RED);
+        }
+    }
+}
+
+/// Restore the most damaged piece of equipment in the inventory
+fn use_repair_kit(_inventory_id: usize, game: &mut Game, _objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
+    let damaged = game.inventory
+        .iter()
+        .enumerate()
+        .filter_map(|(id, item)| item.equipment.map(|e| (id, e)))
+        .filter(|&(_, e)| e.durability < e.max_durability)
+        .min_by_key(|&(_, e)| e.durability * 100 / e.max_durability)
+        .map(|(id, _)| id);
+    match damaged {
+        Some(inventory_id) => {
+            let item = &mut game.inventory[inventory_id];
+            let equipment = item.equipment.as_mut().unwrap();
+            equipment.durability = cmp::min(equipment.max_durability, equipment.durability + REPAIR_KIT_AMOUNT);
+            game.log.add_in(Category::Item, format!("You repair your {}.", item.name), colors::LIGHT_GREEN);
+            UseResult::UsedUp
+        }
+        None => {
+            game.log.add_in(Category::Item, "Your equipment is in good condition.", colors::RED);
+            UseResult::Cancelled
+        }
+    }
+}
+
+fn repair_cost(game: &Game) -> i32 {
+    game.inventory
+        .iter()
+        .filter_map(|item| item.equipment)
+        .map(|e| (e.max_durability - e.durability) * REPAIR_COST)
+        .sum()
+}
+
+fn cast_enchant_weapon(_inventory_id: usize, game: &mut Game, _objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
+    let weapon = game.inventory.iter().position(|item| {
+        item.item == Some(Item::Sword) && item.equipment.is_some_and(|e| e.equipped)
+    });
+    enchant_equipped(weapon, true, game)
+}
+
+fn cast_enchant_armor(_inventory_id: usize, game: &mut Game, _objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
+    let armor: Vec<usize> = game.inventory
+        .iter()
+        .enumerate()
+        .filter(|&(_, item)| {
+            item.item != Some(Item::Sword) &&
+                item.equipment.is_some_and(|e| e.equipped && e.slot != Slot::Ranged)
+        })
+        .map(|(id, _)| id)
+        .collect();
+    let armor = game_rng().choose(&armor).cloned();
+    enchant_equipped(armor, false, game)
+}
+
+fn enchant_equipped(inventory_id: Option<usize>, weapon: bool, game: &mut Game) -> UseResult {
+    let inventory_id = match inventory_id {
+        Some(inventory_id) => inventory_id,
+        None => {
+            let what = if weapon { "weapon" } else { "armor" };
+            game.log.add_in(Category::Item, format!("You have no {} equipped to enchant.", what), colors::RED);
+            return UseResult::Cancelled;
+        }
+    };
+    let item = &mut game.inventory[inventory_id];
+    if item.enchant(weapon) {
+        game.log.add_in(Category::Item, format!("Your {} glows blue. It is now {}.", item.name, item.display_name()),
+                     colors::LIGHT_BLUE);
+    } else {
+        game.log.add_in(Category::Item, format!("Your {} glows briefly, but it can't hold any more magic.", item.name),
+                     colors::WHITE);
+    }
+    UseResult::UsedUp
+}
+
+fn cast_teleport(_inventory_id: usize, game: &mut Game, objects: &mut [Object], tcod: &mut Tcod) -> UseResult {
+    match random_free_tile(&game.map, objects) {
+        Some((x, y)) => {
+            objects[PLAYER].set_pos(x, y);
+            compute_fov(&mut tcod.fov, x, y, &tcod.settings);
+            game.log.add_in(Category::Item, "The world spins around you, and you find yourself somewhere else.",
+                         colors::LIGHT_MAGENTA);
+            UseResult::UsedUp
+        }
+        None => {
+            game.log.add_in(Category::Item, "The scroll fizzles, there is nowhere to go.", colors::RED);
+            UseResult::Cancelled
+        }
+    }
+}
+
+fn cast_blink(_inventory_id: usize, game: &mut Game, objects: &mut [Object], tcod: &mut Tcod) -> UseResult {
+    game.log.add("Left click where to jump, or right click to cancel.", colors::LIGHT_CYAN);
+    let (x, y) = match target_tile(tcod, game, objects, Some(BLINK_RANGE), TargetArea::Tile) {
+        Some(tile_pos) => tile_pos,
+        None => return UseResult::Cancelled,
+    };
+    if is_blocked(x, y, &game.map, objects) {
+        game.log.add_in(Category::Item, "You can't jump into something solid.", colors::RED);
+        return UseResult::Cancelled;
+    }
+    objects[PLAYER].set_pos(x, y);
+    compute_fov(&mut tcod.fov, x, y, &tcod.settings);
+    game.log.add_in(Category::Item, "You blink across the room.", colors::LIGHT_MAGENTA);
+    UseResult::UsedUp
+}
+
+fn drink_speed(_inventory_id: usize, game: &mut Game, objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
+    objects[PLAYER].add_status(StatusKind::Hasted, HASTE_TURNS);
+    game.log.add_in(Category::Item, "Everything around you slows down.", StatusKind::Hasted.color());
+    UseResult::UsedUp
+}
+
+fn drink_invisibility(_inventory_id: usize, game: &mut Game, objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
+    objects[PLAYER].add_status(StatusKind::Invisible, INVISIBILITY_TURNS);
+    game.log.add_in(Category::Item, "Your body fades from sight.", StatusKind::Invisible.color());
+    UseResult::UsedUp
+}
+
+fn cast_cure_poison(_inventory_id: usize, game: &mut Game, objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
+    if !objects[PLAYER].remove_status(StatusKind::Poisoned) {
+        game.log.add_in(Category::Item, "You are not poisoned.", colors::RED);
+        return UseResult::Cancelled;
+    }
+    game.log.add_in(Category::Item, "The burning in your veins fades away.", colors::LIGHT_GREEN);
+    UseResult::UsedUp
+}
+
+/// Rare potions that make the player permanently stronger
+fn drink_stat_potion(inventory_id: usize, game: &mut Game, objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
+    let fighter = objects[PLAYER].fighter.as_mut().unwrap();
+    match game.inventory[inventory_id].item {
+        Some(Item::Strength) => {
+            fighter.attributes.strength += 1;
+            game.log.add_in(Category::Item, "Your muscles bulge with newfound might!", colors::CRIMSON);
+        }
+        Some(Item::Toughness) => {
+            fighter.base_max_hp += TOUGHNESS_HP;
+            fighter.hp += TOUGHNESS_HP;
+            game.log.add_in(Category::Item, "Your skin hardens, you feel much sturdier!", colors::AMBER);
+        }
+        Some(Item::Agility) => {
+            fighter.attributes.dexterity += 1;
+            game.log.add_in(Category::Item, "You feel light on your feet!", colors::TURQUOISE);
+        }
+        Some(Item::Intellect) => {
+            fighter.attributes.intelligence += 1;
+            game.log.add_in(Category::Item, "Your thoughts become crystal clear!", colors::LIGHT_BLUE);
+        }
+        _ => return UseResult::Cancelled,
+    }
+    game.stat_potions += 1;
+    UseResult::UsedUp
+}
+
+fn eat_corpse(inventory_id: usize, game: &mut Game, objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
+    let name = game.inventory[inventory_id].name.clone();
+    let poisonous = game.inventory[inventory_id].poisonous;
+    break_conduct(Conduct::Vegetarian, game);
+    game.nutrition = cmp::min(game.nutrition + CORPSE_NUTRITION, MAX_NUTRITION);
+    game.log.add_in(Category::Item, format!("You eat the {}. Not great, but filling.", name), colors::LIGHT_SEPIA);
+    // Venomous critters don't make for a healthy meal
+    if poisonous {
+        objects[PLAYER].add_status(StatusKind::Poisoned, POISON_TURNS);
+        game.log.add_in(Category::Danger, "Your stomach turns, the meat was poisonous!", StatusKind::Poisoned.color());
+    }
+    UseResult::UsedUp
+}
+
+fn nock_arrow(_inventory_id: usize, game: &mut Game, _objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
+    game.log.add_in(Category::Item, "Equip a bow and press 'f' to shoot the arrows.", colors::WHITE);
+    UseResult::UsedAndKept
+}
+
+fn fire_bow(tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) -> PlayerAction {
+    let bow = get_equipped_in_slot(Slot::Ranged, &game.inventory);
+    if bow.is_none_or(|id| game.inventory[id].equipment.is_some_and(|e| e.durability <= 0)) {
+        game.log.add_in(Category::Item, "You have no working bow equipped.", colors::RED);
+        return PlayerAction::DidntTakeTurn;
+    }
+    let arrows = match game.inventory.iter().position(|item| item.item == Some(Item::Arrow)) {
+        Some(arrows) => arrows,
+        None => {
+            game.log.add_in(Category::Item, "You are out of arrows.", colors::RED);
+            return PlayerAction::DidntTakeTurn;
+        }
+    };
+
+    game.log.add("Left click a target to shoot, or right click to cancel.", colors::LIGHT_CYAN);
+    let target_id = match target_monster(tcod, game, objects, Some(BOW_RANGE), TargetArea::Line) {
+        Some(target_id) => target_id,
+        None => return PlayerAction::DidntTakeTurn,
+    };
+    take_from_inventory(arrows, 1, game);
+    let from = objects[PLAYER].pos();
+    let (to, hit_id) = projectile_path(from, objects[target_id].pos(), &game.map, objects);
+    animate_projectile(tcod, objects, game, (from, to), '*', colors::LIGHT_SEPIA);
+
+    // It's hard to aim with someone trying to hit you, nimble fingers help
+    let threatened = objects
+        .iter()
+        .any(|object| is_hostile(&objects[PLAYER], object) && object.distance_to(&objects[PLAYER]) < 2.0);
+    let cover = if in_cover(&objects[target_id], &game.map) { COVER_AIM_PENALTY } else { 0 };
+    let miss_chance = if threatened { MELEE_SHOT_MISS_CHANCE } else { SHOT_MISS_CHANCE } + cover
+        - objects[PLAYER].attributes(game).dexterity * ACCURACY_PER_DEXTERITY;
+    if game_rng().gen_range(0, 100) < miss_chance {
+        game.log.add_in(Category::Combat, "Your shot goes wide!", colors::WHITE);
+        return PlayerAction::TookTurn;
+    }
+
+    match hit_id {
+        Some(hit_id) => {
+            let damage = objects[PLAYER].ranged_power(game) + BOW_BASE_DAMAGE;
+            projectile_hit(hit_id, damage, "arrow", game, objects);
+        }
+        None => game.log.add_in(Category::Combat, "The arrow hits the wall.", colors::WHITE),
+    }
+    PlayerAction::TookTurn
+}
+
+/// Follow the line until it hits a wall or a creature,
+/// returns where the projectile stops and whom it hit
+fn projectile_path(from: (i32, i32), to: (i32, i32), map: &GameMap, objects: &[Object]) -> ((i32, i32), Option<usize>) {
+    let mut last = from;
+    for (x, y) in tcod::line::Line::new(from, to) {
+        if map[(x, y)].blocked {
+            return (last, None);
+        }
+        let hit_id = objects
+            .iter()
+            .position(|object| object.fighter.is_some() && object.pos() == (x, y));
+        if hit_id.is_some() {
+            return ((x, y), hit_id);
+        }
+        last = (x, y);
+    }
+    (last, None)
+}
+
+fn projectile_hit(target_id: usize, damage: i32, what: &str, game: &mut Game, objects: &mut [Object]) {
+    if in_cover(&objects[target_id], &game.map) && game_rng().gen_range(0, 100) < COVER_DODGE_CHANCE {
+        game.log.add_in(Category::Combat,
+            format!("The {} is lost in the bushes around {}.", what, objects[target_id].name), colors::WHITE);
+        return;
+    }
+    if objects[target_id].spectral {
+        game.log.add_in(Category::Combat, format!("The {} flies through {}.", what, objects[target_id].name), colors::GREY);
+        return;
+    }
+    let mut damage = damage - objects[target_id].defence(game);
+    if objects[target_id].affix == Some(Affix::Armored) {
+        damage /= 2;
+    }
+    if damage > 0 {
+        game.log.add_in(Category::Combat, format!("The {} hits {} for {} damage!", what, objects[target_id].name, damage),
+                     colors::WHITE);
+        game.events.push(GameEvent::Sound(Sound::Hit));
+        game.stats.damage_dealt += damage;
+        if let Some(xp) = objects[target_id].take_damage(damage, true, game) {
+            objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
+            game.log.add_in(Category::Combat, format!("You gain {} XP.", xp), colors::ORANGE);
+        }
+    } else {
+        game.log.add_in(Category::Combat, format!("The {} bounces off {}.", what, objects[target_id].name), colors::WHITE);
+    }
+}
+
+/// Anything can be thrown, potions shatter and the rest lands where it stops
+fn throw_item(inventory_id: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) -> PlayerAction {
+    game.log.add("Left click where to throw, or right click to cancel.", colors::LIGHT_CYAN);
+    let target = match target_tile(tcod, game, objects, Some(THROW_RANGE), TargetArea::Line) {
+        Some(target) => target,
+        None => return PlayerAction::DidntTakeTurn,
+    };
+    let item = &mut game.inventory[inventory_id];
+    if item.equipment.is_some() && !item.unequip(&mut game.log) {
+        return PlayerAction::DidntTakeTurn;
+    }
+
+    let mut item = take_from_inventory(inventory_id, 1, game);
+    let from = objects[PLAYER].pos();
+    let ((x, y), hit_id) = projectile_path(from, target, &game.map, objects);
+    animate_projectile(tcod, objects, game, (from, (x, y)), item.char, item.color);
+
+    if let Some(potion) = item.item.filter(|&kind| is_potion(kind)) {
+        game.log.add_in(Category::Item, "The potion shatters, splashing everything around!", colors::LIGHT_VIOLET);
+        for object in objects.iter_mut() {
+            if object.fighter.is_none() || object.distance(x, y) >= 2.0 {
+                continue;
+            }
+            // A splash does half of what a sip would, the stat potions are just wasted
+            match potion {
+                Item::Heal => object.heal(game.tuning.heal_amount / 2, game),
+                Item::Antidote => {
+                    object.remove_status(StatusKind::Poisoned);
+                }
+                Item::Speed => object.add_status(StatusKind::Hasted, HASTE_TURNS / 2),
+                Item::Invisibility => object.add_status(StatusKind::Invisible, INVISIBILITY_TURNS / 2),
+                _ => {}
+            }
+        }
+        return PlayerAction::TookTurn;
+    }
+
+    if let Some(hit_id) = hit_id {
+        // Weapons hurt, everything else is just a nuisance
+        let damage = if item.item == Some(Item::Sword) {
+            item.equipment.map_or(0, |e| e.power_bonus) + THROWN_WEAPON_DAMAGE
+        } else {
+            THROWN_ITEM_DAMAGE
+        };
+        let name = item.name.clone();
+        projectile_hit(hit_id, damage, &name, game, objects);
+    }
+    item.set_pos(x, y);
+    objects.push(item);
+    PlayerAction::TookTurn
+}
+
+fn admire_idol(_inventory_id: usize, game: &mut Game, _objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
+    game.log.add_in(Category::Item, "You admire the golden idol. Someone in town would pay well for it.", colors::GOLD);
+    UseResult::UsedAndKept
+}
+
+fn toggle_equipment(inventory_id: usize, game: &mut Game, _objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
+    let mut equipment = match game.inventory[inventory_id].equipment {
+        Some(equipment) => equipment,
+        None => return UseResult::Cancelled,
+    };
+    // Slip a new ring onto a free finger, or swap out the left one when both are taken
+    if equipment.slot.is_ring() && !equipment.equipped {
+        equipment.slot = free_ring_slot(&game.inventory).unwrap_or(Slot::LeftRing);
+        game.inventory[inventory_id].equipment.as_mut().unwrap().slot = equipment.slot;
+    }
+    if let Some(old_equipment) = get_equipped_in_slot(equipment.slot, &game.inventory) {
+        if !game.inventory[old_equipment].unequip(&mut game.log) {
+            return UseResult::UsedAndKept;
+        }
+    }
+
+    if equipment.equipped {
+        game.inventory[inventory_id].unequip(&mut game.log);
+    } else {
+        break_conduct(Conduct::NoEquipment, game);
+        game.inventory[inventory_id].equip(& mut game.log);
+        if let Some(kind) = game.inventory[inventory_id].item {
+            game.events.push(GameEvent::Equipped(kind));
+        }
+    }
+    UseResult::UsedAndKept
+}
+
+/// Every slot with what is worn there, and the gear to swap in
+fn show_equipment(tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    loop {
+        let worn = objects[PLAYER].get_all_equipped(game);
+        let attributes = worn.iter().fold(Attributes::default(), |sum, e| sum.plus(e.attributes));
+        let header = format!(
+            "Equipment\nPower {:+}, defence {:+}, max HP {:+}\n\
+             Strength {:+}, dexterity {:+}, intelligence {:+}, perception {:+}\n",
+            worn.iter().map(|e| e.power_bonus).sum::<i32>(),
+            worn.iter().map(|e| e.defence_bonus).sum::<i32>(),
+            worn.iter().map(|e| e.max_hp_bonus).sum::<i32>(),
+            attributes.strength, attributes.dexterity, attributes.intelligence, attributes.perception);
+        let options = Slot::ALL
+            .iter()
+            .map(|&slot| {
+                let item = get_equipped_in_slot(slot, &game.inventory)
+                    .map_or("-".to_string(), |id| game.inventory[id].display_name());
+                format!("{}: {}", slot, item)
+            })
+            .collect::<Vec<_>>();
+        match menu(&header, &options, INVENTORY_WIDTH, &mut tcod.root) {
+            Some(choice) => change_slot(Slot::ALL[choice], tcod, game),
+            None => break,
+        }
+    }
+}
+
+/// Take off what is worn in the slot, or put something else on in its place
+fn change_slot(slot: Slot, tcod: &mut Tcod, game: &mut Game) {
+    let current = get_equipped_in_slot(slot, &game.inventory);
+    let candidates = game.inventory
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| item.equipment.is_some_and(|e| {
+            !e.equipped && (e.slot == slot || e.slot.is_ring() && slot.is_ring())
+        }))
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    let mut options = candidates
+        .iter()
+        .map(|&id| format!("Wear {}", game.inventory[id].display_name()))
+        .collect::<Vec<_>>();
+    if let Some(current) = current {
+        options.insert(0, format!("Take off {}", game.inventory[current].display_name()));
+    }
+    if options.is_empty() {
+        msgbox(&format!("You have nothing to wear on your {}.", slot), INVENTORY_WIDTH, &mut tcod.root);
+        return;
+    }
+    let choice = match menu(&format!("{}\n", slot), &options, INVENTORY_WIDTH, &mut tcod.root) {
+        Some(choice) => choice,
+        None => return,
+    };
+    if let Some(current) = current {
+        if !game.inventory[current].unequip(&mut game.log) || choice == 0 {
+            return;
+        }
+    }
+    let new_id = candidates[choice - current.map_or(0, |_| 1)];
+    // A ring goes on the finger that was picked
+    game.inventory[new_id].equipment.as_mut().unwrap().slot = slot;
+    break_conduct(Conduct::NoEquipment, game);
+    game.inventory[new_id].equip(&mut game.log);
+}
+
+fn assign_hotkey(inventory_id: usize, tcod: &mut Tcod, game: &mut Game) {
+    let (kind, name, char) = {
+        let item = &game.inventory[inventory_id];
+        match item.item {
+            Some(kind) => (kind, item.name.clone(), item.char),
+            None => return,
+        }
+    };
+    let header = format!("Press 1-9 to put the {} on that key, any other to cancel.", name);
+    let (key, _) = menu_key(&header, &[] as &[&str], INVENTORY_WIDTH, &mut tcod.root);
+    let slot = match key.printable.to_digit(10) {
+        Some(digit) if digit > 0 => digit as usize - 1,
+        _ => return,
+    };
+    // One key per kind of item
+    for hotkey in game.hotkeys.iter_mut() {
+        if hotkey.is_some_and(|hotkey| hotkey.item == kind) {
+            *hotkey = None;
+        }
+    }
+    game.hotkeys[slot] = Some(Hotkey { item: kind, char });
+    game.log.add(format!("The {} is on key {} now.", name, slot + 1), colors::WHITE);
+}
+
+fn use_hotkey(slot: usize, tcod: &mut Tcod, game: &mut Game, objects: &mut [Object]) {
+    let hotkey = match game.hotkeys[slot] {
+        Some(hotkey) => hotkey,
+        None => {
+            game.log.add(format!("Nothing is on key {}, put an item there from the inventory.", slot + 1),
+                         colors::WHITE);
+            return;
+        }
+    };
+    match game.inventory.iter().position(|item| item.item == Some(hotkey.item)) {
+        Some(inventory_id) => use_item(inventory_id, game, objects, tcod),
+        None => game.log.add_in(Category::Item, format!("You have nothing for key {} left.", slot + 1), colors::RED),
+    }
+}
+
+/// The worn item that putting this one on would replace
+fn replaced_by(equipment: &Equipment, inventory: &[Object]) -> Option<usize> {
+    if equipment.slot.is_ring() {
+        free_ring_slot(inventory).map_or(get_equipped_in_slot(Slot::LeftRing, inventory), |_| None)
+    } else {
+        get_equipped_in_slot(equipment.slot, inventory)
+    }
+}
+
+/// "sword +3 power vs. dagger +2 power" for gear that isn't worn
+fn compare_equipment(item: &Object, inventory: &[Object]) -> Option<String> {
+    let equipment = item.equipment.filter(|equipment| !equipment.equipped)?;
+    let mine = format!("{} {}", item.name, equipment.bonuses());
+    Some(match replaced_by(&equipment, inventory) {
+        Some(worn_id) => {
+            let worn = &inventory[worn_id];
+            format!("{} vs. {} {}", mine, worn.name, worn.equipment.unwrap().bonuses())
+        }
+        None => format!("{} vs. nothing on your {}", mine, equipment.slot),
+    })
+}
+
+fn is_potion(item: Item) -> bool {
+    use Item::*;
+    matches!(item, Heal | Strength | Toughness | Agility | Intellect | Antidote | Speed | Invisibility)
+}
+
+fn is_scroll(item: Item) -> bool {
+    use Item::*;
+    matches!(item, Lightning | Confuse | Fireball | StinkingCloud | RemoveCurse | EnchantWeapon | EnchantArmor | Teleport |
+             Blink)
+}
+
+fn use_item(
+    inventory_id: usize,
+    game: &mut Game,
+    objects: &mut [Object],
+    tcod: &mut Tcod,
+) {
+    use Item::*;
+    if let Some(item) = game.inventory[inventory_id].item {
+        let on_use = match item {
+            Heal => cast_heal,
+            Lightning => cast_lightning,
+            Confuse => cast_confuse,
+            Fireball => cast_fireball,
+            StinkingCloud => cast_stinking_cloud,
+            Sword => toggle_equipment,
+            Shield => toggle_equipment,
+            Helmet => toggle_equipment,
+            Idol => admire_idol,
+            RemoveCurse => cast_remove_curse,
+            EnchantWeapon => cast_enchant_weapon,
+            EnchantArmor => cast_enchant_armor,
+            RepairKit => use_repair_kit,
+            Bow => toggle_equipment,
+            Armor => toggle_equipment,
+            Boots => toggle_equipment,
+            Gloves => toggle_equipment,
+            Ring => toggle_equipment,
+            RingOfRegeneration => toggle_equipment,
+            RingOfSeeing => toggle_equipment,
+            RingOfSustenance => toggle_equipment,
+            RingOfTeleportation => toggle_equipment,
+            Torch => toggle_equipment,
+            Pickaxe => use_pickaxe,
+            WandOfDigging => zap_digging,
+            Bag | BagOfHolding => open_bag,
+            Arrow => nock_arrow,
+            Teleport => cast_teleport,
+            Blink => cast_blink,
+            Strength | Toughness | Agility | Intellect => drink_stat_potion,
+            Corpse => eat_corpse,
+            Antidote => cast_cure_poison,
+            Speed => drink_speed,
+            Invisibility => drink_invisibility,
+        };
+        match on_use(inventory_id, game, objects, tcod) {
+            UseResult::UsedUp => {
+                if is_scroll(item) {
+                    break_conduct(Conduct::NoScrolls, game);
+                    game.events.push(GameEvent::Sound(Sound::Spell));
+                }
+                game.stats.items_used += 1;
+                game.events.push(GameEvent::UsedItem(item));
+                // destroy after use
+                take_from_inventory(inventory_id, 1, game);
+            }
+            UseResult::Cancelled => {
+                game.log.add_in(Category::Item, "Cancelled", colors::WHITE);
+            }
+            UseResult::UsedAndKept => {
+                game.events.push(GameEvent::UsedItem(item));
+            }
+        }
+    } else {
+        game.log.add_in(Category::Item,
+            format!("The {} cannot be used.", game.inventory[inventory_id].name),
+            colors::WHITE);
+    }
+}
+
+/// The bag is taken out of the inventory while it's open, so it can't go inside itself
+fn open_bag(
+    inventory_id: usize,
+    game: &mut Game,
+    objects: &mut [Object],
+    tcod: &mut Tcod,
+) -> UseResult {
+    let mut bag = game.inventory.remove(inventory_id);
+    bag_menu(&mut bag, 0, tcod, game, objects);
+    game.inventory.insert(inventory_id, bag);
+    UseResult::UsedAndKept
+}
+
+/// Take things out of the bag or put them in, the bags inside open the same way.
+/// The bags it's in are open as well, and weigh `held` without it
+fn bag_menu(bag: &mut Object, held: i32, tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    let capacity = if bag.item == Some(Item::BagOfHolding) { BAG_OF_HOLDING_CAPACITY } else { BAG_CAPACITY };
+    loop {
+        render_all(tcod, objects, game, false);
+        let items = &bag.container.as_ref().unwrap().items;
+        let bags = (0..items.len()).filter(|&id| items[id].container.is_some()).collect::<Vec<_>>();
+        let mut options = items
+            .iter()
+            .map(|item| format!("Take out {}", item.display_name()))
+            .collect::<Vec<_>>();
+        options.extend(bags.iter().map(|&id| format!("Open {}", items[id].name)));
+        options.push("Put an item inside".into());
+        let items_count = items.len();
+
+        let choice = menu(&format!("The {} holds:\n", bag.name), &options, INVENTORY_WIDTH, &mut tcod.root);
+        match choice {
+            Some(index) if index < items_count => {
+                let item = bag.container.as_mut().unwrap().items.remove(index);
+                // A bag of holding made it weigh next to nothing until now
+                let held = held + item_weight(bag);
+                if !can_carry_besides(&item, held, &objects[PLAYER], game) {
+                    bag.container.as_mut().unwrap().items.insert(index, item);
+                    continue;
+                }
+                game.log.add_in(Category::Item, format!("You take the {} out of the {}.", item.name, bag.name),
+                                colors::YELLOW);
+                store_in_inventory(item, game);
+            }
+            Some(index) if index < items_count + bags.len() => {
+                let id = bags[index - items_count];
+                let mut inner = bag.container.as_mut().unwrap().items.remove(id);
+                bag_menu(&mut inner, held + item_weight(bag), tcod, game, objects);
+                bag.container.as_mut().unwrap().items.insert(id, inner);
+            }
+            Some(_) => {
+                if items_count >= capacity {
+                    game.log.add_in(Category::Item, format!("The {} is full.", bag.name), colors::RED);
+                    continue;
+                }
+                let inventory_index = inventory_menu(
+                    &game.inventory,
+                    "Select an item to put inside\n",
+                    &mut tcod.root);
+                if let Some(inventory_index) = inventory_index {
+                    let item = &mut game.inventory[inventory_index];
+                    if item.equipment.is_some() && !item.unequip(&mut game.log) {
+                        continue;
+                    }
+                    let item = game.inventory.remove(inventory_index);
+                    game.log.add_in(Category::Item, format!("You put the {} in the {}.", item.name, bag.name),
+                                    colors::YELLOW);
+                    bag.container.as_mut().unwrap().items.push(item);
+                }
+            }
+            None => return,
+        }
+    }
+}
+
+fn drop_item(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) {
+    let item = &mut game.inventory[inventory_id];
+    if item.equipment.is_some() && !item.unequip(&mut game.log) {
+        return;
+    }
+    let count = if item.count > 1 {
+        let header = format!("Drop how many? (1-{}, Enter for all)", item.count);
+        render_all(tcod, objects, game, false);
+        match ask_quantity(&header, game.inventory[inventory_id].count, &mut tcod.root) {
+            Some(count) => count,
+            None => return,
+        }
+    } else {
+        1
+    };
+    let mut item = take_from_inventory(inventory_id, count, game);
+    item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
+    game.log.add_in(Category::Item, format!("You dropped a {}.", item.display_name()), colors::YELLOW);
+    objects.push(item);
+}
+
+///////////////////////////////// UI Work
+const SCREEN_WIDTH: i32 = 80;
+const SCREEN_HEIGHT: i32 = 50;
+const LEVEL_SCREEN_WIDTH: i32 = 50;
+const BAR_WIDTH: i32 = 20;
+// How bright the remembered objects are drawn
+const REMEMBERED_ITEM_BRIGHTNESS: f32 = 0.5;
+const REMEMBERED_MONSTER_BRIGHTNESS: f32 = 0.25;
+// Over the badly wounded when the critical states are marked
+const LOW_HEALTH_MARKER: char = ';';
+const PANEL_HEIGHT: i32 = 7;
+const COMPACT_PANEL_HEIGHT: i32 = 3;
+const MSG_X: i32 = BAR_WIDTH + 2;
+const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
+const INVENTORY_WIDTH: i32 = 50;
+const CHARACTER_SCREEN_WIDTH: i32 = 40;
+const BOSS_BAR_WIDTH: i32 = 40;
+const BANNER_WIDTH: i32 = 40;
+const END_SCREEN_WIDTH: i32 = 36;
+const QUESTS_SCREEN_WIDTH: i32 = 50;
+const STATS_SCREEN_WIDTH: i32 = 40;
+const HELP_SCREEN_WIDTH: i32 = 60;
+const PROJECTILE_DELAY: u64 = 25;
+const DIALOGUE_WIDTH: i32 = 50;
+
+const LIMIT_FPS: i32 = 60;
+// Waited out between the checks for input while nothing changes on the screen
+const IDLE_FRAME: Duration = Duration::from_millis(16);
+const FORCED_REDRAW: Duration = Duration::from_secs(1);
+// Options of a menu with a letter each, the rest go on the next pages
+const MENU_PAGE_SIZE: usize = 26;
+
+struct Tcod {
+    root: Root,
+    con: Offscreen,
+    panel: Offscreen,
+    fov: Vision,
+    mouse: Mouse,
+    layout: Layout,
+    settings: Settings,
+    bindings: KeyBindings,
+    music: Music,
+    palettes: Vec<Palette>,
+    glyphs: Glyphs,
+    spectators: Spectators,
+    save_slot: SaveSlot,
+    // From the command line, every new game starts from this seed
+    seed: Option<u64>,
+    wizard: bool,
+}
+
+const SETTINGS_FILE: &str = "settings.json";
+
+/// Player preferences, kept between the runs
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct Settings {
+    fullscreen: bool,
+    // Zero for no limit
+    fps: i32,
+    // Only picked up when the window is created
+    renderer: RendererKind,
+    // Name of the palette
+    theme: String,
+    fov: FovKind,
+    torch_radius: i32,
+    fov_light_walls: bool,
+    // In percent
+    volume: i32,
+    muted: bool,
+    music_volume: i32,
+    music_muted: bool,
+    // Reds and greens moved apart for the red-green colorblind
+    colorblind: bool,
+    // Low health and statuses get a glyph or a word besides their color
+    state_marks: bool,
+    narration: Narration,
+    spectate: Spectate,
+    // Picked in the options, the command line one goes first
+    font: Option<String>,
+    // In milliseconds, for the held movement keys
+    key_repeat_delay: i32,
+    key_repeat_interval: i32,
+    // Diagonal steps between two walls, for everyone
+    corner_cutting: bool,
+    // Message categories left out of the panel, they still go to the history
+    hidden_categories: Vec<Category>,
+    auto_pickup: Vec<AutoPickup>,
+    // The saved values of the ones the command line overrides for this time only
+    #[serde(skip)]
+    file_values: FileValues,
+}
+
+/// Written back in place of the command line overrides, until the player changes them in the game
+#[derive(Clone, Copy, Debug, Default)]
+struct FileValues {
+    fullscreen: Option<bool>,
+    renderer: Option<RendererKind>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            fullscreen: false,
+            fps: LIMIT_FPS,
+            renderer: RendererKind::Sdl,
+            theme: "Classic".to_string(),
+            fov: FovKind::Basic,
+            torch_radius: 10,
+            fov_light_walls: true,
+            volume: 70,
+            muted: false,
+            music_volume: 50,
+            music_muted: false,
+            colorblind: false,
+            state_marks: false,
+            narration: Narration::Off,
+            spectate: Spectate::Off,
+            font: None,
+            key_repeat_delay: 250,
+            key_repeat_interval: 60,
+            corner_cutting: true,
+            hidden_categories: vec![],
+            auto_pickup: vec![],
+            file_values: FileValues::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn load() -> Self {
+        let mut text = String::new();
+        File::open(SETTINGS_FILE)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .ok()
+            .and_then(|_| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    /// What the field of view is worked out with, the view is stale once any of it changes
+    pub fn vision(&self) -> (FovKind, i32, bool) {
+        (self.fov, self.torch_radius, self.fov_light_walls)
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let mut stored = serde_json::to_value(self)?;
+        if let Some(fullscreen) = self.file_values.fullscreen {
+            stored["fullscreen"] = fullscreen.into();
+        }
+        if let Some(renderer) = self.file_values.renderer {
+            stored["renderer"] = serde_json::to_value(renderer)?;
+        }
+        let mut file = File::create(SETTINGS_FILE)?;
+        file.write_all(stored.to_string().as_bytes())?;
+        Ok(())
+    }
+
+    pub fn shows(&self, category: Category) -> bool {
+        !self.hidden_categories.contains(&category)
+    }
+
+    pub fn picks_up(&self, item: Item) -> bool {
+        self.auto_pickup.iter().any(|rule| rule.covers(item))
+    }
+}
+
+const FPS_CHOICES: [i32; 4] = [30, 60, 120, 0];
+const VOLUME_STEP: i32 = 10;
+
+/// The tcod field of view algorithms
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum FovKind {
+    Basic,
+    Diamond,
+    Shadow,
+    Permissive,
+    Restrictive,
+}
+
+impl FovKind {
+    fn name(self) -> &'static str {
+        match self {
+            FovKind::Basic => "basic",
+            FovKind::Diamond => "diamond",
+            FovKind::Shadow => "shadow casting",
+            FovKind::Permissive => "permissive",
+            FovKind::Restrictive => "restrictive",
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            FovKind::Basic => FovKind::Diamond,
+            FovKind::Diamond => FovKind::Shadow,
+            FovKind::Shadow => FovKind::Permissive,
+            FovKind::Permissive => FovKind::Restrictive,
+            FovKind::Restrictive => FovKind::Basic,
+        }
+    }
+
+    fn algorithm(self) -> FovAlgorithm {
+        match self {
+            FovKind::Basic => FovAlgorithm::Basic,
+            FovKind::Diamond => FovAlgorithm::Diamond,
+            FovKind::Shadow => FovAlgorithm::Shadow,
+            // The middle of the eight permissiveness levels
+            FovKind::Permissive => FovAlgorithm::Permissive4,
+            FovKind::Restrictive => FovAlgorithm::Restrictive,
+        }
+    }
+}
+
+fn compute_fov(fov: &mut Vision, x: i32, y: i32, settings: &Settings) {
+    fov.los.compute_fov(x, y, settings.torch_radius, settings.fov_light_walls, settings.fov.algorithm());
+}
+
+const BRAZIER_LIGHT: i32 = 6;
+const FIRE_LIGHT: i32 = 3;
+const TORCH_LIGHT: i32 = 6;
+// How far the player makes things out in the dark with no torch
+const DARK_SIGHT: i32 = 1;
+
+/// What the player can see: the line of sight, cut down to the lit tiles on the dark levels
+struct Vision {
+    los: FovMap,
+    // Light of every tile from 0 to 1, None while the whole level is lit
+    light: Option<Vec<Vec<f32>>>,
+    // The tiles each light source reaches, by its place and radius. Kept until it moves or the walls change
+    lit_areas: HashMap<((i32, i32), i32), LitArea>,
+}
+
+/// The tiles a light source reaches, with how bright they get
+type LitArea = Vec<((i32, i32), f32)>;
+
+impl Vision {
+    pub fn new() -> Self {
+        Vision { los: FovMap::new(MAP_WIDTH, MAP_HEIGHT), light: None, lit_areas: HashMap::new() }
+    }
+
+    pub fn set(&mut self, x: i32, y: i32, transparent: bool, walkable: bool) {
+        self.los.set(x, y, transparent, walkable);
+        self.lit_areas.clear();
+    }
+
+    pub fn is_in_fov(&self, x: i32, y: i32) -> bool {
+        self.los.is_in_fov(x, y) && self.light_at(x, y) > 0.0
+    }
+
+    /// Seen from the player's place if there was light, what the monsters go by
+    pub fn in_line_of_sight(&self, x: i32, y: i32) -> bool {
+        self.los.is_in_fov(x, y)
+    }
+
+    pub fn light_at(&self, x: i32, y: i32) -> f32 {
+        self.light.as_ref().map_or(1.0, |light| light[x as usize][y as usize])
+    }
+}
+
+/// Light radius of the object, if it gives off any
+fn light_radius(id: usize, object: &Object, game: &Game) -> Option<i32> {
+    if id == PLAYER {
+        Some(if wears_effect(PassiveEffect::Light, game) { TORCH_LIGHT } else { DARK_SIGHT })
+    } else {
+        object.light
+    }
+}
+
+/// Light the dark level from the braziers, fires and the player, fading with the distance.
+/// Returns true when it had to clobber the line of sight, compute it again then
+fn light_up(vision: &mut Vision, game: &Game, objects: &[Object]) -> bool {
+    let mut sources: Vec<((i32, i32), i32)> = objects
+        .iter()
+        .enumerate()
+        .filter_map(|(id, object)| light_radius(id, object, game).map(|radius| (object.pos(), radius)))
+        .collect();
+    for (x, y) in game.map.positions() {
+        if game.map[(x, y)].burning > 0 {
+            sources.push(((x, y), FIRE_LIGHT));
+        }
+    }
+    // Nothing moved, flickered out or got knocked down since the last time
+    let unchanged = vision.light.is_some() && sources.len() == vision.lit_areas.len() &&
+        sources.iter().all(|source| vision.lit_areas.contains_key(source));
+    if unchanged {
+        return false;
+    }
+    vision.lit_areas.retain(|source, _| sources.contains(source));
+    let mut clobbered = false;
+    for &((source_x, source_y), radius) in &sources {
+        if vision.lit_areas.contains_key(&((source_x, source_y), radius)) {
+            continue;
+        }
+        vision.los.compute_fov(source_x, source_y, radius, true, FovAlgorithm::Basic);
+        clobbered = true;
+        let mut area = vec![];
+        for x in cmp::max(source_x - radius, 0)..cmp::min(source_x + radius + 1, MAP_WIDTH) {
+            for y in cmp::max(source_y - radius, 0)..cmp::min(source_y + radius + 1, MAP_HEIGHT) {
+                if vision.los.is_in_fov(x, y) {
+                    let distance = (((x - source_x).pow(2) + (y - source_y).pow(2)) as f32).sqrt();
+                    area.push(((x, y), 1.0 - distance / (radius + 1) as f32));
+                }
+            }
+        }
+        vision.lit_areas.insert(((source_x, source_y), radius), area);
+    }
+    let mut light = vec![vec![0.0f32; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    for &((x, y), level) in vision.lit_areas.values().flatten() {
+        let tile = &mut light[x as usize][y as usize];
+        *tile = tile.max(level);
+    }
+    vision.light = Some(light);
+    clobbered
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ValueEnum)]
+enum RendererKind {
+    Sdl,
+    #[value(name = "opengl")]
+    OpenGl,
+    Glsl,
+}
+
+impl RendererKind {
+    fn name(self) -> &'static str {
+        match self {
+            RendererKind::Sdl => "SDL",
+            RendererKind::OpenGl => "OpenGL",
+            RendererKind::Glsl => "GLSL",
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            RendererKind::Sdl => RendererKind::OpenGl,
+            RendererKind::OpenGl => RendererKind::Glsl,
+            RendererKind::Glsl => RendererKind::Sdl,
+        }
+    }
+
+    fn renderer(self) -> Renderer {
+        match self {
+            RendererKind::Sdl => Renderer::SDL,
+            RendererKind::OpenGl => Renderer::OpenGL,
+            RendererKind::Glsl => Renderer::GLSL,
+        }
+    }
+}
+
+const PALETTES_FILE: &str = "palettes.toml";
+
+/// Named colors of the map tiles, with the objects recolored by name
+#[derive(Clone, Debug, Deserialize)]
+struct Palette {
+    name: String,
+    dark_wall: colors::Color,
+    light_wall: colors::Color,
+    dark_ground: colors::Color,
+    light_ground: colors::Color,
+    #[serde(default)]
+    objects: HashMap<String, colors::Color>,
+}
+
+impl Palette {
+    fn new(name: &str, dark_wall: colors::Color, light_wall: colors::Color,
+           dark_ground: colors::Color, light_ground: colors::Color) -> Self {
+        Palette { name: name.to_string(), dark_wall, light_wall, dark_ground, light_ground, objects: HashMap::new() }
+    }
+
+    pub fn tile_color(&self, visible: bool, wall: bool) -> colors::Color {
+        match (visible, wall) {
+            // Outside of FOV
+            (false, true) => self.dark_wall,
+            (false, false) => self.dark_ground,
+            // Inside the FOV
+            (true, false) => self.light_ground,
+            (true, true) => self.light_wall,
+        }
+    }
+
+    pub fn object_color(&self, object: &Object) -> colors::Color {
+        self.objects.get(&object.name).cloned().unwrap_or(object.color)
+    }
+}
+
+#[derive(Deserialize)]
+struct PaletteFile {
+    #[serde(default)]
+    palette: Vec<Palette>,
+}
+
+fn builtin_palettes() -> Vec<Palette> {
+    let rgb = |r, g, b| colors::Color { r, g, b };
+    vec![
+        Palette::new("Classic", rgb(20, 20, 20), rgb(80, 80, 50), rgb(80, 50, 50), rgb(130, 130, 80)),
+        Palette::new("High contrast",
+                     colors::DARKER_GREY, colors::LIGHTER_GREY, colors::DARKEST_GREY, colors::DARK_GREY),
+        Palette::new("Sepia", rgb(30, 22, 12), rgb(112, 86, 52), rgb(58, 44, 28), rgb(160, 130, 90)),
+    ]
+}
+
+/// The built-in palettes, then the ones of the palettes file that replace or add to them
+fn load_palettes() -> Vec<Palette> {
+    let mut palettes = builtin_palettes();
+    match read_palette_file() {
+        Ok(file) => {
+            for palette in file.palette {
+                palettes.retain(|other| other.name != palette.name);
+                palettes.push(palette);
+            }
+        }
+        Err(error) => warn!("Cannot load {}: {}", PALETTES_FILE, error),
+    }
+    palettes
+}
+
+fn read_palette_file() -> Result<PaletteFile, Box<dyn Error>> {
+    let mut text = String::new();
+    let mut file = File::open(PALETTES_FILE)?;
+    file.read_to_string(&mut text)?;
+    let result = toml::from_str::<PaletteFile>(&text)?;
+    Ok(result)
+}
+
+/// Reds turn to vermilion and greens to bluish green, so they don't look alike
+fn safe_color(color: colors::Color, colorblind: bool) -> colors::Color {
+    if !colorblind {
+        return color;
+    }
+    let (hue, saturation, value) = color.hsv();
+    if saturation < 0.2 {
+        color
+    } else if !(20.0..330.0).contains(&hue) {
+        colors::Color::new_from_hsv(25.0, saturation, value)
+    } else if (75.0..165.0).contains(&hue) {
+        colors::Color::new_from_hsv(170.0, saturation, value)
+    } else {
+        color
+    }
+}
+
+/// The palette picked in the options, or the first one if it's gone
+fn current_palette<'a>(palettes: &'a [Palette], name: &str) -> &'a Palette {
+    palettes.iter().find(|palette| palette.name == name).unwrap_or(&palettes[0])
+}
+
+const GLYPHS_FILE: &str = "glyphs.toml";
+
+/// How an object is drawn, the glyph and the CP437 index are only used with a CP437 font
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default)]
+struct GlyphSpec {
+    glyph: Option<char>,
+    // Same as the glyph, for the characters that are awkward to type
+    code_point: Option<u32>,
+    cp437: Option<u8>,
+    fallback: Option<char>,
+}
+
+impl GlyphSpec {
+    fn unicode(&self) -> Option<char> {
+        self.glyph.or_else(|| self.code_point.and_then(std::char::from_u32))
+    }
+}
+
+/// Glyphs of the objects by name, overriding the chars they are built with
+#[derive(Default)]
+struct Glyphs {
+    specs: HashMap<String, GlyphSpec>,
+    // The font is laid out as CP437, so the extended glyphs can be mapped onto it
+    cp437: bool,
+}
+
+impl Glyphs {
+    pub fn load(cp437: bool) -> Self {
+        match read_glyph_file() {
+            Ok(specs) => Glyphs { specs, cp437 },
+            Err(error) => {
+                warn!("Cannot load {}: {}", GLYPHS_FILE, error);
+                Glyphs { specs: HashMap::new(), cp437 }
+            }
+        }
+    }
+
+    /// Point the Unicode glyphs at their CP437 tiles, the chars below 256 are there already
+    pub fn map_to_font(&self, root: &mut Root) {
+        if !self.cp437 {
+            return;
+        }
+        for spec in self.specs.values() {
+            if let (Some(glyph), Some(index)) = (spec.unicode(), spec.cp437) {
+                root.map_ascii_code_to_font(glyph as i32, index as i32 % 16, index as i32 / 16);
+            }
+        }
+    }
+
+    pub fn char_for(&self, object: &Object) -> char {
+        let spec = match self.specs.get(&object.name) {
+            Some(spec) => spec,
+            None => return object.char,
+        };
+        let extended = match (spec.unicode(), spec.cp437) {
+            (Some(glyph), Some(_)) => Some(glyph),
+            (None, Some(index)) => Some(index as char),
+            (glyph, None) => glyph.filter(|glyph| glyph.is_ascii()),
+        };
+        match extended {
+            Some(glyph) if self.cp437 || glyph.is_ascii() => glyph,
+            _ => spec.fallback.unwrap_or(object.char),
+        }
+    }
+}
+
+fn read_glyph_file() -> Result<HashMap<String, GlyphSpec>, Box<dyn Error>> {
+    let mut text = String::new();
+    let mut file = File::open(GLYPHS_FILE)?;
+    file.read_to_string(&mut text)?;
+    let result = toml::from_str::<HashMap<String, GlyphSpec>>(&text)?;
+    Ok(result)
+}
+
+/// Items picked up just by walking over them
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum AutoPickup {
+    Potions,
+    Scrolls,
+    Arrows,
+}
+
+impl AutoPickup {
+    const ALL: [AutoPickup; 3] = [AutoPickup::Potions, AutoPickup::Scrolls, AutoPickup::Arrows];
+
+    fn name(self) -> &'static str {
+        match self {
+            AutoPickup::Potions => "potions",
+            AutoPickup::Scrolls => "scrolls",
+            AutoPickup::Arrows => "arrows",
+        }
+    }
+
+    fn covers(self, item: Item) -> bool {
+        match self {
+            AutoPickup::Potions => is_potion(item),
+            AutoPickup::Scrolls => is_scroll(item),
+            AutoPickup::Arrows => item == Item::Arrow,
+        }
     }
 }
 
-fn closest_monster(max_range: i32, objects: &mut [Object], tcod: &Tcod) -> Option<usize> {
-    let mut closest_enemy = None;
-    let mut closest_dist = (max_range + 1) as f32;
+fn apply_display_settings(tcod: &mut Tcod) {
+    tcod.root.set_fullscreen(tcod.settings.fullscreen);
+    tcod::system::set_fps(tcod.settings.fps);
+}
 
-    for (id, object) in objects.iter().enumerate() {
-        if id != PLAYER && object.fighter.is_some() && object.ai.is_some() &&
-            tcod.fov.is_in_fov(object.x, object.y) {
-            let dist = objects[PLAYER].distance_to(object);
-            if dist < closest_dist {
-                closest_enemy = Some(id);
-                closest_dist = dist;
+fn checkbox(on: bool) -> &'static str {
+    if on { "[x]" } else { "[ ]" }
+}
+
+/// Every option applies at once, except the renderer that needs a restart
+fn show_options(tcod: &mut Tcod) {
+    loop {
+        let settings = &tcod.settings;
+        let fps = if settings.fps == 0 { "unlimited".to_string() } else { settings.fps.to_string() };
+        let mut options = vec![
+            format!("{} Fullscreen", checkbox(settings.fullscreen)),
+            format!("Frame rate: {}", fps),
+            format!("Renderer: {} (after a restart)", settings.renderer.name()),
+            format!("Color theme: {}", current_palette(&tcod.palettes, &settings.theme).name),
+            format!("Sound volume: {}%", settings.volume),
+            format!("{} Mute sound", checkbox(settings.muted)),
+            format!("Music volume: {}%", settings.music_volume),
+            format!("{} Mute music", checkbox(settings.music_muted)),
+            format!("{} Colorblind colors", checkbox(settings.colorblind)),
+            format!("{} Mark critical states", checkbox(settings.state_marks)),
+            format!("Text output: {}", settings.narration.name()),
+            format!("Font: {}", settings.font.as_deref().unwrap_or(DEFAULT_FONT)),
+            format!("Field of view: {}", settings.fov.name()),
+            format!("Torch radius: {}", if settings.torch_radius == 0 {
+                "unlimited".to_string()
+            } else {
+                settings.torch_radius.to_string()
+            }),
+            format!("{} Light up the walls", checkbox(settings.fov_light_walls)),
+            format!("Key repeat delay: {} ms", settings.key_repeat_delay),
+            format!("Key repeat rate: {}", if settings.key_repeat_interval == 0 {
+                "off".to_string()
+            } else {
+                format!("every {} ms", settings.key_repeat_interval)
+            }),
+            format!("{} Diagonal moves between walls", checkbox(settings.corner_cutting)),
+            format!("Spectator output: {}", settings.spectate.name()),
+        ];
+        let fixed = options.len();
+        options.extend(Category::ALL.iter().map(|&category| {
+            format!("{} Show {} messages", checkbox(settings.shows(category)), category.name().to_lowercase())
+        }));
+        options.extend(AutoPickup::ALL.iter().map(|&rule| {
+            format!("{} Pick up {}", checkbox(settings.auto_pickup.contains(&rule)), rule.name())
+        }));
+        let choice = match menu("Options\n", &options, INVENTORY_WIDTH, &mut tcod.root) {
+            Some(choice) => choice,
+            None => break,
+        };
+        let settings = &mut tcod.settings;
+        match choice {
+            0 => {
+                settings.fullscreen = !settings.fullscreen;
+                settings.file_values.fullscreen = None;
+            }
+            1 => {
+                let next = FPS_CHOICES.iter().position(|&fps| fps == settings.fps).map_or(0, |i| i + 1);
+                settings.fps = FPS_CHOICES[next % FPS_CHOICES.len()];
+            }
+            2 => {
+                settings.renderer = settings.renderer.next();
+                settings.file_values.renderer = None;
+            }
+            3 => {
+                let palettes = &tcod.palettes;
+                let current = palettes.iter().position(|palette| palette.name == settings.theme).unwrap_or(0);
+                settings.theme = palettes[(current + 1) % palettes.len()].name.clone();
+            }
+            4 => settings.volume = (settings.volume + VOLUME_STEP) % (100 + VOLUME_STEP),
+            5 => settings.muted = !settings.muted,
+            6 => settings.music_volume = (settings.music_volume + VOLUME_STEP) % (100 + VOLUME_STEP),
+            7 => settings.music_muted = !settings.music_muted,
+            8 => settings.colorblind = !settings.colorblind,
+            9 => settings.state_marks = !settings.state_marks,
+            10 => settings.narration = settings.narration.next(),
+            11 => choose_font(tcod),
+            12 => settings.fov = settings.fov.next(),
+            13 => {
+                let radii = Tuning::load().torch_radii;
+                let next = radii.iter().position(|&r| r == settings.torch_radius).map_or(0, |i| i + 1);
+                settings.torch_radius = radii[next % radii.len()];
+            }
+            14 => settings.fov_light_walls = !settings.fov_light_walls,
+            15 => {
+                let next = KEY_REPEAT_DELAYS.iter().position(|&d| d == settings.key_repeat_delay).map_or(0, |i| i + 1);
+                settings.key_repeat_delay = KEY_REPEAT_DELAYS[next % KEY_REPEAT_DELAYS.len()];
+            }
+            16 => {
+                let next = KEY_REPEAT_INTERVALS
+                    .iter()
+                    .position(|&i| i == settings.key_repeat_interval)
+                    .map_or(0, |i| i + 1);
+                settings.key_repeat_interval = KEY_REPEAT_INTERVALS[next % KEY_REPEAT_INTERVALS.len()];
+            }
+            17 => settings.corner_cutting = !settings.corner_cutting,
+            18 => settings.spectate = settings.spectate.next(),
+            _ if choice < fixed + Category::ALL.len() => {
+                let category = Category::ALL[choice - fixed];
+                if settings.shows(category) {
+                    settings.hidden_categories.push(category);
+                } else {
+                    settings.hidden_categories.retain(|&c| c != category);
+                }
+            }
+            _ => {
+                let rule = AutoPickup::ALL[choice - fixed - Category::ALL.len()];
+                if settings.auto_pickup.contains(&rule) {
+                    settings.auto_pickup.retain(|&r| r != rule);
+                } else {
+                    settings.auto_pickup.push(rule);
+                }
             }
         }
+        apply_display_settings(tcod);
+    }
+    if let Err(error) = tcod.settings.save() {
+        warn!("Cannot write {}: {}", SETTINGS_FILE, error);
     }
-    closest_enemy
 }
 
-enum UseResult {
-    UsedUp,
-    Cancelled,
-    UsedAndKept,
+/// Opened with Escape during the game
+fn game_menu(tcod: &mut Tcod) -> PlayerAction {
+    loop {
+        let choices = &["Continue", "Options", "Help", "Save and quit", "Abandon run"];
+        let choice = menu("Paused\n", choices, 24, &mut tcod.root);
+        match choice {
+            Some(1) => show_options(tcod),
+            Some(2) => show_help(tcod),
+            Some(3) => return PlayerAction::Exit,
+            Some(4) => {
+                let header = "Give up on this run? It ends here like any other death.\n";
+                if menu(header, &["Keep playing", "Abandon the run"], 30, &mut tcod.root) == Some(1) {
+                    return PlayerAction::Abandon;
+                }
+            }
+            _ => return PlayerAction::DidntTakeTurn,
+        }
+    }
 }
 
-fn cast_heal(
-    _inventory_id: usize,
-    game: &mut Game,
-    objects: &mut [Object],
-    _tcod: &mut Tcod
-) -> UseResult {
-    let player = &mut objects[PLAYER];
-    if let Some(fighter) = player.fighter {
-        if fighter.hp == objects[PLAYER].max_hp(game) {
-            game.log.add("You are already at full health.", colors::RED);
-            return UseResult::Cancelled;
+const KEYBINDINGS_FILE: &str = "keybindings.toml";
+
+/// Everything the player can do with a key, named as in the bindings file
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    MoveNorth,
+    MoveSouth,
+    MoveWest,
+    MoveEast,
+    MoveNorthWest,
+    MoveNorthEast,
+    MoveSouthWest,
+    MoveSouthEast,
+    Wait,
+    PickUp,
+    Fire,
+    Throw,
+    Inventory,
+    Equipment,
+    Drop,
+    Character,
+    Descend,
+    Ascend,
+    Quests,
+    Travel,
+    Rest,
+    Disarm,
+    Interact,
+    MarkTile,
+    MessageLog,
+    Settings,
+    Stats,
+    Bestiary,
+    Help,
+    Fullscreen,
+    Snapshot,
+    GameMenu,
+}
+
+impl Action {
+    const ALL: [Action; 32] = [
+        Action::MoveNorth, Action::MoveSouth, Action::MoveWest, Action::MoveEast,
+        Action::MoveNorthWest, Action::MoveNorthEast, Action::MoveSouthWest, Action::MoveSouthEast,
+        Action::Wait, Action::PickUp, Action::Fire, Action::Throw, Action::Inventory, Action::Equipment, Action::Drop,
+        Action::Character, Action::Descend, Action::Ascend, Action::Quests, Action::Travel, Action::Rest,
+        Action::Disarm, Action::Interact, Action::MarkTile, Action::MessageLog, Action::Settings, Action::Stats,
+        Action::Bestiary, Action::Help,
+        Action::Fullscreen, Action::Snapshot, Action::GameMenu,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Action::MoveNorth => "move_north",
+            Action::MoveSouth => "move_south",
+            Action::MoveWest => "move_west",
+            Action::MoveEast => "move_east",
+            Action::MoveNorthWest => "move_north_west",
+            Action::MoveNorthEast => "move_north_east",
+            Action::MoveSouthWest => "move_south_west",
+            Action::MoveSouthEast => "move_south_east",
+            Action::Wait => "wait",
+            Action::PickUp => "pick_up",
+            Action::Fire => "fire",
+            Action::Throw => "throw",
+            Action::Inventory => "inventory",
+            Action::Equipment => "equipment",
+            Action::Drop => "drop",
+            Action::Character => "character",
+            Action::Descend => "descend",
+            Action::Ascend => "ascend",
+            Action::Quests => "quests",
+            Action::Travel => "travel",
+            Action::Rest => "rest",
+            Action::Disarm => "disarm",
+            Action::Interact => "interact",
+            Action::MarkTile => "mark_tile",
+            Action::MessageLog => "message_log",
+            Action::Settings => "settings",
+            Action::Stats => "stats",
+            Action::Bestiary => "bestiary",
+            Action::Help => "help",
+            Action::Fullscreen => "fullscreen",
+            Action::Snapshot => "snapshot",
+            Action::GameMenu => "game_menu",
         }
-        game.log.add("Your wounds are healing!", colors::LIGHT_VIOLET);
-        objects[PLAYER].heal(HEAL_AMOUNT, &game);
-        return UseResult::UsedUp;
     }
-    UseResult::Cancelled
+
+    fn default_keys(self) -> &'static [&'static str] {
+        match self {
+            Action::MoveNorth => &["Up", "NumPad8", "k"],
+            Action::MoveSouth => &["Down", "NumPad2", "j"],
+            Action::MoveWest => &["Left", "NumPad4", "h"],
+            Action::MoveEast => &["Right", "NumPad6", "l"],
+            Action::MoveNorthWest => &["Home", "NumPad7", "y"],
+            Action::MoveNorthEast => &["PageUp", "NumPad9", "u"],
+            Action::MoveSouthWest => &["End", "NumPad1", "b"],
+            Action::MoveSouthEast => &["PageDown", "NumPad3", "n"],
+            Action::Wait => &["NumPad5", "Space", "."],
+            Action::PickUp => &["g"],
+            Action::Fire => &["f"],
+            Action::Throw => &["t"],
+            Action::Inventory => &["i"],
+            Action::Equipment => &["e"],
+            Action::Drop => &["d"],
+            Action::Character => &["c"],
+            Action::Descend => &["<"],
+            Action::Ascend => &[">"],
+            Action::Quests => &["q"],
+            Action::Travel => &["_"],
+            Action::Rest => &["R"],
+            Action::Disarm => &["D"],
+            Action::Interact => &["a"],
+            Action::MarkTile => &["m"],
+            Action::MessageLog => &["Ctrl+p"],
+            Action::Settings => &["o"],
+            Action::Stats => &["s"],
+            Action::Bestiary => &["Ctrl+b"],
+            Action::Help => &["?"],
+            Action::Fullscreen => &["Alt+Enter"],
+            Action::Snapshot => &["F12"],
+            Action::GameMenu => &["Escape"],
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Action::MoveNorth => "Move or attack north",
+            Action::MoveSouth => "Move or attack south",
+            Action::MoveWest => "Move or attack west",
+            Action::MoveEast => "Move or attack east",
+            Action::MoveNorthWest => "Move or attack north-west",
+            Action::MoveNorthEast => "Move or attack north-east",
+            Action::MoveSouthWest => "Move or attack south-west",
+            Action::MoveSouthEast => "Move or attack south-east",
+            Action::Wait => "Wait a turn",
+            Action::PickUp => "Pick up, read a note or open a chest",
+            Action::Fire => "Shoot the bow",
+            Action::Throw => "Throw an item",
+            Action::Inventory => "Use an item",
+            Action::Equipment => "Worn equipment",
+            Action::Drop => "Drop an item",
+            Action::Character => "Character sheet",
+            Action::Descend => "Go down, or travel to the stairs",
+            Action::Ascend => "Go up, or travel to the stairs",
+            Action::Quests => "Journal of quests and notes",
+            Action::Travel => "Travel somewhere",
+            Action::Rest => "Rest until healed",
+            Action::Disarm => "Disarm a trap nearby",
+            Action::Interact => "Use the chest or altar here",
+            Action::MarkTile => "Mark a tile to travel to",
+            Action::MessageLog => "Message history",
+            Action::Settings => "Options",
+            Action::Stats => "Statistics",
+            Action::Bestiary => "Monsters met in all the runs",
+            Action::Help => "This help",
+            Action::Fullscreen => "Toggle fullscreen",
+            Action::Snapshot => "Save the map as an image or text",
+            Action::GameMenu => "Pause menu, save and quit",
+        }
+    }
+
+    fn group(self) -> &'static str {
+        use Action::*;
+        match self {
+            MoveNorth | MoveSouth | MoveWest | MoveEast | MoveNorthWest | MoveNorthEast | MoveSouthWest
+            | MoveSouthEast | Wait | Descend | Ascend | Travel | Rest | MarkTile => "Movement",
+            PickUp | Fire | Throw | Inventory | Equipment | Drop | Disarm | Interact => "Items",
+            Character | Quests | MessageLog | Stats | Bestiary | Help => "Info",
+            Settings | Fullscreen | Snapshot | GameMenu => "System",
+        }
+    }
+
+    /// Where the move actions step, Shift with their key runs that way
+    fn step(self) -> Option<(i32, i32)> {
+        match self {
+            Action::MoveNorth => Some((0, -1)),
+            Action::MoveSouth => Some((0, 1)),
+            Action::MoveWest => Some((-1, 0)),
+            Action::MoveEast => Some((1, 0)),
+            Action::MoveNorthWest => Some((-1, -1)),
+            Action::MoveNorthEast => Some((1, -1)),
+            Action::MoveSouthWest => Some((-1, 1)),
+            Action::MoveSouthEast => Some((1, 1)),
+            _ => None,
+        }
+    }
+
+    /// Held down, these keep going at the key repeat rate
+    fn repeats(self) -> bool {
+        use Action::*;
+        matches!(self, MoveNorth | MoveSouth | MoveWest | MoveEast | MoveNorthWest | MoveNorthEast
+            | MoveSouthWest | MoveSouthEast | Wait)
+    }
 }
 
-fn cast_lightning(
-    _inventory_id: usize,
-    game: &mut Game,
-    objects: &mut [Object],
-    tcod: &mut Tcod,
-) -> UseResult {
-    let monster_id = closest_monster(LIGHTNING_RANGE, objects, tcod);
-    if let Some(monster_id) = monster_id {
-        game.log.add(format!("A lightning strikes {} with a loud thunder for {} damage!",
-                             objects[monster_id].name, LIGHTNING_DAMAGE),
-                colors::LIGHT_BLUE,
-        );
-        if let Some(xp) = objects[monster_id].take_damage(LIGHTNING_DAMAGE, &mut game.log) {
-            objects[PLAYER].fighter.as_mut().unwrap().xp += xp;
+// Presses past this many, made while the turns catch up, are dropped
+const KEY_BUFFER_SIZE: usize = 8;
+const KEY_REPEAT_DELAYS: [i32; 4] = [150, 250, 400, 600];
+// Zero turns the repeat off
+const KEY_REPEAT_INTERVALS: [i32; 5] = [40, 60, 100, 150, 0];
+
+/// Key presses waiting for their turn, and the movement key held down
+#[derive(Default)]
+struct KeyQueue {
+    buffer: VecDeque<Key>,
+    held: Option<Key>,
+    next_repeat: Option<Instant>,
+}
+
+impl KeyQueue {
+    /// Take in every pending event, true if there was any. The system's own key repeat is
+    /// ignored in favor of the one from the settings
+    pub fn poll(&mut self, tcod: &mut Tcod, clicked: &mut bool) -> bool {
+        let mut any = false;
+        while let Some((_, event)) = input::check_for_event(input::MOUSE | input::KEY) {
+            any = true;
+            match event {
+                Event::Mouse(mouse) => {
+                    tcod.mouse = mouse;
+                    *clicked |= mouse.lbutton_pressed;
+                }
+                Event::Key(key) if key.pressed => {
+                    if self.held.is_some_and(|held| same_key(held, key)) {
+                        continue;
+                    }
+                    if self.buffer.len() < KEY_BUFFER_SIZE {
+                        self.buffer.push_back(key);
+                    }
+                    // A shifted move already runs on its own
+                    let repeats = !key.shift && tcod.bindings.action(key).is_some_and(Action::repeats);
+                    self.held = if repeats { Some(key) } else { None };
+                    let delay = Duration::from_millis(tcod.settings.key_repeat_delay as u64);
+                    self.next_repeat = Some(Instant::now() + delay);
+                }
+                Event::Key(key) => {
+                    if self.held.is_some_and(|held| held.code == key.code) {
+                        self.held = None;
+                    }
+                }
+            }
         }
-        UseResult::UsedUp
-    } else {
-        game.log.add("No enemy is close enough to strike.", colors::RED);
-        UseResult::Cancelled
+        any
+    }
+
+    /// The next key to handle, a repeat of the held one once the buffer is empty
+    pub fn next(&mut self, settings: &Settings) -> Key {
+        if let Some(key) = self.buffer.pop_front() {
+            return key;
+        }
+        match (self.held, self.next_repeat) {
+            (Some(key), Some(at)) if settings.key_repeat_interval > 0 && Instant::now() >= at => {
+                self.next_repeat = Some(at + Duration::from_millis(settings.key_repeat_interval as u64));
+                key
+            }
+            _ => Default::default(),
+        }
+    }
+
+    /// Forget the waiting and held keys, when something needs the player's attention
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.held = None;
     }
 }
 
-fn cast_confuse(
-    _inventory_id: usize,
-    game: &mut Game,
-    objects: &mut [Object],
-    tcod: &mut Tcod,
-) -> UseResult {
-    game.log.add(
-        "Left click an enemy to confuse it, or right click to cancel.",
-        colors::LIGHT_CYAN);
-    let monster_id = target_monster(tcod, game, objects, Some(CONFUSE_RANGE as f32));
-    if let Some(monster_id) = monster_id {
-        let old_ai = objects[monster_id].ai.take().unwrap_or(Ai::Basic);
-        objects[monster_id].ai = Some(Ai::Confused {
-            previous_ai: Box::new(old_ai),
-            num_turns: CONFUSE_NUM_TURNS,
-        });
-        game.log.add(
-            format!("{} starts stumbling around!", objects[monster_id].name),
-            colors::LIGHT_GREEN,
-        );
-        UseResult::UsedUp
+fn same_key(a: Key, b: Key) -> bool {
+    a.code == b.code && a.printable == b.printable
+}
+
+/// A key as written in the bindings file, like "k", "R", "Ctrl+p" or "NumPad8"
+#[derive(Clone, Debug)]
+struct KeyBinding {
+    name: String,
+    // Char for the printable keys
+    code: KeyCode,
+    printable: char,
+    shift: bool,
+    ctrl: bool,
+    alt: bool,
+}
+
+impl KeyBinding {
+    pub fn parse(name: &str) -> Result<KeyBinding, String> {
+        let mut binding = KeyBinding {
+            name: name.to_string(),
+            code: KeyCode::Char,
+            printable: '\0',
+            shift: false,
+            ctrl: false,
+            alt: false,
+        };
+        let mut rest = name;
+        while rest.len() > 1 {
+            if let Some(key) = rest.strip_prefix("Ctrl+") {
+                binding.ctrl = true;
+                rest = key;
+            } else if let Some(key) = rest.strip_prefix("Alt+") {
+                binding.alt = true;
+                rest = key;
+            } else if let Some(key) = rest.strip_prefix("Shift+") {
+                binding.shift = true;
+                rest = key;
+            } else {
+                break;
+            }
+        }
+        let mut chars = rest.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => {
+                let (printable, shifted) = unshifted_char(c);
+                binding.printable = printable;
+                binding.shift |= shifted;
+            }
+            _ => binding.code = key_code(rest).ok_or_else(|| format!("unknown key '{}'", name))?,
+        }
+        Ok(binding)
+    }
+
+    pub fn same_key(&self, other: &KeyBinding) -> bool {
+        self.code == other.code && self.printable == other.printable && self.shift == other.shift &&
+            self.ctrl == other.ctrl && self.alt == other.alt
+    }
+
+    pub fn matches(&self, key: Key) -> bool {
+        let modifiers = key.ctrl == self.ctrl && key.alt == self.alt;
+        if self.code == KeyCode::Char {
+            modifiers && key.printable == self.printable && key.shift == self.shift
+        } else {
+            modifiers && key.code == self.code
+        }
+    }
+}
+
+/// Tcod reports the unshifted character with the shift flag, so "R" is Shift and 'r'.
+/// Symbols follow the US layout
+fn unshifted_char(c: char) -> (char, bool) {
+    const SHIFTED: &str = "~!@#$%^&*()_+{}|:\"<>?";
+    const UNSHIFTED: &str = "`1234567890-=[]\\;',./";
+    if c.is_ascii_uppercase() {
+        (c.to_ascii_lowercase(), true)
+    } else if let Some(index) = SHIFTED.find(c) {
+        (UNSHIFTED.as_bytes()[index] as char, true)
     } else {
-        game.log.add("No enemy is close enought to strike.", colors::RED);
-        UseResult::Cancelled
+        (c, false)
     }
 }
 
-fn cast_fireball(
-    _inventory_id: usize,
-    game: &mut Game,
-    objects: &mut [Object],
-    tcod: &mut Tcod,
-) -> UseResult {
-    game.log.add(
-        "Left click to target tile for the fireball, right click to cancel",
-        colors::LIGHT_CYAN);
-    let (x, y) = match target_tile(tcod, game, objects, None) {
-        Some(tile_pos) => tile_pos,
-        None => return UseResult::Cancelled,
+fn key_code(name: &str) -> Option<KeyCode> {
+    use tcod::input::KeyCode::*;
+    let code = match name {
+        "Up" => Up,
+        "Down" => Down,
+        "Left" => Left,
+        "Right" => Right,
+        "Home" => Home,
+        "End" => End,
+        "PageUp" => PageUp,
+        "PageDown" => PageDown,
+        "Insert" => Insert,
+        "Delete" => Delete,
+        "Enter" => Enter,
+        "Escape" => Escape,
+        "Space" => Spacebar,
+        "Tab" => Tab,
+        "Backspace" => Backspace,
+        "NumPad0" => NumPad0,
+        "NumPad1" => NumPad1,
+        "NumPad2" => NumPad2,
+        "NumPad3" => NumPad3,
+        "NumPad4" => NumPad4,
+        "NumPad5" => NumPad5,
+        "NumPad6" => NumPad6,
+        "NumPad7" => NumPad7,
+        "NumPad8" => NumPad8,
+        "NumPad9" => NumPad9,
+        "F1" => F1,
+        "F2" => F2,
+        "F3" => F3,
+        "F4" => F4,
+        "F5" => F5,
+        "F6" => F6,
+        "F7" => F7,
+        "F8" => F8,
+        "F9" => F9,
+        "F10" => F10,
+        "F11" => F11,
+        "F12" => F12,
+        _ => return None,
     };
+    Some(code)
+}
 
-    game.log.add(
-        format!("The fireball explodes, burning everything within {} tiles!", FIREBALL_RADIUS),
-        colors::ORANGE);
+// Names the actions went by in older bindings files, with what they are called now
+const RENAMED_ACTIONS: &[(&str, &str)] = &[("save_and_quit", "game_menu"), ("key_bindings", "help")];
 
-    let mut xp_to_gain = 0;
-    for (id, obj) in objects.iter_mut().enumerate() {
-        if obj.distance(x, y) <= FIREBALL_RADIUS as f32 && obj.fighter.is_some() {
-            game.log.add(
-                format!("The {} gets burned for {} hit points.", obj.name, FIREBALL_DAMAGE),
-                colors::ORANGE);
-            if let Some(xp) = obj.take_damage(FIREBALL_DAMAGE, &mut game.log) {
-                if id != PLAYER {
-                    xp_to_gain += xp;
-                }
+/// Keys of every action, the first binding matching a key wins
+struct KeyBindings {
+    bindings: Vec<(Action, KeyBinding)>,
+}
+
+impl KeyBindings {
+    /// Read the bindings file, writing the defaults on the first run
+    /// and falling back to them if it's broken
+    pub fn load() -> Self {
+        if !std::path::Path::new(KEYBINDINGS_FILE).exists() {
+            let written = File::create(KEYBINDINGS_FILE)
+                .and_then(|mut file| file.write_all(default_bindings_text().as_bytes()));
+            if let Err(error) = written {
+                warn!("Cannot write {}: {}", KEYBINDINGS_FILE, error);
+            }
+        }
+        match load_key_bindings() {
+            Ok(bindings) => bindings,
+            Err(error) => {
+                warn!("Cannot load {}: {}", KEYBINDINGS_FILE, error);
+                KeyBindings::from_table(&BTreeMap::new())
             }
         }
     }
-    objects[PLAYER].fighter.as_mut().unwrap().xp += xp_to_gain;
-    UseResult::UsedUp
+
+    /// Actions missing from the table keep their default keys, unknown actions and keys are skipped.
+    /// Shift with a move key runs, so such a key given to another action could never be used and is left out
+    fn from_table(table: &BTreeMap<String, Vec<String>>) -> KeyBindings {
+        let mut table = table.clone();
+        for &(old, new) in RENAMED_ACTIONS {
+            if let Some(keys) = table.remove(old) {
+                table.entry(new.to_string()).or_insert(keys);
+            }
+        }
+        for unknown in table.keys().filter(|name| Action::ALL.iter().all(|action| action.name() != *name)) {
+            warn!("Skipping the unknown action '{}' in {}", unknown, KEYBINDINGS_FILE);
+        }
+        let mut bindings = vec![];
+        for &action in Action::ALL.iter() {
+            match table.get(action.name()) {
+                Some(keys) => for key in keys {
+                    match KeyBinding::parse(key) {
+                        Ok(binding) => bindings.push((action, binding)),
+                        Err(error) => warn!("Skipping {} of {} in {}", error, action.name(), KEYBINDINGS_FILE),
+                    }
+                },
+                None => for key in action.default_keys() {
+                    bindings.push((action, KeyBinding::parse(key).expect("Broken default key")));
+                },
+            }
+        }
+        let runs: Vec<KeyBinding> = bindings
+            .iter()
+            .filter(|(action, _)| action.step().is_some())
+            .map(|(_, binding)| KeyBinding { shift: true, ..binding.clone() })
+            .collect();
+        bindings.retain(|(action, binding)| {
+            let clash = action.step().is_none() && runs.iter().any(|run| run.same_key(binding));
+            if clash {
+                warn!("'{}' of {} runs instead, as Shift with a move key", binding.name, action.name());
+            }
+            !clash
+        });
+        KeyBindings { bindings }
+    }
+
+    pub fn action(&self, key: Key) -> Option<Action> {
+        self.bindings.iter().find(|(_, binding)| binding.matches(key)).map(|&(action, _)| action)
+    }
+
+    pub fn keys(&self, action: Action) -> Vec<&str> {
+        self.bindings
+            .iter()
+            .filter(|&&(bound, _)| bound == action)
+            .map(|(_, binding)| binding.name.as_str())
+            .collect()
+    }
 }
 
-fn toggle_equipment(inventory_id: usize, game: &mut Game, _objects: &mut [Object], _tcod: &mut Tcod) -> UseResult {
-    let equipment = match game.inventory[inventory_id].equipment {
-        Some(equipment) => equipment,
-        None => return UseResult::Cancelled,
-    };
-    if let Some(old_equipment) = get_equipped_in_slot(equipment.slot, &game.inventory) {
-        game.inventory[old_equipment].unequip(&mut game.log);
+fn default_bindings_text() -> String {
+    let mut text = String::from("# Every action takes a list of keys: letters and symbols as typed, \"Ctrl+\", \"Alt+\"\n\
+                                 # and \"Shift+\" modifiers, arrows, \"Home\", \"PageUp\", \"NumPad0\" to \"NumPad9\", \"F1\" and so on\n");
+    for action in Action::ALL.iter() {
+        let keys = action.default_keys().iter().map(|key| format!("{:?}", key)).collect::<Vec<_>>();
+        text.push_str(&format!("{} = [{}]\n", action.name(), keys.join(", ")));
     }
+    text
+}
 
-    if equipment.equipped {
-        game.inventory[inventory_id].unequip(&mut game.log);
+fn load_key_bindings() -> Result<KeyBindings, Box<dyn Error>> {
+    let mut text = String::new();
+    let mut file = File::open(KEYBINDINGS_FILE)?;
+    file.read_to_string(&mut text)?;
+    let table = toml::from_str::<BTreeMap<String, Vec<String>>>(&text)?;
+    let result = KeyBindings::from_table(&table);
+    Ok(result)
+}
+
+/// Every command with its current keys, grouped the same way as the actions
+fn show_help(tcod: &mut Tcod) {
+    let mut text = format!("Commands, change the keys in {}\n", KEYBINDINGS_FILE);
+    for group in &["Movement", "Items", "Info", "System"] {
+        text.push_str(&format!("\n{}\n", group));
+        for &action in Action::ALL.iter().filter(|action| action.group() == *group) {
+            let keys = tcod.bindings.keys(action).join(" ");
+            text.push_str(&format!("{:<22}{}\n", keys, action.description()));
+        }
+        if *group == "Movement" {
+            text.push_str(&format!("{:<22}{}\n", "Shift+move", "Run until something comes up"));
+            text.push_str(&format!("{:<22}{}\n", "Left click", "Walk there, or attack"));
+        }
+        if *group == "Items" {
+            text.push_str(&format!("{:<22}{}\n", "1-9", "Use the item on the hotkey"));
+        }
+    }
+    msgbox(&text, HELP_SCREEN_WIDTH, &mut tcod.root);
+}
+
+/// Placement of the stats panel, picked at startup to fit the screen
+#[derive(Clone, Copy, Debug)]
+struct Layout {
+    screen_height: i32,
+    panel_height: i32,
+    compact: bool,
+}
+
+impl Layout {
+    pub fn full() -> Self {
+        Layout { screen_height: SCREEN_HEIGHT, panel_height: PANEL_HEIGHT, compact: false }
+    }
+
+    pub fn compact() -> Self {
+        Layout {
+            screen_height: MAP_HEIGHT + COMPACT_PANEL_HEIGHT,
+            panel_height: COMPACT_PANEL_HEIGHT,
+            compact: true,
+        }
+    }
+
+    pub fn panel_y(&self) -> i32 {
+        self.screen_height - self.panel_height
+    }
+
+    pub fn msg_height(&self) -> i32 {
+        self.panel_height - 1
+    }
+}
+
+const DEFAULT_FONT: &str = "courier12x12_aa_tc.png";
+// Offered in the options, the missing ones are left out
+const BUNDLED_FONTS: [&str; 4] = ["courier12x12_aa_tc.png", "arial10x10.png", "Bisasam_16x16.png", "Cheepicus_15x15.png"];
+
+/// Layout of the font by the libtcod name suffixes "_tc", "_ro" and "_as", otherwise
+/// the wide images are taken as the 32x8 tcod layout and the rest as the 16x16 CP437 one
+fn detect_font_layout(font_name: &str) -> FontLayout {
+    let stem = std::path::Path::new(font_name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    if stem.ends_with("_tc") {
+        FontLayout::Tcod
+    } else if stem.ends_with("_ro") {
+        FontLayout::AsciiInRow
+    } else if stem.ends_with("_as") {
+        FontLayout::AsciiInCol
     } else {
-        game.inventory[inventory_id].equip(& mut game.log);
+        match tcod::image::Image::from_file(font_name).ok().map(|image| image.get_size()) {
+            Some((width, height)) if width == height * 4 => FontLayout::Tcod,
+            _ => FontLayout::AsciiInRow,
+        }
     }
-    UseResult::UsedAndKept
 }
 
-fn use_item(
-    inventory_id: usize,
-    game: &mut Game,
-    objects: &mut [Object],
-    tcod: &mut Tcod,
-) {
-    use Item::*;
-    if let Some(item) = game.inventory[inventory_id].item {
-        let on_use = match item {
-            Heal => cast_heal,
-            Lightning => cast_lightning,
-            Confuse => cast_confuse,
-            Fireball => cast_fireball,
-            Sword => toggle_equipment,
-            Shield => toggle_equipment,
-            Helmet => toggle_equipment,
-        };
-        match on_use(inventory_id, game, objects, tcod) {
-            UseResult::UsedUp => {
-                // destroy after use
-                game.inventory.remove(inventory_id);
-            }
-            UseResult::Cancelled => {
-                game.log.add("Cancelled", colors::WHITE);
-            }
-            UseResult::UsedAndKept => {}
+const WINDOW_TITLE: &str = "SEWERS OF THE DAMNED";
+
+/// The name of the character and where they are, kept up to date while playing
+fn game_title(game: &Game) -> String {
+    let place = match game.objective.arena_wave() {
+        Some(wave) => format!("arena wave {}", wave),
+        None if game.dungeon_level == 0 => "town".to_string(),
+        None => format!("depth {}", game.dungeon_level),
+    };
+    format!("{} - {}, {}", WINDOW_TITLE, game.name, place)
+}
+
+fn init_root(font_name: &str, font_layout: FontLayout, layout: &Layout, settings: &Settings) -> Root {
+    Root::initializer()
+        .font(font_name, font_layout)
+        .font_type(FontType::Default)
+        .size(SCREEN_WIDTH, layout.screen_height)
+        .title(WINDOW_TITLE)
+        .renderer(settings.renderer.renderer())
+        .fullscreen(settings.fullscreen)
+        .init()
+}
+
+/// Recreate the root console with another font, false if it's too big for the screen
+fn switch_font(tcod: &mut Tcod, font_name: &str) -> bool {
+    let font_layout = detect_font_layout(font_name);
+    let layout = match choose_layout(font_name, &font_layout) {
+        Some(layout) => layout,
+        None => return false,
+    };
+    tcod.root = init_root(font_name, font_layout, &layout, &tcod.settings);
+    tcod.panel = Offscreen::new(SCREEN_WIDTH, layout.panel_height);
+    tcod.layout = layout;
+    tcod.glyphs.cp437 = matches!(font_layout, FontLayout::AsciiInRow);
+    tcod.glyphs.map_to_font(&mut tcod.root);
+    tcod::system::set_fps(tcod.settings.fps);
+    true
+}
+
+fn choose_font(tcod: &mut Tcod) {
+    let fonts: Vec<&str> = BUNDLED_FONTS
+        .iter()
+        .cloned()
+        .filter(|font| std::path::Path::new(font).exists())
+        .collect();
+    let current = tcod.settings.font.clone().unwrap_or_else(|| DEFAULT_FONT.to_string());
+    let options = fonts
+        .iter()
+        .map(|&font| format!("{} {}", if font == current { "*" } else { " " }, font))
+        .collect::<Vec<_>>();
+    if let Some(choice) = menu("Font\n", &options, INVENTORY_WIDTH, &mut tcod.root) {
+        if switch_font(tcod, fonts[choice]) {
+            tcod.settings.font = Some(fonts[choice].to_string());
+        } else {
+            msgbox("This font is too big for the screen.", 30, &mut tcod.root);
         }
-    } else {
-        game.log.add(
-            format!("The {} cannot be used.", game.inventory[inventory_id].name),
-            colors::WHITE);
     }
 }
 
-fn drop_item(
-    inventory_id: usize,
-    game: &mut Game,
-    objects: &mut Vec<Object>,
-) {
-    let mut item = game.inventory.remove(inventory_id);
-    if item.equipment.is_some() {
-        item.unequip(&mut game.log);
+/// Size in pixels of a single character of the font, guessed from the font image
+fn font_char_size(font_name: &str, font_layout: &FontLayout) -> Option<(i32, i32)> {
+    let (columns, rows) = match *font_layout {
+        FontLayout::Tcod => (32, 8),
+        _ => (16, 16),
+    };
+    let font = tcod::image::Image::from_file(font_name).ok()?;
+    let (width, height) = font.get_size();
+    if width > 0 && height > 0 {
+        Some((width / columns, height / rows))
+    } else {
+        None
     }
-    item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
-    game.log.add(format!("You dropped a {}.", item.name), colors::YELLOW);
-    objects.push(item);
 }
 
-///////////////////////////////// UI Work
-const SCREEN_WIDTH: i32 = 80;
-const SCREEN_HEIGHT: i32 = 50;
-const LEVEL_SCREEN_WIDTH: i32 = 40;
-const BAR_WIDTH: i32 = 20;
-const PANEL_HEIGHT: i32 = 7;
-const PANEL_Y: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
-const COLOR_DARK_WALL: colors::Color = colors::Color    { r: 20,     g: 20,   b: 20 };
-const COLOR_LIGHT_WALL: colors::Color = colors::Color   { r: 80,   g: 80, b: 50 };
-const COLOR_DARK_GROUND: colors::Color = colors::Color  { r: 80,    g: 50,  b: 50 };
-const COLOR_LIGHT_GROUND: colors::Color = colors::Color { r: 130,   g: 130, b: 80 };
-const MSG_X: i32 = BAR_WIDTH + 2;
-const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
-const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
-const INVENTORY_WIDTH: i32 = 50;
-const CHARACTER_SCREEN_WIDTH: i32 = 30;
-
-const LIMIT_FPS: i32 = 60;
+/// Pick the layout that fits the desktop, or None if even the compact one does not fit.
+/// When the sizes can't be detected, assume the full layout fits.
+fn choose_layout(font_name: &str, font_layout: &FontLayout) -> Option<Layout> {
+    let (screen_w, screen_h) = tcod::system::get_current_resolution();
+    let (char_w, char_h) = match font_char_size(font_name, font_layout) {
+        Some(size) => size,
+        None => return Some(Layout::full()),
+    };
+    if screen_w <= 0 || screen_h <= 0 {
+        return Some(Layout::full());
+    }
+    let fits = |layout: &Layout| {
+        SCREEN_WIDTH * char_w <= screen_w && layout.screen_height * char_h <= screen_h
+    };
+    [Layout::full(), Layout::compact()].iter().cloned().find(fits)
+}
 
-struct Tcod {
-    root: Root,
-    con: Offscreen,
-    panel: Offscreen,
-    fov: FovMap,
-    mouse: Mouse,
+/// Tell the player the font is too big for the screen instead of drawing out of bounds
+fn too_small_screen(font_name: &str, font_layout: FontLayout) {
+    let width = 40;
+    let height = 8;
+    let mut root = Root::initializer()
+        .font(font_name, font_layout)
+        .font_type(FontType::Default)
+        .size(width, height)
+        .title(WINDOW_TITLE)
+        .init();
+    root.set_default_foreground(colors::LIGHT_RED);
+    root.print_rect_ex(
+        width / 2, 1,
+        width - 2, height - 2,
+        BackgroundFlag::None,
+        TextAlignment::Center,
+        format!("The screen is too small for this font. \
+                 The game needs at least {}x{} characters.\n\n\
+                 Try a smaller font. Press any key to exit.",
+                SCREEN_WIDTH, Layout::compact().screen_height),
+    );
+    root.flush();
+    root.wait_for_keypress(true);
 }
 
 fn handle_keys(key: Key,
@@ -1146,114 +9587,275 @@ fn handle_keys(key: Key,
                game: &mut Game,
 ) -> PlayerAction {
     use PlayerAction::*;
-    use tcod::input::Key;
-    use tcod::input::KeyCode::*;
 
     let alive = objects[0].alive;
-    let action = match (key, alive) {
-        (Key { code: Enter, alt: true, .. }, _) => {
-            let fullscreen = tcod.root.is_fullscreen();
-            tcod.root.set_fullscreen(!fullscreen);
+    // Shift turns any move key into a run, the arrows match their binding with or without it
+    if key.shift && alive {
+        let mut unshifted = key;
+        unshifted.shift = false;
+        if let Some(step) = tcod.bindings.action(unshifted).and_then(Action::step) {
+            start_run(step, tcod, game, objects);
+            return DidntTakeTurn;
+        }
+    }
+    let action = match (tcod.bindings.action(key), alive) {
+        (Some(Action::Fullscreen), _) => {
+            tcod.settings.fullscreen = !tcod.root.is_fullscreen();
+            tcod.settings.file_values.fullscreen = None;
+            apply_display_settings(tcod);
+            if let Err(error) = tcod.settings.save() {
+                warn!("Cannot write {}: {}", SETTINGS_FILE, error);
+            }
             DidntTakeTurn
         }
-        (Key { code: Escape, .. }, _) => return Exit,
-        (Key { code: Up, .. }, true) | (Key { code: NumPad8, .. }, true) => {
-            player_move_or_attack(0, -1, game, objects);
+        (Some(Action::GameMenu), _) => return game_menu(tcod),
+        (Some(Action::MoveNorth), true) => {
+            player_move_or_attack(0, -1, tcod, game, objects);
             TookTurn
         }
-        (Key { code: Down, .. }, true) | (Key { code: NumPad2, .. }, true) => {
-            player_move_or_attack(0, 1, game, objects);
+        (Some(Action::MoveSouth), true) => {
+            player_move_or_attack(0, 1, tcod, game, objects);
             TookTurn
         }
-        (Key { code: Left, .. }, true) | (Key { code: NumPad4, .. }, true) => {
-            player_move_or_attack(-1, 0, game, objects);
+        (Some(Action::MoveWest), true) => {
+            player_move_or_attack(-1, 0, tcod, game, objects);
             TookTurn
         }
-        (Key { code: Right, .. }, true) | (Key { code: NumPad6, .. }, true) => {
-            player_move_or_attack(1, 0, game, objects);
+        (Some(Action::MoveEast), true) => {
+            player_move_or_attack(1, 0, tcod, game, objects);
             TookTurn
         }
-        (Key { code: Home, .. }, true) | (Key { code: NumPad7, .. }, true) => {
-            player_move_or_attack(-1, -1, game, objects);
+        (Some(Action::MoveNorthWest), true) => {
+            player_move_or_attack(-1, -1, tcod, game, objects);
             TookTurn
         }
-        (Key { code: PageUp, .. }, true) | (Key { code: NumPad9, .. }, true) => {
-            player_move_or_attack(1, -1, game, objects);
+        (Some(Action::MoveNorthEast), true) => {
+            player_move_or_attack(1, -1, tcod, game, objects);
             TookTurn
         }
-        (Key { code: End, .. }, true) | (Key { code: NumPad1, .. }, true) => {
-            player_move_or_attack(-1, 1, game, objects);
+        (Some(Action::MoveSouthWest), true) => {
+            player_move_or_attack(-1, 1, tcod, game, objects);
             TookTurn
         }
-        (Key { code: PageDown, .. }, true) | (Key { code: NumPad3, .. }, true) => {
-            player_move_or_attack(1, 1, game, objects);
+        (Some(Action::MoveSouthEast), true) => {
+            player_move_or_attack(1, 1, tcod, game, objects);
             TookTurn
         }
-        (Key { code: NumPad5, .. }, true) | (Key { code: Spacebar, .. }, true) => {
+        (Some(Action::Wait), true) => {
             // Wait for a turn
             TookTurn
         }
-        (Key {printable: 'g', .. }, true) => {
-            let item_id = objects
+        (Some(Action::PickUp), true) => {
+            let note_id = objects
+                .iter()
+                .position(|object| object.pos() == objects[PLAYER].pos() && object.note.is_some());
+            if let Some(note_id) = note_id {
+                read_note(note_id, game, objects, &mut tcod.root);
+                return DidntTakeTurn;
+            }
+            let container_id = objects
                 .iter()
                 .position(|object| object.pos() == objects[PLAYER].pos() &&
-                          object.item.is_some());
-            if let Some(item_id) = item_id {
-                pick_item_up(item_id, objects, game);
+                          object.container.is_some() && object.item.is_none());
+            let item_ids = objects
+                .iter()
+                .enumerate()
+                .filter(|(_, object)| object.pos() == objects[PLAYER].pos() && object.appearance().item.is_some())
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>();
+            if let Some(container_id) = container_id {
+                open_container(container_id, tcod, game, objects)
+            } else {
+                let picked = if item_ids.len() > 1 {
+                    let names = item_ids.iter().map(|&id| objects[id].appearance().display_name()).collect::<Vec<_>>();
+                    let picked = multi_select_menu(
+                        "Select the items to pick up, Enter to confirm\n",
+                        &names,
+                        INVENTORY_WIDTH,
+                        &mut tcod.root);
+                    picked.unwrap_or_default().into_iter().map(|index| item_ids[index]).collect()
+                } else {
+                    item_ids
+                };
+                // Picking up swaps the last object in, so go from the back
+                for item_id in picked.into_iter().rev() {
+                    pick_item_up(item_id, objects, game);
+                }
+                DidntTakeTurn
             }
-            DidntTakeTurn
         }
-        (Key {printable: 'i', .. }, true) => {
+        (Some(Action::Fire), true) => fire_bow(tcod, game, objects),
+        (Some(Action::Throw), true) => {
             let inventory_index = inventory_menu(
                 &game.inventory,
-                "Press the key next to an item to use it, any other to cancel.\n",
+                "Select an item to throw\n",
+                &mut tcod.root);
+            match inventory_index {
+                Some(inventory_index) => throw_item(inventory_index, tcod, game, objects),
+                None => DidntTakeTurn,
+            }
+        }
+        (Some(Action::Inventory), true) => {
+            if game.inventory.is_empty() {
+                inventory_menu(&game.inventory, "", &mut tcod.root);
+                return DidntTakeTurn;
+            }
+            let header = format!(
+                "Carrying {} of {} lb. Press the key next to an item to use it, Shift and the key to put it \
+                 on a hotkey, any other to cancel.\n",
+                format_weight(carried_weight(game)), format_weight(carry_capacity(&objects[PLAYER], game)));
+            let (key, index) = menu_key(
+                &header,
+                &inventory_options(&game.inventory),
+                INVENTORY_WIDTH,
                 &mut tcod.root);
-            if let Some(inventory_index) = inventory_index {
-                use_item(inventory_index, game, objects, tcod);
+            match index {
+                Some(inventory_index) if key.shift => assign_hotkey(inventory_index, tcod, game),
+                Some(inventory_index) => use_item(inventory_index, game, objects, tcod),
+                None => {}
             }
             DidntTakeTurn
         }
-        (Key { printable: 'd', .. }, true) => {
-            let inventory_index = inventory_menu(
-                &game.inventory,
-                "Select and item to drop\n",
+        (Some(Action::Equipment), true) => {
+            show_equipment(tcod, game, objects);
+            DidntTakeTurn
+        }
+        (Some(Action::Drop), true) => {
+            if game.inventory.is_empty() {
+                game.log.add_in(Category::Item, "You have nothing to drop.", colors::WHITE);
+                return DidntTakeTurn;
+            }
+            let picked = multi_select_menu(
+                "Select the items to drop, Enter to confirm\n",
+                &inventory_options(&game.inventory),
+                INVENTORY_WIDTH,
                 &mut tcod.root);
-            if let Some(inventory_index) = inventory_index {
-                drop_item(inventory_index, game, objects);
+            // Drop from the back so the other indices stay put
+            for inventory_index in picked.unwrap_or_default().into_iter().rev() {
+                drop_item(inventory_index, tcod, game, objects);
             }
             DidntTakeTurn
         }
-        (Key { printable: 'c', .. }, true) => {
+        (Some(Action::Character), true) => {
             let player = &objects[PLAYER];
             let level = player.level;
-            let level_up_xp = LEVEL_UP_BASE + player.level * LEVEL_UP_FACTOR;
+            let level_up_xp = game.tuning.level_up_base + player.level * game.tuning.level_up_factor;
             if let Some(fighter) = player.fighter.as_ref() {
+                let worn = Slot::ALL
+                    .iter()
+                    .map(|&slot| {
+                        let item = get_equipped_in_slot(slot, &game.inventory)
+                            .map_or("-".to_string(), |id| game.inventory[id].display_name());
+                        format!("{}: {}", slot, item)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let attributes = player.attributes(game);
                 let msg = format!(
                     "Character information
 
+Class: {} ({})
 Level: {}
 Experience: {}
 Next level up: {}
 
+Strength: {}
+Dexterity: {}
+Intelligence: {}
+Perception: {}
+
 Max HP: {}
 Attack: {}
-Defence: {}",
-                    level, fighter.xp, level_up_xp, player.max_hp(game),
-                    player.power(game), player.defence(game));
+Ranged attack: {}
+Defence: {}
+
+Gold: {}
+
+{}",
+                    game.class.name(), game.class.perk(), level, fighter.xp, level_up_xp,
+                    attributes.strength, attributes.dexterity, attributes.intelligence, attributes.perception,
+                    player.max_hp(game),
+                    player.power(game), player.ranged_power(game), player.defence(game), game.gold, worn);
                 msgbox(&msg, CHARACTER_SCREEN_WIDTH, &mut tcod.root);
             }
             DidntTakeTurn
         }
-        (Key { printable: ',', shift: true, .. }, true) => {
+        (Some(Action::Descend), true) => {
             let player_on_stairs = objects
                 .iter()
                 .any(|object| object.pos() == objects[PLAYER].pos() && object.name == "stairs down");
-            if player_on_stairs {
+            let guard = objects.iter().find(|object| object.guards_stairs && object.alive).map(|g| g.name.clone());
+            if let (true, Some(guard)) = (player_on_stairs, guard) {
+                game.log.add(format!("The way down is sealed while {} lives.", guard), colors::RED);
+            } else if player_on_stairs && game.objective.is_tutorial() {
+                game.objective = RunObjective::Tutorial { step: TutorialStep::Done };
+            } else if player_on_stairs {
                 next_level(tcod, objects, game);
+            } else {
+                travel_to_named("stairs down", tcod, game, objects);
+            }
+            DidntTakeTurn
+        }
+        (Some(Action::Ascend), true) => {
+            let player_on_stairs = objects
+                .iter()
+                .any(|object| object.pos() == objects[PLAYER].pos() && object.name == "stairs up");
+            if player_on_stairs {
+                previous_level(tcod, objects, game);
+            } else {
+                travel_to_named("stairs up", tcod, game, objects);
             }
             DidntTakeTurn
         }
-        (Key {printable: '=', .. }, true) => {
+        (Some(Action::Quests), true) => {
+            show_journal(game, &mut tcod.root);
+            DidntTakeTurn
+        }
+        (Some(Action::Travel), true) => {
+            travel_menu(tcod, game, objects);
+            DidntTakeTurn
+        }
+        (Some(Action::Rest), true) => {
+            start_rest(tcod, game, objects);
+            DidntTakeTurn
+        }
+        (Some(Action::Disarm), true) => disarm_trap(tcod, game, objects),
+        (Some(Action::Interact), true) => interact(tcod, game, objects),
+        (Some(Action::MarkTile), true) => {
+            game.travel_mark = Some((game.dungeon_level, objects[PLAYER].pos()));
+            game.log.add("You mark this spot to travel back to.", colors::WHITE);
+            DidntTakeTurn
+        }
+        (Some(Action::MessageLog), _) => {
+            show_message_log(&game.log, &mut tcod.root);
+            DidntTakeTurn
+        }
+        (Some(Action::Settings), _) => {
+            show_options(tcod);
+            DidntTakeTurn
+        }
+        (Some(Action::Stats), true) => {
+            show_stats(Some(&current_stats(game)), &mut tcod.root);
+            DidntTakeTurn
+        }
+        (Some(Action::Bestiary), _) => {
+            show_bestiary(&mut tcod.root);
+            DidntTakeTurn
+        }
+        (Some(Action::Help), _) => {
+            show_help(tcod);
+            DidntTakeTurn
+        }
+        (Some(Action::Snapshot), _) => {
+            save_snapshot(tcod, game, objects);
+            DidntTakeTurn
+        }
+        (None, true) if ('1'..='9').contains(&key.printable) && !key.shift => {
+            use_hotkey(key.printable as usize - '1' as usize, tcod, game, objects);
+            DidntTakeTurn
+        }
+        // Debug cheat, not meant to be bound and only there in the runs left out of the stats
+        (None, true) if key.printable == '=' && game.wizard => {
             uncover_map(game);
             DidntTakeTurn
         }
@@ -1262,6 +9864,54 @@ Defence: {}",
     action
 }
 
+/// What gets hit around the targeted tile, drawn while picking the target
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TargetArea {
+    Tile,
+    Burst(i32),
+    // Projectiles stop at the first wall or creature on the way
+    Line,
+}
+
+fn target_overlay(tcod: &mut Tcod, game: &Game, objects: &[Object], max_range: Option<f32>, area: TargetArea) {
+    let out_of_range = |x: i32, y: i32| max_range.is_some_and(|range| objects[PLAYER].distance(x, y) > range);
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            if tcod.fov.is_in_fov(x, y) && out_of_range(x, y) {
+                tcod.root.set_char_background(x, y, colors::DARKER_RED, BackgroundFlag::Set);
+            }
+        }
+    }
+
+    let (x, y) = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
+    if x < 0 || y < 0 || x >= MAP_WIDTH || y >= MAP_HEIGHT || !tcod.fov.is_in_fov(x, y) {
+        return;
+    }
+    let affected = match area {
+        TargetArea::Tile => vec![(x, y)],
+        TargetArea::Burst(radius) => {
+            let mut tiles = vec![];
+            for tx in cmp::max(x - radius, 0)..cmp::min(x + radius + 1, MAP_WIDTH) {
+                for ty in cmp::max(y - radius, 0)..cmp::min(y + radius + 1, MAP_HEIGHT) {
+                    if (((tx - x).pow(2) + (ty - y).pow(2)) as f32).sqrt() <= radius as f32 {
+                        tiles.push((tx, ty));
+                    }
+                }
+            }
+            tiles
+        }
+        TargetArea::Line => {
+            let from = objects[PLAYER].pos();
+            let (to, _) = projectile_path(from, (x, y), &game.map, objects);
+            tcod::line::Line::new(from, to).collect()
+        }
+    };
+    let color = if out_of_range(x, y) { colors::RED } else { colors::DARK_ORANGE };
+    for (tx, ty) in affected {
+        tcod.root.set_char_background(tx, ty, color, BackgroundFlag::Set);
+    }
+}
+
 /// return the position of a tile left-clicked in the player's FOV
 /// or (None, None) if right-clicked
 fn target_tile(
@@ -1269,6 +9919,7 @@ fn target_tile(
     game: &mut Game,
     objects: &[Object],
     max_range: Option<f32>,
+    area: TargetArea,
 ) -> Option<(i32, i32)> {
     use tcod::input::KeyCode::Escape;
     loop {
@@ -1283,6 +9934,7 @@ fn target_tile(
             None => {},
         }
         render_all(tcod, objects, game, false);
+        target_overlay(tcod, game, objects, max_range, area);
         let (x, y) = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
 
         // accept the target if the player clicked in fov, filter by range is specified
@@ -1304,9 +9956,10 @@ fn target_monster(
     game: &mut Game,
     objects: &[Object],
     max_range: Option<f32>,
+    area: TargetArea,
 ) -> Option<usize> {
     loop {
-        match target_tile(tcod, game, objects, max_range) {
+        match target_tile(tcod, game, objects, max_range, area) {
             Some((x, y)) => {
                 for (id, obj) in objects.iter().enumerate() {
                     if obj.pos() == (x, y) && obj.fighter.is_some() && id != PLAYER {
@@ -1319,88 +9972,269 @@ fn target_monster(
     }
 }
 
-fn save_game(objects: &[Object], game: &Game) -> Result<(), Box<Error>> {
+/// Where the run is saved, named slots sit next to the default one as savegame-NAME
+#[derive(Clone, Debug, Default)]
+struct SaveSlot {
+    name: Option<String>,
+}
+
+impl SaveSlot {
+    fn file(&self) -> String {
+        match &self.name {
+            Some(name) => format!("savegame-{}", name),
+            None => "savegame".to_string(),
+        }
+    }
+
+    fn backup(&self) -> String {
+        format!("{}.bak", self.file())
+    }
+
+    fn temp(&self) -> String {
+        format!("{}.tmp", self.file())
+    }
+
+    /// Removes the save together with its backup
+    fn delete(&self) {
+        let _ = std::fs::remove_file(self.file());
+        let _ = std::fs::remove_file(self.backup());
+    }
+}
+
+/// The save is written next to the old one and swapped in only when complete,
+/// the previous save is kept as a backup
+fn save_game(objects: &[Object], game: &Game, slot: &SaveSlot) -> Result<(), Box<dyn Error>> {
     let save_data = serde_json::to_string(&(objects, game))?;
-    let mut file = File::create("savegame")?;
+    let mut file = File::create(slot.temp())?;
     file.write_all(save_data.as_bytes())?;
+    file.sync_all()?;
+    // Copied, so there is a save on disk at every moment. The rename swaps the new one in at once
+    if std::path::Path::new(&slot.file()).exists() {
+        std::fs::copy(slot.file(), slot.backup())?;
+    }
+    std::fs::rename(slot.temp(), slot.file())?;
     Ok(())
 }
 
-fn load_game() -> Result<(Vec<Object>, Game), Box<Error>> {
+fn read_save(path: &str) -> Result<(Vec<Object>, Game), Box<dyn Error>> {
     let mut json_save_state = String::new();
-    let mut file = File::open("savegame")?;
+    let mut file = File::open(path)?;
     file.read_to_string(&mut json_save_state)?;
-    let result = serde_json::from_str::<(Vec<Object>, Game)>(&json_save_state)?;
+    let mut result = serde_json::from_str::<(Vec<Object>, Game)>(&json_save_state)?;
+    // Saves from before the naming only knew the player by its index
+    result.0[PLAYER].player = true;
+    // Saves from before the factions had everyone neutral, the monsters are picked out by their AI
+    result.0[PLAYER].faction = Faction::Player;
+    for object in result.0.iter_mut().filter(|object| object.ai.is_some() && object.npc.is_none()) {
+        if object.faction == Faction::Neutral {
+            object.faction = monster_faction(&object.name.to_lowercase());
+        }
+    }
+    // Saves from before the turn counter only counted them in the stats
+    if result.1.turn == 0 {
+        result.1.turn = result.1.stats.turns;
+    }
     Ok(result)
 }
 
+/// Falls back to the backup when the save can't be read, the flag tells if that happened
+fn load_game(slot: &SaveSlot) -> Result<(Vec<Object>, Game, bool), Box<dyn Error>> {
+    match read_save(&slot.file()) {
+        Ok((objects, game)) => Ok((objects, game, false)),
+        Err(error) => {
+            warn!("Cannot load {}: {}", slot.file(), error);
+            let (objects, game) = read_save(&slot.backup())?;
+            Ok((objects, game, true))
+        }
+    }
+}
+
 fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root) -> Option<usize> {
-    assert!(options.len() <= 26, "Menu can only fit 26 options");
+    menu_key(header, options, width, root).1
+}
+
+/// Letters toggle the options, Enter confirms the picked ones and Escape cancels
+fn multi_select_menu<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root) -> Option<Vec<usize>> {
+    use tcod::input::KeyCode::{Enter, Escape};
+
+    let mut picked = vec![false; options.len()];
+    // Stays on the same page while toggling
+    let mut first = 0;
+    loop {
+        let marked = options
+            .iter()
+            .zip(&picked)
+            .map(|(option, &on)| format!("{} {}", checkbox(on), option.as_ref()))
+            .collect::<Vec<_>>();
+        let (key, index) = paged_menu_key(header, &marked, width, root, &mut first);
+        match key.code {
+            Enter => return Some((0..options.len()).filter(|&index| picked[index]).collect()),
+            Escape => return None,
+            _ => {
+                if let Some(index) = index {
+                    picked[index] = !picked[index];
+                }
+            }
+        }
+    }
+}
+
+/// The key a click in a menu stands for
+fn menu_click(code: KeyCode, printable: char) -> Key {
+    let mut key = Key::default();
+    key.code = code;
+    key.printable = printable;
+    key.pressed = true;
+    key
+}
 
+/// Show the menu until a key other than turning the pages is pressed, returns the key and the option
+/// it picked. Clicking an option counts as pressing its letter, a right click as Escape, and the window
+/// without options closes on any click
+fn menu_key<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root) -> (Key, Option<usize>) {
+    paged_menu_key(header, options, width, root, &mut 0)
+}
+
+/// The options are split into pages of at most 26, one letter for each, `first` is the first option
+/// of the page shown
+fn paged_menu_key<T: AsRef<str>>(header: &str, options: &[T], width: i32, root: &mut Root, first: &mut usize)
+                                 -> (Key, Option<usize>) {
+    use tcod::input::KeyCode::{Char, Escape, Up, Down, PageUp, PageDown};
+
+    let screen_height = root.height();
     let header_height = if header.is_empty() {
         0
     } else {
-        root.get_height_rect(0, 0, width, SCREEN_HEIGHT, header)
+        cmp::min(root.get_height_rect(0, 0, width, screen_height, header), screen_height - 1)
     };
-    let height = options.len() as i32 + header_height;
+    // On small screens the pages get shorter, with a line below telling where the rest is
+    let fits = |rows: i32| cmp::max(1, cmp::min(rows, MENU_PAGE_SIZE as i32)) as usize;
+    let mut page_size = fits(screen_height - header_height);
+    if options.len() > page_size {
+        page_size = fits(screen_height - header_height - 1);
+    }
+    let pages = cmp::max(1, options.len().div_ceil(page_size));
+    let footer_height = if pages > 1 { 1 } else { 0 };
+    let visible = cmp::min(options.len(), page_size);
+    let height = visible as i32 + header_height + footer_height;
+    let x = SCREEN_WIDTH / 2 - width / 2;
+    let y = screen_height / 2 - height / 2;
+    *first = cmp::min(*first, (pages - 1) * page_size);
+    let mut hovered = None;
+    let mut dirty = true;
+    let escape = menu_click(Escape, '\0');
 
-    // off-screen console representing the window
-    let mut window = Offscreen::new(width, height);
+    loop {
+        if root.window_closed() {
+            return (escape, None);
+        }
+        if !dirty {
+            match input::check_for_event(input::KEY_PRESS | input::MOUSE).map(|event| event.1) {
+                Some(Event::Key(key)) => match key.code {
+                    Up | PageUp if *first > 0 => *first -= page_size,
+                    Down | PageDown if *first + page_size < options.len() => *first += page_size,
+                    _ => {
+                        let letter = key.printable.to_ascii_lowercase();
+                        let index = if letter.is_ascii_lowercase() {
+                            Some(*first + (letter as u8 - b'a') as usize)
+                                .filter(|&index| index < cmp::min(*first + page_size, options.len()))
+                        } else {
+                            None
+                        };
+                        return (key, index);
+                    }
+                },
+                Some(Event::Mouse(mouse)) => {
+                    let (mx, my) = (mouse.cx as i32, mouse.cy as i32);
+                    let row = my - y - header_height;
+                    let on_page = cmp::min(page_size, options.len() - *first) as i32;
+                    let over = if mx >= x && mx < x + width && row >= 0 && row < on_page {
+                        Some(*first + row as usize)
+                    } else {
+                        None
+                    };
+                    if mouse.rbutton_pressed || (mouse.lbutton_pressed && options.is_empty()) {
+                        return (escape, None);
+                    }
+                    if let (true, Some(index)) = (mouse.lbutton_pressed, over) {
+                        return (menu_click(Char, (b'a' + (index - *first) as u8) as char), Some(index));
+                    }
+                    if mouse.wheel_up && *first > 0 {
+                        *first -= page_size;
+                    } else if mouse.wheel_down && *first + page_size < options.len() {
+                        *first += page_size;
+                    } else if over == hovered {
+                        continue;
+                    }
+                    hovered = over;
+                }
+                None => {
+                    std::thread::sleep(IDLE_FRAME);
+                    continue;
+                }
+            }
+        }
+        dirty = false;
 
-    window.set_default_foreground(colors::WHITE);
-    window.print_rect_ex(
-        0, 0,
-        width, height,
-        BackgroundFlag::None,
-        TextAlignment::Left,
-        header,
-    );
+        // off-screen console representing the window
+        let mut window = Offscreen::new(width, cmp::max(height, 1));
 
-    for (index, option_text) in options.iter().enumerate() {
-        let menu_letter = (b'a' + index as u8) as char;
-        let text = format!("({}) {}", menu_letter, option_text.as_ref());
-        window.print_ex(
-            0,
-            header_height + index as i32,
+        window.set_default_foreground(colors::WHITE);
+        window.print_rect_ex(
+            0, 0,
+            width, header_height,
             BackgroundFlag::None,
             TextAlignment::Left,
-            text,
+            header,
         );
-    }
 
-    // blit the window to the root console
-    let x = SCREEN_WIDTH / 2 - width / 2;
-    let y = SCREEN_HEIGHT / 2 - height / 2;
-    tcod::console::blit(&mut window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
+        for (index, option_text) in options.iter().enumerate().skip(*first).take(page_size) {
+            let menu_letter = (b'a' + (index - *first) as u8) as char;
+            let text = format!("({}) {}", menu_letter, option_text.as_ref());
+            let row = header_height + (index - *first) as i32;
+            window.print_ex(0, row, BackgroundFlag::None, TextAlignment::Left, text);
+            if hovered == Some(index) {
+                for column in 0..width {
+                    window.set_char_background(column, row, colors::DARK_AZURE, BackgroundFlag::Set);
+                }
+            }
+        }
+        if pages > 1 {
+            window.set_default_foreground(colors::LIGHT_GREY);
+            window.print_ex(0, height - 1, BackgroundFlag::None, TextAlignment::Left,
+                            format!("Page {} of {}, PgUp/PgDn for more", *first / page_size + 1, pages));
+        }
 
-    root.flush();
-    let key = root.wait_for_keypress(true);
+        // blit the window to the root console
+        tcod::console::blit(&window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
+        root.flush();
+    }
+}
 
-    // ASCII code to index
-    if key.printable.is_alphabetic() {
-        let index = key.printable.to_ascii_lowercase() as usize - 'a' as usize;
-        if index < options.len() {
-            Some(index)
-        } else {
-            None
+fn inventory_options(inventory: &[Object]) -> Vec<String> {
+    inventory.iter().map(|item| {
+        match item.equipment {
+            Some(equipment) if equipment.equipped => {
+                format!("{} (on {}, {})", item.display_name(), equipment.slot, equipment.condition())
+            }
+            Some(equipment) => {
+                let worn = replaced_by(&equipment, inventory).and_then(|id| inventory[id].equipment);
+                let change = equipment.bonuses_over(worn.as_ref());
+                format!("{} ({}, {})", item.display_name(), equipment.condition(), change)
+            }
+            _ => match &item.container {
+                Some(container) => format!("{} ({} inside)", item.display_name(), container.items.len()),
+                None => item.display_name(),
+            },
         }
-    } else {
-        None
-    }
+    }).collect()
 }
 
 fn inventory_menu(inventory: &[Object], header: &str, root: &mut Root) -> Option<usize> {
     let options = if inventory.len() == 0 {
         vec!["Inventory is empty.".into()]
     } else {
-        inventory.iter().map(|item| {
-            match item.equipment {
-                Some(equipment) if equipment.equipped => {
-                    format!("{} (on {})", item.name, equipment.slot)
-                }
-                _ => item.name.clone()
-            }
-        }).collect()
+        inventory_options(inventory)
     };
 
     let inventory_index = menu(header, &options, INVENTORY_WIDTH, root);
@@ -1412,13 +10246,45 @@ fn inventory_menu(inventory: &[Object], header: &str, root: &mut Root) -> Option
     }
 }
 
-fn get_names_under_mouse(mouse: Mouse, objects: &[Object], fov_map: &FovMap) -> String {
+fn get_names_under_mouse(
+    mouse: Mouse,
+    objects: &[Object],
+    game: &Game,
+    fov_map: &Vision,
+    see_invisible: bool,
+) -> String {
     let (x, y) = (mouse.cx as i32, mouse.cy as i32);
 
     let names = objects
         .iter()
-        .filter(|obj| {obj.pos() == (x, y) && fov_map.is_in_fov(obj.x, obj.y)})
-        .map(|obj| obj.name.clone())
+        .filter(|obj| {obj.pos() == (x, y) && obj.shown(see_invisible) && fov_map.is_in_fov(obj.x, obj.y)})
+        .map(|obj| {
+            let obj = obj.appearance();
+            if let Some(comparison) = compare_equipment(obj, &game.inventory) {
+                return comparison;
+            }
+            // Monsters show how hurt they are and what ails them
+            let mut notes = obj.statuses.iter().map(|status| status.kind.name()).collect::<Vec<_>>();
+            if let Some(Furniture::Altar { ready_at }) = obj.furniture {
+                notes.push(if ready_at > game.turn { "silent" } else { "humming" });
+            }
+            if obj.alive && !obj.is_player() {
+                if obj.speed != SpeedTier::Normal {
+                    notes.insert(0, obj.speed.name());
+                }
+                if obj.spectral {
+                    notes.insert(0, "spectral");
+                }
+                if let Some((wounds, _)) = wound_level(obj, game) {
+                    notes.insert(0, wounds);
+                }
+            }
+            if notes.is_empty() {
+                obj.display_name()
+            } else {
+                format!("{} ({})", obj.display_name(), notes.join(", "))
+            }
+        })
         .collect::<Vec<_>>();
 
     if names.len() > 0 {
@@ -1459,11 +10325,136 @@ fn render_bar(
     );
 }
 
-fn uncover_map(game: &mut Game) {
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            game.map[x as usize][y as usize].explored = true;
+/// Show a framed banner in the middle of the map and wait for a keypress
+fn banner(title: &str, text: &str, root: &mut Root) {
+    let width = BANNER_WIDTH;
+    let height = root.get_height_rect(0, 0, width - 4, root.height(), text) + 4;
+
+    let mut window = Offscreen::new(width, height);
+    window.set_default_foreground(colors::GOLD);
+    window.print_frame(0, 0, width, height, true, BackgroundFlag::Set, Some(title));
+    window.set_default_foreground(colors::WHITE);
+    window.print_rect_ex(
+        width / 2, 2,
+        width - 4, height - 4,
+        BackgroundFlag::None,
+        TextAlignment::Center,
+        text,
+    );
+
+    let x = SCREEN_WIDTH / 2 - width / 2;
+    let y = MAP_HEIGHT / 4;
+    blit(&window, (0, 0), (width, height), root, (x, y), 1.0, 0.9);
+    root.flush();
+    root.wait_for_keypress(true);
+}
+
+/// Mark the last tile a monster was seen on when it goes out of sight, and clear the mark when it's back
+fn remember_monsters(tcod: &Tcod, objects: &mut [Object], game: &mut Game) {
+    let see_invisible = wears_effect(PassiveEffect::SeeInvisible, game);
+    let palette = current_palette(&tcod.palettes, &tcod.settings.theme);
+    for object in objects.iter_mut() {
+        if object.is_player() || object.fighter.is_none() || object.disguise.is_some() {
+            continue;
+        }
+        let in_view = tcod.fov.is_in_fov(object.x, object.y) && object.shown(see_invisible);
+        match (in_view && object.alive, object.last_seen) {
+            // Back in sight, or dead, so the mark is no longer needed
+            (seen, Some((pos, true))) if seen || !object.alive => {
+                let tile = &mut game.map[pos];
+                if tile.glimpse.is_some_and(|glimpse| glimpse.monster) {
+                    tile.glimpse = None;
+                }
+                object.last_seen = if seen { Some((object.pos(), false)) } else { None };
+            }
+            (true, _) => object.last_seen = Some((object.pos(), false)),
+            (false, Some((pos, false))) if object.alive => {
+                let color = safe_color(palette.object_color(object), tcod.settings.colorblind);
+                let char = tcod.glyphs.char_for(object);
+                game.map[pos].glimpse = Some(Glimpse { char, color, monster: true });
+                object.last_seen = Some((pos, true));
+            }
+            (false, _) => {}
+        }
+    }
+}
+
+/// Announce unique monsters the first time they come into view
+fn introduce_uniques(tcod: &mut Tcod, objects: &mut [Object], game: &mut Game) {
+    for object in objects.iter_mut() {
+        if object.unique && !object.introduced && object.fighter.is_some() &&
+            tcod.fov.is_in_fov(object.x, object.y) {
+            object.introduced = true;
+            game.log.add(format!("{} has noticed you!", object.name), colors::GOLD);
+            banner(
+                "A powerful foe appears",
+                &format!("{}\n\nblocks your path. Prepare for a fight!", object.name),
+                &mut tcod.root,
+            );
+        }
+    }
+}
+
+const SNAPSHOT_DIR: &str = "screenshots";
+
+/// Save the map as it is on the screen, as a PNG of the window or as plain text
+fn save_snapshot(tcod: &mut Tcod, game: &mut Game, objects: &[Object]) {
+    let png = match menu("Save the map as:\n", &["PNG image", "ASCII text"], INVENTORY_WIDTH, &mut tcod.root) {
+        Some(choice) => choice == 0,
+        None => return,
+    };
+    // Drawn again so the menu isn't in the picture
+    render_all(tcod, objects, game, false);
+    tcod.root.flush();
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let path = format!("{}/turn{}_{}.{}", SNAPSHOT_DIR, game.turn, seconds, if png { "png" } else { "txt" });
+    let saved = std::fs::create_dir_all(SNAPSHOT_DIR).and_then(|_| {
+        if png {
+            tcod::system::save_screenshot(&path);
+            Ok(())
+        } else {
+            File::create(&path).and_then(|mut file| file.write_all(map_text(&tcod.con, &game.map).as_bytes()))
         }
+    });
+    match saved {
+        Ok(()) => game.log.add(format!("The map is saved to {}.", path), colors::WHITE),
+        Err(error) => game.log.add(format!("Cannot save the map: {}", error), colors::RED),
+    }
+}
+
+/// The drawn map, the tiles shown only by their background get a character of their own
+fn map_text(con: &Offscreen, map: &GameMap) -> String {
+    let mut text = String::new();
+    for y in 0..MAP_HEIGHT {
+        let line: String = (0..MAP_WIDTH)
+            .map(|x| {
+                let drawn = con.get_char(x, y);
+                match map[(x, y)] {
+                    _ if drawn.is_ascii_graphic() => drawn,
+                    tile if !tile.explored => ' ',
+                    tile if tile.blocked => '#',
+                    _ => '.',
+                }
+            })
+            .collect();
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+    text
+}
+
+fn level_name(level: u32) -> String {
+    match Theme::for_level(level) {
+        Theme::Town => "Town".into(),
+        theme => format!("{} level {}", theme, level),
+    }
+}
+
+fn uncover_map(game: &mut Game) {
+    for tile in game.map.tiles_mut() {
+        tile.explored = true;
     }
 }
 
@@ -1473,53 +10464,147 @@ fn render_all(tcod: &mut Tcod,
               fov_recompute: bool,
 ) {
     tcod.con.set_default_background(colors::BLACK);
-    if fov_recompute {
+    let colorblind = tcod.settings.colorblind;
+    let marks = tcod.settings.state_marks;
+    let dark = Theme::for_level(game.dungeon_level).dark();
+    let mut clobbered = false;
+    if dark {
+        clobbered = light_up(&mut tcod.fov, game, objects);
+    } else {
+        tcod.fov.light = None;
+    }
+    if fov_recompute || clobbered {
         let player = &objects[PLAYER];
-        tcod.fov.compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+        compute_fov(&mut tcod.fov, player.x, player.y, &tcod.settings);
     }
     // Draw map
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            let visible = tcod.fov.is_in_fov(x, y);
-            let tile = game.map[x as usize][y as usize];
-            let color = match (visible, tile.block_sight) {
-                // Outside of FOV
-                (false, true) => COLOR_DARK_WALL,
-                (false, false) => COLOR_DARK_GROUND,
-                // Inside the FOV
-                (true, false) => COLOR_LIGHT_GROUND,
-                (true, true) => COLOR_LIGHT_WALL,
-            };
-            let explored = &mut game.map[x as usize][y as usize].explored;
-            if visible {
-                *explored = true;
-            }
-            if *explored {
-                if !tile.blocked && tile.block_sight {
-                    // Draw a bush
-                    tcod.con.set_default_foreground(colors::DARK_GREEN);
-                    tcod.con.set_default_background(COLOR_DARK_GROUND);
-                    tcod.con.put_char(x, y, '*', BackgroundFlag::Set);
-                } else {
-                    tcod.con.set_default_background(colors::BLACK);
-                    tcod.con.set_char_background(x, y, color, BackgroundFlag::Set);
-                }
+    for (x, y) in game.map.positions() {
+        let visible = tcod.fov.is_in_fov(x, y);
+        let tile = game.map[(x, y)];
+        let palette = current_palette(&tcod.palettes, &tcod.settings.theme);
+        // Dimmed down to the dark color as the light fades
+        let lit = colors::lerp(palette.tile_color(false, tile.block_sight),
+                               palette.tile_color(true, tile.block_sight),
+                               tcod.fov.light_at(x, y));
+        let color = safe_color(if visible { lit } else { palette.tile_color(false, tile.block_sight) }, colorblind);
+        let dark_ground = safe_color(palette.dark_ground, colorblind);
+        let stored = &mut game.map[(x, y)];
+        if visible {
+            stored.explored = true;
+            // Items seen again are filled in below by what's there now. Where a monster went out of sight
+            // stays marked until it's seen again
+            if !stored.glimpse.is_some_and(|glimpse| glimpse.monster) {
+                stored.glimpse = None;
+            }
+        }
+        if stored.explored {
+            if tile.burning > 0 {
+                // Flicker between the colors of the flames
+                let flame = [colors::FLAME, colors::ORANGE, colors::YELLOW, colors::RED];
+                let flame = *game_rng().choose(&flame).unwrap();
+                tcod.con.set_default_foreground(safe_color(flame, colorblind));
+                tcod.con.set_default_background(dark_ground);
+                tcod.con.put_char(x, y, '^', BackgroundFlag::Set);
+            } else if tile.water {
+                tcod.con.set_default_foreground(safe_color(colors::LIGHT_BLUE, colorblind));
+                tcod.con.set_default_background(dark_ground);
+                tcod.con.put_char(x, y, '~', BackgroundFlag::Set);
+            } else if tile.is_bush() {
+                // Draw a bush
+                tcod.con.set_default_foreground(safe_color(colors::DARK_GREEN, colorblind));
+                tcod.con.set_default_background(dark_ground);
+                tcod.con.put_char(x, y, '*', BackgroundFlag::Set);
             } else {
-                tcod.con.set_default_foreground(colors::BLACK);
                 tcod.con.set_default_background(colors::BLACK);
+                tcod.con.set_char_background(x, y, color, BackgroundFlag::Set);
+                if tile.web {
+                    tcod.con.set_default_foreground(safe_color(colors::LIGHTER_GREY, colorblind));
+                    tcod.con.put_char(x, y, '"', BackgroundFlag::None);
+                }
+            }
+            if let (true, Some(cloud)) = (visible, tile.cloud) {
+                let under = tcod.con.get_char_background(x, y);
+                let tinted = colors::lerp(under, safe_color(cloud.kind.color(), colorblind), CLOUD_OPACITY);
+                tcod.con.set_char_background(x, y, tinted, BackgroundFlag::Set);
+            }
+            if let Some(glimpse) = game.map[(x, y)].glimpse {
+                let brightness = if glimpse.monster {
+                    REMEMBERED_MONSTER_BRIGHTNESS
+                } else {
+                    REMEMBERED_ITEM_BRIGHTNESS
+                };
+                let faded = colors::lerp(color, glimpse.color, brightness);
+                tcod.con.set_default_foreground(safe_color(faded, colorblind));
+                tcod.con.put_char(x, y, glimpse.char, BackgroundFlag::None);
             }
+        } else {
+            tcod.con.set_default_foreground(colors::BLACK);
+            tcod.con.set_default_background(colors::BLACK);
         }
     }
     // Draw objects
+    let see_invisible = wears_effect(PassiveEffect::SeeInvisible, game);
     let mut to_draw: Vec<_> = objects.
         iter().
-        filter(|obj| obj.always_visible || tcod.fov.is_in_fov(obj.x, obj.y)).
+        filter(|obj| obj.shown(see_invisible) && (obj.always_visible || tcod.fov.is_in_fov(obj.x, obj.y))).
         collect();
     to_draw.sort_by(|o1, o2| { o1.blocks.cmp(&o2.blocks) });
     for object in to_draw {
+        // Mimics are drawn as whatever they pass for
+        let object = object.appearance();
         if object.always_visible || tcod.fov.is_in_fov(object.x, object.y) {
             object.draw(&mut tcod.con);
+            let color = current_palette(&tcod.palettes, &tcod.settings.theme).object_color(object);
+            tcod.con.set_char_foreground(object.x, object.y, safe_color(color, colorblind));
+            let char = tcod.glyphs.char_for(object);
+            tcod.con.set_char(object.x, object.y, char);
+            // The monsters are remembered once they go out of sight
+            let stored = &mut game.map[(object.x, object.y)];
+            let marker = stored.glimpse.is_some_and(|glimpse| glimpse.monster);
+            if object.fighter.is_none() && !marker && tcod.fov.is_in_fov(object.x, object.y) {
+                stored.glimpse = Some(Glimpse { char, color, monster: false });
+            }
+        }
+    }
+    // Tint the wounded monsters by their health and mark their statuses above them,
+    // the player is marked too when the critical states are
+    for (id, object) in objects.iter().enumerate() {
+        if (id == PLAYER && !marks) || !object.alive || object.disguise.is_some() || !object.shown(see_invisible) ||
+            !tcod.fov.is_in_fov(object.x, object.y) {
+            continue;
+        }
+        if id != PLAYER {
+            if let Some((_, color)) = wound_level(object, game) {
+                tcod.con.set_char_background(object.x, object.y, safe_color(color, colorblind), BackgroundFlag::Set);
+            }
         }
+        let marker = match object.statuses.first() {
+            Some(status) => Some((status.kind.marker(), status.kind.color())),
+            None if marks && low_health(object, game) => Some((LOW_HEALTH_MARKER, colors::RED)),
+            None => None,
+        };
+        if let Some((marker, color)) = marker {
+            let (x, y) = (object.x, object.y - 1);
+            let taken = objects.iter().any(|other| other.pos() == (x, y) && other.shown(see_invisible));
+            if y >= 0 && !taken && tcod.fov.is_in_fov(x, y) {
+                tcod.con.set_default_foreground(safe_color(color, colorblind));
+                tcod.con.put_char(x, y, marker, BackgroundFlag::None);
+            }
+        }
+    }
+    // Draw a big health bar for the visible boss
+    let boss = objects
+        .iter()
+        .find(|obj| obj.unique && obj.fighter.is_some() && tcod.fov.is_in_fov(obj.x, obj.y));
+    if let Some(boss) = boss {
+        render_bar(&mut tcod.con,
+                   (MAP_WIDTH - BOSS_BAR_WIDTH) / 2, 1,
+                   BOSS_BAR_WIDTH,
+                   &boss.name,
+                   boss.fighter.map_or(0, |f| f.hp), boss.max_hp(game),
+                   colors::GOLD,
+                   colors::DARKER_AMBER
+        );
     }
     blit(&mut tcod.con, (0, 0), (MAP_WIDTH, MAP_HEIGHT),
          &mut tcod.root, (0, 0),
@@ -1530,47 +10615,123 @@ fn render_all(tcod: &mut Tcod,
     tcod.panel.set_default_background(colors::BLACK);
     tcod.panel.clear();
 
-    let mut y = MSG_HEIGHT as i32;
-    for &(ref msg, color) in game.log.iter().rev() {
-        let msg_height = tcod.panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+    let mut y = tcod.layout.msg_height();
+    let settings = &tcod.settings;
+    let shown = game.log.entries.iter().rev().filter(|entry| settings.shows(entry.category));
+    for entry in shown {
+        let msg = entry.display();
+        let msg_height = tcod.panel.get_height_rect(MSG_X, y, MSG_WIDTH, 0, &msg);
         y -= msg_height;
         if y < 0 {
             break;
         }
-        tcod.panel.set_default_foreground(color);
-        tcod.panel.print_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+        tcod.panel.set_default_foreground(safe_color(entry.color, colorblind));
+        tcod.panel.print_rect(MSG_X, y, MSG_WIDTH, 0, &msg);
     }
 
     let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
     let max_hp = objects[PLAYER].max_hp(game);
+    // The bar turns green while the poison works
+    let poisoned = objects[PLAYER].has_status(StatusKind::Poisoned);
+    let (bar_color, back_color) = if poisoned {
+        (colors::LIGHT_GREEN, colors::DARKER_GREEN)
+    } else {
+        (colors::LIGHT_RED, colors::DARKER_RED)
+    };
+    let bar_name = if marks && poisoned {
+        "Poisoned HP"
+    } else if marks && low_health(&objects[PLAYER], game) {
+        "Low HP"
+    } else {
+        "HP"
+    };
     render_bar(&mut tcod.panel,
                1, 1,
                BAR_WIDTH,
-               "HP",
+               bar_name,
                hp, max_hp,
-               colors::LIGHT_RED,
-               colors::DARKER_RED
+               safe_color(bar_color, colorblind),
+               safe_color(back_color, colorblind)
     );
+    // The compact panel has no spare line between the bar and the level name
+    let level_y = if tcod.layout.compact { 2 } else { 3 };
     tcod.panel.print_ex(
-        1, 3,
+        1, level_y,
         BackgroundFlag::None,
         TextAlignment::Left,
-        format!("Sewers level {}", game.dungeon_level),
+        level_name(game.dungeon_level),
     );
+    if !tcod.layout.compact {
+        let here = game.stats.depth_turns.get(&game.dungeon_level).cloned().unwrap_or(0);
+        tcod.panel.set_default_foreground(colors::LIGHT_GREY);
+        tcod.panel.print_ex(
+            1, level_y - 1,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            format!("Turn {} ({} here)", game.turn, here),
+        );
+        tcod.panel.set_default_foreground(colors::GOLD);
+        tcod.panel.print_ex(
+            1, level_y + 1,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            format!("Gold: {}", game.gold),
+        );
+        let hunger = if game.nutrition == 0 {
+            Some(("Starving", colors::RED))
+        } else if game.nutrition <= HUNGRY_NUTRITION {
+            Some(("Hungry", colors::YELLOW))
+        } else {
+            None
+        };
+        if let Some((text, color)) = hunger {
+            tcod.panel.set_default_foreground(safe_color(color, colorblind));
+            tcod.panel.print_ex(BAR_WIDTH, level_y + 1, BackgroundFlag::None, TextAlignment::Right, text);
+        }
+        let mut status_x = 1;
+        let load = burden(&objects[PLAYER], game);
+        if load != Burden::Unburdened {
+            let text = format!("{} ", load.name());
+            tcod.panel.set_default_foreground(safe_color(load.color(), colorblind));
+            tcod.panel.print_ex(status_x, level_y + 2, BackgroundFlag::None, TextAlignment::Left, &text);
+            status_x += text.len() as i32;
+        }
+        for effect in &objects[PLAYER].statuses {
+            let text = format!("{} ", effect.kind.name());
+            tcod.panel.set_default_foreground(safe_color(effect.kind.color(), colorblind));
+            tcod.panel.print_ex(status_x, level_y + 2, BackgroundFlag::None, TextAlignment::Left, &text);
+            status_x += text.len() as i32;
+        }
+        // Hotbar: the key, then the item, greyed out when none are left
+        for (slot, hotkey) in game.hotkeys.iter().enumerate() {
+            let x = 1 + slot as i32 * 2;
+            tcod.panel.set_default_foreground(colors::DARK_GREY);
+            tcod.panel.put_char(x, level_y + 3, (b'1' + slot as u8) as char, BackgroundFlag::None);
+            let (char, color) = match hotkey {
+                Some(hotkey) => match game.inventory.iter().find(|item| item.item == Some(hotkey.item)) {
+                    Some(item) => (item.char, item.color),
+                    None => (hotkey.char, colors::DARKER_GREY),
+                },
+                None => (' ', colors::BLACK),
+            };
+            tcod.panel.set_default_foreground(safe_color(color, colorblind));
+            tcod.panel.put_char(x + 1, level_y + 3, char, BackgroundFlag::None);
+        }
+    }
 
     // Draw names under mouse
     tcod.panel.set_default_foreground(colors::LIGHT_GREY);
     tcod.panel.print_ex(1, 0,
                    BackgroundFlag::None,
                    TextAlignment::Left,
-                   get_names_under_mouse(tcod.mouse, objects, &tcod.fov)
+                   get_names_under_mouse(tcod.mouse, objects, game, &tcod.fov, see_invisible)
     );
 
     blit(&tcod.panel,
          (0, 0),
-         (SCREEN_WIDTH, PANEL_HEIGHT),
+         (SCREEN_WIDTH, tcod.layout.panel_height),
          &mut tcod.root,
-         (0, PANEL_Y),
+         (0, tcod.layout.panel_y()),
          1.0,
          1.0
      );
@@ -1579,95 +10740,499 @@ fn render_all(tcod: &mut Tcod,
     tcod.root.flush();
 }
 
-fn initialise_fov(tcod: &mut Tcod, map: &Map) {
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            tcod.fov.set(
-                x,
-                y,
-                !map[x as usize][y as usize].block_sight,
-                !map[x as usize][y as usize].blocked
-            );
-        }
+fn initialise_fov(tcod: &mut Tcod, map: &GameMap) {
+    for (x, y) in map.positions() {
+        tcod.fov.set(
+            x,
+            y,
+            !map[(x, y)].opaque(),
+            !map[(x, y)].blocked
+        );
     }
     tcod.con.clear(); // Clear the remnants of the previous games
 }
 
-fn new_game(tcod: &mut Tcod) -> (Vec<Object>, Game) {
-    let mut player = Object::new("Player", 0, 0, '@', colors::WHITE, true);
-    player.alive = true;
-    player.fighter = Some(Fighter {
-        base_max_hp: 100,
-        hp: 100,
-        base_defence: 1,
-        base_power: 2,
-        xp: 0,
-        on_death: DeathCallback::Player,
-    });
-
-    let mut objects = vec![player];
-    let mut game = Game {
-        map: make_map(&mut objects, 1),
-        log: vec![],
-        inventory: vec![],
-        dungeon_level: 1,
-    };
-
+fn make_dagger() -> Object {
     let mut dagger = Object::new("dagger", 0, 0, '-', colors::SKY, false);
     dagger.item = Some(Item::Sword);
     dagger.equipment = Some(Equipment {
-        equipped: true,
+        equipped: false,
         slot: Slot::LeftHand,
         max_hp_bonus: 0,
         defence_bonus: 0,
         power_bonus: 2,
+        cursed: false,
+        enchantment: 0,
+        durability: 30,
+        max_durability: 30,
+        effect: None,
+        attributes: Attributes::default(),
     });
+    dagger
+}
+
+/// A fresh character in the town, or in the middle of the arena
+fn new_game(mode: GameMode, tcod: &mut Tcod) -> (Vec<Object>, Game) {
+    if let Some(seed) = tcod.seed {
+        seed_rng(seed);
+    }
+    // The tutorial always hands out the same warrior
+    let (class, name, conducts) = if mode == GameMode::Tutorial {
+        (Class::Warrior, DEFAULT_NAME.to_string(), vec![])
+    } else {
+        let options = Class::ALL
+            .iter()
+            .map(|class| format!("{} - {}", class.name(), class.perk()))
+            .collect::<Vec<_>>();
+        let choice = menu("Choose your class:\n", &options, INVENTORY_WIDTH, &mut tcod.root);
+        let class = Class::ALL[choice.unwrap_or(0)];
+        let name = ask_name("What is your name?", &mut tcod.root);
+        let conducts = if mode == GameMode::Arena { vec![] } else { choose_conducts(&mut tcod.root) };
+        (class, name, conducts)
+    };
+
+    let mut player = Object::new(&name, 0, 0, '@', colors::WHITE, true);
+    player.player = true;
+    player.alive = true;
+    player.fighter = Some(class.fighter());
+    player.faction = Faction::Player;
+
+    let mut objects = vec![player];
+    let (map, dungeon_level, objective) = match mode {
+        GameMode::Dungeon => (make_town(&mut objects), 0, RunObjective::Descent { depth: DESCENT_DEPTH }),
+        GameMode::Arena => (make_arena(&mut objects), 1, RunObjective::Arena { wave: 0, lull: ARENA_FIRST_LULL }),
+        GameMode::Tutorial => {
+            (make_tutorial(&mut objects), 1, RunObjective::Tutorial { step: TutorialStep::Move })
+        }
+    };
+    let mut game = Game {
+        map,
+        log: Messages::default(),
+        inventory: vec![],
+        dungeon_level,
+        objective,
+        gold: 0,
+        levels: HashMap::new(),
+        quests: vec![],
+        journal: vec![],
+        stat_potions: 0,
+        events: vec![],
+        nutrition: MAX_NUTRITION,
+        conducts,
+        stats: RunStats::default(),
+        turn: 0,
+        travel_mark: None,
+        last_shop: None,
+        activity: None,
+        corner_cutting: true,
+        hotkeys: Default::default(),
+        combat: CombatConfig::load(),
+        tuning: Tuning::load(),
+        bestiary: Bestiary::load(),
+        dialogues: Dialogues::load(),
+        notes: Notes::load(),
+        class,
+        wizard: tcod.wizard,
+        name,
+    };
+    if mode == GameMode::Tutorial {
+        game.inventory = vec![make_old_sword()];
+        // Wounded, so the healing potion is worth drinking
+        let fighter = objects[PLAYER].fighter.as_mut().unwrap();
+        fighter.hp /= 2;
+        initialise_fov(tcod, &game.map);
+        game.log.add("Welcome to the tutorial! Nothing here is saved.", colors::LIGHT_AZURE);
+        return (objects, game);
+    }
+    game.inventory = class.starting_kit();
+    if keeps_conduct(Conduct::NoEquipment, &game) {
+        for item in game.inventory.iter_mut() {
+            if let Some(ref mut equipment) = item.equipment {
+                equipment.equipped = false;
+            }
+        }
+    }
 
-    game.inventory.push(dagger);
+    let choice = menu("Do you take your dog along?\n", &["Yes", "No"], INVENTORY_WIDTH, &mut tcod.root);
+    if choice == Some(0) {
+        let dog = make_dog(0, 0);
+        place_near_player(dog, &mut game, &mut objects);
+    }
 
     initialise_fov(tcod, &game.map);
 
+    if mode == GameMode::Arena {
+        game.log.add("The crowd roars as you step into the arena. The first wave is coming!", colors::RED);
+        return (objects, game);
+    }
     game.log.add(
-        "Welcome stranger! Prepare to perish in the Sewers of the Damned!",
+        format!("Welcome {}! Prepare to perish in the Sewers of the Damned!", game.name),
         colors::RED);
+    game.log.add(
+        "The way down is in the middle of the fountain. Visit the shop and the healer first.",
+        colors::LIGHT_AZURE);
 
     (objects, game)
 }
 
 fn play_game(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod) {
     let mut previous_player_pos = (-1, -1);
-    let mut key = Default::default();
-
-    while !tcod.root.window_closed() {
-        tcod.con.clear();
-        tcod.con.set_default_foreground(colors::WHITE);
+    let mut dirty = true;
+    let mut last_render = Instant::now();
+    let mut keys = KeyQueue::default();
+    let mut title = String::new();
+    let mut tutorial_shown = None;
 
+    loop {
+        // Closing the window keeps the run, just like quitting from the menu
+        if tcod.root.window_closed() {
+            // The window is gone, so the errors file is the only place left to tell
+            if !game.objective.is_tutorial() {
+                if let Err(error) = save_game(objects, game, &tcod.save_slot) {
+                    warn!("Cannot save {}: {}", tcod.save_slot.file(), error);
+                }
+            }
+            break
+        }
+        let current_title = game_title(game);
+        if current_title != title {
+            tcod.root.set_window_title(&current_title);
+            title = current_title;
+        }
+        let mut clicked = false;
         let fov_recompute = previous_player_pos != (objects[PLAYER].x, objects[PLAYER].y);
 
-        match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
-            Some((_, Event::Mouse(m))) => tcod.mouse = m,
-            Some((_, Event::Key(k))) => key = k,
-            _ => key = Default::default(),
+        game.corner_cutting = tcod.settings.corner_cutting;
+        if game.wizard {
+            game.map.tiles_mut().for_each(|tile| tile.explored = true);
         }
-
-        render_all(tcod, &objects, game, fov_recompute);
-
+        dirty |= keys.poll(tcod, &mut clicked);
+        let key = if clicked { Default::default() } else { keys.next(&tcod.settings) };
+        dirty |= key.code != input::KeyCode::NoKey;
+
+        // The same frame isn't drawn again, unless the fires flicker or it's been a while,
+        // in case the window got covered
+        let animating = game.activity.is_some() || game.map.tiles().any(|tile| tile.burning > 0);
+        if dirty || fov_recompute || animating || last_render.elapsed() >= FORCED_REDRAW {
+            tcod.con.clear();
+            tcod.con.set_default_foreground(colors::WHITE);
+            render_all(tcod, objects, game, fov_recompute);
+            dirty = false;
+            last_render = Instant::now();
+        } else {
+            std::thread::sleep(IDLE_FRAME);
+        }
+        introduce_uniques(tcod, objects, game);
+        note_sightings(tcod, objects, game);
+        remember_monsters(tcod, objects, game);
+        dirty |= direct_tutorial(tcod, objects, game, &mut tutorial_shown);
+        tcod.music.play(Track::Level(Theme::for_level(game.dungeon_level)), &tcod.settings);
+
+        let log_version = game.log.version();
+        let vision = tcod.settings.vision();
         let player = &mut objects[PLAYER];
         previous_player_pos = (player.x, player.y);
-        let player_action = handle_keys(key, tcod, objects, game);
+        // Any key or click stops the current activity
+        let player_action = if game.activity.is_some() && key.code == input::KeyCode::NoKey && !clicked {
+            continue_activity(tcod, game, objects)
+        } else {
+            game.activity = None;
+            if clicked && objects[PLAYER].alive {
+                handle_click(tcod, game, objects)
+            } else {
+                handle_keys(key, tcod, objects, game)
+            }
+        };
+        // Changed in the options, so the view is worked out again without waiting for a step
+        if tcod.settings.vision() != vision {
+            previous_player_pos = (-1, -1);
+        }
         if player_action == PlayerAction::Exit {
-            save_game(objects, game).ok().expect("Cannot save");
+            if !game.objective.is_tutorial() {
+                if let Err(error) = save_game(objects, game, &tcod.save_slot) {
+                    warn!("Cannot save {}: {}", tcod.save_slot.file(), error);
+                    msgbox("\nThe game could not be saved.\n", 24, &mut tcod.root);
+                }
+            }
+            break
+        }
+        if player_action == PlayerAction::Abandon {
+            let reason = match game.objective {
+                RunObjective::Arena { wave, .. } => format!("Gave up in wave {} of the arena", wave),
+                RunObjective::Descent { .. } => format!("Gave up on level {}", game.dungeon_level),
+                RunObjective::Tutorial { .. } => break,
+            };
+            let summary = RunSummary::new(Outcome::Defeat, reason, objects, game);
+            end_run(&summary, tcod);
             break
         }
         level_up(objects, game, tcod);
 
         if objects[PLAYER].alive && player_action != PlayerAction::DidntTakeTurn {
-            for id in 0..objects.len() {
-                if objects[id].ai.is_some() {
-                    ai_take_turn(id, game, objects, &tcod.fov);
+            game.turn += 1;
+            debug!(target: "turn", "turn {}: {:?} key {:?} {:?}, player at {:?} with {} hp", game.turn, player_action,
+                   key.code, key.printable, objects[PLAYER].pos(), objects[PLAYER].fighter.map_or(0, |f| f.hp));
+            *game.stats.depth_turns.entry(game.dungeon_level).or_insert(0) += 1;
+            game.log.turn = game.turn;
+            detect_traps(game, objects);
+            // A hasted player gets a free turn every other turn, a slowed one gives the monsters an extra one
+            let player = &objects[PLAYER];
+            let monster_rounds = if player.has_status(StatusKind::Hasted) {
+                game.turn % 2
+            } else if player.has_status(StatusKind::Slowed) {
+                1 + game.turn % 2
+            } else {
+                1
+            };
+            // Hauling too much makes every step cost more
+            let monster_rounds = if player.pos() != previous_player_pos {
+                monster_rounds + burden(player, game).extra_rounds(game.turn)
+            } else {
+                monster_rounds
+            };
+            for _ in 0..monster_rounds {
+                for id in 0..objects.len() {
+                    for _ in 0..actions_this_turn(&objects[id], game.turn) {
+                        if objects[id].ai.is_some() {
+                            ai_take_turn(id, game, objects, &tcod.fov);
+                        }
+                    }
+                }
+            }
+            tick_statuses(game, objects);
+            apply_passive_effects(tcod, game, objects);
+            digest(game, objects);
+            update_fires(tcod, game, objects);
+            if update_clouds(game, objects, &mut tcod.fov) {
+                previous_player_pos = (-1, -1);
+            }
+            revive_corpses(game, objects, &tcod.fov);
+            level_events(tcod, game, objects);
+            escort_prisoners(game, objects);
+            direct_arena(game, objects);
+            if game.log.unseen_danger > 1 {
+                more_prompt(tcod, objects, game);
+            }
+            if game.log.unseen_danger > 0 {
+                game.activity = None;
+                keys.clear();
+            }
+        }
+        game.log.unseen_danger = 0;
+
+        process_events(game, objects, &tcod.settings);
+        let took_turn = player_action != PlayerAction::DidntTakeTurn;
+        dirty |= took_turn || game.log.version() != log_version;
+        narrate(&tcod.settings, game, objects, &mut tcod.fov, took_turn);
+        spectate(tcod, game, objects, took_turn);
+
+        if let Some((outcome, reason)) = game.objective.evaluate(objects, game) {
+            render_all(tcod, objects, game, false);
+            if game.objective.is_tutorial() {
+                finish_tutorial(outcome, &mut tcod.root);
+                break
+            }
+            let summary = RunSummary::new(outcome, reason, objects, game);
+            end_run(&summary, tcod);
+            break
+        }
+    }
+    tcod.root.set_window_title(WINDOW_TITLE);
+}
+
+/// Letters, digits, spaces and dashes up to MAX_NAME_LENGTH, nothing typed or Escape gives the default name
+fn ask_name(header: &str, root: &mut Root) -> String {
+    use tcod::input::KeyCode::{Backspace, Enter, Escape};
+
+    let width = cmp::max(header.len(), MAX_NAME_LENGTH) as i32 + 4;
+    let mut input = String::new();
+    loop {
+        let mut window = Offscreen::new(width, 3);
+        window.set_default_foreground(colors::WHITE);
+        window.print_ex(0, 0, BackgroundFlag::None, TextAlignment::Left, header);
+        window.print_ex(0, 2, BackgroundFlag::None, TextAlignment::Left, format!("> {}_", input));
+
+        let x = SCREEN_WIDTH / 2 - width / 2;
+        let y = root.height() / 2 - 1;
+        tcod::console::blit(&window, (0, 0), (width, 3), root, (x, y), 1.0, 0.7);
+        root.flush();
+
+        let key = root.wait_for_keypress(true);
+        match key.code {
+            Enter => break,
+            Escape => return DEFAULT_NAME.to_string(),
+            Backspace => {
+                input.pop();
+            }
+            _ if (key.printable.is_ascii_alphanumeric() || key.printable == ' ' || key.printable == '-') &&
+                input.len() < MAX_NAME_LENGTH => input.push(key.printable),
+            _ => {}
+        }
+    }
+    match input.trim() {
+        "" => DEFAULT_NAME.to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Let the player type in a number up to max, Enter confirms (all of them if nothing is typed)
+/// and Escape cancels
+fn ask_quantity(header: &str, max: i32, root: &mut Root) -> Option<i32> {
+    use tcod::input::KeyCode::{Backspace, Enter, Escape};
+
+    let width = cmp::max(header.len() as i32, 10) + 2;
+    let mut input = String::new();
+    loop {
+        let mut window = Offscreen::new(width, 3);
+        window.set_default_foreground(colors::WHITE);
+        window.print_ex(0, 0, BackgroundFlag::None, TextAlignment::Left, header);
+        window.print_ex(0, 2, BackgroundFlag::None, TextAlignment::Left, format!("> {}_", input));
+
+        let x = SCREEN_WIDTH / 2 - width / 2;
+        let y = root.height() / 2 - 1;
+        tcod::console::blit(&window, (0, 0), (width, 3), root, (x, y), 1.0, 0.7);
+        root.flush();
+
+        let key = root.wait_for_keypress(true);
+        match key.code {
+            Enter => break,
+            Escape => return None,
+            Backspace => {
+                input.pop();
+            }
+            _ if key.printable.is_ascii_digit() && input.len() < 4 => input.push(key.printable),
+            _ => {}
+        }
+    }
+    if input.is_empty() {
+        return Some(max);
+    }
+    match input.parse::<i32>() {
+        Ok(count) if count > 0 => Some(cmp::min(count, max)),
+        _ => None,
+    }
+}
+
+/// Hold the game until a key is pressed, so a burst of bad news doesn't scroll away unread
+fn more_prompt(tcod: &mut Tcod, objects: &[Object], game: &mut Game) {
+    render_all(tcod, objects, game, false);
+    tcod.root.set_default_foreground(colors::YELLOW);
+    tcod.root.print_ex(SCREEN_WIDTH - 1, tcod.layout.screen_height - 1,
+                       BackgroundFlag::None, TextAlignment::Right, "--more--");
+    tcod.root.flush();
+    tcod.root.wait_for_keypress(true);
+}
+
+/// Break the text into lines that fit the width, on word boundaries where possible
+fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.len() + 1 + word.len() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+        while line.len() > width {
+            let rest = line.split_off(width);
+            lines.push(std::mem::replace(&mut line, rest));
+        }
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Full screen view of every message so far grouped by turn, scrolled with the arrows and page keys,
+/// '/' filters the messages by the typed text
+fn show_message_log(log: &Messages, root: &mut Root) {
+    use tcod::input::KeyCode::{Backspace, Down, End, Enter, Escape, Home, PageDown, PageUp, Up};
+
+    let height = root.height();
+    let page = height - 2;
+    let mut query = String::new();
+    let mut typing = false;
+    let mut top: Option<i32> = None;
+    loop {
+        let needle = query.to_lowercase();
+        let mut lines: Vec<(String, colors::Color)> = vec![];
+        let mut last_turn = None;
+        for entry in log.entries.iter().filter(|entry| entry.text.to_lowercase().contains(&needle)) {
+            if last_turn != Some(entry.turn) {
+                lines.push((format!("-- Turn {} --", entry.turn), colors::DARK_GREY));
+                last_turn = Some(entry.turn);
+            }
+            for line in wrap_text(&entry.display(), SCREEN_WIDTH as usize - 2) {
+                lines.push((line, entry.color));
+            }
+        }
+        let last_top = cmp::max(lines.len() as i32 - page, 0);
+        // Start at the newest messages
+        let first = cmp::min(top.unwrap_or(last_top), last_top);
+
+        let mut window = Offscreen::new(SCREEN_WIDTH, height);
+        window.set_default_foreground(colors::LIGHT_GREY);
+        let header = if typing || !query.is_empty() {
+            format!("Search: {}{}", query, if typing { "_" } else { "" })
+        } else {
+            "Message log: arrows and PgUp/PgDn scroll, / searches, Esc closes".to_string()
+        };
+        window.print_ex(1, 0, BackgroundFlag::None, TextAlignment::Left, header);
+        for (row, (line, color)) in lines.iter().skip(first as usize).take(page as usize).enumerate() {
+            window.set_default_foreground(*color);
+            window.print_ex(1, row as i32 + 2, BackgroundFlag::None, TextAlignment::Left, line);
+        }
+        tcod::console::blit(&window, (0, 0), (SCREEN_WIDTH, height), root, (0, 0), 1.0, 1.0);
+        root.flush();
+
+        let key = root.wait_for_keypress(true);
+        if typing {
+            match key.code {
+                Enter | Escape => typing = false,
+                Backspace => {
+                    query.pop();
                 }
+                _ if key.printable != '\0' && !key.printable.is_control() => query.push(key.printable),
+                _ => {}
             }
+            top = None;
+            continue;
         }
+        top = Some(match key.code {
+            Up => cmp::max(first - 1, 0),
+            Down => first + 1,
+            PageUp => cmp::max(first - page, 0),
+            PageDown => first + page,
+            Home => 0,
+            End => last_top,
+            Escape => return,
+            _ if key.printable == '/' => {
+                typing = true;
+                first
+            }
+            _ => first,
+        });
+    }
+}
+
+/// Show something flying over the map, one tile at a time
+fn animate_projectile(
+    tcod: &mut Tcod,
+    objects: &[Object],
+    game: &mut Game,
+    (from, to): ((i32, i32), (i32, i32)),
+    char: char,
+    color: colors::Color,
+) {
+    for (x, y) in tcod::line::Line::new(from, to) {
+        render_all(tcod, objects, game, false);
+        tcod.root.set_default_foreground(color);
+        tcod.root.put_char(x, y, char, BackgroundFlag::None);
+        tcod.root.flush();
+        std::thread::sleep(std::time::Duration::from_millis(PROJECTILE_DELAY));
     }
 }
 
@@ -1676,34 +11241,118 @@ fn msgbox(text: &str, width: i32, root: &mut Root) {
     menu(text, options, width, root);
 }
 
+/// Drawn instead of the background image when it's missing: sewer bricks with the water running below
+fn draw_title_screen(root: &mut Root, screen_height: i32) {
+    root.clear();
+    for y in 0..screen_height {
+        for x in 0..SCREEN_WIDTH {
+            let color = if y >= screen_height - 6 {
+                let ripple = (x * 7 + y * 13) % 5 == 0;
+                if ripple { colors::DARKER_SKY } else { colors::DARKEST_AZURE }
+            } else {
+                // Every other row of bricks is shifted by half a brick
+                let offset = if (y / 2) % 2 == 0 { 0 } else { 3 };
+                let mortar = y % 2 == 1 || (x + offset) % 6 == 0;
+                if mortar { colors::DARKEST_GREY } else { colors::DARKER_SEPIA }
+            };
+            root.set_char_background(x, y, color, BackgroundFlag::Set);
+        }
+    }
+    root.set_default_foreground(colors::LIGHT_YELLOW);
+    root.print_ex(SCREEN_WIDTH / 2, screen_height / 2 - 4, BackgroundFlag::None, TextAlignment::Center,
+                  "SEWERS OF THE DAMNED");
+    root.print_ex(SCREEN_WIDTH / 2, screen_height / 2, BackgroundFlag::None, TextAlignment::Center, "By me");
+}
+
+/// What's waiting in the save, to show next to the menu entry
+fn describe_save(slot: &SaveSlot) -> Option<String> {
+    let (objects, game) = read_save(&slot.file()).or_else(|_| read_save(&slot.backup())).ok()?;
+    let place = match game.objective.arena_wave() {
+        Some(wave) => format!("arena wave {}", wave),
+        None => format!("depth {}", game.dungeon_level),
+    };
+    Some(format!("{}, {} level {}, {}", game.name, game.class.name(), objects[PLAYER].level, place))
+}
+
+/// Play on from the save of the current slot
+fn continue_game(tcod: &mut Tcod) {
+    let (mut objects, mut game, from_backup) = match load_game(&tcod.save_slot) {
+        Ok(saved) => saved,
+        Err(_) if !std::path::Path::new(&tcod.save_slot.file()).exists() => {
+            msgbox("\nNo saved game to load.\n", 24, &mut tcod.root);
+            return;
+        }
+        Err(error) => {
+            warn!("Cannot load {}: {}", tcod.save_slot.file(), error);
+            msgbox("\nThe save can't be read, it may be from an older version of the game.\n", 36,
+                   &mut tcod.root);
+            return;
+        }
+    };
+    if from_backup {
+        msgbox("\nThe save is damaged, continuing from the one before it.\n", 30, &mut tcod.root);
+    }
+    game.wizard |= tcod.wizard;
+    initialise_fov(tcod, &game.map);
+    play_game(&mut objects, &mut game, tcod);
+}
+
 fn main_menu(tcod: &mut Tcod) {
-    let img = tcod::image::Image::from_file("menu_background.png")
-        .ok()
-        .expect("Background image not found.");
+    let img = tcod::image::Image::from_file("menu_background.png").ok();
+    if img.is_none() {
+        warn!("Cannot load menu_background.png, drawing the title screen instead");
+    }
     while !tcod.root.window_closed() {
-        // Show the image at twice the resolution
-        tcod::image::blit_2x(&img, (0, 0), (-1, -1), &mut tcod.root, (0, 0));
-        let choices = &["Play a new game", "Continue last game", "Quit"];
-        let choice = menu("", choices, 24, &mut tcod.root);
+        tcod.music.play(Track::Menu, &tcod.settings);
+        match img.as_ref() {
+            // Show the image at twice the resolution
+            Some(img) => tcod::image::blit_2x(img, (0, 0), (-1, -1), &mut tcod.root, (0, 0)),
+            None => draw_title_screen(&mut tcod.root, tcod.layout.screen_height),
+        }
+        let save = describe_save(&tcod.save_slot);
+        let continue_label = match &save {
+            Some(save) => format!("Continue ({})", save),
+            None => "Continue last game".to_string(),
+        };
+        let choices = &["Play a new game", "Arena", "Tutorial", &continue_label, "Delete save", "High scores",
+                        "Bestiary", "Statistics", "Options", "Quit"];
+        let choice = menu("", choices, 40, &mut tcod.root);
 
         match choice {
-            Some(0) => {
-                let (mut objects, mut game) = new_game(tcod);
+            Some(choice @ 0..=2) => {
+                let mode = [GameMode::Dungeon, GameMode::Arena, GameMode::Tutorial][choice];
+                let (mut objects, mut game) = new_game(mode, tcod);
                 play_game(&mut objects, &mut game, tcod);
             }
-            Some(1) => {
-                match load_game() {
-                    Ok((mut objects, mut game)) => {
-                        initialise_fov(tcod, &game.map);
-                        play_game(&mut objects, &mut game, tcod);
-                    }
-                    Err(_e) => {
-                        msgbox("\nNo saved game to load.\n", 24, &mut tcod.root);
+            Some(3) => {
+                continue_game(tcod);
+            }
+            Some(4) => {
+                let save = match save {
+                    Some(save) => save,
+                    None => {
+                        msgbox("\nNo saved game to delete.\n", 24, &mut tcod.root);
                         continue;
                     }
+                };
+                let header = format!("Delete the saved {}? There is no way back.\n", save);
+                if menu(&header, &["Keep it", "Delete"], 30, &mut tcod.root) == Some(1) {
+                    tcod.save_slot.delete();
                 }
             }
-            Some(2) => {
+            Some(5) => {
+                show_high_scores(&mut tcod.root);
+            }
+            Some(6) => {
+                show_bestiary(&mut tcod.root);
+            }
+            Some(7) => {
+                show_stats(None, &mut tcod.root);
+            }
+            Some(8) => {
+                show_options(tcod);
+            }
+            Some(9) => {
                 break;
             }
             _ => {}
@@ -1711,38 +11360,236 @@ fn main_menu(tcod: &mut Tcod) {
     }
 }
 
-pub fn run_game(font_name: &str, font_layout: FontLayout) -> () {
-    let root = Root::initializer()
-        .font(font_name, font_layout)
-        .font_type(FontType::Default)
-        .size(SCREEN_WIDTH, SCREEN_HEIGHT)
-        .title("SEWERS OF THE DAMNED")
-        .init();
-    tcod::system::set_fps(LIMIT_FPS);
+/////////////////////// Command line
+/// Sewers of the Damned, a roguelike in the sewers under the town
+#[derive(Parser)]
+#[command(version)]
+pub struct Args {
+    /// Font image to use instead of the one picked in the options
+    #[arg(long)]
+    font: Option<String>,
+    /// Order of the glyphs in the font, detected from its name and size if not given
+    #[arg(long, value_enum)]
+    font_layout: Option<FontOrder>,
+    /// Start in fullscreen
+    #[arg(long)]
+    fullscreen: bool,
+    /// Seed of the random generator, the same seed and moves play out the same game
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Continue the game in this save slot right away, and save there
+    #[arg(long, value_name = "SLOT")]
+    load: Option<String>,
+    /// Renderer to use instead of the one picked in the options
+    #[arg(long, value_enum)]
+    backend: Option<RendererKind>,
+    /// Reveal the maps and keep the player alive, these runs don't count for the stats
+    #[arg(long)]
+    wizard: bool,
+    /// Print a generated dungeon level as text and quit, without opening a window
+    #[arg(long)]
+    headless: bool,
+    /// Record the turns, combat rolls and monster decisions to trace.log
+    #[arg(long)]
+    trace: bool,
+}
+
+/// Glyph orders of the libtcod fonts, as in the "_tc", "_ro" and "_as" name suffixes
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum FontOrder {
+    Tcod,
+    Row,
+    Col,
+}
+
+impl FontOrder {
+    fn layout(self) -> FontLayout {
+        match self {
+            FontOrder::Tcod => FontLayout::Tcod,
+            FontOrder::Row => FontLayout::AsciiInRow,
+            FontOrder::Col => FontLayout::AsciiInCol,
+        }
+    }
+}
+
+/// The first dungeon level with everything on it, walls are '#' and floor is '.'
+fn print_level() {
+    let mut player = Object::new(DEFAULT_NAME, 0, 0, '@', colors::WHITE, true);
+    player.player = true;
+    player.alive = true;
+    let mut objects = vec![player];
+    let map = make_map(&mut objects, 1, &Tuning::load(), &Dialogues::load(), &Notes::load());
+    for y in 0..MAP_HEIGHT {
+        let line: String = (0..MAP_WIDTH)
+            .map(|x| match objects.iter().rev().find(|object| object.pos() == (x, y) && !object.hidden) {
+                Some(object) => object.appearance().char,
+                None if map[(x, y)].blocked => '#',
+                None => '.',
+            })
+            .collect();
+        println!("{}", line.trim_end());
+    }
+}
+
+pub fn run_game(args: Args) {
+    if let Err(error) = start_logging(args.trace) {
+        eprintln!("Cannot write {}: {}", TRACE_FILE, error);
+    }
+    let seed = args.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    seed_rng(seed);
+    info!(target: "trace", "seed {}", seed);
+    if args.headless {
+        print_level();
+        return;
+    }
+
+    let mut settings = Settings::load();
+    if args.fullscreen {
+        settings.file_values.fullscreen = Some(settings.fullscreen);
+        settings.fullscreen = true;
+    }
+    if let Some(backend) = args.backend {
+        settings.file_values.renderer = Some(settings.renderer);
+        settings.renderer = backend;
+    }
+    let font_name = match args.font {
+        Some(font_name) => font_name,
+        None => settings.font
+            .clone()
+            .filter(|font| std::path::Path::new(font).exists())
+            .unwrap_or_else(|| DEFAULT_FONT.to_string()),
+    };
+    let font_name = font_name.as_str();
+    let font_layout = args.font_layout.map_or_else(|| detect_font_layout(font_name), FontOrder::layout);
+    let layout = match choose_layout(font_name, &font_layout) {
+        Some(layout) => layout,
+        None => {
+            too_small_screen(font_name, font_layout);
+            return;
+        }
+    };
+    let glyphs = Glyphs::load(matches!(font_layout, FontLayout::AsciiInRow));
+    let mut root = init_root(font_name, font_layout, &layout, &settings);
+    glyphs.map_to_font(&mut root);
+    tcod::system::set_fps(settings.fps);
 
     let mut tcod = Tcod {
         root: root,
         con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
-        panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
-        fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
+        panel: Offscreen::new(SCREEN_WIDTH, layout.panel_height),
+        fov: Vision::new(),
         mouse: Default::default(),
+        layout,
+        settings,
+        bindings: KeyBindings::load(),
+        music: Music::default(),
+        palettes: load_palettes(),
+        glyphs,
+        spectators: Spectators::default(),
+        save_slot: SaveSlot { name: args.load.clone() },
+        seed: args.seed,
+        wizard: args.wizard,
     };
 
     tcod.root.set_default_foreground(colors::LIGHT_YELLOW);
     tcod.root.print_ex(
         SCREEN_WIDTH / 2,
-        SCREEN_HEIGHT / 2 - 4,
+        layout.screen_height / 2 - 4,
         BackgroundFlag::None,
         TextAlignment::Center,
         "SEWERS OF THE DAMNED"
     );
     tcod.root.print_ex(
         SCREEN_WIDTH / 2,
-        SCREEN_HEIGHT / 2,
+        layout.screen_height / 2,
         BackgroundFlag::None,
         TextAlignment::Center,
         "By me",
     );
 
+    if args.load.is_some() {
+        continue_game(&mut tcod);
+    }
     main_menu(&mut tcod);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_tuning_is_valid() {
+        assert!(Tuning::default().validate().is_ok());
+    }
+
+    #[test]
+    fn tuning_rejects_out_of_range_values() {
+        let tuning = Tuning { heal_amount: 0, ..Tuning::default() };
+        assert!(tuning.validate().is_err());
+        let tuning = Tuning { torch_radii: vec![6, -1], ..Tuning::default() };
+        assert!(tuning.validate().is_err());
+        let tuning = Tuning { torch_radii: vec![], ..Tuning::default() };
+        assert!(tuning.validate().is_err());
+    }
+
+    fn table(entries: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        entries
+            .iter()
+            .map(|(action, keys)| (action.to_string(), keys.iter().map(|key| key.to_string()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn key_bindings_fall_back_to_defaults() {
+        let bindings = KeyBindings::from_table(&table(&[("fly", &["f"]), ("wait", &["w"])]));
+        assert_eq!(bindings.keys(Action::Wait), vec!["w"]);
+        assert_eq!(bindings.keys(Action::MoveNorth), Action::MoveNorth.default_keys().to_vec());
+    }
+
+    #[test]
+    fn key_bindings_rename_old_actions() {
+        let bindings = KeyBindings::from_table(&table(&[("save_and_quit", &["q"])]));
+        assert_eq!(bindings.keys(Action::GameMenu), vec!["q"]);
+    }
+
+    #[test]
+    fn key_bindings_drop_shifted_move_keys() {
+        let bindings = KeyBindings::from_table(&table(&[("pick_up", &["K", "g"])]));
+        assert_eq!(bindings.keys(Action::PickUp), vec!["g"]);
+    }
+
+    #[test]
+    fn burden_costs_extra_rounds() {
+        let rounds = |burden: Burden| (0..4).map(|turn| burden.extra_rounds(turn)).sum::<i32>();
+        assert_eq!(rounds(Burden::Unburdened), 0);
+        assert_eq!(rounds(Burden::Burdened), 2);
+        assert_eq!(rounds(Burden::Strained), 4);
+    }
+
+    #[test]
+    fn tutorial_equip_step_waits_for_the_sword() {
+        assert_eq!(TutorialStep::Equip.next(&GameEvent::UsedItem(Item::Sword)), None);
+        assert_eq!(TutorialStep::Equip.next(&GameEvent::Equipped(Item::Shield)), None);
+        assert_eq!(TutorialStep::Equip.next(&GameEvent::Equipped(Item::Sword)), Some(TutorialStep::Scroll));
+    }
+
+    #[test]
+    fn high_scores_keep_the_best() {
+        let entry = |score| HighScore {
+            score,
+            name: String::new(),
+            class: Class::Warrior,
+            character_level: 1,
+            reason: String::new(),
+            turns: 0,
+            arena_wave: None,
+        };
+        let mut high_scores = vec![];
+        for score in 0..HIGH_SCORES as i32 + 5 {
+            add_high_score(&mut high_scores, entry(score));
+        }
+        let scores: Vec<i32> = high_scores.iter().map(|entry| entry.score).collect();
+        let best: Vec<i32> = (5..HIGH_SCORES as i32 + 5).rev().collect();
+        assert_eq!(scores, best);
+    }
+}